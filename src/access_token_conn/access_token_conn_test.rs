@@ -0,0 +1,114 @@
+use super::*;
+
+use stun::error_code::CODE_UNAUTHORIZED;
+use stun::textattrs::TextAttribute;
+use tokio::net::UdpSocket;
+use util::Error;
+
+const KID: &str = "north-1";
+const REALM: &str = "webrtc.rs";
+
+fn test_access_token() -> TurnAccessToken {
+    TurnAccessToken {
+        kid: KID.to_owned(),
+        mac_key: vec![0x5A; 32],
+        access_token: b"opaque token".to_vec(),
+    }
+}
+
+/// Returns the conn under test and the socket standing in for the TURN server.
+async fn access_token_pair() -> Result<(AccessTokenConn, UdpSocket), Error> {
+    let client = UdpSocket::bind("127.0.0.1:0").await?;
+    let server = UdpSocket::bind("127.0.0.1:0").await?;
+    client.connect(server.local_addr()?).await?;
+    server.connect(client.local_addr()?).await?;
+    Ok((
+        AccessTokenConn::new(Arc::new(client), test_access_token()),
+        server,
+    ))
+}
+
+/// Builds a request the way the turn client does, signed with long-term credentials.
+fn signed_request(method: Method) -> Result<Message, Error> {
+    let mut m = Message::new();
+    m.build(&[
+        Box::new(TransactionId::new()),
+        Box::new(MessageType::new(method, CLASS_REQUEST)),
+        Box::new(TextAttribute::new(ATTR_USERNAME, KID.to_owned())),
+        Box::new(TextAttribute::new(ATTR_REALM, REALM.to_owned())),
+        Box::new(TextAttribute::new(ATTR_NONCE, "nonce".to_owned())),
+        Box::new(MessageIntegrity::new_long_term_integrity(
+            KID.to_owned(),
+            REALM.to_owned(),
+            String::new(),
+        )),
+    ])?;
+    Ok(m)
+}
+
+async fn receive(server: &UdpSocket) -> Result<Message, Error> {
+    let mut buf = vec![0u8; 1500];
+    let n = server.recv(&mut buf).await?;
+    let mut m = Message::new();
+    m.unmarshal_binary(&buf[..n])?;
+    Ok(m)
+}
+
+#[tokio::test]
+async fn test_access_token_conn_signs_with_session_key() -> Result<(), Error> {
+    let (conn, server) = access_token_pair().await?;
+
+    let allocate = signed_request(METHOD_ALLOCATE)?;
+    conn.send(&allocate.raw).await?;
+    let mut received = receive(&server).await?;
+    assert_eq!(received.transaction_id, allocate.transaction_id);
+    assert_eq!(
+        received.get(ATTR_ACCESS_TOKEN)?,
+        b"opaque token".to_vec(),
+        "Allocate requests carry the token"
+    );
+    let username = TextAttribute::get_from_as(&received, ATTR_USERNAME)?;
+    assert_eq!(username.text, KID);
+    MessageIntegrity(test_access_token().mac_key).check(&mut received)?;
+
+    // the later requests are only signed
+    let refresh = signed_request(METHOD_REFRESH)?;
+    conn.send(&refresh.raw).await?;
+    let mut received = receive(&server).await?;
+    assert!(!received.contains(ATTR_ACCESS_TOKEN));
+    MessageIntegrity(test_access_token().mac_key).check(&mut received)?;
+
+    // and what isn't signed goes through as is
+    let mut allocate = Message::new();
+    allocate.build(&[
+        Box::new(TransactionId::new()),
+        Box::new(MessageType::new(METHOD_ALLOCATE, CLASS_REQUEST)),
+    ])?;
+    conn.send(&allocate.raw).await?;
+    assert_eq!(receive(&server).await?.raw, allocate.raw);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_access_token_conn_rejected() -> Result<(), Error> {
+    let (conn, server) = access_token_pair().await?;
+
+    let allocate = signed_request(METHOD_ALLOCATE)?;
+    conn.send(&allocate.raw).await?;
+    receive(&server).await?;
+    assert_eq!(conn.rejection(), None);
+
+    let mut response = Message::new();
+    response.build(&[
+        Box::new(allocate.transaction_id),
+        Box::new(MessageType::new(METHOD_ALLOCATE, CLASS_ERROR_RESPONSE)),
+        Box::new(CODE_UNAUTHORIZED),
+    ])?;
+    server.send(&response.raw).await?;
+    let mut buf = vec![0u8; 1500];
+    conn.recv(&mut buf).await?;
+    assert_eq!(conn.rejection(), Some(401), "the server rejected the token");
+
+    Ok(())
+}
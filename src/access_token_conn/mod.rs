@@ -0,0 +1,140 @@
+#[cfg(test)]
+mod access_token_conn_test;
+
+use crate::credential_provider::TurnAccessToken;
+use crate::util::{decode_stun_message, stun_error_code};
+
+use util::Conn;
+
+use async_trait::async_trait;
+use std::collections::HashSet;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+use stun::agent::TransactionId;
+use stun::attributes::*;
+use stun::fingerprint::FINGERPRINT;
+use stun::integrity::MessageIntegrity;
+use stun::message::*;
+
+/// The ACCESS-TOKEN attribute (RFC 7635, section 6.2).
+pub const ATTR_ACCESS_TOKEN: AttrType = AttrType(0x001B);
+
+const BAD_REQUEST: u16 = 400;
+const STALE_NONCE: u16 = 438;
+
+/// Authenticates the requests of a turn client with an OAuth access token (RFC 7635).
+///
+/// The client only does long-term credentials, so it is given the key id as its username and
+/// this conn re-signs what it sends: MESSAGE-INTEGRITY is keyed with the session key instead,
+/// and the Allocate requests carry the token.
+pub struct AccessTokenConn {
+    conn: Arc<dyn Conn + Send + Sync>,
+    access_token: TurnAccessToken,
+    allocations: Mutex<HashSet<TransactionId>>,
+    rejection: AtomicU16,
+}
+
+impl AccessTokenConn {
+    pub fn new(conn: Arc<dyn Conn + Send + Sync>, access_token: TurnAccessToken) -> Self {
+        Self {
+            conn,
+            access_token,
+            allocations: Mutex::new(HashSet::new()),
+            rejection: AtomicU16::new(0),
+        }
+    }
+
+    /// Returns the error code the server answered an Allocate request carrying the token with:
+    /// 401 for a token it doesn't accept, 420 if it doesn't know ACCESS-TOKEN at all.
+    pub fn rejection(&self) -> Option<u16> {
+        match self.rejection.load(Ordering::SeqCst) {
+            0 => None,
+            code => Some(code),
+        }
+    }
+
+    /// Returns `buf` signed with the session key, or `None` if it isn't signed at all.
+    fn sign(&self, buf: &[u8]) -> Option<Vec<u8>> {
+        let m = decode_stun_message(buf)?;
+        if !m.contains(ATTR_MESSAGE_INTEGRITY) {
+            return None;
+        }
+
+        let mut signed = Message::new();
+        signed.typ = m.typ;
+        signed.transaction_id = m.transaction_id;
+        signed.write_header();
+        for attr in &m.attributes.0 {
+            if attr.typ != ATTR_MESSAGE_INTEGRITY && attr.typ != ATTR_FINGERPRINT {
+                signed.add(attr.typ, &attr.value);
+            }
+        }
+        if m.typ == MessageType::new(METHOD_ALLOCATE, CLASS_REQUEST) {
+            signed.add(ATTR_ACCESS_TOKEN, &self.access_token.access_token);
+            self.allocations.lock().unwrap().insert(m.transaction_id);
+        }
+        MessageIntegrity(self.access_token.mac_key.clone())
+            .add_to(&mut signed)
+            .ok()?;
+        if m.contains(ATTR_FINGERPRINT) {
+            FINGERPRINT.add_to(&mut signed).ok()?;
+        }
+        Some(signed.raw)
+    }
+
+    fn on_recv(&self, buf: &[u8]) {
+        let m = match decode_stun_message(buf) {
+            Some(m) if m.typ.class == CLASS_ERROR_RESPONSE => m,
+            _ => return,
+        };
+        if !self.allocations.lock().unwrap().remove(&m.transaction_id) {
+            return;
+        }
+        match stun_error_code(&m) {
+            // the client retries with the new nonce
+            Some(STALE_NONCE) => {}
+            code => self
+                .rejection
+                .store(code.unwrap_or(BAD_REQUEST), Ordering::SeqCst),
+        }
+    }
+}
+
+#[async_trait]
+impl Conn for AccessTokenConn {
+    async fn connect(&self, addr: SocketAddr) -> io::Result<()> {
+        self.conn.connect(addr).await
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.conn.recv(buf).await?;
+        self.on_recv(&buf[..n]);
+        Ok(n)
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let (n, addr) = self.conn.recv_from(buf).await?;
+        self.on_recv(&buf[..n]);
+        Ok((n, addr))
+    }
+
+    async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        match self.sign(buf) {
+            Some(signed) => self.conn.send(&signed).await.map(|_| buf.len()),
+            None => self.conn.send(buf).await,
+        }
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        match self.sign(buf) {
+            Some(signed) => self.conn.send_to(&signed, target).await.map(|_| buf.len()),
+            None => self.conn.send_to(buf, target).await,
+        }
+    }
+
+    async fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.conn.local_addr().await
+    }
+}
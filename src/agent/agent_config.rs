@@ -1,12 +1,21 @@
 use super::*;
+use crate::credential_provider::*;
+use crate::dns_srv::*;
 use crate::errors::*;
 use crate::mdns::*;
+use crate::network_policy::*;
 use crate::network_type::*;
+use crate::port_mapping::*;
+use crate::proxy::*;
+use crate::socket_factory::*;
 use crate::url::*;
 
+#[cfg(feature = "serde")]
+use serde::Deserialize;
 use util::vnet::net::*;
 use util::Error;
 
+use std::net::IpAddr;
 use std::time::Duration;
 
 /// The interval at which the agent performs candidate checks in the connecting phase.
@@ -33,6 +42,12 @@ pub(crate) const DEFAULT_PRFLX_ACCEPTANCE_MIN_WAIT: Duration = Duration::from_mi
 /// Wait time before nominating a relay candidate.
 pub(crate) const DEFAULT_RELAY_ACCEPTANCE_MIN_WAIT: Duration = Duration::from_millis(2000);
 
+/// How long to try resolving the mDNS name of a remote candidate.
+pub(crate) const DEFAULT_MULTICAST_DNS_QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to try resolving the DNS name of a remote candidate.
+pub(crate) const DEFAULT_DNS_RESOLVE_TIMEOUT: Duration = Duration::from_secs(5);
+
 /// Max binding request before considering a pair failed.
 pub(crate) const DEFAULT_MAX_BINDING_REQUESTS: u16 = 7;
 
@@ -51,10 +66,15 @@ pub(crate) fn default_candidate_types() -> Vec<CandidateType> {
 }
 
 pub(crate) type InterfaceFilterFn = Box<dyn (Fn(&str) -> bool) + Send + Sync>;
+pub(crate) type IpFilterFn = Box<dyn (Fn(IpAddr) -> bool) + Send + Sync>;
 
 /// Collects the arguments to `ice::Agent` construction into a single structure, for
 /// future-proofness of the interface.
+///
+/// With the `serde` feature it can be deserialized, e.g. from a configuration file. The fields
+/// holding trait objects or functions are left out, they have to be set in code.
 #[derive(Default)]
+#[cfg_attr(feature = "serde", derive(Deserialize), serde(default))]
 pub struct AgentConfig {
     pub urls: Vec<Url>,
 
@@ -75,9 +95,17 @@ pub struct AgentConfig {
     /// Controls mDNS behavior for the ICE agent.
     pub multicast_dns_mode: MulticastDnsMode,
 
-    /// Controls the hostname for this agent. If none is specified a random one will be generated.
+    /// Controls the mDNS host name of the most preferred host candidate. Every other local IP gets
+    /// a random name of its own, and so do all of them if none is specified.
     pub multicast_dns_host_name: String,
 
+    /// Configures the sockets used for mDNS.
+    pub multicast_dns_config: MulticastDnsConfig,
+
+    /// How long to try resolving the mDNS name of a remote candidate before reporting a
+    /// candidate error. Defaults to 5 seconds when this property is nil.
+    pub multicast_dns_query_timeout: Option<Duration>,
+
     /// Defaults to 5 seconds when this property is nil.
     /// If the duration is 0, the ICE Agent will never go to disconnected.
     pub disconnected_timeout: Option<Duration>,
@@ -124,6 +152,33 @@ pub struct AgentConfig {
     /// instances) and to eliminate the need of server reflexisive candidate gathering.
     pub nat_1to1_ips: Vec<String>,
 
+    /// The typed 1:1 NAT configuration, with port translation, per-interface rules and IPv6
+    /// prefix translation. Supersedes `nat_1to1_ips`, which can't be set along with it.
+    pub nat_mapping: NatMappingConfig,
+
+    /// Asks the local gateway for a port mapping with PCP or NAT-PMP, which is advertised as a
+    /// server reflexive candidate. Requires the srflx candidate type.
+    pub port_mapping: Option<PortMappingConfig>,
+
+    /// How the DNS SRV records of the STUN and TURN URLs without a port are looked up.
+    pub dns_srv: DnsSrvConfig,
+
+    /// Supplies the credentials of the TURN servers before each allocation, so the TURN URLs need
+    /// no username and password. When this is nil, the ones of the URLs are used.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub credential_provider: Option<Arc<dyn CredentialProvider + Send + Sync>>,
+
+    /// An OAuth access token that authorizes the allocations on the TURN servers (RFC 7635). The
+    /// username and password of the URLs are only used when a server rejects it. Tokens from a
+    /// credential provider take precedence.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub turn_access_token: Option<TurnAccessToken>,
+
+    /// Dials the TURN servers of TCP and TLS URLs through a proxy, such as `HttpConnectProxy` or
+    /// `Socks5Proxy`. When this is nil, they are dialed directly.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub proxy_dialer: Option<Arc<dyn ProxyDialer + Send + Sync>>,
+
     /// Specify a minimum wait time before selecting host candidates.
     pub host_acceptance_min_wait: Option<Duration>,
     /// Specify a minimum wait time before selecting srflx candidates.
@@ -135,12 +190,40 @@ pub struct AgentConfig {
 
     /// Net is the our abstracted network interface for internal development purpose only
     /// (see (github.com/pion/transport/vnet)[github.com/pion/transport/vnet]).
+    #[cfg_attr(feature = "serde", serde(skip))]
     pub net: Option<Arc<Net>>,
 
     /// A function that you can use in order to whitelist or blacklist the interfaces which are
     /// used to gather ICE candidates.
+    #[cfg_attr(feature = "serde", serde(skip))]
     pub interface_filter: Option<InterfaceFilterFn>,
 
+    /// A function that you can use in order to whitelist or blacklist the addresses of the
+    /// interfaces which are used to gather ICE candidates, e.g. to drop link-local addresses or
+    /// the ranges of container bridges.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub ip_filter: Option<IpFilterFn>,
+
+    /// The local addresses to gather host candidates from. When set, the interfaces are not
+    /// enumerated, and neither `interface_filter` nor `ip_filter` are applied. The addresses are
+    /// preferred in the given order.
+    pub bind_addresses: Vec<IpAddr>,
+
+    /// Gathers host candidates from loopback addresses too, which is only useful for local
+    /// testing.
+    pub include_loopback: bool,
+
+    /// Classifies the network interfaces and assigns them a cost. Host candidates of cheaper
+    /// interfaces are preferred, and the cheapest valid pair is nominated. When this is nil, it
+    /// defaults to `DefaultNetworkPolicy`, which guesses from the interface names.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub network_policy: Option<Arc<dyn NetworkPolicy + Send + Sync>>,
+
+    /// Creates the sockets of host candidates. When this is nil, the sockets are bound through
+    /// `net`.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub socket_factory: Option<Arc<dyn SocketFactory + Send + Sync>>,
+
     /// Controls if self-signed certificates are accepted when connecting to TURN servers via TLS or
     /// DTLS.
     pub insecure_skip_verify: bool,
@@ -209,33 +292,34 @@ impl AgentConfig {
         mdns_mode: MulticastDnsMode,
         candidate_types: &[CandidateType],
     ) -> Result<Option<ExternalIpMapper>, Error> {
-        if let Some(ext_ip_mapper) =
+        let ext_ip_mapper = if self.nat_mapping.rules.is_empty() {
             ExternalIpMapper::new(self.nat_1to1_ip_candidate_type, &self.nat_1to1_ips)?
-        {
-            if ext_ip_mapper.candidate_type == CandidateType::Host {
-                if mdns_mode == MulticastDnsMode::QueryAndGather {
-                    return Err(ERR_MULTICAST_DNS_WITH_NAT_1TO1_IP_MAPPING.to_owned());
-                }
-                let mut candi_host_enabled = false;
-                for candi_type in candidate_types {
-                    if *candi_type == CandidateType::Host {
-                        candi_host_enabled = true;
-                        break;
+        } else if self.nat_1to1_ips.is_empty() {
+            ExternalIpMapper::from_config(&self.nat_mapping)?
+        } else {
+            return Err(ERR_NAT_MAPPING_WITH_NAT_1TO1_IPS.to_owned());
+        };
+
+        if let Some(ext_ip_mapper) = ext_ip_mapper {
+            match ext_ip_mapper.candidates {
+                NatMappingCandidates::Host => {
+                    if mdns_mode == MulticastDnsMode::QueryAndGather {
+                        return Err(ERR_MULTICAST_DNS_WITH_NAT_1TO1_IP_MAPPING.to_owned());
+                    }
+                    if !candidate_types.contains(&CandidateType::Host) {
+                        return Err(ERR_INEFFECTIVE_NAT_1TO1_IP_MAPPING_HOST.to_owned());
                     }
                 }
-                if !candi_host_enabled {
-                    return Err(ERR_INEFFECTIVE_NAT_1TO1_IP_MAPPING_HOST.to_owned());
-                }
-            } else if ext_ip_mapper.candidate_type == CandidateType::ServerReflexive {
-                let mut candi_srflx_enabled = false;
-                for candi_type in candidate_types {
-                    if *candi_type == CandidateType::ServerReflexive {
-                        candi_srflx_enabled = true;
-                        break;
+                NatMappingCandidates::ServerReflexive => {
+                    if !candidate_types.contains(&CandidateType::ServerReflexive) {
+                        return Err(ERR_INEFFECTIVE_NAT_1TO1_IP_MAPPING_SRFLX.to_owned());
                     }
                 }
-                if !candi_srflx_enabled {
-                    return Err(ERR_INEFFECTIVE_NAT_1TO1_IP_MAPPING_SRFLX.to_owned());
+                // the srflx candidates are derived from the host ones
+                NatMappingCandidates::HostAndServerReflexive => {
+                    if !candidate_types.contains(&CandidateType::Host) {
+                        return Err(ERR_INEFFECTIVE_NAT_1TO1_IP_MAPPING_SRFLX.to_owned());
+                    }
                 }
             }
 
@@ -0,0 +1,72 @@
+use super::*;
+
+use std::fmt;
+use std::sync::PoisonError;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+
+/// An event of an agent, delivered by `Agent::events`.
+#[derive(Clone)]
+pub enum AgentEvent {
+    ConnectionStateChange(ConnectionState),
+    GatheringStateChange(GatheringState),
+    /// A local candidate was gathered. The end of the gathering is a `GatheringStateChange` to
+    /// `Complete`.
+    Candidate(Arc<dyn Candidate + Send + Sync>),
+    /// A local candidate was removed, e.g. because its TURN allocation could not be kept alive.
+    CandidateRemoved(Arc<dyn Candidate + Send + Sync>),
+    CandidateError(CandidateError),
+    SelectedCandidatePairChange {
+        local: Arc<dyn Candidate + Send + Sync>,
+        remote: Arc<dyn Candidate + Send + Sync>,
+    },
+}
+
+impl fmt::Debug for AgentEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ConnectionStateChange(state) => {
+                f.debug_tuple("ConnectionStateChange").field(state).finish()
+            }
+            Self::GatheringStateChange(state) => {
+                f.debug_tuple("GatheringStateChange").field(state).finish()
+            }
+            Self::Candidate(c) => write!(f, "Candidate({})", c),
+            Self::CandidateRemoved(c) => write!(f, "CandidateRemoved({})", c),
+            Self::CandidateError(err) => f.debug_tuple("CandidateError").field(err).finish(),
+            Self::SelectedCandidatePairChange { local, remote } => {
+                write!(f, "SelectedCandidatePairChange({} <-> {})", local, remote)
+            }
+        }
+    }
+}
+
+/// The senders of the event streams of an agent. Events are sent where they happen, usually with
+/// the agent lock held, so the streams are unbounded: a slow reader never holds up the agent.
+#[derive(Default, Clone)]
+pub(crate) struct AgentEventSenders(Arc<std::sync::Mutex<Vec<mpsc::UnboundedSender<AgentEvent>>>>);
+
+impl AgentEventSenders {
+    pub(crate) fn subscribe(&self) -> impl Stream<Item = AgentEvent> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.senders().push(tx);
+        UnboundedReceiverStream::new(rx)
+    }
+
+    /// Sends `event` to the streams, forgetting the dropped ones.
+    pub(crate) fn send(&self, event: &AgentEvent) {
+        self.senders().retain(|tx| tx.send(event.clone()).is_ok());
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.senders().is_empty()
+    }
+
+    /// Ends the streams.
+    pub(crate) fn close(&self) {
+        self.senders().clear();
+    }
+
+    fn senders(&self) -> std::sync::MutexGuard<'_, Vec<mpsc::UnboundedSender<AgentEvent>>> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
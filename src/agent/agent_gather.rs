@@ -1,24 +1,39 @@
 use super::*;
+use crate::credential_provider::*;
+use crate::dns_srv::*;
 use crate::errors::*;
+use crate::mdns::{mdns_conn::*, *};
+use crate::network_policy::*;
 use crate::network_type::*;
+use crate::port_mapping::*;
+use crate::proxy::*;
+use crate::socket_factory::*;
 use crate::url::{ProtoType, SchemeType, Url};
 use crate::util::*;
 
 use util::{vnet::net::*, Conn, Error};
 
+use crate::access_token_conn::AccessTokenConn;
+use crate::allocation_monitor::*;
 use crate::candidate::candidate_base::CandidateBaseConfig;
 use crate::candidate::candidate_host::CandidateHostConfig;
 use crate::candidate::candidate_relay::CandidateRelayConfig;
 use crate::candidate::candidate_server_reflexive::CandidateServerReflexiveConfig;
 use crate::candidate::*;
+use crate::stream_conn::StreamConn;
+use crate::tcp_type::TcpType;
 use defer::defer;
-use std::net::{Ipv4Addr, Ipv6Addr};
-use std::str::FromStr;
+use std::convert::TryFrom;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::sync::Arc;
+use tokio::net::TcpStream;
 use waitgroup::WaitGroup;
 
 const STUN_GATHER_TIMEOUT: Duration = Duration::from_secs(5);
 
+/// The most time left on the credentials of a relay candidate when it is allocated again.
+const CREDENTIALS_RENEWAL_MARGIN: Duration = Duration::from_secs(30);
+
 pub(crate) struct GatherCandidatesInternalParams {
     pub(crate) candidate_types: Vec<CandidateType>,
     pub(crate) urls: Vec<Url>,
@@ -27,12 +42,25 @@ pub(crate) struct GatherCandidatesInternalParams {
     pub(crate) port_min: u16,
     pub(crate) mdns_mode: MulticastDnsMode,
     pub(crate) mdns_name: String,
+    pub(crate) mdns_conn: Option<Arc<MulticastDnsConn>>,
     pub(crate) net: Arc<Net>,
     pub(crate) interface_filter: Arc<Option<InterfaceFilterFn>>,
+    pub(crate) ip_filter: Arc<Option<IpFilterFn>>,
+    pub(crate) bind_addresses: Vec<IpAddr>,
+    pub(crate) include_loopback: bool,
+    pub(crate) network_policy: Arc<dyn NetworkPolicy + Send + Sync>,
+    pub(crate) socket_factory: Arc<dyn SocketFactory + Send + Sync>,
     pub(crate) ext_ip_mapper: Arc<Option<ExternalIpMapper>>,
+    pub(crate) port_mapping: Option<PortMappingConfig>,
+    pub(crate) dns_srv: DnsSrvConfig,
+    pub(crate) credential_provider: Option<Arc<dyn CredentialProvider + Send + Sync>>,
+    pub(crate) turn_access_token: Option<TurnAccessToken>,
+    pub(crate) proxy_dialer: Option<Arc<dyn ProxyDialer + Send + Sync>>,
+    pub(crate) insecure_skip_verify: bool,
     pub(crate) agent_internal: Arc<Mutex<AgentInternal>>,
     pub(crate) gathering_state: Arc<AtomicU8>,
     pub(crate) chan_candidate_tx: ChanCandidateTx,
+    pub(crate) events: AgentEventSenders,
 }
 
 struct GatherCandidatesLocalParams {
@@ -41,7 +69,13 @@ struct GatherCandidatesLocalParams {
     port_min: u16,
     mdns_mode: MulticastDnsMode,
     mdns_name: String,
+    mdns_conn: Option<Arc<MulticastDnsConn>>,
     interface_filter: Arc<Option<InterfaceFilterFn>>,
+    ip_filter: Arc<Option<IpFilterFn>>,
+    bind_addresses: Vec<IpAddr>,
+    include_loopback: bool,
+    network_policy: Arc<dyn NetworkPolicy + Send + Sync>,
+    socket_factory: Arc<dyn SocketFactory + Send + Sync>,
     ext_ip_mapper: Arc<Option<ExternalIpMapper>>,
     net: Arc<Net>,
     agent_internal: Arc<Mutex<AgentInternal>>,
@@ -52,15 +86,40 @@ struct GatherCandidatesSrflxMappedParasm {
     port_max: u16,
     port_min: u16,
     ext_ip_mapper: Arc<Option<ExternalIpMapper>>,
+    network_policy: Arc<dyn NetworkPolicy + Send + Sync>,
+    net: Arc<Net>,
+    agent_internal: Arc<Mutex<AgentInternal>>,
+}
+
+struct GatherCandidatesPortMappingParams {
+    config: PortMappingConfig,
+    network_types: Vec<NetworkType>,
+    port_max: u16,
+    port_min: u16,
+    network_policy: Arc<dyn NetworkPolicy + Send + Sync>,
     net: Arc<Net>,
     agent_internal: Arc<Mutex<AgentInternal>>,
 }
 
+/// How relay candidates are allocated, for the first time and again after an allocation is lost.
+#[derive(Default, Clone)]
+pub(crate) struct RelayAllocationParams {
+    pub(crate) dns_srv: DnsSrvConfig,
+    pub(crate) credential_provider: Option<Arc<dyn CredentialProvider + Send + Sync>>,
+    pub(crate) turn_access_token: Option<TurnAccessToken>,
+    pub(crate) proxy_dialer: Option<Arc<dyn ProxyDialer + Send + Sync>>,
+    pub(crate) insecure_skip_verify: bool,
+    /// Classifies the interface of the relay candidates, it is unknown without one.
+    pub(crate) network_policy: Option<Arc<dyn NetworkPolicy + Send + Sync>>,
+}
+
 struct GatherCandidatesSrflxParams {
     urls: Vec<Url>,
     network_types: Vec<NetworkType>,
     port_max: u16,
     port_min: u16,
+    dns_srv: DnsSrvConfig,
+    network_policy: Arc<dyn NetworkPolicy + Send + Sync>,
     net: Arc<Net>,
     agent_internal: Arc<Mutex<AgentInternal>>,
 }
@@ -69,10 +128,10 @@ impl Agent {
     pub(crate) async fn gather_candidates_internal(params: GatherCandidatesInternalParams) {
         Self::set_gathering_state(
             &params.chan_candidate_tx,
+            &params.events,
             &params.gathering_state,
             GatheringState::Gathering,
-        )
-        .await;
+        );
 
         let wg = WaitGroup::new();
 
@@ -86,7 +145,13 @@ impl Agent {
                         port_min: params.port_min,
                         mdns_mode: params.mdns_mode,
                         mdns_name: params.mdns_name.clone(),
+                        mdns_conn: params.mdns_conn.clone(),
                         interface_filter: Arc::clone(&params.interface_filter),
+                        ip_filter: Arc::clone(&params.ip_filter),
+                        bind_addresses: params.bind_addresses.clone(),
+                        include_loopback: params.include_loopback,
+                        network_policy: Arc::clone(&params.network_policy),
+                        socket_factory: Arc::clone(&params.socket_factory),
                         ext_ip_mapper: Arc::clone(&params.ext_ip_mapper),
                         net: Arc::clone(&params.net),
                         agent_internal: Arc::clone(&params.agent_internal),
@@ -107,6 +172,8 @@ impl Agent {
                         network_types: params.network_types.clone(),
                         port_max: params.port_max,
                         port_min: params.port_min,
+                        dns_srv: params.dns_srv.clone(),
+                        network_policy: Arc::clone(&params.network_policy),
                         net: Arc::clone(&params.net),
                         agent_internal: Arc::clone(&params.agent_internal),
                     };
@@ -125,6 +192,7 @@ impl Agent {
                                 port_max: params.port_max,
                                 port_min: params.port_min,
                                 ext_ip_mapper: Arc::clone(&params.ext_ip_mapper),
+                                network_policy: Arc::clone(&params.network_policy),
                                 net: Arc::clone(&params.net),
                                 agent_internal: Arc::clone(&params.agent_internal),
                             };
@@ -137,10 +205,41 @@ impl Agent {
                             });
                         }
                     }
+                    if let Some(port_mapping) = &params.port_mapping {
+                        let w3 = wg.worker();
+                        let port_mapping_params = GatherCandidatesPortMappingParams {
+                            config: port_mapping.clone(),
+                            network_types: params.network_types.clone(),
+                            port_max: params.port_max,
+                            port_min: params.port_min,
+                            network_policy: Arc::clone(&params.network_policy),
+                            net: Arc::clone(&params.net),
+                            agent_internal: Arc::clone(&params.agent_internal),
+                        };
+                        tokio::spawn(async move {
+                            let _d = defer(move || {
+                                drop(w3);
+                            });
+
+                            if let Err(err) =
+                                Self::gather_candidates_port_mapping(port_mapping_params).await
+                            {
+                                log::warn!("Failed to gather a port mapping candidate: {}", err);
+                            }
+                        });
+                    }
                 }
                 CandidateType::Relay => {
                     let w = wg.worker();
                     let urls = params.urls.clone();
+                    let relay_params = RelayAllocationParams {
+                        dns_srv: params.dns_srv.clone(),
+                        credential_provider: params.credential_provider.clone(),
+                        turn_access_token: params.turn_access_token.clone(),
+                        proxy_dialer: params.proxy_dialer.clone(),
+                        insecure_skip_verify: params.insecure_skip_verify,
+                        network_policy: Some(Arc::clone(&params.network_policy)),
+                    };
                     let net = Arc::clone(&params.net);
                     let agent_internal = Arc::clone(&params.agent_internal);
                     tokio::spawn(async move {
@@ -148,7 +247,8 @@ impl Agent {
                             drop(w);
                         });
 
-                        Self::gather_candidates_relay(urls, net, agent_internal).await;
+                        Self::gather_candidates_relay(urls, relay_params, net, agent_internal)
+                            .await;
                     });
                 }
                 _ => {}
@@ -160,22 +260,24 @@ impl Agent {
 
         Self::set_gathering_state(
             &params.chan_candidate_tx,
+            &params.events,
             &params.gathering_state,
             GatheringState::Complete,
-        )
-        .await;
+        );
     }
 
-    async fn set_gathering_state(
+    fn set_gathering_state(
         chan_candidate_tx: &ChanCandidateTx,
+        events: &AgentEventSenders,
         gathering_state: &Arc<AtomicU8>,
         new_state: GatheringState,
     ) {
-        if GatheringState::from(gathering_state.load(Ordering::SeqCst)) != new_state
-            && new_state == GatheringState::Complete
-        {
-            if let Some(tx) = chan_candidate_tx {
-                let _ = tx.send(None).await;
+        if GatheringState::from(gathering_state.load(Ordering::SeqCst)) != new_state {
+            events.send(&AgentEvent::GatheringStateChange(new_state));
+            if new_state == GatheringState::Complete {
+                if let Some(tx) = chan_candidate_tx {
+                    let _ = tx.send(None);
+                }
             }
         }
 
@@ -189,7 +291,13 @@ impl Agent {
             port_min,
             mdns_mode,
             mdns_name,
+            mdns_conn,
             interface_filter,
+            ip_filter,
+            bind_addresses,
+            include_loopback,
+            network_policy,
+            socket_factory,
             ext_ip_mapper,
             net,
             agent_internal,
@@ -199,36 +307,50 @@ impl Agent {
             params.port_min,
             params.mdns_mode,
             params.mdns_name,
+            params.mdns_conn,
             params.interface_filter,
+            params.ip_filter,
+            params.bind_addresses,
+            params.include_loopback,
+            params.network_policy,
+            params.socket_factory,
             params.ext_ip_mapper,
             params.net,
             params.agent_internal,
         );
 
-        let ips = local_interfaces(&net, &*interface_filter, &network_types).await;
-        for ip in ips {
-            let mut mapped_ip = ip;
-
-            if mdns_mode != MulticastDnsMode::QueryAndGather && ext_ip_mapper.is_some() {
-                if let Some(ext_ip_mapper2) = &*ext_ip_mapper {
-                    if ext_ip_mapper2.candidate_type == CandidateType::Host {
-                        if let Ok(mi) = ext_ip_mapper2.find_external_ip(&ip.to_string()) {
-                            mapped_ip = mi;
-                        } else {
-                            log::warn!(
-                                "1:1 NAT mapping is enabled but no external IP is found for {}",
-                                ip
-                            );
-                        }
-                    }
-                }
-            }
-
-            let address = if mdns_mode == MulticastDnsMode::QueryAndGather {
-                mdns_name.clone()
-            } else {
-                mapped_ip.to_string()
-            };
+        let addrs = if bind_addresses.is_empty() {
+            local_interfaces(
+                &net,
+                &*interface_filter,
+                &ip_filter,
+                include_loopback,
+                &*network_policy,
+                &network_types,
+            )
+            .await
+        } else {
+            bind_interface_addrs(&bind_addresses, &network_types)
+        };
+        // the configured host name goes to the most preferred address, the others get fresh
+        // names, so that the candidates of different IPs can't be correlated
+        let mut static_mdns_name = Some(mdns_name).filter(|name| !name.is_empty());
+
+        // the addresses come most preferred first, so their position is the preference rank
+        for (rank, local_addr) in addrs.into_iter().enumerate() {
+            let ip = local_addr.ip;
+
+            // the 1:1 NAT mappings translating the host candidates' addresses, either in place
+            // or into srflx siblings
+            let host_ext_ip_mapper = (*ext_ip_mapper).as_ref().filter(|m| {
+                m.candidates == NatMappingCandidates::HostAndServerReflexive
+                    || (m.candidates == NatMappingCandidates::Host
+                        && mdns_mode != MulticastDnsMode::QueryAndGather)
+            });
+            // only the forwarded local ports are reachable from the external ones
+            let (bind_port_max, bind_port_min) = host_ext_ip_mapper
+                .and_then(|m| m.local_port_range(ip, &local_addr.interface_name))
+                .map_or((port_max, port_min), |(min, max)| (max, min));
 
             //TODO: for network in networks
             let network = UDP.to_owned();
@@ -252,9 +374,9 @@ impl Agent {
                 case udp:*/
 
                 let conn: Arc<dyn Conn + Send + Sync> = match listen_udp_in_port_range(
-                    &net,
-                    port_max,
-                    port_min,
+                    &*socket_factory,
+                    bind_port_max,
+                    bind_port_min,
                     SocketAddr::new(ip, 0),
                 )
                 .await
@@ -274,13 +396,49 @@ impl Agent {
                     }
                 };
 
+                let mapped_addr = host_ext_ip_mapper.and_then(|m| {
+                    match m.find_external_addr(
+                        SocketAddr::new(ip, port),
+                        &local_addr.interface_name,
+                    ) {
+                        Ok(mapped_addr) => Some((m.candidates, mapped_addr)),
+                        Err(err) => {
+                            log::warn!(
+                                "1:1 NAT mapping is enabled but no external address is found for {}:{}: {}",
+                                ip,
+                                port,
+                                err
+                            );
+                            None
+                        }
+                    }
+                });
+                let (mapped_ip, mapped_port) = match mapped_addr {
+                    Some((NatMappingCandidates::Host, mapped_addr)) => {
+                        (mapped_addr.ip(), mapped_addr.port())
+                    }
+                    _ => (ip, port),
+                };
+
+                let address = if mdns_mode == MulticastDnsMode::QueryAndGather {
+                    static_mdns_name
+                        .take()
+                        .unwrap_or_else(generate_multicast_dns_name)
+                } else {
+                    mapped_ip.to_string()
+                };
+
                 let host_config = CandidateHostConfig {
                     base_config: CandidateBaseConfig {
                         network: network.clone(),
                         address,
-                        port,
+                        port: mapped_port,
                         component: COMPONENT_RTP,
-                        conn: Some(conn),
+                        local_preference_rank: u16::try_from(rank).unwrap_or(u16::MAX),
+                        network_id: local_addr.network_id,
+                        network_cost: local_addr.network_cost,
+                        interface_type: local_addr.interface_type,
+                        conn: Some(Arc::clone(&conn)),
                         ..CandidateBaseConfig::default()
                     },
                     ..CandidateHostConfig::default()
@@ -302,6 +460,9 @@ impl Agent {
                                 );
                                 continue;
                             }
+                            if let Some(mdns_conn) = &mdns_conn {
+                                mdns_conn.register(&candidate.address(), ip).await;
+                            }
                         }
                         Arc::new(candidate)
                     }
@@ -323,22 +484,95 @@ impl Agent {
                         if let Err(close_err) = candidate.close().await {
                             log::warn!("Failed to close candidate: {}", close_err);
                         }
+                        ai.unregister_multicast_dns_name(&candidate).await;
                         log::warn!(
                             "Failed to append to localCandidates and run onCandidateHdlr: {}",
                             err
                         );
+                        continue;
                     }
                 }
+
+                if let Some((NatMappingCandidates::HostAndServerReflexive, mapped_addr)) =
+                    mapped_addr
+                {
+                    // the local address stays hidden behind the mDNS name
+                    let rel_addr = if mdns_mode == MulticastDnsMode::QueryAndGather {
+                        if ip.is_ipv4() {
+                            Ipv4Addr::UNSPECIFIED.to_string()
+                        } else {
+                            Ipv6Addr::UNSPECIFIED.to_string()
+                        }
+                    } else {
+                        ip.to_string()
+                    };
+                    let srflx_config = CandidateServerReflexiveConfig {
+                        base_config: CandidateBaseConfig {
+                            network: network.clone(),
+                            address: mapped_addr.ip().to_string(),
+                            port: mapped_addr.port(),
+                            component: COMPONENT_RTP,
+                            local_preference_rank: u16::try_from(rank).unwrap_or(u16::MAX),
+                            network_id: local_addr.network_id,
+                            network_cost: local_addr.network_cost,
+                            interface_type: local_addr.interface_type,
+                            ..CandidateBaseConfig::default()
+                        },
+                        rel_addr,
+                        rel_port: port,
+                        tcp_type: TcpType::Unspecified,
+                    };
+
+                    Self::add_mapped_srflx_candidate(srflx_config, &agent_internal).await;
+                }
             }
         }
     }
 
+    /// Adds the srflx sibling of a host candidate. It has no socket of its own, the host candidate
+    /// is its base and receives the traffic sent to the mapped address.
+    async fn add_mapped_srflx_candidate(
+        srflx_config: CandidateServerReflexiveConfig,
+        agent_internal: &Arc<Mutex<AgentInternal>>,
+    ) {
+        let (network, address, port) = (
+            srflx_config.base_config.network.clone(),
+            srflx_config.base_config.address.clone(),
+            srflx_config.base_config.port,
+        );
+        let candidate: Arc<dyn Candidate + Send + Sync> = match srflx_config
+            .new_candidate_server_reflexive(Some(Arc::clone(agent_internal)))
+            .await
+        {
+            Ok(candidate) => Arc::new(candidate),
+            Err(err) => {
+                log::warn!(
+                    "Failed to create server reflexive candidate: {} {} {}: {}",
+                    network,
+                    address,
+                    port,
+                    err
+                );
+                return;
+            }
+        };
+
+        let mut ai = agent_internal.lock().await;
+        if let Err(err) = ai.add_candidate(&candidate).await {
+            log::warn!(
+                "Failed to append to localCandidates and run onCandidateHdlr: {}",
+                err
+            );
+        }
+    }
+
     async fn gather_candidates_srflx_mapped(params: GatherCandidatesSrflxMappedParasm) {
-        let (network_types, port_max, port_min, ext_ip_mapper, net, agent_internal) = (
+        let (network_types, port_max, port_min, ext_ip_mapper, network_policy, net, agent_internal) = (
             params.network_types,
             params.port_max,
             params.port_min,
             params.ext_ip_mapper,
+            params.network_policy,
             params.net,
             params.agent_internal,
         );
@@ -355,21 +589,27 @@ impl Agent {
             let net2 = Arc::clone(&net);
             let agent_internal2 = Arc::clone(&agent_internal);
             let ext_ip_mapper2 = Arc::clone(&ext_ip_mapper);
+            let network_policy = Arc::clone(&network_policy);
 
             tokio::spawn(async move {
                 let _d = defer(move || {
                     drop(w);
                 });
 
+                let bind_ip: IpAddr = if network_type.is_ipv4() {
+                    Ipv4Addr::new(0, 0, 0, 0).into()
+                } else {
+                    Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0).into()
+                };
+                let (bind_port_max, bind_port_min) = (*ext_ip_mapper2)
+                    .as_ref()
+                    .and_then(|m| m.local_port_range(bind_ip, ""))
+                    .map_or((port_max, port_min), |(min, max)| (max, min));
                 let conn: Arc<dyn Conn + Send + Sync> = match listen_udp_in_port_range(
-                    &net2,
-                    port_max,
-                    port_min,
-                    if network_type.is_ipv4() {
-                        SocketAddr::new(Ipv4Addr::new(0, 0, 0, 0).into(), 0)
-                    } else {
-                        SocketAddr::new(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0).into(), 0)
-                    },
+                    &NetSocketFactory::new(Arc::clone(&net2)),
+                    bind_port_max,
+                    bind_port_min,
+                    SocketAddr::new(bind_ip, 0),
                 )
                 .await
                 {
@@ -381,10 +621,15 @@ impl Agent {
                 };
 
                 let laddr = conn.local_addr().await?;
-                let mapped_ip = {
+                let (mapped_addr, rule_index) = {
                     if let Some(ext_ip_mapper3) = &*ext_ip_mapper2 {
-                        match ext_ip_mapper3.find_external_ip(&laddr.ip().to_string()) {
-                            Ok(ip) => ip,
+                        match ext_ip_mapper3.find_external_addr(laddr, "") {
+                            Ok(addr) => (
+                                addr,
+                                ext_ip_mapper3
+                                    .rule_index(laddr.ip(), "")
+                                    .unwrap_or_default(),
+                            ),
                             Err(err) => {
                                 log::warn!(
                                     "1:1 NAT mapping is enabled but no external IP is found for {}: {}",
@@ -400,17 +645,28 @@ impl Agent {
                     }
                 };
 
+                // the socket isn't bound to an interface, the one towards the outside is used
+                let base = base_interface_addr(&net2, &*network_policy, laddr, mapped_addr).await;
+                // rules keep their configured order, IPv6 goes before IPv4 for each of them
+                let local_preference_rank =
+                    u16::try_from(rule_index * 2 + usize::from(mapped_addr.is_ipv4()))
+                        .unwrap_or(u16::MAX);
                 let srflx_config = CandidateServerReflexiveConfig {
                     base_config: CandidateBaseConfig {
                         network: network.clone(),
-                        address: mapped_ip.to_string(),
-                        port: laddr.port(),
+                        address: mapped_addr.ip().to_string(),
+                        port: mapped_addr.port(),
                         component: COMPONENT_RTP,
+                        local_preference_rank,
+                        network_id: base.network_id,
+                        network_cost: base.network_cost,
+                        interface_type: base.interface_type,
                         conn: Some(conn),
                         ..CandidateBaseConfig::default()
                     },
                     rel_addr: laddr.ip().to_string(),
                     rel_port: laddr.port(),
+                    tcp_type: TcpType::Unspecified,
                 };
 
                 let candidate: Arc<dyn Candidate + Send + Sync> = match srflx_config
@@ -420,10 +676,9 @@ impl Agent {
                     Ok(candidate) => Arc::new(candidate),
                     Err(err) => {
                         log::warn!(
-                            "Failed to create server reflexive candidate: {} {} {}: {}",
+                            "Failed to create server reflexive candidate: {} {}: {}",
                             network,
-                            mapped_ip,
-                            laddr.port(),
+                            mapped_addr,
                             err
                         );
                         return Ok(());
@@ -450,27 +705,277 @@ impl Agent {
         wg.wait().await;
     }
 
+    /// Asks the gateway to map the port of a new socket, and advertises the mapped address.
+    async fn gather_candidates_port_mapping(
+        params: GatherCandidatesPortMappingParams,
+    ) -> Result<(), Error> {
+        let (config, network_types, port_max, port_min, network_policy, net, agent_internal) = (
+            params.config,
+            params.network_types,
+            params.port_max,
+            params.port_min,
+            params.network_policy,
+            params.net,
+            params.agent_internal,
+        );
+
+        let gateway = config
+            .gateway
+            .or_else(|| {
+                if net.is_virtual() {
+                    None
+                } else {
+                    default_gateway()
+                }
+            })
+            .ok_or_else(|| ERR_NO_DEFAULT_GATEWAY.to_owned())?;
+        let network_type = if gateway.is_ipv4() {
+            NetworkType::Udp4
+        } else {
+            NetworkType::Udp6
+        };
+        if !network_types.contains(&network_type) {
+            return Ok(());
+        }
+        let server_addr = SocketAddr::new(gateway, PORT_MAPPING_SERVER_PORT);
+
+        // the gateway only maps ports of the address it sees the requests from
+        let local_ip = local_ip_towards(&net, server_addr).await?;
+        let conn: Arc<dyn Conn + Send + Sync> = listen_udp_in_port_range(
+            &NetSocketFactory::new(Arc::clone(&net)),
+            port_max,
+            port_min,
+            SocketAddr::new(local_ip, 0),
+        )
+        .await?;
+        let laddr = conn.local_addr().await?;
+
+        let lifetime = config.lifetime.unwrap_or(DEFAULT_PORT_MAPPING_LIFETIME);
+        let mut mapping = PortMapping::request(&net, gateway, laddr, lifetime).await?;
+
+        let base = base_interface_addr(&net, &*network_policy, laddr, server_addr).await;
+        let srflx_config = CandidateServerReflexiveConfig {
+            base_config: CandidateBaseConfig {
+                network: network_type.to_string(),
+                address: mapping.external.ip().to_string(),
+                port: mapping.external.port(),
+                component: COMPONENT_RTP,
+                network_id: base.network_id,
+                network_cost: base.network_cost,
+                interface_type: base.interface_type,
+                conn: Some(conn),
+                ..CandidateBaseConfig::default()
+            },
+            rel_addr: laddr.ip().to_string(),
+            rel_port: laddr.port(),
+            tcp_type: TcpType::Unspecified,
+        };
+        let candidate: Arc<dyn Candidate + Send + Sync> = Arc::new(
+            srflx_config
+                .new_candidate_server_reflexive(Some(Arc::clone(&agent_internal)))
+                .await?,
+        );
+
+        let close_rx = {
+            let mut ai = agent_internal.lock().await;
+            match ai.add_candidate(&candidate).await {
+                Ok(()) => ai
+                    .port_mapping_close_tx
+                    .as_ref()
+                    .map(broadcast::Sender::subscribe),
+                Err(err) => {
+                    if let Err(close_err) = candidate.close().await {
+                        log::warn!("Failed to close candidate: {}", close_err);
+                    }
+                    log::warn!(
+                        "Failed to append to localCandidates and run onCandidateHdlr: {}",
+                        err
+                    );
+                    None
+                }
+            }
+        };
+
+        if let Some(close_rx) = close_rx {
+            tokio::spawn(async move {
+                Self::keep_port_mapping(
+                    &net,
+                    &mut mapping,
+                    lifetime,
+                    candidate,
+                    agent_internal,
+                    close_rx,
+                )
+                .await;
+            });
+        } else if let Err(err) = mapping.release(&net).await {
+            log::debug!(
+                "Failed to release port mapping {}: {}",
+                mapping.external,
+                err
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Returns a candidate like the one of a port mapping, for the external address of the
+    /// mapping. It shares the socket of the candidate it replaces.
+    async fn port_mapping_candidate(
+        candidate: &Arc<dyn Candidate + Send + Sync>,
+        mapping: &PortMapping,
+        agent_internal: &Arc<Mutex<AgentInternal>>,
+    ) -> Result<Arc<dyn Candidate + Send + Sync>, Error> {
+        let srflx_config = CandidateServerReflexiveConfig {
+            base_config: CandidateBaseConfig {
+                network: candidate.network_type().to_string(),
+                address: mapping.external.ip().to_string(),
+                port: mapping.external.port(),
+                component: candidate.component(),
+                network_id: candidate.network_id(),
+                network_cost: candidate.network_cost(),
+                interface_type: candidate.interface_type(),
+                conn: candidate.get_conn().cloned(),
+                ..CandidateBaseConfig::default()
+            },
+            rel_addr: mapping.internal.ip().to_string(),
+            rel_port: mapping.internal.port(),
+            tcp_type: TcpType::Unspecified,
+        };
+        Ok(Arc::new(
+            srflx_config
+                .new_candidate_server_reflexive(Some(Arc::clone(agent_internal)))
+                .await?,
+        ))
+    }
+
+    /// Renews the mapping halfway through its lifetime until the agent closes or restarts, then
+    /// releases it. A renewal that fails is retried before the mapping expires, and the candidate
+    /// is replaced when the gateway maps the port to another address, or removed once the mapping
+    /// expired.
+    async fn keep_port_mapping(
+        net: &Arc<Net>,
+        mapping: &mut PortMapping,
+        lifetime: Duration,
+        mut candidate: Arc<dyn Candidate + Send + Sync>,
+        agent_internal: Arc<Mutex<AgentInternal>>,
+        mut close_rx: broadcast::Receiver<()>,
+    ) {
+        let mut expires_at = Instant::now() + mapping.lifetime;
+        let mut renew_in = (mapping.lifetime / 2).max(Duration::from_secs(1));
+        loop {
+            tokio::select! {
+                () = tokio::time::sleep(renew_in) => {}
+                _ = close_rx.recv() => break,
+            }
+
+            let external = mapping.external;
+            if let Err(err) = mapping.renew(net, lifetime).await {
+                let left = expires_at.saturating_duration_since(Instant::now());
+                if left == Duration::from_secs(0) {
+                    log::warn!("Port mapping {} expired: {}", external, err);
+                    agent_internal
+                        .lock()
+                        .await
+                        .remove_candidate(&candidate)
+                        .await;
+                    break;
+                }
+                log::warn!("Failed to renew port mapping {}: {}", external, err);
+                renew_in = (left / 2).max(Duration::from_secs(1)).min(left);
+                continue;
+            }
+            expires_at = Instant::now() + mapping.lifetime;
+            renew_in = (mapping.lifetime / 2).max(Duration::from_secs(1));
+            if mapping.external == external {
+                continue;
+            }
+
+            log::debug!(
+                "Port mapping of {} moved from {} to {}",
+                mapping.internal,
+                external,
+                mapping.external
+            );
+            let new_candidate =
+                match Self::port_mapping_candidate(&candidate, mapping, &agent_internal).await {
+                    Ok(new_candidate) => new_candidate,
+                    Err(err) => {
+                        log::warn!("Failed to create port mapping candidate: {}", err);
+                        agent_internal
+                            .lock()
+                            .await
+                            .remove_candidate(&candidate)
+                            .await;
+                        break;
+                    }
+                };
+
+            let mut ai = agent_internal.lock().await;
+            if !ai.remove_candidate(&candidate).await {
+                // removed by a restart or close in the meantime
+                let _ = new_candidate.close().await;
+                break;
+            }
+            if let Err(err) = ai.add_candidate(&new_candidate).await {
+                let _ = new_candidate.close().await;
+                log::warn!("Failed to add moved port mapping candidate: {}", err);
+                break;
+            }
+            drop(ai);
+            candidate = new_candidate;
+        }
+
+        if let Err(err) = mapping.release(net).await {
+            log::debug!(
+                "Failed to release port mapping {}: {}",
+                mapping.external,
+                err
+            );
+        }
+    }
+
     async fn gather_candidates_srflx(params: GatherCandidatesSrflxParams) {
-        let (urls, network_types, port_max, port_min, net, agent_internal) = (
+        let (urls, network_types, port_max, port_min, dns_srv, network_policy, net, agent_internal) = (
             params.urls,
             params.network_types,
             params.port_max,
             params.port_min,
+            params.dns_srv,
+            params.network_policy,
             params.net,
             params.agent_internal,
         );
 
+        // The mapping of an outbound TCP connection is only reachable with a listening ICE-TCP
+        // base behind it, and there are no TCP bases to discover a mapping for.
+        for url in urls.iter().filter(|url| url.proto == ProtoType::Tcp) {
+            log::warn!(
+                "Not gathering server reflexive candidates over TCP from {}, there are no ICE-TCP bases",
+                url
+            );
+        }
+
         let wg = WaitGroup::new();
         for network_type in network_types {
             if network_type.is_tcp() {
                 continue;
             }
 
-            for url in &urls {
+            for (url_index, url) in urls.iter().enumerate() {
+                if url.proto == ProtoType::Tcp {
+                    continue;
+                }
+
                 let w = wg.worker();
                 let network = network_type.to_string();
                 let is_ipv4 = network_type.is_ipv4();
+                // servers keep their configured order, IPv6 goes before IPv4 for each of them
+                let local_preference_rank =
+                    u16::try_from(url_index * 2 + usize::from(is_ipv4)).unwrap_or(u16::MAX);
                 let url = url.clone();
+                let dns_srv = dns_srv.clone();
+                let network_policy = Arc::clone(&network_policy);
                 let net2 = Arc::clone(&net);
                 let agent_internal2 = Arc::clone(&agent_internal);
 
@@ -479,62 +984,84 @@ impl Agent {
                         drop(w);
                     });
 
-                    let host_port = format!("{}:{}", url.host, url.port);
-                    let server_addr = match net2.resolve_addr(is_ipv4, &host_port).await {
-                        Ok(addr) => addr,
-                        Err(err) => {
-                            log::warn!("failed to resolve stun host: {}: {}", host_port, err);
-                            return Ok(());
-                        }
-                    };
+                    // the SRV targets are tried in order until one of them answers
+                    let mut mapping = None;
+                    for (host, port) in resolve_url_targets(&net2, &dns_srv, &url).await {
+                        let host_port = format!("{}:{}", host, port);
+                        let server_addr = match net2.resolve_addr(is_ipv4, &host_port).await {
+                            Ok(addr) => addr,
+                            Err(err) => {
+                                log::warn!("failed to resolve stun host: {}: {}", host_port, err);
+                                continue;
+                            }
+                        };
 
-                    let conn: Arc<dyn Conn + Send + Sync> = match listen_udp_in_port_range(
-                        &net2,
-                        port_max,
-                        port_min,
-                        if is_ipv4 {
-                            SocketAddr::new(Ipv4Addr::new(0, 0, 0, 0).into(), 0)
-                        } else {
-                            SocketAddr::new(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0).into(), 0)
-                        },
-                    )
-                    .await
-                    {
-                        Ok(conn) => conn,
-                        Err(err) => {
-                            log::warn!("Failed to listen for {}: {}", server_addr, err);
-                            return Ok(());
-                        }
-                    };
+                        let conn: Arc<dyn Conn + Send + Sync> = match listen_udp_in_port_range(
+                            &NetSocketFactory::new(Arc::clone(&net2)),
+                            port_max,
+                            port_min,
+                            if is_ipv4 {
+                                SocketAddr::new(Ipv4Addr::new(0, 0, 0, 0).into(), 0)
+                            } else {
+                                SocketAddr::new(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0).into(), 0)
+                            },
+                        )
+                        .await
+                        {
+                            Ok(conn) => conn,
+                            Err(err) => {
+                                log::warn!("Failed to listen for {}: {}", server_addr, err);
+                                return Ok(());
+                            }
+                        };
 
-                    let xoraddr =
                         match get_xormapped_addr(&conn, server_addr, STUN_GATHER_TIMEOUT).await {
-                            Ok(xoraddr) => xoraddr,
+                            Ok(xoraddr) => {
+                                mapping = Some((conn, xoraddr, server_addr));
+                                break;
+                            }
                             Err(err) => {
                                 log::warn!(
-                                    "could not get server reflexive address {} {}: {}",
+                                    "could not get server reflexive address {} {} from {}: {}",
                                     network,
                                     url,
+                                    host_port,
                                     err
                                 );
-                                return Ok(());
                             }
-                        };
+                        }
+                    }
+                    let (conn, xoraddr, server_addr) = match mapping {
+                        Some((conn, xoraddr, server_addr)) => (conn, xoraddr, server_addr),
+                        None => {
+                            #[cfg(feature = "metrics")]
+                            METRICS.on_gather_failure(&url.to_string());
+                            return Ok(());
+                        }
+                    };
 
                     let (ip, port) = (xoraddr.ip, xoraddr.port);
 
                     let laddr = conn.local_addr().await?;
+                    let base =
+                        base_interface_addr(&net2, &*network_policy, laddr, server_addr).await;
                     let srflx_config = CandidateServerReflexiveConfig {
                         base_config: CandidateBaseConfig {
                             network: network.clone(),
                             address: ip.to_string(),
                             port,
                             component: COMPONENT_RTP,
+                            local_preference_rank,
+                            network_id: base.network_id,
+                            network_cost: base.network_cost,
+                            interface_type: base.interface_type,
+                            url: Some(url.without_credentials()),
                             conn: Some(conn),
                             ..CandidateBaseConfig::default()
                         },
                         rel_addr: laddr.ip().to_string(),
                         rel_port: laddr.port(),
+                        tcp_type: TcpType::Unspecified,
                     };
 
                     let candidate: Arc<dyn Candidate + Send + Sync> = match srflx_config
@@ -577,26 +1104,32 @@ impl Agent {
 
     pub(crate) async fn gather_candidates_relay(
         urls: Vec<Url>,
+        relay_params: RelayAllocationParams,
         net: Arc<Net>,
         agent_internal: Arc<Mutex<AgentInternal>>,
     ) {
         let wg = WaitGroup::new();
 
-        for url in urls {
-            if url.scheme != SchemeType::Turn && url.scheme != SchemeType::Turns {
-                continue;
-            }
-            if url.username.is_empty() {
+        let turn_urls = urls
+            .into_iter()
+            .filter(|url| url.scheme == SchemeType::Turn || url.scheme == SchemeType::Turns);
+        // TURN servers are preferred in the order they are configured
+        // the credentials come from elsewhere when there is a provider or an access token
+        let url_credentials_needed =
+            relay_params.credential_provider.is_none() && relay_params.turn_access_token.is_none();
+        for (rank, url) in turn_urls.enumerate() {
+            if url_credentials_needed && url.username.is_empty() {
                 log::error!("Failed to gather relay candidates: {}", *ERR_USERNAME_EMPTY);
                 return;
             }
-            if url.password.is_empty() {
+            if url_credentials_needed && url.password.is_empty() {
                 log::error!("Failed to gather relay candidates: {}", *ERR_PASSWORD_EMPTY);
                 return;
             }
 
             let w = wg.worker();
-            let network = NetworkType::Udp4.to_string();
+            let local_preference_rank = u16::try_from(rank).unwrap_or(u16::MAX);
+            let relay_params = relay_params.clone();
             let net2 = Arc::clone(&net);
             let agent_internal2 = Arc::clone(&agent_internal);
 
@@ -605,125 +1138,508 @@ impl Agent {
                     drop(w);
                 });
 
-                let turn_server_addr = format!("{}:{}", url.host, url.port);
-
-                let (loc_conn, rel_addr, rel_port) =
-                    if url.proto == ProtoType::Udp && url.scheme == SchemeType::Turn {
-                        let loc_conn = match net2.bind(SocketAddr::from_str("0.0.0.0:0")?).await {
-                            Ok(c) => c,
-                            Err(err) => {
-                                log::warn!("Failed to listen due to error: {}", err);
-                                return Ok(());
-                            }
-                        };
-
-                        let local_addr = loc_conn.local_addr().await?;
-                        let rel_addr = local_addr.ip().to_string();
-                        let rel_port = local_addr.port();
-                        (loc_conn, rel_addr, rel_port)
-                    /*TODO: case url.proto == ProtoType::UDP && url.scheme == SchemeType::TURNS{
-                    case a.proxyDialer != nil && url.Proto == ProtoTypeTCP && (url.Scheme == SchemeTypeTURN || url.Scheme == SchemeTypeTURNS):
-                    case url.Proto == ProtoTypeTCP && url.Scheme == SchemeTypeTURN:
-                    case url.Proto == ProtoTypeTCP && url.Scheme == SchemeTypeTURNS:*/
-                    } else {
-                        log::warn!("Unable to handle URL in gather_candidates_relay {}", url);
-                        return Ok(());
-                    };
-
-                let cfg = turn::client::ClientConfig {
-                    stun_serv_addr: String::new(),
-                    turn_serv_addr: turn_server_addr.clone(),
-                    username: url.username,
-                    password: url.password,
-                    realm: String::new(),
-                    software: String::new(),
-                    rto_in_ms: 0,
-                    conn: loc_conn,
-                    vnet: Some(Arc::clone(&net2)),
-                };
-                let client = match turn::client::Client::new(cfg).await {
-                    Ok(client) => Arc::new(client),
+                let (candidate, monitor, credentials_expiry) = match Self::allocate_relay_candidate(
+                    &url,
+                    &relay_params,
+                    local_preference_rank,
+                    &net2,
+                    &agent_internal2,
+                )
+                .await
+                {
+                    Ok(allocation) => allocation,
                     Err(err) => {
+                        let ai = agent_internal2.lock().await;
+                        ai.candidate_error(err);
+                        return;
+                    }
+                };
+
+                {
+                    let mut ai = agent_internal2.lock().await;
+                    if let Err(err) = ai.add_candidate(&candidate).await {
+                        if let Err(close_err) = candidate.close().await {
+                            log::warn!("Failed to close candidate: {}", close_err);
+                        }
                         log::warn!(
-                            "Failed to build new turn.Client {} {}\n",
-                            turn_server_addr,
+                            "Failed to append to localCandidates and run onCandidateHdlr: {}",
                             err
                         );
-                        return Ok(());
+                        return;
                     }
+                }
+
+                tokio::spawn(async move {
+                    Self::watch_relay_allocation(
+                        url,
+                        relay_params,
+                        local_preference_rank,
+                        net2,
+                        agent_internal2,
+                        candidate,
+                        monitor,
+                        credentials_expiry,
+                    )
+                    .await;
+                });
+            });
+        }
+
+        wg.wait().await;
+    }
+
+    /// Allocates a relayed address on the TURN server of `url` and creates the matching relay
+    /// candidate, which still has to be added to the agent. The SRV targets of the URL are tried
+    /// in order, and the monitor of the allocation that succeeded is returned along with the
+    /// expiry of its credentials. The credentials come from the credential provider when there is
+    /// one.
+    async fn allocate_relay_candidate(
+        url: &Url,
+        relay_params: &RelayAllocationParams,
+        local_preference_rank: u16,
+        net: &Arc<Net>,
+        agent_internal: &Arc<Mutex<AgentInternal>>,
+    ) -> Result<
+        (
+            Arc<dyn Candidate + Send + Sync>,
+            AllocationMonitor,
+            Option<SystemTime>,
+        ),
+        CandidateError,
+    > {
+        let mut last_err = CandidateError {
+            address: String::new(),
+            port: 0,
+            url: url.to_string(),
+            error_code: CANDIDATE_ERROR_CODE_UNREACHABLE,
+            error_text: format!("{} has no TURN server available", url.host),
+        };
+        let credentials = match &relay_params.credential_provider {
+            Some(credential_provider) => match credential_provider.credentials(url).await {
+                Ok(credentials) => credentials,
+                Err(err) => {
+                    last_err.error_text = format!("Failed to get TURN credentials: {}", err);
+                    return Err(last_err);
+                }
+            },
+            None => TurnCredentials {
+                username: url.username.clone(),
+                password: url.password.clone(),
+                access_token: relay_params.turn_access_token.clone(),
+                expiry: None,
+            },
+        };
+        for (host, port) in resolve_url_targets(net, &relay_params.dns_srv, url).await {
+            // IPv6 literals need brackets to be told apart from the port
+            let turn_server_addr = host.parse::<IpAddr>().map_or_else(
+                |_| format!("{}:{}", host, port),
+                |ip| SocketAddr::new(ip, port).to_string(),
+            );
+            let mut result = Self::allocate_relay_candidate_on(
+                url,
+                &turn_server_addr,
+                &credentials,
+                relay_params,
+                local_preference_rank,
+                net,
+                agent_internal,
+            )
+            .await;
+            // the server answered, but not with an allocation for the token
+            let token_rejected = credentials.access_token.is_some()
+                && matches!(&result, Err(err) if err.error_code != CANDIDATE_ERROR_CODE_UNREACHABLE);
+            if token_rejected
+                && !credentials.username.is_empty()
+                && !credentials.password.is_empty()
+            {
+                log::debug!(
+                    "{} rejected the access token, falling back to long-term credentials",
+                    turn_server_addr
+                );
+                let long_term_credentials = TurnCredentials {
+                    access_token: None,
+                    ..credentials.clone()
                 };
-                if let Err(err) = client.listen().await {
-                    let _ = client.close().await;
-                    log::warn!(
-                        "Failed to listen on turn.Client {} {}",
+                result = Self::allocate_relay_candidate_on(
+                    url,
+                    &turn_server_addr,
+                    &long_term_credentials,
+                    relay_params,
+                    local_preference_rank,
+                    net,
+                    agent_internal,
+                )
+                .await;
+            }
+            match result {
+                Ok((candidate, monitor)) => return Ok((candidate, monitor, credentials.expiry)),
+                Err(err) => {
+                    log::debug!(
+                        "Failed to allocate on {}: {}",
                         turn_server_addr,
-                        err
+                        err.error_text
                     );
-                    return Ok(());
+                    last_err = err;
                 }
+            }
+        }
+        Err(last_err)
+    }
 
-                let relay_conn = match client.allocate().await {
-                    Ok(conn) => conn,
+    /// Allocates on the TURN server at `turn_server_addr`, watching the requests that keep the
+    /// allocation alive with an `AllocationMonitor`. With an access token, the requests are signed
+    /// with its session key and a rejected token is reported with the error code of the server.
+    async fn allocate_relay_candidate_on(
+        url: &Url,
+        turn_server_addr: &str,
+        credentials: &TurnCredentials,
+        relay_params: &RelayAllocationParams,
+        local_preference_rank: u16,
+        net: &Arc<Net>,
+        agent_internal: &Arc<Mutex<AgentInternal>>,
+    ) -> Result<(Arc<dyn Candidate + Send + Sync>, AllocationMonitor), CandidateError> {
+        let network = NetworkType::Udp4.to_string();
+        let candidate_error = |rel_addr: Option<SocketAddr>, error_text: String| CandidateError {
+            address: rel_addr.map_or_else(String::new, |addr| addr.ip().to_string()),
+            port: rel_addr.map_or(0, |addr| addr.port()),
+            url: url.to_string(),
+            error_code: CANDIDATE_ERROR_CODE_UNREACHABLE,
+            error_text,
+        };
+
+        let (loc_conn, local_addr, turn_server_addr, relay_protocol) =
+            if url.proto == ProtoType::Udp && url.scheme == SchemeType::Turn {
+                let loc_conn = match net
+                    .bind(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0))
+                    .await
+                {
+                    Ok(c) => c,
                     Err(err) => {
-                        let _ = client.close().await;
-                        log::warn!(
-                            "Failed to allocate on turn.Client {} {}",
-                            turn_server_addr,
-                            err
-                        );
-                        return Ok(());
+                        return Err(candidate_error(
+                            None,
+                            format!("Failed to listen due to error: {}", err),
+                        ));
                     }
                 };
 
-                let raddr = relay_conn.local_addr().await?;
-                let relay_config = CandidateRelayConfig {
-                    base_config: CandidateBaseConfig {
-                        network: network.clone(),
-                        address: raddr.ip().to_string(),
-                        port: raddr.port(),
-                        component: COMPONENT_RTP,
-                        conn: Some(Arc::new(relay_conn)),
-                        ..CandidateBaseConfig::default()
-                    },
-                    rel_addr,
-                    rel_port,
-                    relay_client: Some(Arc::clone(&client)),
+                let local_addr = match loc_conn.local_addr().await {
+                    Ok(addr) => addr,
+                    Err(err) => return Err(candidate_error(None, err.to_string())),
                 };
-
-                let candidate: Arc<dyn Candidate + Send + Sync> = match relay_config
-                    .new_candidate_relay(Some(agent_internal2.clone()))
-                    .await
+                (loc_conn, local_addr, turn_server_addr.to_owned(), "udp")
+            } else if url.proto == ProtoType::Tcp
+                && (url.scheme == SchemeType::Turn || url.scheme == SchemeType::Turns)
+            {
+                let stream_conn = match tokio::time::timeout(
+                    STUN_GATHER_TIMEOUT,
+                    Self::dial_turn_server(url, turn_server_addr, relay_params, net),
+                )
+                .await
                 {
-                    Ok(candidate) => Arc::new(candidate),
-                    Err(err) => {
-                        let _ = client.close().await;
-                        log::warn!(
-                            "Failed to create relay candidate: {} {}: {}",
-                            network,
-                            raddr,
-                            err
-                        );
-                        return Ok(());
+                    Ok(Ok(stream_conn)) => stream_conn,
+                    Ok(Err(err)) => {
+                        return Err(candidate_error(
+                            None,
+                            format!("Failed to connect to {}: {}", turn_server_addr, err),
+                        ));
+                    }
+                    Err(_) => {
+                        return Err(candidate_error(
+                            None,
+                            format!("Timed out connecting to {}", turn_server_addr),
+                        ));
                     }
                 };
 
+                // the stream ignores the destination of what the client sends, the server is
+                // whatever is on the other end of it
+                let local_addr = match stream_conn.local_addr().await {
+                    Ok(addr) => addr,
+                    Err(err) => return Err(candidate_error(None, err.to_string())),
+                };
+                let remote_addr = stream_conn.remote_addr().to_string();
+                let relay_protocol = if url.scheme == SchemeType::Turns {
+                    "tls"
+                } else {
+                    "tcp"
+                };
+                let loc_conn: Arc<dyn Conn + Send + Sync> = Arc::new(stream_conn);
+                (loc_conn, local_addr, remote_addr, relay_protocol)
+            } else {
+                return Err(candidate_error(
+                    None,
+                    format!("Unable to handle URL in gather_candidates_relay {}", url),
+                ));
+            };
+
+        let (loc_conn, access_token_conn, username) = match &credentials.access_token {
+            Some(access_token) => {
+                let access_token_conn =
+                    Arc::new(AccessTokenConn::new(loc_conn, access_token.clone()));
+                let loc_conn: Arc<dyn Conn + Send + Sync> = Arc::clone(&access_token_conn) as _;
+                (loc_conn, Some(access_token_conn), access_token.kid.clone())
+            }
+            None => (loc_conn, None, credentials.username.clone()),
+        };
+        let (loc_conn, monitor) = AllocationMonitorConn::new(loc_conn, ALLOCATION_REQUEST_TIMEOUT);
+        let cfg = turn::client::ClientConfig {
+            stun_serv_addr: String::new(),
+            turn_serv_addr: turn_server_addr.clone(),
+            // with an access token, the password only keys what the access token conn re-signs
+            username,
+            password: credentials.password.clone(),
+            realm: String::new(),
+            software: String::new(),
+            rto_in_ms: 0,
+            conn: Arc::new(loc_conn),
+            vnet: Some(Arc::clone(net)),
+        };
+        let client = match turn::client::Client::new(cfg).await {
+            Ok(client) => Arc::new(client),
+            Err(err) => {
+                return Err(candidate_error(
+                    Some(local_addr),
+                    format!(
+                        "Failed to build new turn.Client {} {}",
+                        turn_server_addr, err
+                    ),
+                ));
+            }
+        };
+        if let Err(err) = client.listen().await {
+            let _ = client.close().await;
+            return Err(candidate_error(
+                Some(local_addr),
+                format!(
+                    "Failed to listen on turn.Client {} {}",
+                    turn_server_addr, err
+                ),
+            ));
+        }
+
+        let relay_conn = match client.allocate().await {
+            Ok(conn) => conn,
+            Err(err) => {
+                let _ = client.close().await;
+                let mut err = candidate_error(
+                    Some(local_addr),
+                    format!(
+                        "Failed to allocate on turn.Client {} {}",
+                        turn_server_addr, err
+                    ),
+                );
+                if let Some(code) = access_token_conn.and_then(|c| c.rejection()) {
+                    err.error_code = code;
+                }
+                return Err(err);
+            }
+        };
+
+        let raddr = match relay_conn.local_addr().await {
+            Ok(addr) => addr,
+            Err(err) => {
+                let _ = client.close().await;
+                return Err(candidate_error(Some(local_addr), err.to_string()));
+            }
+        };
+        let base = match (
+            &relay_params.network_policy,
+            net.resolve_addr(true, &turn_server_addr).await,
+        ) {
+            (Some(network_policy), Ok(server_addr)) => {
+                base_interface_addr(net, &**network_policy, local_addr, server_addr).await
+            }
+            _ => LocalInterfaceAddr::unknown(local_addr.ip()),
+        };
+        let relay_config = CandidateRelayConfig {
+            base_config: CandidateBaseConfig {
+                network: network.clone(),
+                address: raddr.ip().to_string(),
+                port: raddr.port(),
+                component: COMPONENT_RTP,
+                local_preference_rank,
+                network_id: base.network_id,
+                network_cost: base.network_cost,
+                interface_type: base.interface_type,
+                url: Some(url.without_credentials()),
+                conn: Some(Arc::new(relay_conn)),
+                ..CandidateBaseConfig::default()
+            },
+            rel_addr: local_addr.ip().to_string(),
+            rel_port: local_addr.port(),
+            relay_protocol: relay_protocol.to_owned(),
+            relay_client: Some(Arc::clone(&client)),
+        };
+
+        match relay_config
+            .new_candidate_relay(Some(Arc::clone(agent_internal)))
+            .await
+        {
+            Ok(candidate) => Ok((Arc::new(candidate), monitor)),
+            Err(err) => {
+                let _ = client.close().await;
+                Err(candidate_error(
+                    Some(local_addr),
+                    format!(
+                        "Failed to create relay candidate: {} {}: {}",
+                        network, raddr, err
+                    ),
+                ))
+            }
+        }
+    }
+
+    /// Opens the TCP connection to the TURN server at `turn_server_addr`, tunneled through the
+    /// proxy when there is one, and secures it with TLS for turns URLs.
+    async fn dial_turn_server(
+        url: &Url,
+        turn_server_addr: &str,
+        relay_params: &RelayAllocationParams,
+        net: &Arc<Net>,
+    ) -> Result<StreamConn, Error> {
+        let stream = if let Some(proxy_dialer) = &relay_params.proxy_dialer {
+            // the proxy resolves the name, it may be the only one able to
+            let (host, port) = if let Ok(addr) = turn_server_addr.parse::<SocketAddr>() {
+                (addr.ip().to_string(), addr.port())
+            } else {
+                let (host, port) = turn_server_addr
+                    .rsplit_once(':')
+                    .ok_or_else(|| ERR_HOST.to_owned())?;
+                let port = port.parse().map_err(|_| ERR_PORT.to_owned())?;
+                (host.to_owned(), port)
+            };
+            proxy_dialer.dial(&host, port).await?
+        } else {
+            if net.is_virtual() {
+                return Err(ERR_TCP_NOT_SUPPORTED_BY_VNET.to_owned());
+            }
+            let server_addr = if let Ok(addr) = turn_server_addr.parse::<SocketAddr>() {
+                addr
+            } else {
+                net.resolve_addr(true, turn_server_addr).await?
+            };
+            TcpStream::connect(server_addr).await?
+        };
+        // STUN and TURN messages are small, don't let Nagle delay them
+        stream.set_nodelay(true)?;
+
+        if url.scheme == SchemeType::Turns {
+            StreamConn::from_tcp_with_tls(stream, &url.host, relay_params.insecure_skip_verify)
+                .await
+        } else {
+            StreamConn::from_tcp(stream)
+        }
+    }
+
+    /// Watches the allocation behind a relay candidate. When the allocation is lost, its refresh
+    /// failed or the server no longer knows it, the candidate is removed and the allocation is
+    /// made again, replacing it with a new relay candidate. Before the credentials of the allocation expire, the new one is
+    /// made first with fresh credentials and then replaces the candidate. Runs until the candidate
+    /// is closed or can't be replaced.
+    #[allow(clippy::too_many_arguments)]
+    async fn watch_relay_allocation(
+        url: Url,
+        relay_params: RelayAllocationParams,
+        local_preference_rank: u16,
+        net: Arc<Net>,
+        agent_internal: Arc<Mutex<AgentInternal>>,
+        mut candidate: Arc<dyn Candidate + Send + Sync>,
+        mut monitor: AllocationMonitor,
+        mut credentials_expiry: Option<SystemTime>,
+    ) {
+        loop {
+            let mut closed_ch_rx = {
+                let closed_ch = candidate.get_closed_ch();
+                let closed = closed_ch.lock().await;
+                match &*closed {
+                    Some(closed_ch_tx) => closed_ch_tx.subscribe(),
+                    None => return,
+                }
+            };
+
+            let renewal = async {
+                match credentials_expiry {
+                    Some(expiry) => tokio::time::sleep(credentials_renewal_delay(expiry)).await,
+                    None => std::future::pending().await,
+                }
+            };
+            let lost = tokio::select! {
+                err = monitor.lost() => Some(err),
+                () = renewal => None,
+                _ = closed_ch_rx.recv() => return,
+            };
+
+            let local_ufrag = {
+                let mut ai = agent_internal.lock().await;
+                if let Some(err) = &lost {
+                    log::warn!(
+                        "relay candidate {} lost its allocation on {}: {}",
+                        candidate,
+                        url,
+                        err
+                    );
+                    if !ai.remove_candidate(&candidate).await {
+                        // removed by a restart or close in the meantime
+                        return;
+                    }
+                } else {
+                    log::debug!(
+                        "credentials of relay candidate {} expire, allocating again",
+                        candidate
+                    );
+                }
+                ai.local_ufrag.clone()
+            };
+
+            let (new_candidate, new_monitor, new_credentials_expiry) =
+                match Self::allocate_relay_candidate(
+                    &url,
+                    &relay_params,
+                    local_preference_rank,
+                    &net,
+                    &agent_internal,
+                )
+                .await
                 {
-                    let mut ai = agent_internal2.lock().await;
-                    if let Err(err) = ai.add_candidate(&candidate).await {
-                        if let Err(close_err) = candidate.close().await {
-                            log::warn!("Failed to close candidate: {}", close_err);
+                    Ok(allocation) => allocation,
+                    Err(err) => {
+                        agent_internal.lock().await.candidate_error(err);
+                        if lost.is_some() {
+                            return;
                         }
-                        log::warn!(
-                            "Failed to append to localCandidates and run onCandidateHdlr: {}",
-                            err
-                        );
+                        // the candidate stays until its allocation is lost
+                        credentials_expiry = None;
+                        continue;
                     }
-                }
+                };
 
-                Ok::<(), Error>(())
-            });
+            {
+                let mut ai = agent_internal.lock().await;
+                if ai.done_tx.is_none() || ai.local_ufrag != local_ufrag {
+                    // the agent was closed or restarted while allocating
+                    let _ = new_candidate.close().await;
+                    return;
+                }
+                if let Err(err) = ai.add_candidate(&new_candidate).await {
+                    let _ = new_candidate.close().await;
+                    log::warn!("Failed to add re-allocated relay candidate: {}", err);
+                    return;
+                }
+                if lost.is_none() {
+                    ai.remove_candidate(&candidate).await;
+                }
+                drop(ai);
+            }
+            candidate = new_candidate;
+            monitor = new_monitor;
+            credentials_expiry = new_credentials_expiry;
         }
-
-        wg.wait().await;
     }
 }
+
+/// Returns how long until a relay candidate is allocated again before its credentials expire at
+/// `expiry`: a tenth of the time left before, and at most `CREDENTIALS_RENEWAL_MARGIN`.
+fn credentials_renewal_delay(expiry: SystemTime) -> Duration {
+    let left = expiry.duration_since(SystemTime::now()).unwrap_or_default();
+    left.saturating_sub(std::cmp::min(left / 10, CREDENTIALS_RENEWAL_MARGIN))
+}
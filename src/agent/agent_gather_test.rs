@@ -1,10 +1,17 @@
+use super::agent_gather::RelayAllocationParams;
+use super::agent_test::{build_wan, gather_and_wait, start_turn_server};
 use super::agent_vnet_test::*;
 use super::*;
+use crate::candidate::candidate_base::CandidateBaseConfig;
+use crate::candidate::candidate_host::CandidateHostConfig;
+use crate::socket_factory::*;
 use crate::util::*;
 
+use async_trait::async_trait;
 use ipnet::IpNet;
 use std::str::FromStr;
-use util::vnet::*;
+use stun::error_code::*;
+use util::{vnet::*, Conn};
 
 #[tokio::test]
 async fn test_vnet_gather_no_local_ip_address() -> Result<(), Error> {
@@ -16,7 +23,15 @@ async fn test_vnet_gather_no_local_ip_address() -> Result<(), Error> {
     })
     .await?;
 
-    let local_ips = local_interfaces(&vnet, &a.interface_filter, &[NetworkType::Udp4]).await;
+    let local_ips = local_interfaces(
+        &vnet,
+        &a.interface_filter,
+        &a.ip_filter,
+        a.include_loopback,
+        &*a.network_policy,
+        &[NetworkType::Udp4],
+    )
+    .await;
     assert!(local_ips.is_empty(), "should return no local IP");
 
     a.close().await?;
@@ -42,10 +57,19 @@ async fn test_vnet_gather_dynamic_ip_address() -> Result<(), Error> {
     })
     .await?;
 
-    let local_ips = local_interfaces(&nw, &a.interface_filter, &[NetworkType::Udp4]).await;
+    let local_ips = local_interfaces(
+        &nw,
+        &a.interface_filter,
+        &a.ip_filter,
+        a.include_loopback,
+        &*a.network_policy,
+        &[NetworkType::Udp4],
+    )
+    .await;
     assert!(!local_ips.is_empty(), "should have one local IP");
 
-    for ip in &local_ips {
+    for local_ip in &local_ips {
+        let ip = &local_ip.ip;
         if ip.is_loopback() {
             panic!("should not return loopback IP");
         }
@@ -75,20 +99,46 @@ async fn test_vnet_gather_listen_udp() -> Result<(), Error> {
     })
     .await?;
 
-    let local_ips = local_interfaces(&nw, &a.interface_filter, &[NetworkType::Udp4]).await;
+    let local_ips = local_interfaces(
+        &nw,
+        &a.interface_filter,
+        &a.ip_filter,
+        a.include_loopback,
+        &*a.network_policy,
+        &[NetworkType::Udp4],
+    )
+    .await;
     assert!(!local_ips.is_empty(), "should have one local IP");
 
-    let ip = local_ips[0];
+    let ip = local_ips[0].ip;
 
-    let _ = listen_udp_in_port_range(&nw, 0, 0, SocketAddr::new(ip, 0)).await?;
+    let _ = listen_udp_in_port_range(
+        &NetSocketFactory::new(Arc::clone(&nw)),
+        0,
+        0,
+        SocketAddr::new(ip, 0),
+    )
+    .await?;
 
-    let result = listen_udp_in_port_range(&nw, 4999, 5000, SocketAddr::new(ip, 0)).await;
+    let result = listen_udp_in_port_range(
+        &NetSocketFactory::new(Arc::clone(&nw)),
+        4999,
+        5000,
+        SocketAddr::new(ip, 0),
+    )
+    .await;
     assert!(
         result.is_err(),
         "listenUDP with invalid port range did not return ErrPort"
     );
 
-    let conn = listen_udp_in_port_range(&nw, 5000, 5000, SocketAddr::new(ip, 0)).await?;
+    let conn = listen_udp_in_port_range(
+        &NetSocketFactory::new(Arc::clone(&nw)),
+        5000,
+        5000,
+        SocketAddr::new(ip, 0),
+    )
+    .await?;
     let port = conn.local_addr().await?.port();
     assert_eq!(
         port, 5000,
@@ -101,6 +151,62 @@ async fn test_vnet_gather_listen_udp() -> Result<(), Error> {
     Ok(())
 }
 
+#[tokio::test]
+async fn test_vnet_gather_unique_local_preferences() -> Result<(), Error> {
+    let r = Arc::new(Mutex::new(router::Router::new(router::RouterConfig {
+        cidr: "1.2.3.0/24".to_owned(),
+        ..Default::default()
+    })?));
+    let nw = Arc::new(net::Net::new(Some(net::NetConfig {
+        static_ips: vec!["1.2.3.4".to_owned(), "1.2.3.5".to_owned()],
+        ..Default::default()
+    })));
+    connect_net2router(&nw, &r).await?;
+
+    let a = Agent::new(AgentConfig {
+        network_types: vec![NetworkType::Udp4],
+        multicast_dns_mode: MulticastDnsMode::Disabled,
+        net: Some(nw),
+        ..Default::default()
+    })
+    .await?;
+
+    let (done_tx, mut done_rx) = mpsc::channel::<()>(1);
+    let done_tx = Arc::new(Mutex::new(Some(done_tx)));
+    a.on_candidate(Box::new(
+        move |c: Option<Arc<dyn Candidate + Send + Sync>>| {
+            let done_tx_clone = Arc::clone(&done_tx);
+            Box::pin(async move {
+                if c.is_none() {
+                    let mut tx = done_tx_clone.lock().await;
+                    tx.take();
+                }
+            })
+        },
+    ))
+    .await;
+
+    a.gather_candidates().await?;
+    let _ = done_rx.recv().await;
+
+    let mut candidates = a.get_local_candidates().await?;
+    assert_eq!(candidates.len(), 2, "There must be two host candidates");
+    candidates.sort_by_key(|c| std::cmp::Reverse(c.priority()));
+
+    // interfaces keep their order, so the first address is the most preferred one
+    assert_eq!(candidates[0].address(), "1.2.3.4");
+    assert_eq!(candidates[1].address(), "1.2.3.5");
+    assert_ne!(
+        candidates[0].priority(),
+        candidates[1].priority(),
+        "host candidates must have unique priorities"
+    );
+
+    a.close().await?;
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_vnet_gather_with_nat_1to1_as_host_candidates() -> Result<(), Error> {
     let external_ip0 = "1.2.3.4";
@@ -225,6 +331,124 @@ async fn test_vnet_gather_with_nat_1to1_as_host_candidates() -> Result<(), Error
     Ok(())
 }
 
+#[tokio::test]
+async fn test_vnet_gather_with_nat_mapping_port_range() -> Result<(), Error> {
+    let lan = Arc::new(Mutex::new(router::Router::new(router::RouterConfig {
+        cidr: "10.0.0.0/24".to_owned(),
+        ..Default::default()
+    })?));
+    let nw = Arc::new(net::Net::new(Some(net::NetConfig {
+        static_ips: vec!["10.0.0.1".to_owned()],
+        ..Default::default()
+    })));
+    connect_net2router(&nw, &lan).await?;
+
+    let a = Agent::new(AgentConfig {
+        network_types: vec![NetworkType::Udp4],
+        nat_mapping: NatMappingConfig {
+            candidates: NatMappingCandidates::HostAndServerReflexive,
+            rules: vec![NatMappingRule {
+                external_ip: "1.2.3.4".parse().unwrap(),
+                local_ip: Some("10.0.0.1".parse().unwrap()),
+                port_range: Some(NatPortRange {
+                    local_min: 5000,
+                    local_max: 5009,
+                    external_min: 40000,
+                }),
+                interface: Some("eth0".to_owned()),
+                ..NatMappingRule::default()
+            }],
+        },
+        net: Some(Arc::clone(&nw)),
+        ..Default::default()
+    })
+    .await?;
+
+    let (done_tx, mut done_rx) = mpsc::channel::<()>(1);
+    let done_tx = Arc::new(Mutex::new(Some(done_tx)));
+    a.on_candidate(Box::new(
+        move |c: Option<Arc<dyn Candidate + Send + Sync>>| {
+            let done_tx_clone = Arc::clone(&done_tx);
+            Box::pin(async move {
+                if c.is_none() {
+                    let mut tx = done_tx_clone.lock().await;
+                    tx.take();
+                }
+            })
+        },
+    ))
+    .await;
+
+    a.gather_candidates().await?;
+    let _ = done_rx.recv().await;
+
+    let candidates = a.get_local_candidates().await?;
+    assert_eq!(
+        candidates.len(),
+        2,
+        "a host candidate and its srflx sibling"
+    );
+    let host = candidates
+        .iter()
+        .find(|c| c.candidate_type() == CandidateType::Host)
+        .expect("the host candidate keeps its local address");
+    let srflx = candidates
+        .iter()
+        .find(|c| c.candidate_type() == CandidateType::ServerReflexive)
+        .expect("the mapped address goes to a srflx candidate");
+
+    assert_eq!(host.address(), "10.0.0.1");
+    assert!(
+        (5000..=5009).contains(&host.port()),
+        "the socket must be bound to a forwarded port: {}",
+        host.port()
+    );
+    assert_eq!(srflx.address(), "1.2.3.4");
+    assert_eq!(srflx.port(), host.port() + 35000);
+    let related_address = srflx.related_address().unwrap();
+    assert_eq!(related_address.address, "10.0.0.1");
+    assert_eq!(related_address.port, host.port());
+    assert!(
+        srflx.get_conn().is_none(),
+        "the socket and its recv loop are the host candidate's"
+    );
+
+    // the checks of the srflx candidate are the host candidate's
+    let remote: Arc<dyn Candidate + Send + Sync> = Arc::new(
+        CandidateHostConfig {
+            base_config: CandidateBaseConfig {
+                network: "udp".to_owned(),
+                address: "10.0.0.2".to_owned(),
+                port: 5000,
+                component: COMPONENT_RTP,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+        .new_candidate_host(None)
+        .await?,
+    );
+    {
+        let mut ai = a.agent_internal.lock().await;
+        ai.add_remote_candidate(&remote).await;
+        let checklist = ai.agent_conn.checklist.lock().await;
+        assert_eq!(checklist.len(), 1);
+        assert!(checklist[0].local.equal(&**host));
+    }
+
+    // and removing it leaves the host candidate's socket alone
+    {
+        let mut ai = a.agent_internal.lock().await;
+        assert!(ai.remove_candidate(srflx).await);
+    }
+    assert!(host.get_conn().is_some());
+    assert_eq!(a.get_local_candidates().await?.len(), 1);
+
+    a.close().await?;
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_vnet_gather_with_nat_1to1_as_srflx_candidates() -> Result<(), Error> {
     let wan = Arc::new(Mutex::new(router::Router::new(router::RouterConfig {
@@ -332,7 +556,15 @@ async fn test_vnet_gather_with_interface_filter() -> Result<(), Error> {
         })
         .await?;
 
-        let local_ips = local_interfaces(&nw, &a.interface_filter, &[NetworkType::Udp4]).await;
+        let local_ips = local_interfaces(
+            &nw,
+            &a.interface_filter,
+            &a.ip_filter,
+            a.include_loopback,
+            &*a.network_policy,
+            &[NetworkType::Udp4],
+        )
+        .await;
         assert!(
             local_ips.is_empty(),
             "InterfaceFilter should have excluded everything"
@@ -352,7 +584,15 @@ async fn test_vnet_gather_with_interface_filter() -> Result<(), Error> {
         })
         .await?;
 
-        let local_ips = local_interfaces(&nw, &a.interface_filter, &[NetworkType::Udp4]).await;
+        let local_ips = local_interfaces(
+            &nw,
+            &a.interface_filter,
+            &a.ip_filter,
+            a.include_loopback,
+            &*a.network_policy,
+            &[NetworkType::Udp4],
+        )
+        .await;
         assert_eq!(
             local_ips.len(),
             1,
@@ -365,6 +605,236 @@ async fn test_vnet_gather_with_interface_filter() -> Result<(), Error> {
     Ok(())
 }
 
+#[tokio::test]
+async fn test_vnet_gather_with_ip_filter() -> Result<(), Error> {
+    let r = Arc::new(Mutex::new(router::Router::new(router::RouterConfig {
+        cidr: "1.2.3.0/24".to_owned(),
+        ..Default::default()
+    })?));
+    let nw = Arc::new(net::Net::new(Some(net::NetConfig::default())));
+    connect_net2router(&nw, &r).await?;
+
+    //"IPFilter should exclude the address"
+    {
+        let a = Agent::new(AgentConfig {
+            net: Some(Arc::clone(&nw)),
+            ip_filter: Some(Box::new(|ip: IpAddr| -> bool {
+                !matches!(ip, IpAddr::V4(ip) if ip.octets()[..3] == [1, 2, 3])
+            })),
+            ..Default::default()
+        })
+        .await?;
+
+        let local_ips = local_interfaces(
+            &nw,
+            &a.interface_filter,
+            &a.ip_filter,
+            a.include_loopback,
+            &*a.network_policy,
+            &[NetworkType::Udp4],
+        )
+        .await;
+        assert!(
+            local_ips.is_empty(),
+            "IPFilter should have excluded everything"
+        );
+
+        a.close().await?;
+    }
+
+    //"Loopback addresses are gathered last when included"
+    {
+        let a = Agent::new(AgentConfig {
+            net: Some(Arc::clone(&nw)),
+            include_loopback: true,
+            ..Default::default()
+        })
+        .await?;
+
+        let local_ips = local_interfaces(
+            &nw,
+            &a.interface_filter,
+            &a.ip_filter,
+            a.include_loopback,
+            &*a.network_policy,
+            &[NetworkType::Udp4],
+        )
+        .await;
+        assert_eq!(local_ips.len(), 2, "loopback should have been included");
+        assert!(!local_ips[0].ip.is_loopback());
+        assert!(local_ips[1].ip.is_loopback());
+
+        a.close().await?;
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_bind_interface_addrs() {
+    let bind_addresses: Vec<IpAddr> = vec![
+        "10.0.0.2".parse().unwrap(),
+        "fd00::2".parse().unwrap(),
+        "10.0.0.1".parse().unwrap(),
+    ];
+
+    let addrs = bind_interface_addrs(&bind_addresses, &[NetworkType::Udp4]);
+    let ips: Vec<IpAddr> = addrs.iter().map(|addr| addr.ip).collect();
+    assert_eq!(ips, vec![bind_addresses[0], bind_addresses[2]]);
+    assert!(addrs.iter().all(|addr| addr.network_id == 0));
+
+    let addrs = bind_interface_addrs(&bind_addresses, &[NetworkType::Udp6]);
+    assert_eq!(addrs.len(), 1);
+    assert_eq!(addrs[0].ip, bind_addresses[1]);
+}
+
+#[tokio::test]
+async fn test_vnet_gather_with_bind_addresses() -> Result<(), Error> {
+    let r = Arc::new(Mutex::new(router::Router::new(router::RouterConfig {
+        cidr: "1.2.3.0/24".to_owned(),
+        ..Default::default()
+    })?));
+    let nw = Arc::new(net::Net::new(Some(net::NetConfig::default())));
+    connect_net2router(&nw, &r).await?;
+
+    let local_ips = local_interfaces(
+        &nw,
+        &None,
+        &None,
+        false,
+        &DefaultNetworkPolicy,
+        &[NetworkType::Udp4],
+    )
+    .await;
+    assert_eq!(local_ips.len(), 1);
+    let bind_ip = local_ips[0].ip;
+
+    let a = Agent::new(AgentConfig {
+        net: Some(Arc::clone(&nw)),
+        network_types: vec![NetworkType::Udp4],
+        candidate_types: vec![CandidateType::Host],
+        // the filter must not apply to explicit bind addresses
+        interface_filter: Some(Box::new(|_: &str| -> bool { false })),
+        bind_addresses: vec![bind_ip],
+        ..Default::default()
+    })
+    .await?;
+
+    let (done_tx, mut done_rx) = mpsc::channel::<()>(1);
+    let done_tx = Arc::new(Mutex::new(Some(done_tx)));
+    a.on_candidate(Box::new(
+        move |c: Option<Arc<dyn Candidate + Send + Sync>>| {
+            let done_tx_clone = Arc::clone(&done_tx);
+            Box::pin(async move {
+                if c.is_none() {
+                    let mut tx = done_tx_clone.lock().await;
+                    tx.take();
+                }
+            })
+        },
+    ))
+    .await;
+
+    a.gather_candidates().await?;
+    let _ = done_rx.recv().await;
+
+    let candidates = a.get_local_candidates().await?;
+    assert_eq!(candidates.len(), 1);
+    assert_eq!(candidates[0].address(), bind_ip.to_string());
+
+    a.close().await?;
+
+    Ok(())
+}
+
+struct PreboundSocketFactory {
+    conn: Mutex<Option<Arc<dyn Conn + Send + Sync>>>,
+    laddrs: Mutex<Vec<SocketAddr>>,
+}
+
+#[async_trait]
+impl SocketFactory for PreboundSocketFactory {
+    async fn bind_udp(&self, laddr: SocketAddr) -> Result<Arc<dyn Conn + Send + Sync>, Error> {
+        self.laddrs.lock().await.push(laddr);
+        let mut conn = self.conn.lock().await;
+        conn.take()
+            .ok_or_else(|| Error::new("no pre-bound socket left".to_owned()))
+    }
+}
+
+#[tokio::test]
+async fn test_vnet_gather_with_socket_factory() -> Result<(), Error> {
+    let r = Arc::new(Mutex::new(router::Router::new(router::RouterConfig {
+        cidr: "1.2.3.0/24".to_owned(),
+        ..Default::default()
+    })?));
+    let nw = Arc::new(net::Net::new(Some(net::NetConfig::default())));
+    connect_net2router(&nw, &r).await?;
+
+    let local_ips = local_interfaces(
+        &nw,
+        &None,
+        &None,
+        false,
+        &DefaultNetworkPolicy,
+        &[NetworkType::Udp4],
+    )
+    .await;
+    assert_eq!(local_ips.len(), 1);
+    let ip = local_ips[0].ip;
+
+    let prebound = nw.bind(SocketAddr::new(ip, 6000)).await?;
+    let factory = Arc::new(PreboundSocketFactory {
+        conn: Mutex::new(Some(prebound)),
+        laddrs: Mutex::new(vec![]),
+    });
+
+    let a = Agent::new(AgentConfig {
+        net: Some(Arc::clone(&nw)),
+        network_types: vec![NetworkType::Udp4],
+        candidate_types: vec![CandidateType::Host],
+        socket_factory: Some(Arc::clone(&factory) as Arc<dyn SocketFactory + Send + Sync>),
+        ..Default::default()
+    })
+    .await?;
+
+    let (done_tx, mut done_rx) = mpsc::channel::<()>(1);
+    let done_tx = Arc::new(Mutex::new(Some(done_tx)));
+    a.on_candidate(Box::new(
+        move |c: Option<Arc<dyn Candidate + Send + Sync>>| {
+            let done_tx_clone = Arc::clone(&done_tx);
+            Box::pin(async move {
+                if c.is_none() {
+                    let mut tx = done_tx_clone.lock().await;
+                    tx.take();
+                }
+            })
+        },
+    ))
+    .await;
+
+    a.gather_candidates().await?;
+    let _ = done_rx.recv().await;
+
+    {
+        let laddrs = factory.laddrs.lock().await;
+        assert_eq!(*laddrs, vec![SocketAddr::new(ip, 0)]);
+    }
+
+    let candidates = a.get_local_candidates().await?;
+    assert_eq!(candidates.len(), 1);
+    assert_eq!(candidates[0].address(), ip.to_string());
+    assert_eq!(
+        candidates[0].port(),
+        6000,
+        "the pre-bound socket should be used"
+    );
+
+    a.close().await?;
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_vnet_gather_turn_connection_leak() -> Result<(), Error> {
     let turn_server_url = Url {
@@ -374,6 +844,7 @@ async fn test_vnet_gather_turn_connection_leak() -> Result<(), Error> {
         username: "user".to_owned(),
         password: "pass".to_owned(),
         proto: ProtoType::Udp,
+        explicit_port: true,
     };
 
     // buildVNet with a Symmetric NATs for both LANs
@@ -400,6 +871,7 @@ async fn test_vnet_gather_turn_connection_leak() -> Result<(), Error> {
         let agent_internal = Arc::clone(&a_agent.agent_internal);
         Agent::gather_candidates_relay(
             vec![turn_server_url.clone()],
+            RelayAllocationParams::default(),
             Arc::clone(&v.net0),
             agent_internal,
         )
@@ -412,3 +884,350 @@ async fn test_vnet_gather_turn_connection_leak() -> Result<(), Error> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_gather_srflx_over_tcp_skipped() -> Result<(), Error> {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let server_addr = listener.local_addr()?;
+
+    let a = Agent::new(AgentConfig {
+        urls: vec![Url {
+            scheme: SchemeType::Stun,
+            host: server_addr.ip().to_string(),
+            port: server_addr.port(),
+            username: String::new(),
+            password: String::new(),
+            proto: ProtoType::Tcp,
+            explicit_port: true,
+        }],
+        network_types: vec![NetworkType::Udp4, NetworkType::Tcp4],
+        candidate_types: vec![CandidateType::ServerReflexive],
+        multicast_dns_mode: MulticastDnsMode::Disabled,
+        ..Default::default()
+    })
+    .await?;
+
+    let (done_tx, mut done_rx) = mpsc::channel::<()>(1);
+    let done_tx = Arc::new(Mutex::new(Some(done_tx)));
+    a.on_candidate(Box::new(
+        move |c: Option<Arc<dyn Candidate + Send + Sync>>| {
+            let done_tx_clone = Arc::clone(&done_tx);
+            Box::pin(async move {
+                if c.is_none() {
+                    let mut tx = done_tx_clone.lock().await;
+                    tx.take();
+                }
+            })
+        },
+    ))
+    .await;
+
+    a.gather_candidates().await?;
+    let _ = done_rx.recv().await;
+
+    // without a listening ICE-TCP base, the mapping of a TCP connection is useless
+    assert!(
+        a.get_local_candidates().await?.is_empty(),
+        "STUN over TCP should not gather candidates"
+    );
+    assert!(
+        tokio::time::timeout(Duration::from_millis(100), listener.accept())
+            .await
+            .is_err(),
+        "the STUN server should not be contacted"
+    );
+
+    a.close().await?;
+
+    Ok(())
+}
+
+struct CellularNetworkPolicy;
+
+impl NetworkPolicy for CellularNetworkPolicy {
+    fn classify(&self, _interface_name: &str) -> InterfaceType {
+        InterfaceType::Cellular
+    }
+}
+
+#[tokio::test]
+async fn test_vnet_gather_srflx_and_relay_take_the_cost_of_their_base() -> Result<(), Error> {
+    let server_url = |scheme: SchemeType| Url {
+        scheme,
+        host: VNET_STUN_SERVER_IP.to_owned(),
+        port: VNET_STUN_SERVER_PORT,
+        username: "user".to_owned(),
+        password: "pass".to_owned(),
+        proto: ProtoType::Udp,
+        explicit_port: true,
+    };
+
+    let v = build_vnet(nat::NatType::default(), nat::NatType::default()).await?;
+
+    let a = Agent::new(AgentConfig {
+        urls: vec![server_url(SchemeType::Stun), server_url(SchemeType::Turn)],
+        network_types: vec![NetworkType::Udp4],
+        multicast_dns_mode: MulticastDnsMode::Disabled,
+        network_policy: Some(Arc::new(CellularNetworkPolicy)),
+        net: Some(Arc::clone(&v.net0)),
+        ..Default::default()
+    })
+    .await?;
+
+    let (done_tx, mut done_rx) = mpsc::channel::<()>(1);
+    let done_tx = Arc::new(Mutex::new(Some(done_tx)));
+    a.on_candidate(Box::new(
+        move |c: Option<Arc<dyn Candidate + Send + Sync>>| {
+            let done_tx_clone = Arc::clone(&done_tx);
+            Box::pin(async move {
+                if c.is_none() {
+                    let mut tx = done_tx_clone.lock().await;
+                    tx.take();
+                }
+            })
+        },
+    ))
+    .await;
+
+    a.gather_candidates().await?;
+    let _ = done_rx.recv().await;
+
+    let candidates = a.get_local_candidates().await?;
+    let host = candidates
+        .iter()
+        .find(|c| c.candidate_type() == CandidateType::Host)
+        .expect("a host candidate");
+    assert_eq!(host.network_cost(), NETWORK_COST_CELLULAR);
+    for candidate_type in [CandidateType::ServerReflexive, CandidateType::Relay] {
+        let c = candidates
+            .iter()
+            .find(|c| c.candidate_type() == candidate_type)
+            .unwrap_or_else(|| panic!("a {} candidate", candidate_type));
+        // sent over the same metered link, they must not look cheaper than the host candidate
+        assert_eq!(c.network_cost(), host.network_cost(), "{}", c);
+        assert_eq!(c.network_id(), host.network_id(), "{}", c);
+        assert_eq!(c.interface_type(), InterfaceType::Cellular, "{}", c);
+    }
+
+    a.close().await?;
+    v.close().await?;
+
+    Ok(())
+}
+
+/// Stands in for the socket of a TURN server. Once `permission_error` is set, `CreatePermission`
+/// requests are answered with it instead of by the server, and with `answer_others` unset the
+/// other responses of the server are dropped.
+struct FaultyTurnServerConn {
+    conn: Arc<dyn Conn + Send + Sync>,
+    permission_error: std::sync::Mutex<Option<ErrorCode>>,
+    answer_others: std::sync::atomic::AtomicBool,
+}
+
+impl FaultyTurnServerConn {
+    fn fail(&self, permission_error: ErrorCode, answer_others: bool) {
+        self.answer_others.store(answer_others, Ordering::SeqCst);
+        *self.permission_error.lock().unwrap() = Some(permission_error);
+    }
+}
+
+#[async_trait]
+impl Conn for FaultyTurnServerConn {
+    async fn connect(&self, addr: SocketAddr) -> std::io::Result<()> {
+        self.conn.connect(addr).await
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.conn.recv(buf).await
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+        self.conn.recv_from(buf).await
+    }
+
+    async fn send(&self, buf: &[u8]) -> std::io::Result<usize> {
+        self.conn.send(buf).await
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> std::io::Result<usize> {
+        let permission_error = *self.permission_error.lock().unwrap();
+        if let (Some(code), Some(m)) = (permission_error, decode_stun_message(buf)) {
+            if m.typ.method == METHOD_CREATE_PERMISSION {
+                let mut response = Message::new();
+                response
+                    .build(&[
+                        Box::new(m.transaction_id),
+                        Box::new(MessageType::new(
+                            METHOD_CREATE_PERMISSION,
+                            CLASS_ERROR_RESPONSE,
+                        )),
+                        Box::new(code),
+                    ])
+                    .unwrap();
+                self.conn.send_to(&response.raw, target).await?;
+                return Ok(buf.len());
+            }
+            if !self.answer_others.load(Ordering::SeqCst) {
+                return Ok(buf.len());
+            }
+        }
+        self.conn.send_to(buf, target).await
+    }
+
+    async fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.conn.local_addr().await
+    }
+}
+
+/// Gathers a relay candidate from a TURN server on a `FaultyTurnServerConn`. Returns the agent,
+/// the candidate, the conn and the server.
+async fn gather_relay_from_faulty_server() -> Result<
+    (
+        Agent,
+        Arc<dyn Candidate + Send + Sync>,
+        Arc<FaultyTurnServerConn>,
+        turn::server::Server,
+    ),
+    Error,
+> {
+    let (agent_net, server_net) = build_wan(&[VNET_STUN_SERVER_IP], None).await?;
+    let conn = Arc::new(FaultyTurnServerConn {
+        conn: server_net
+            .bind(SocketAddr::new(
+                VNET_STUN_SERVER_IP.parse().unwrap(),
+                VNET_STUN_SERVER_PORT,
+            ))
+            .await?,
+        permission_error: std::sync::Mutex::new(None),
+        answer_others: std::sync::atomic::AtomicBool::new(true),
+    });
+    let server = start_turn_server(
+        server_net,
+        Arc::clone(&conn) as Arc<dyn Conn + Send + Sync>,
+        Box::new(TestAuthHandler::new()),
+    )
+    .await?;
+
+    let a = Agent::new(AgentConfig {
+        urls: vec![Url {
+            scheme: SchemeType::Turn,
+            host: VNET_STUN_SERVER_IP.to_owned(),
+            port: VNET_STUN_SERVER_PORT,
+            username: "user".to_owned(),
+            password: "pass".to_owned(),
+            proto: ProtoType::Udp,
+            explicit_port: true,
+        }],
+        network_types: vec![NetworkType::Udp4],
+        candidate_types: vec![CandidateType::Relay],
+        multicast_dns_mode: MulticastDnsMode::Disabled,
+        net: Some(agent_net),
+        ..Default::default()
+    })
+    .await?;
+    let candidates = gather_and_wait(&a).await?;
+    assert_eq!(candidates.len(), 1, "There must be one relay candidate");
+    assert_eq!(candidates[0].candidate_type(), CandidateType::Relay);
+    let relay = Arc::clone(&candidates[0]);
+
+    Ok((a, relay, conn, server))
+}
+
+/// Asks the TURN server of `relay` for a permission to send to a peer, returns whether it was
+/// granted.
+async fn create_permission(relay: &Arc<dyn Candidate + Send + Sync>) -> bool {
+    let relay_conn = relay.get_conn().expect("relay candidate has a conn");
+    let peer_addr = SocketAddr::from_str("27.3.4.5:5000").unwrap();
+    relay_conn.send_to(b"ping", peer_addr).await.is_ok()
+}
+
+#[tokio::test]
+async fn test_vnet_gather_relay_removed_when_allocation_lost() -> Result<(), Error> {
+    let (a, relay, conn, server) = gather_relay_from_faulty_server().await?;
+
+    let (removed_tx, mut removed_rx) = mpsc::channel(1);
+    a.on_candidate_removed(Box::new(move |c: Arc<dyn Candidate + Send + Sync>| {
+        let removed_tx = removed_tx.clone();
+        Box::pin(async move {
+            let _ = removed_tx.send(c).await;
+        })
+    }))
+    .await;
+
+    let (error_tx, mut error_rx) = mpsc::channel(1);
+    a.on_candidate_error(Box::new(move |err: CandidateError| {
+        let error_tx = error_tx.clone();
+        Box::pin(async move {
+            let _ = error_tx.send(err).await;
+        })
+    }))
+    .await;
+
+    // The server lost the allocation, and doesn't answer the re-allocation either
+    conn.fail(CODE_ALLOC_MISMATCH, false);
+    assert!(!create_permission(&relay).await);
+
+    let removed = tokio::time::timeout(Duration::from_secs(5), removed_rx.recv())
+        .await
+        .expect("relay candidate should have been removed")
+        .expect("handler should be called");
+    assert_eq!(removed.id(), relay.id());
+
+    let err = tokio::time::timeout(Duration::from_secs(20), error_rx.recv())
+        .await
+        .expect("re-allocation should have failed")
+        .expect("handler should be called");
+    assert_eq!(err.url, "turn:1.2.3.4:3478?transport=udp");
+    assert_eq!(err.error_code, 701);
+
+    assert!(
+        a.get_local_candidates().await?.is_empty(),
+        "relay candidate should not be replaced"
+    );
+
+    let stats = a.get_local_candidates_stats().await;
+    assert_eq!(stats.len(), 1);
+    assert_eq!(stats[0].id, relay.id());
+    assert!(
+        stats[0].deleted,
+        "removed candidate should be marked deleted"
+    );
+
+    a.close().await?;
+    server.close()?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_vnet_gather_relay_kept_when_permission_forbidden() -> Result<(), Error> {
+    let (a, relay, conn, server) = gather_relay_from_faulty_server().await?;
+
+    let (removed_tx, mut removed_rx) = mpsc::channel(1);
+    a.on_candidate_removed(Box::new(move |c: Arc<dyn Candidate + Send + Sync>| {
+        let removed_tx = removed_tx.clone();
+        Box::pin(async move {
+            let _ = removed_tx.send(c).await;
+        })
+    }))
+    .await;
+
+    // only this peer is refused, the allocation is fine
+    conn.fail(CODE_FORBIDDEN, true);
+    assert!(!create_permission(&relay).await);
+
+    assert!(
+        tokio::time::timeout(Duration::from_secs(1), removed_rx.recv())
+            .await
+            .is_err(),
+        "relay candidate should have been kept"
+    );
+    let candidates = a.get_local_candidates().await?;
+    assert_eq!(candidates.len(), 1);
+    assert_eq!(candidates[0].id(), relay.id());
+
+    a.close().await?;
+    server.close()?;
+
+    Ok(())
+}
@@ -4,7 +4,11 @@ use crate::candidate::candidate_base::{CandidateBase, CandidateBaseConfig};
 use crate::candidate::candidate_peer_reflexive::CandidatePeerReflexiveConfig;
 use crate::util::*;
 
-pub type ChanCandidateTx = Option<Arc<mpsc::Sender<Option<Arc<dyn Candidate + Send + Sync>>>>>;
+/// How many stats of removed local candidates are kept for `get_local_candidates_stats`.
+pub const MAX_REMOVED_CANDIDATES_STATS: usize = 64;
+
+pub type ChanCandidateTx =
+    Option<Arc<mpsc::UnboundedSender<Option<Arc<dyn Candidate + Send + Sync>>>>>;
 
 pub struct AgentInternal {
     // State owned by the taskLoop
@@ -16,12 +20,12 @@ pub struct AgentInternal {
     pub(crate) done_rx: Option<mpsc::Receiver<()>>,
 
     pub(crate) chan_candidate_tx: ChanCandidateTx,
-    pub(crate) chan_candidate_pair_tx: Option<mpsc::Sender<()>>,
-    pub(crate) chan_state_tx: Option<mpsc::Sender<ConnectionState>>,
-
-    pub(crate) on_connection_state_change_hdlr: Option<OnConnectionStateChangeHdlrFn>,
-    pub(crate) on_selected_candidate_pair_change_hdlr: Option<OnSelectedCandidatePairChangeHdlrFn>,
-    pub(crate) on_candidate_hdlr: Option<OnCandidateHdlrFn>,
+    pub(crate) chan_candidate_pair_tx: Option<mpsc::UnboundedSender<Arc<CandidatePair>>>,
+    pub(crate) chan_state_tx: Option<mpsc::UnboundedSender<ConnectionState>>,
+    pub(crate) chan_candidate_removed_tx:
+        Option<mpsc::UnboundedSender<Arc<dyn Candidate + Send + Sync>>>,
+    pub(crate) chan_candidate_error_tx: Option<mpsc::UnboundedSender<CandidateError>>,
+    pub(crate) events: AgentEventSenders,
 
     // force candidate to be contacted immediately (instead of waiting for task ticker)
     pub(crate) force_candidate_contact_tx: mpsc::Sender<bool>,
@@ -34,6 +38,11 @@ pub struct AgentInternal {
     pub(crate) nominated_pair: Option<Arc<CandidatePair>>,
 
     pub(crate) connection_state: ConnectionState,
+    // When the agent entered the current connection state
+    pub(crate) connection_state_changed_at: Instant,
+    // How long the agent was in each of the previous connection states
+    pub(crate) connection_state_durations: HashMap<ConnectionState, Duration>,
+    pub(crate) selected_candidate_pair_changes: u32,
 
     pub(crate) started_ch_tx: Option<broadcast::Sender<()>>,
 
@@ -67,11 +76,21 @@ pub struct AgentInternal {
     pub(crate) remote_pwd: String,
     pub(crate) remote_candidates: HashMap<NetworkType, Vec<Arc<dyn Candidate + Send + Sync>>>,
 
+    // Stats of the last local candidates removed or closed since the last restart, marked deleted
+    pub(crate) removed_candidates_stats: VecDeque<CandidateStats>,
+
     // LRU of outbound Binding request Transaction IDs
     pub(crate) pending_binding_requests: Vec<BindingRequest>,
 
     pub(crate) insecure_skip_verify: bool,
 
+    // Answers the mDNS names of the local host candidates
+    pub(crate) mdns_conn: Option<Arc<MulticastDnsConn>>,
+    // Dropped to cancel the mDNS and DNS lookups of remote candidates
+    pub(crate) remote_resolve_cancel_tx: Option<broadcast::Sender<()>>,
+    // Dropped to release the gateway port mappings
+    pub(crate) port_mapping_close_tx: Option<broadcast::Sender<()>>,
+
     pub(crate) agent_conn: Arc<AgentConn>,
 }
 
@@ -79,6 +98,16 @@ pub struct AgentInternal {
 unsafe impl Send for AgentInternal {}
 unsafe impl Sync for AgentInternal {}
 
+#[cfg(feature = "metrics")]
+impl Drop for AgentInternal {
+    fn drop(&mut self) {
+        // closed agents were already uncounted
+        if self.connection_state != ConnectionState::Closed {
+            METRICS.on_agent_dropped(self.connection_state);
+        }
+    }
+}
+
 impl AgentInternal {
     pub(crate) async fn start_connectivity_checks(
         &mut self,
@@ -210,12 +239,31 @@ impl AgentInternal {
             }
 
             log::info!("Setting new connection state: {}", new_state);
+            #[cfg(feature = "metrics")]
+            {
+                METRICS.on_connection_state_change(self.connection_state, new_state);
+                // the first connection, on_connected_tx is taken once the agent is connected
+                if new_state == ConnectionState::Connected
+                    && self.connection_state == ConnectionState::Checking
+                    && self.on_connected_tx.is_some()
+                {
+                    METRICS.on_connected(self.connection_state_changed_at.elapsed());
+                }
+            }
+            *self
+                .connection_state_durations
+                .entry(self.connection_state)
+                .or_default() += self.connection_state_changed_at.elapsed();
             self.connection_state = new_state;
+            self.connection_state_changed_at = Instant::now();
 
             // Call handler after finishing current task since we may be holding the agent lock
-            // and the handler may also require it
+            // and the handler may also require it. The channels are unbounded so that we never
+            // wait on a handler with the lock held.
+            self.events
+                .send(&AgentEvent::ConnectionStateChange(new_state));
             if let Some(chan_state_tx) = &self.chan_state_tx {
-                let _ = chan_state_tx.send(new_state).await;
+                let _ = chan_state_tx.send(new_state);
             }
         }
     }
@@ -227,15 +275,30 @@ impl AgentInternal {
             p.nominated.store(true, Ordering::SeqCst);
             {
                 let mut selected_pair = self.agent_conn.selected_pair.lock().await;
-                *selected_pair = Some(p);
+                if !selected_pair
+                    .as_ref()
+                    .map_or(false, |selected| Arc::ptr_eq(selected, &p))
+                {
+                    self.selected_candidate_pair_changes += 1;
+                    #[cfg(feature = "metrics")]
+                    METRICS.on_selected_candidate_pair(
+                        p.local.candidate_type(),
+                        p.remote.candidate_type(),
+                    );
+                }
+                *selected_pair = Some(Arc::clone(&p));
             }
 
             self.update_connection_state(ConnectionState::Connected)
                 .await;
 
             // Notify when the selected pair changes
+            self.events.send(&AgentEvent::SelectedCandidatePairChange {
+                local: Arc::clone(&p.local),
+                remote: Arc::clone(&p.remote),
+            });
             if let Some(chan_candidate_pair_tx) = &self.chan_candidate_pair_tx {
-                let _ = chan_candidate_pair_tx.send(()).await;
+                let _ = chan_candidate_pair_tx.send(Arc::clone(&p));
             }
 
             // Signal connected
@@ -293,6 +356,9 @@ impl AgentInternal {
         local: Arc<dyn Candidate + Send + Sync>,
         remote: Arc<dyn Candidate + Send + Sync>,
     ) {
+        if is_advertised_only(&*local) {
+            return;
+        }
         let p = Arc::new(CandidatePair::new(local, remote, self.is_controlling));
         let mut checklist = self.agent_conn.checklist.lock().await;
         checklist.push(p);
@@ -341,6 +407,10 @@ impl AgentInternal {
             if total_time_to_failure != Duration::from_secs(0)
                 && disconnected_time > total_time_to_failure
             {
+                #[cfg(feature = "metrics")]
+                if self.connection_state != ConnectionState::Failed {
+                    METRICS.on_consent_expired();
+                }
                 self.update_connection_state(ConnectionState::Failed).await;
             } else if self.disconnected_timeout != Duration::from_secs(0)
                 && disconnected_time > self.disconnected_timeout
@@ -468,8 +538,9 @@ impl AgentInternal {
         }
 
         self.request_connectivity_check();
+        self.events.send(&AgentEvent::Candidate(Arc::clone(c)));
         if let Some(chan_candidate_tx) = &self.chan_candidate_tx {
-            let _ = chan_candidate_tx.send(Some(c.clone())).await;
+            let _ = chan_candidate_tx.send(Some(c.clone()));
         }
 
         Ok(())
@@ -490,25 +561,110 @@ impl AgentInternal {
         self.chan_candidate_tx.take();
         self.chan_candidate_pair_tx.take();
         self.chan_state_tx.take();
+        self.chan_candidate_removed_tx.take();
+        self.chan_candidate_error_tx.take();
+        // The Closed state was the last event
+        self.events.close();
+        self.remote_resolve_cancel_tx.take();
+        self.port_mapping_close_tx.take();
 
         self.agent_conn.done.store(true, Ordering::SeqCst);
 
         Ok(())
     }
 
+    /// Removes a single local candidate, closing it and dropping the pairs it is part of.
+    /// Returns false if the candidate was already gone, e.g. after a restart.
+    pub(crate) async fn remove_candidate(&mut self, c: &Arc<dyn Candidate + Send + Sync>) -> bool {
+        let network_type = c.network_type();
+        let removed = self
+            .local_candidates
+            .get_mut(&network_type)
+            .map_or(false, |cands| {
+                let len = cands.len();
+                cands.retain(|cand| !cand.equal(&**c));
+                cands.len() != len
+            });
+        if !removed {
+            return false;
+        }
+
+        if let Err(err) = c.close().await {
+            log::warn!("Failed to close candidate {}: {}", c, err);
+        }
+        self.unregister_multicast_dns_name(c).await;
+
+        {
+            let mut checklist = self.agent_conn.checklist.lock().await;
+            checklist.retain(|p| !p.local.equal(&**c));
+        }
+
+        if self
+            .nominated_pair
+            .as_ref()
+            .map_or(false, |p| p.local.equal(&**c))
+        {
+            self.nominated_pair = None;
+        }
+
+        let selected_pair_removed = self
+            .agent_conn
+            .get_selected_pair()
+            .await
+            .map_or(false, |p| p.local.equal(&**c));
+        if selected_pair_removed {
+            // Go back to checking with the pairs that are left
+            self.set_selected_pair(None).await;
+            self.update_connection_state(ConnectionState::Checking)
+                .await;
+            self.request_connectivity_check();
+        }
+
+        self.keep_removed_candidate_stats(c).await;
+
+        self.events
+            .send(&AgentEvent::CandidateRemoved(Arc::clone(c)));
+        if let Some(chan_candidate_removed_tx) = &self.chan_candidate_removed_tx {
+            let _ = chan_candidate_removed_tx.send(c.clone());
+        }
+
+        true
+    }
+
+    /// Notifies the candidate error handler.
+    pub(crate) fn candidate_error(&self, err: CandidateError) {
+        log::warn!(
+            "candidate error {} {}: {}",
+            err.url,
+            err.error_code,
+            err.error_text
+        );
+        // remote candidates that don't resolve have no server, they aren't gather failures
+        #[cfg(feature = "metrics")]
+        if !err.url.is_empty() {
+            METRICS.on_gather_failure(&err.url);
+        }
+        self.events.send(&AgentEvent::CandidateError(err.clone()));
+        if let Some(chan_candidate_error_tx) = &self.chan_candidate_error_tx {
+            let _ = chan_candidate_error_tx.send(err);
+        }
+    }
+
     /// Remove all candidates.
     /// This closes any listening sockets and removes both the local and remote candidate lists.
     ///
     /// This is used for restarts, failures and on close.
     pub(crate) async fn delete_all_candidates(&mut self) {
-        for cs in &mut self.local_candidates.values_mut() {
-            for c in cs {
-                if let Err(err) = c.close().await {
-                    log::warn!("Failed to close candidate {}: {}", c, err);
-                }
+        for c in std::mem::take(&mut self.local_candidates)
+            .values()
+            .flatten()
+        {
+            if let Err(err) = c.close().await {
+                log::warn!("Failed to close candidate {}: {}", c, err);
             }
+            self.unregister_multicast_dns_name(c).await;
+            self.keep_removed_candidate_stats(c).await;
         }
-        self.local_candidates.clear();
 
         for cs in self.remote_candidates.values_mut() {
             for c in cs {
@@ -520,6 +676,28 @@ impl AgentInternal {
         self.remote_candidates.clear();
     }
 
+    /// Keeps the stats of a removed local candidate, marked deleted, dropping the oldest ones
+    /// past `MAX_REMOVED_CANDIDATES_STATS`.
+    async fn keep_removed_candidate_stats(&mut self, c: &Arc<dyn Candidate + Send + Sync>) {
+        let mut stats = Self::candidate_stats(c).await;
+        stats.deleted = true;
+        if self.removed_candidates_stats.len() == MAX_REMOVED_CANDIDATES_STATS {
+            self.removed_candidates_stats.pop_front();
+        }
+        self.removed_candidates_stats.push_back(stats);
+    }
+
+    /// Stops answering the mDNS name of a local host candidate, so that it can't be resolved
+    /// once the candidate is gone.
+    pub(crate) async fn unregister_multicast_dns_name(&self, c: &Arc<dyn Candidate + Send + Sync>) {
+        if c.candidate_type() != CandidateType::Host || !c.address().ends_with(".local") {
+            return;
+        }
+        if let Some(mdns_conn) = &self.mdns_conn {
+            mdns_conn.unregister(&c.address()).await;
+        }
+    }
+
     pub(crate) fn find_remote_candidate(
         &self,
         network_type: NetworkType,
@@ -553,6 +731,15 @@ impl AgentInternal {
             is_use_candidate: m.contains(ATTR_USE_CANDIDATE),
         });
 
+        if let Some(p) = self.find_pair(local, remote).await {
+            let selected_pair = self.agent_conn.get_selected_pair().await;
+            let consent =
+                selected_pair.map_or(false, |selected_pair| Arc::ptr_eq(&selected_pair, &p));
+            p.on_request_sent(consent);
+        }
+        #[cfg(feature = "metrics")]
+        METRICS.on_binding_request_sent();
+
         self.send_stun(m, local, remote).await;
     }
 
@@ -588,6 +775,9 @@ impl AgentInternal {
             );
         } else {
             self.send_stun(&out, local, remote).await;
+            if let Some(p) = self.find_pair(local, remote).await {
+                p.counters.responses_sent.fetch_add(1, Ordering::SeqCst);
+            }
         }
     }
 
@@ -725,8 +915,15 @@ impl AgentInternal {
 
             log::trace!("inbound STUN (Request) from {} to {}", remote, local);
 
+            #[cfg(feature = "metrics")]
+            METRICS.on_binding_request_received();
+
             if let Some(rc) = &remote_candidate {
                 self.handle_binding_request(m, local, rc).await;
+                // the pair may have just been added by the selector
+                if let Some(p) = self.find_pair(local, rc).await {
+                    p.counters.requests_received.fetch_add(1, Ordering::SeqCst);
+                }
             }
         }
 
@@ -735,18 +932,32 @@ impl AgentInternal {
         }
     }
 
-    /// Processes non STUN traffic from a remote candidate, and returns true if it is an actual
-    /// remote candidate.
+    /// Processes non STUN traffic of `n` bytes from a remote candidate, and returns true if it is
+    /// an actual remote candidate.
     pub(crate) async fn validate_non_stun_traffic(
         &self,
         local: &Arc<dyn Candidate + Send + Sync>,
         remote: SocketAddr,
+        n: usize,
     ) -> bool {
-        self.find_remote_candidate(local.network_type(), remote)
-            .map_or(false, |remote_candidate| {
-                remote_candidate.seen(false);
-                true
-            })
+        // the data mostly comes through the selected pair, spare the lookups for it
+        if let Some(p) = self.agent_conn.get_selected_pair().await {
+            if p.remote.addr().await == remote && p.local.id() == local.id() {
+                p.remote.seen(false);
+                p.on_packet_received(n);
+                return true;
+            }
+        }
+
+        if let Some(remote_candidate) = self.find_remote_candidate(local.network_type(), remote) {
+            remote_candidate.seen(false);
+            if let Some(p) = self.find_pair(local, &remote_candidate).await {
+                p.on_packet_received(n);
+            }
+            true
+        } else {
+            false
+        }
     }
 
     /// Sets the credentials of the remote agent.
@@ -806,8 +1017,20 @@ impl AgentInternal {
                 )
                 .await;
             });
+        } else if is_advertised_only(&**candidate) {
+            log::debug!(
+                "Not starting recv loop for {}, its base receives",
+                candidate
+            );
         } else {
             log::error!("Can't start due to conn is_none");
         }
     }
 }
+
+/// A server reflexive candidate without a socket of its own is the mapped address of a host
+/// candidate, its base. It is only advertised: the checks of the peer reach the base, which is
+/// paired instead (RFC 8445, section 6.1.2.4).
+fn is_advertised_only(c: &(dyn Candidate + Send + Sync)) -> bool {
+    c.candidate_type() == CandidateType::ServerReflexive && c.get_conn().is_none()
+}
@@ -286,6 +286,7 @@ impl ControllingSelector for AgentInternal {
             let selected_pair_is_none = self.agent_conn.get_selected_pair().await.is_none();
 
             if let Some(p) = self.find_pair(local, remote).await {
+                p.on_response_received(pending_request.timestamp.elapsed());
                 p.state
                     .store(CandidatePairState::Succeeded as u8, Ordering::SeqCst);
                 log::trace!(
@@ -434,6 +435,7 @@ impl ControlledSelector for AgentInternal {
             );
 
             if let Some(p) = self.find_pair(local, remote).await {
+                p.on_response_received(pending_request.timestamp.elapsed());
                 p.state
                     .store(CandidatePairState::Succeeded as u8, Ordering::SeqCst);
                 log::trace!("Found valid candidate pair: {}", p);
@@ -1,14 +1,103 @@
-use crate::candidate::{CandidatePairState, CandidateType};
+use crate::candidate::{system_time_from_unix_nanos, Candidate, CandidatePairState, CandidateType};
 
 use crate::agent::agent_internal::AgentInternal;
+use crate::control::Role;
+use crate::network_policy::InterfaceType;
 use crate::network_type::NetworkType;
-use std::sync::atomic::Ordering;
-use tokio::time::Instant;
+use crate::state::{ConnectionState, GatheringState};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The id of the transport stats of an agent, which the candidate and candidate pair stats refer
+/// to. An agent is a single transport.
+pub const TRANSPORT_STATS_ID: &str = "T01";
+
+/// All the stats of an agent, modeled on the result of `RTCPeerConnection.getStats()`. The entries
+/// share the timestamp of the report and refer to each other by id.
+#[derive(Debug, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "camelCase")
+)]
+pub struct StatsReport {
+    pub timestamp: SystemTime,
+    pub transport: TransportStats,
+    pub candidate_pairs: Vec<CandidatePairStats>,
+    /// The local candidates, including the deleted ones.
+    pub local_candidates: Vec<CandidateStats>,
+    pub remote_candidates: Vec<CandidateStats>,
+}
+
+/// Contains the ICE transport statistics of an agent, the fields of `RTCTransportStats` and
+/// `RTCIceTransport` that don't depend on DTLS.
+#[derive(Debug, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "camelCase")
+)]
+pub struct TransportStats {
+    /// The timestamp associated with this struct.
+    pub timestamp: SystemTime,
+
+    /// The transport id, `TRANSPORT_STATS_ID`.
+    pub id: String,
+
+    /// The total number of payload bytes sent on this transport, through the connection returned
+    /// by `Agent::dial` or `Agent::accept`.
+    pub bytes_sent: u64,
+
+    /// The total number of payload bytes received on this transport, through the connection
+    /// returned by `Agent::dial` or `Agent::accept`.
+    pub bytes_received: u64,
+
+    /// The role of the agent, unspecified until the connectivity checks start.
+    pub ice_role: Role,
+
+    pub ice_local_username_fragment: String,
+
+    /// Empty until the remote credentials are known.
+    pub ice_remote_username_fragment: String,
+
+    pub ice_state: ConnectionState,
+
+    pub ice_gathering_state: GatheringState,
+
+    /// The id of the selected candidate pair stats, empty when there is none.
+    pub selected_candidate_pair_id: String,
+
+    /// The number of times the selected candidate pair has changed, going from none to a pair
+    /// included.
+    pub selected_candidate_pair_changes: u32,
+
+    /// How long the agent has been in each connection state, in seconds, the current one included.
+    pub connection_state_durations: HashMap<ConnectionState, f64>,
+}
 
 /// Contains ICE candidate pair statistics.
+///
+/// The timestamps are wall-clock times, so that they can be exported. The ones of events that
+/// haven't happened yet are the UNIX epoch.
+#[derive(Debug, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "camelCase")
+)]
 pub struct CandidatePairStats {
     /// The timestamp associated with this struct.
-    pub timestamp: Instant,
+    pub timestamp: SystemTime,
+
+    /// The id of the candidate pair, made of the ids of its candidates.
+    pub id: String,
+
+    /// The id of the transport stats.
+    pub transport_id: String,
 
     /// The id of the local candidate.
     pub local_candidate_id: String,
@@ -39,22 +128,22 @@ pub struct CandidatePairStats {
 
     /// The timestamp at which the last packet was sent on this particular candidate pair, excluding
     /// STUN packets.
-    pub last_packet_sent_timestamp: Instant,
+    pub last_packet_sent_timestamp: SystemTime,
 
     /// The timestamp at which the last packet was received on this particular candidate pair,
     /// excluding STUN packets.
-    pub last_packet_received_timestamp: Instant,
+    pub last_packet_received_timestamp: SystemTime,
 
     /// The timestamp at which the first STUN request was sent on this particular candidate pair.
-    pub first_request_timestamp: Instant,
+    pub first_request_timestamp: SystemTime,
 
     /// The timestamp at which the last STUN request was sent on this particular candidate pair.
     /// The average interval between two consecutive connectivity checks sent can be calculated with
     /// (last_request_timestamp - first_request_timestamp) / requests_sent.
-    pub last_request_timestamp: Instant,
+    pub last_request_timestamp: SystemTime,
 
     /// Timestamp at which the last STUN response was received on this particular candidate pair.
-    pub last_response_timestamp: Instant,
+    pub last_response_timestamp: SystemTime,
 
     /// The sum of all round trip time measurements in seconds since the beginning of the session,
     /// based on STUN connectivity check responses (responses_received), including those that reply
@@ -109,13 +198,15 @@ pub struct CandidatePairStats {
     pub consent_requests_sent: u64,
 
     /// The timestamp at which the latest valid STUN binding response expired.
-    pub consent_expired_timestamp: Instant,
+    pub consent_expired_timestamp: SystemTime,
 }
 
 impl Default for CandidatePairStats {
     fn default() -> Self {
         Self {
-            timestamp: Instant::now(),
+            timestamp: UNIX_EPOCH,
+            id: String::new(),
+            transport_id: String::new(),
             local_candidate_id: String::new(),
             remote_candidate_id: String::new(),
             state: CandidatePairState::default(),
@@ -124,11 +215,11 @@ impl Default for CandidatePairStats {
             packets_received: 0,
             bytes_sent: 0,
             bytes_received: 0,
-            last_packet_sent_timestamp: Instant::now(),
-            last_packet_received_timestamp: Instant::now(),
-            first_request_timestamp: Instant::now(),
-            last_request_timestamp: Instant::now(),
-            last_response_timestamp: Instant::now(),
+            last_packet_sent_timestamp: UNIX_EPOCH,
+            last_packet_received_timestamp: UNIX_EPOCH,
+            first_request_timestamp: UNIX_EPOCH,
+            last_request_timestamp: UNIX_EPOCH,
+            last_response_timestamp: UNIX_EPOCH,
             total_round_trip_time: 0.0,
             current_round_trip_time: 0.0,
             available_outgoing_bitrate: 0.0,
@@ -141,20 +232,28 @@ impl Default for CandidatePairStats {
             retransmissions_received: 0,
             retransmissions_sent: 0,
             consent_requests_sent: 0,
-            consent_expired_timestamp: Instant::now(),
+            consent_expired_timestamp: UNIX_EPOCH,
         }
     }
 }
 
 /// Contains ICE candidate statistics related to the `ICETransport` objects.
 #[derive(Debug, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "camelCase")
+)]
 pub struct CandidateStats {
     // The timestamp associated with this struct.
-    pub timestamp: Instant,
+    pub timestamp: SystemTime,
 
     /// The candidate id.
     pub id: String,
 
+    /// The id of the transport stats.
+    pub transport_id: String,
+
     /// The type of network interface used by the base of a local candidate (the address the ICE
     /// agent sends from). Only present for local candidates; it's not possible to know what type of
     /// network interface a remote candidate is using.
@@ -165,8 +264,17 @@ pub struct CandidateStats {
     /// when the next hop is over a cellular connection.
     pub network_type: NetworkType,
 
+    /// The type of the interface the base of a local candidate is on, as classified by the
+    /// `NetworkPolicy`. Unknown for remote candidates.
+    pub interface_type: InterfaceType,
+
+    /// The address of the candidate as it is advertised, which is an mDNS name for host
+    /// candidates hidden behind one.
+    pub address: String,
+
     /// The IP address of the candidate, allowing for IPv4 addresses and IPv6 addresses, but fully
-    /// qualified domain names (FQDNs) are not allowed.
+    /// qualified domain names (FQDNs) are not allowed. It is the resolved address of a candidate
+    /// advertised with an mDNS name, or the name itself until it is resolved.
     pub ip: String,
 
     /// The port number of the candidate.
@@ -197,9 +305,12 @@ pub struct CandidateStats {
 impl Default for CandidateStats {
     fn default() -> Self {
         Self {
-            timestamp: Instant::now(),
+            timestamp: UNIX_EPOCH,
             id: String::new(),
+            transport_id: String::new(),
             network_type: NetworkType::default(),
+            interface_type: InterfaceType::default(),
+            address: String::new(),
             ip: String::new(),
             port: 0,
             candidate_type: CandidateType::default(),
@@ -212,17 +323,104 @@ impl Default for CandidateStats {
 }
 
 impl AgentInternal {
+    /// Returns the stats of the agent and of all its candidates and candidate pairs, with the
+    /// timestamp of the report.
+    pub(crate) async fn get_stats(&self, ice_gathering_state: GatheringState) -> StatsReport {
+        let timestamp = SystemTime::now();
+
+        let selected_candidate_pair_id = self
+            .agent_conn
+            .get_selected_pair()
+            .await
+            .map(|p| p.id())
+            .unwrap_or_default();
+        let mut connection_state_durations: HashMap<ConnectionState, f64> = self
+            .connection_state_durations
+            .iter()
+            .map(|(state, duration)| (*state, duration.as_secs_f64()))
+            .collect();
+        *connection_state_durations
+            .entry(self.connection_state)
+            .or_default() += self.connection_state_changed_at.elapsed().as_secs_f64();
+
+        let transport = TransportStats {
+            timestamp,
+            id: TRANSPORT_STATS_ID.to_owned(),
+            bytes_sent: self.agent_conn.bytes_sent() as u64,
+            bytes_received: self.agent_conn.bytes_received() as u64,
+            ice_role: if self.started_ch_tx.is_some() {
+                Role::Unspecified
+            } else if self.is_controlling {
+                Role::Controlling
+            } else {
+                Role::Controlled
+            },
+            ice_local_username_fragment: self.local_ufrag.clone(),
+            ice_remote_username_fragment: self.remote_ufrag.clone(),
+            ice_state: self.connection_state,
+            ice_gathering_state,
+            selected_candidate_pair_id,
+            selected_candidate_pair_changes: self.selected_candidate_pair_changes,
+            connection_state_durations,
+        };
+
+        let mut candidate_pairs = self.get_candidate_pairs_stats().await;
+        for stats in &mut candidate_pairs {
+            stats.timestamp = timestamp;
+        }
+        let mut local_candidates = self.get_local_candidates_stats().await;
+        let mut remote_candidates = self.get_remote_candidates_stats().await;
+        for stats in local_candidates
+            .iter_mut()
+            .chain(remote_candidates.iter_mut())
+        {
+            stats.timestamp = timestamp;
+        }
+
+        StatsReport {
+            timestamp,
+            transport,
+            candidate_pairs,
+            local_candidates,
+            remote_candidates,
+        }
+    }
+
     /// Returns a list of candidate pair stats.
     pub(crate) async fn get_candidate_pairs_stats(&self) -> Vec<CandidatePairStats> {
         let checklist = self.agent_conn.checklist.lock().await;
         let mut res = Vec::with_capacity(checklist.len());
         for cp in &*checklist {
+            let counters = &cp.counters;
+            let timestamp =
+                |nanos: &AtomicU64| system_time_from_unix_nanos(nanos.load(Ordering::SeqCst));
+            let seconds = |nanos: &AtomicU64| {
+                Duration::from_nanos(nanos.load(Ordering::SeqCst)).as_secs_f64()
+            };
             let stat = CandidatePairStats {
-                timestamp: Instant::now(),
+                timestamp: SystemTime::now(),
+                id: cp.id(),
+                transport_id: TRANSPORT_STATS_ID.to_owned(),
                 local_candidate_id: cp.local.id(),
                 remote_candidate_id: cp.remote.id(),
                 state: cp.state.load(Ordering::SeqCst).into(),
                 nominated: cp.nominated.load(Ordering::SeqCst),
+                packets_sent: counters.packets_sent.load(Ordering::SeqCst),
+                packets_received: counters.packets_received.load(Ordering::SeqCst),
+                bytes_sent: counters.bytes_sent.load(Ordering::SeqCst),
+                bytes_received: counters.bytes_received.load(Ordering::SeqCst),
+                last_packet_sent_timestamp: timestamp(&counters.last_packet_sent_timestamp),
+                last_packet_received_timestamp: timestamp(&counters.last_packet_received_timestamp),
+                first_request_timestamp: timestamp(&counters.first_request_timestamp),
+                last_request_timestamp: timestamp(&counters.last_request_timestamp),
+                last_response_timestamp: timestamp(&counters.last_response_timestamp),
+                total_round_trip_time: seconds(&counters.total_round_trip_time),
+                current_round_trip_time: seconds(&counters.current_round_trip_time),
+                requests_received: counters.requests_received.load(Ordering::SeqCst),
+                requests_sent: counters.requests_sent.load(Ordering::SeqCst),
+                responses_received: counters.responses_received.load(Ordering::SeqCst),
+                responses_sent: counters.responses_sent.load(Ordering::SeqCst),
+                consent_requests_sent: counters.consent_requests_sent.load(Ordering::SeqCst),
                 ..CandidatePairStats::default()
             };
             res.push(stat);
@@ -230,51 +428,57 @@ impl AgentInternal {
         res
     }
 
-    /// Returns a list of local candidates stats.
-    pub(crate) fn get_local_candidates_stats(&self) -> Vec<CandidateStats> {
+    /// Returns a list of local candidates stats, including the ones of the deleted candidates.
+    pub(crate) async fn get_local_candidates_stats(&self) -> Vec<CandidateStats> {
         let mut res = Vec::with_capacity(self.local_candidates.len());
-        for (network_type, local_candidates) in &self.local_candidates {
+        for local_candidates in self.local_candidates.values() {
             for c in local_candidates {
-                let stat = CandidateStats {
-                    timestamp: Instant::now(),
-                    id: c.id(),
-                    network_type: *network_type,
-                    ip: c.address(),
-                    port: c.port(),
-                    candidate_type: c.candidate_type(),
-                    priority: c.priority(),
-                    // URL string
-                    relay_protocol: "udp".to_owned(),
-                    // Deleted bool
-                    ..CandidateStats::default()
-                };
-                res.push(stat);
+                res.push(Self::candidate_stats(c).await);
             }
         }
+        res.extend(self.removed_candidates_stats.iter().cloned());
         res
     }
 
-    /// Returns a list of remote candidates stats.
-    pub(crate) fn get_remote_candidates_stats(&self) -> Vec<CandidateStats> {
+    /// Returns the stats of a single local candidate.
+    pub(crate) async fn candidate_stats(c: &Arc<dyn Candidate + Send + Sync>) -> CandidateStats {
+        CandidateStats {
+            interface_type: c.interface_type(),
+            url: c.url().map(|url| url.to_string()).unwrap_or_default(),
+            relay_protocol: c.relay_protocol(),
+            ..Self::common_candidate_stats(c).await
+        }
+    }
+
+    /// Returns a list of remote candidates stats. Only the fields that can be known of a remote
+    /// candidate are set.
+    pub(crate) async fn get_remote_candidates_stats(&self) -> Vec<CandidateStats> {
         let mut res = Vec::with_capacity(self.remote_candidates.len());
-        for (network_type, remote_candidates) in &self.remote_candidates {
+        for remote_candidates in self.remote_candidates.values() {
             for c in remote_candidates {
-                let stat = CandidateStats {
-                    timestamp: Instant::now(),
-                    id: c.id(),
-                    network_type: *network_type,
-                    ip: c.address(),
-                    port: c.port(),
-                    candidate_type: c.candidate_type(),
-                    priority: c.priority(),
-                    // URL string
-                    relay_protocol: "udp".to_owned(),
-                    // Deleted bool
-                    ..CandidateStats::default()
-                };
-                res.push(stat);
+                res.push(Self::common_candidate_stats(c).await);
             }
         }
         res
     }
+
+    async fn common_candidate_stats(c: &Arc<dyn Candidate + Send + Sync>) -> CandidateStats {
+        let addr = c.addr().await;
+        CandidateStats {
+            timestamp: SystemTime::now(),
+            id: c.id(),
+            transport_id: TRANSPORT_STATS_ID.to_owned(),
+            network_type: c.network_type(),
+            address: c.address(),
+            ip: if addr.ip().is_unspecified() {
+                c.address()
+            } else {
+                addr.ip().to_string()
+            },
+            port: c.port(),
+            candidate_type: c.candidate_type(),
+            priority: c.priority(),
+            ..CandidateStats::default()
+        }
+    }
 }
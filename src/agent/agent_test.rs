@@ -17,9 +17,105 @@ use std::ops::Sub;
 use std::str::FromStr;
 use stun::message::*;
 use stun::textattrs::Username;
+use tokio_stream::StreamExt;
+use turn::auth::AuthHandler;
 use util::{vnet::*, Conn, Error};
 use waitgroup::{WaitGroup, Worker};
 
+/// Collects the candidates of an agent from its events until gathering is complete.
+pub(crate) async fn gathered_candidates(
+    events: &mut (impl Stream<Item = AgentEvent> + Unpin),
+) -> Vec<Arc<dyn Candidate + Send + Sync>> {
+    let mut candidates = vec![];
+    while let Some(event) = events.next().await {
+        match event {
+            AgentEvent::Candidate(c) => candidates.push(c),
+            AgentEvent::GatheringStateChange(GatheringState::Complete) => break,
+            _ => {}
+        }
+    }
+    candidates
+}
+
+/// Gathers the candidates of `a` and returns them once gathering is complete.
+pub(crate) async fn gather_and_wait(
+    a: &Agent,
+) -> Result<Vec<Arc<dyn Candidate + Send + Sync>>, Error> {
+    let mut events = Box::pin(a.events());
+    a.gather_candidates().await?;
+    Ok(gathered_candidates(&mut events).await)
+}
+
+/// Builds a 1.2.3.0/24 WAN with a server net holding `server_ips`, and a client net at 1.2.3.10,
+/// or at 10.0.0.1 behind a NAT of `nat_type` when given. Returns the client and server nets.
+pub(crate) async fn build_wan(
+    server_ips: &[&str],
+    nat_type: Option<nat::NatType>,
+) -> Result<(Arc<net::Net>, Arc<net::Net>), Error> {
+    let wan = Arc::new(Mutex::new(router::Router::new(router::RouterConfig {
+        cidr: "1.2.3.0/24".to_owned(),
+        ..Default::default()
+    })?));
+    let server_net = Arc::new(net::Net::new(Some(net::NetConfig {
+        static_ips: server_ips.iter().map(|ip| (*ip).to_owned()).collect(),
+        ..Default::default()
+    })));
+    connect_net2router(&server_net, &wan).await?;
+
+    let client_net = if let Some(nat_type) = nat_type {
+        let lan = Arc::new(Mutex::new(router::Router::new(router::RouterConfig {
+            static_ips: vec!["1.2.3.10".to_owned()],
+            cidr: "10.0.0.0/24".to_owned(),
+            nat_type: Some(nat_type),
+            ..Default::default()
+        })?));
+        let client_net = Arc::new(net::Net::new(Some(net::NetConfig {
+            static_ips: vec!["10.0.0.1".to_owned()],
+            ..Default::default()
+        })));
+        connect_net2router(&client_net, &lan).await?;
+        connect_router2router(&lan, &wan).await?;
+        client_net
+    } else {
+        let client_net = Arc::new(net::Net::new(Some(net::NetConfig {
+            static_ips: vec!["1.2.3.10".to_owned()],
+            ..Default::default()
+        })));
+        connect_net2router(&client_net, &wan).await?;
+        client_net
+    };
+
+    start_router(&wan).await?;
+
+    Ok((client_net, server_net))
+}
+
+/// Runs a TURN server of the `webrtc.rs` realm on `conn`, a socket of `server_net`, relaying
+/// from the address of `conn`.
+pub(crate) async fn start_turn_server(
+    server_net: Arc<net::Net>,
+    conn: Arc<dyn Conn + Send + Sync>,
+    auth_handler: Box<dyn AuthHandler + Send + Sync>,
+) -> Result<turn::server::Server, Error> {
+    let relay_address = conn.local_addr().await?.ip();
+    turn::server::Server::new(turn::server::config::ServerConfig {
+        conn_configs: vec![turn::server::config::ConnConfig {
+            conn,
+            relay_addr_generator: Box::new(
+                turn::relay::relay_static::RelayAddressGeneratorStatic {
+                    relay_address,
+                    address: "0.0.0.0".to_owned(),
+                    net: server_net,
+                },
+            ),
+        }],
+        realm: "webrtc.rs".to_owned(),
+        auth_handler: Arc::new(auth_handler),
+        channel_bind_timeout: Duration::from_secs(0),
+    })
+    .await
+}
+
 #[tokio::test]
 async fn test_pair_search() -> Result<(), Error> {
     let config = AgentConfig::default();
@@ -90,6 +186,7 @@ async fn test_pair_priority() -> Result<(), Error> {
         },
         rel_addr: "4.3.2.1".to_owned(),
         rel_port: 43212,
+        ..Default::default()
     };
 
     let srflx_remote = srflx_config
@@ -1065,6 +1162,7 @@ async fn test_candidate_pair_stats() -> Result<(), Error> {
             },
             rel_addr: "4.3.2.1".to_owned(),
             rel_port: 43212,
+            ..Default::default()
         }
         .new_candidate_server_reflexive(Some(Arc::clone(&a.agent_internal)))
         .await?,
@@ -1197,6 +1295,7 @@ async fn test_local_candidate_stats() -> Result<(), Error> {
                 address: "192.168.1.1".to_owned(),
                 port: 19216,
                 component: 1,
+                interface_type: InterfaceType::Wifi,
                 ..Default::default()
             },
             ..Default::default()
@@ -1212,10 +1311,12 @@ async fn test_local_candidate_stats() -> Result<(), Error> {
                 address: "192.168.1.1".to_owned(),
                 port: 19217,
                 component: 1,
+                url: Some(Url::parse_url("stun:stun.example.com:3478")?),
                 ..Default::default()
             },
             rel_addr: "4.3.2.1".to_owned(),
             rel_port: 43212,
+            ..Default::default()
         }
         .new_candidate_server_reflexive(Some(Arc::clone(&a.agent_internal)))
         .await?,
@@ -1261,6 +1362,8 @@ async fn test_local_candidate_stats() -> Result<(), Error> {
             "invalid stats CandidateType"
         );
         assert_eq!(stats.ip, candidate.address(), "invalid stats IP");
+        assert_eq!(stats.address, candidate.address(), "invalid stats address");
+        assert!(!stats.deleted);
     }
 
     assert_eq!(
@@ -1268,11 +1371,73 @@ async fn test_local_candidate_stats() -> Result<(), Error> {
         host_local.id(),
         "missing host local stat"
     );
+    assert_eq!(host_local_stat.network_type, NetworkType::Udp4);
+    assert_eq!(host_local_stat.interface_type, InterfaceType::Wifi);
+    assert_eq!(host_local_stat.url, "");
     assert_eq!(
         srflx_local_stat.id,
         srflx_local.id(),
         "missing srflx local stat"
     );
+    assert_eq!(srflx_local_stat.url, "stun:stun.example.com:3478");
+    assert_eq!(srflx_local_stat.relay_protocol, "");
+
+    a.close().await?;
+
+    // closed candidates stay visible
+    let local_stats = a.get_local_candidates_stats().await;
+    assert_eq!(local_stats.len(), 2);
+    assert!(local_stats.iter().all(|stats| stats.deleted));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_removed_candidates_stats_bounded() -> Result<(), Error> {
+    let a = Agent::new(AgentConfig::default()).await?;
+
+    let mut candidates = vec![];
+    for port in 0..=MAX_REMOVED_CANDIDATES_STATS as u16 {
+        let c: Arc<dyn Candidate + Send + Sync> = Arc::new(
+            CandidateHostConfig {
+                base_config: CandidateBaseConfig {
+                    network: "udp".to_owned(),
+                    address: "192.168.1.1".to_owned(),
+                    port: 20000 + port,
+                    component: 1,
+                    ..Default::default()
+                },
+                ..Default::default()
+            }
+            .new_candidate_host(Some(Arc::clone(&a.agent_internal)))
+            .await?,
+        );
+        candidates.push(c);
+    }
+
+    {
+        let mut ai = a.agent_internal.lock().await;
+        ai.local_candidates
+            .insert(NetworkType::Udp4, candidates.clone());
+        for c in &candidates {
+            assert!(ai.remove_candidate(c).await);
+        }
+    }
+
+    // only the last ones are kept
+    let local_stats = a.get_local_candidates_stats().await;
+    assert_eq!(local_stats.len(), MAX_REMOVED_CANDIDATES_STATS);
+    assert!(local_stats.iter().all(|stats| stats.deleted));
+    assert!(
+        local_stats
+            .iter()
+            .all(|stats| stats.id != candidates[0].id()),
+        "the oldest stats should be dropped"
+    );
+
+    // a restart forgets them
+    a.restart(String::new(), String::new()).await?;
+    assert!(a.get_local_candidates_stats().await.is_empty());
 
     a.close().await?;
 
@@ -1311,6 +1476,7 @@ async fn test_remote_candidate_stats() -> Result<(), Error> {
             },
             rel_addr: "4.3.2.1".to_owned(),
             rel_port: 43212,
+            ..Default::default()
         }
         .new_candidate_server_reflexive(Some(Arc::clone(&a.agent_internal)))
         .await?,
@@ -1402,6 +1568,11 @@ async fn test_remote_candidate_stats() -> Result<(), Error> {
             "invalid stats CandidateType"
         );
         assert_eq!(stats.ip, candidate.address(), "invalid stats IP");
+        assert_eq!(stats.url, "", "remote candidates have no URL");
+        assert_eq!(
+            stats.relay_protocol, "",
+            "remote candidates have no relay protocol"
+        );
     }
 
     assert_eq!(
@@ -1527,6 +1698,29 @@ async fn test_init_ext_ip_mapping() -> Result<(), Error> {
         panic!("expected error, but got ok");
     }
 
+    // NewAgent should return an error when both forms of 1:1 NAT mapping are set.
+    if let Err(err) = Agent::new(AgentConfig {
+        nat_1to1_ips: vec!["1.2.3.4".to_owned()],
+        nat_mapping: NatMappingConfig {
+            rules: vec![NatMappingRule {
+                external_ip: "1.2.3.5".parse().unwrap(),
+                ..NatMappingRule::default()
+            }],
+            ..NatMappingConfig::default()
+        },
+        ..Default::default()
+    })
+    .await
+    {
+        assert_eq!(
+            err, *ERR_NAT_MAPPING_WITH_NAT_1TO1_IPS,
+            "Unexpected error: {}",
+            err
+        );
+    } else {
+        panic!("expected error, but got ok");
+    }
+
     Ok(())
 }
 
@@ -2187,3 +2381,331 @@ async fn test_lite_lifecycle() -> Result<(), Error> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_resolve_remote_candidate_host_name() -> Result<(), Error> {
+    let wan = Arc::new(Mutex::new(router::Router::new(router::RouterConfig {
+        cidr: "0.0.0.0/0".to_owned(),
+        ..Default::default()
+    })?));
+    let net = Arc::new(net::Net::new(Some(net::NetConfig {
+        static_ips: vec!["192.168.0.1".to_owned()],
+        ..Default::default()
+    })));
+    connect_net2router(&net, &wan).await?;
+    {
+        let mut w = wan.lock().await;
+        w.add_host("peer.example.test".to_owned(), "192.168.0.2".to_owned())
+            .await?;
+    }
+    start_router(&wan).await?;
+
+    let a = Agent::new(AgentConfig {
+        network_types: vec![NetworkType::Udp4],
+        multicast_dns_mode: MulticastDnsMode::Disabled,
+        net: Some(net),
+        ..Default::default()
+    })
+    .await?;
+
+    let c: Arc<dyn Candidate + Send + Sync> = Arc::new(
+        a.unmarshal_remote_candidate(
+            "1 1 udp 2130706431 peer.example.test 5000 typ host".to_owned(),
+        )
+        .await?,
+    );
+    assert_eq!(c.network_type(), NetworkType::Udp4);
+    a.add_remote_candidate(&c).await?;
+
+    let deadline = Instant::now() + Duration::from_secs(3);
+    loop {
+        {
+            let ai = a.agent_internal.lock().await;
+            if let Some(candidates) = ai.remote_candidates.get(&NetworkType::Udp4) {
+                assert_eq!(candidates.len(), 1, "one candidate per resolved address");
+                assert_eq!(candidates[0].address(), "192.168.0.2");
+                assert_eq!(candidates[0].port(), 5000);
+                assert_eq!(candidates[0].priority(), 2130706431);
+                assert_eq!(candidates[0].foundation(), "1");
+                break;
+            }
+        }
+        assert!(
+            Instant::now() < deadline,
+            "the host name should have been resolved"
+        );
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    a.close().await?;
+    {
+        let mut w = wan.lock().await;
+        w.stop().await?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_resolve_remote_candidate_names_of_all_types() -> Result<(), Error> {
+    let wan = Arc::new(Mutex::new(router::Router::new(router::RouterConfig {
+        cidr: "0.0.0.0/0".to_owned(),
+        ..Default::default()
+    })?));
+    let net = Arc::new(net::Net::new(Some(net::NetConfig {
+        static_ips: vec!["192.168.0.1".to_owned()],
+        ..Default::default()
+    })));
+    connect_net2router(&net, &wan).await?;
+    {
+        let mut w = wan.lock().await;
+        w.add_host("srflx.example.test".to_owned(), "27.1.1.1".to_owned())
+            .await?;
+        w.add_host("relay.example.test".to_owned(), "27.2.2.2".to_owned())
+            .await?;
+    }
+    start_router(&wan).await?;
+
+    let a = Agent::new(AgentConfig {
+        network_types: vec![NetworkType::Udp4],
+        multicast_dns_mode: MulticastDnsMode::Disabled,
+        net: Some(net),
+        ..Default::default()
+    })
+    .await?;
+
+    for raw in [
+        "2 1 udp 1694498815 srflx.example.test 5000 typ srflx raddr 192.168.0.2 rport 5000",
+        "3 1 udp 16777215 relay.example.test 5001 typ relay raddr 27.1.1.1 rport 5000",
+    ] {
+        let c: Arc<dyn Candidate + Send + Sync> =
+            Arc::new(a.unmarshal_remote_candidate(raw.to_owned()).await?);
+        a.add_remote_candidate(&c).await?;
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(3);
+    loop {
+        {
+            let ai = a.agent_internal.lock().await;
+            let candidates = ai.remote_candidates.get(&NetworkType::Udp4);
+            if let Some(candidates) = candidates.filter(|candidates| candidates.len() == 2) {
+                let srflx = candidates
+                    .iter()
+                    .find(|c| c.candidate_type() == CandidateType::ServerReflexive)
+                    .expect("srflx candidate");
+                assert_eq!(srflx.address(), "27.1.1.1");
+                assert_eq!(srflx.port(), 5000);
+                assert_eq!(srflx.priority(), 1694498815);
+                assert_eq!(
+                    srflx.related_address(),
+                    Some(CandidateRelatedAddress {
+                        address: "192.168.0.2".to_owned(),
+                        port: 5000,
+                    })
+                );
+                let relay = candidates
+                    .iter()
+                    .find(|c| c.candidate_type() == CandidateType::Relay)
+                    .expect("relay candidate");
+                assert_eq!(relay.address(), "27.2.2.2");
+                assert_eq!(relay.port(), 5001);
+                assert_eq!(relay.foundation(), "3");
+                break;
+            }
+        }
+        assert!(
+            Instant::now() < deadline,
+            "the names should have been resolved"
+        );
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    a.close().await?;
+    {
+        let mut w = wan.lock().await;
+        w.stop().await?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_lookup_ips_returns_every_address() -> Result<(), Error> {
+    let net = Arc::new(net::Net::new(None));
+
+    let ips = Agent::lookup_ips(&net, "localhost:0").await;
+    let expected: Vec<IpAddr> = tokio::net::lookup_host("localhost:0")
+        .await?
+        .map(|addr| addr.ip())
+        .collect();
+    assert!(!ips.is_empty());
+    for ip in expected {
+        assert!(ips.contains(&ip), "{} is missing from {:?}", ip, ips);
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_unresolved_remote_candidate_host_name() -> Result<(), Error> {
+    let wan = Arc::new(Mutex::new(router::Router::new(router::RouterConfig {
+        cidr: "0.0.0.0/0".to_owned(),
+        ..Default::default()
+    })?));
+    let net = Arc::new(net::Net::new(Some(net::NetConfig {
+        static_ips: vec!["192.168.0.1".to_owned()],
+        ..Default::default()
+    })));
+    connect_net2router(&net, &wan).await?;
+    start_router(&wan).await?;
+
+    let a = Agent::new(AgentConfig {
+        network_types: vec![NetworkType::Udp4],
+        multicast_dns_mode: MulticastDnsMode::Disabled,
+        net: Some(net),
+        ..Default::default()
+    })
+    .await?;
+
+    let (error_tx, mut error_rx) = mpsc::channel::<CandidateError>(1);
+    a.on_candidate_error(Box::new(move |err: CandidateError| {
+        let error_tx = error_tx.clone();
+        Box::pin(async move {
+            let _ = error_tx.send(err).await;
+        })
+    }))
+    .await;
+
+    let c: Arc<dyn Candidate + Send + Sync> = Arc::new(
+        a.unmarshal_remote_candidate(
+            "1 1 udp 2130706431 missing.example.test 5000 typ host".to_owned(),
+        )
+        .await?,
+    );
+    a.add_remote_candidate(&c).await?;
+
+    let err = tokio::time::timeout(Duration::from_secs(3), error_rx.recv())
+        .await
+        .expect("the lookup should have failed")
+        .unwrap();
+    assert_eq!(err.address, "missing.example.test");
+    assert_eq!(err.port, 5000);
+    assert_eq!(err.error_code, CANDIDATE_ERROR_CODE_UNREACHABLE);
+    assert_eq!(err.error_text, ERR_DNS_NO_ADDRESS.to_string());
+    {
+        let ai = a.agent_internal.lock().await;
+        assert!(ai.remote_candidates.is_empty());
+    }
+    #[cfg(feature = "metrics")]
+    assert!(
+        !crate::metrics::render().contains("url=\"\""),
+        "a remote candidate isn't a gather failure"
+    );
+
+    a.close().await?;
+    {
+        let mut w = wan.lock().await;
+        w.stop().await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "serde")]
+#[tokio::test]
+async fn test_agent_config_deserialize() -> Result<(), Error> {
+    let config: AgentConfig = serde_json::from_str(
+        r#"{
+            "urls": [
+                {"scheme": "stun", "host": "stun.example.test", "port": 3478},
+                {"scheme": "turn", "host": "turn.example.test", "port": 3478, "proto": "tcp",
+                 "username": "user", "password": "pass"}
+            ],
+            "local_ufrag": "8hhY",
+            "local_pwd": "asd88fgpdd777uzjYhagZg",
+            "multicast_dns_mode": "query-only",
+            "network_types": ["udp4", "tcp4"],
+            "candidate_types": ["host", "srflx"],
+            "disconnected_timeout": {"secs": 10, "nanos": 0},
+            "nat_mapping": {
+                "candidates": "host-and-server-reflexive",
+                "rules": [{"external_ip": "203.0.113.7", "local_ip": "10.0.0.1"}]
+            },
+            "bind_addresses": ["127.0.0.1"],
+            "include_loopback": true
+        }"#,
+    )
+    .unwrap();
+
+    assert_eq!(config.urls.len(), 2);
+    assert_eq!(config.urls[1].scheme, SchemeType::Turn);
+    assert_eq!(config.urls[1].proto, ProtoType::Tcp);
+    assert_eq!(config.urls[1].username, "user");
+    assert_eq!(config.multicast_dns_mode, MulticastDnsMode::QueryOnly);
+    assert_eq!(
+        config.candidate_types,
+        vec![CandidateType::Host, CandidateType::ServerReflexive]
+    );
+    assert_eq!(config.disconnected_timeout, Some(Duration::from_secs(10)));
+    assert_eq!(config.failed_timeout, None);
+    assert_eq!(
+        config.nat_mapping.candidates,
+        NatMappingCandidates::HostAndServerReflexive
+    );
+    assert_eq!(config.nat_mapping.rules[0].port_range, None);
+
+    let a = Agent::new(AgentConfig {
+        network_types: vec![NetworkType::Udp4],
+        ..config
+    })
+    .await?;
+    a.close().await?;
+
+    Ok(())
+}
+
+#[cfg(feature = "serde")]
+#[tokio::test]
+async fn test_agent_stats_serialize() -> Result<(), Error> {
+    let stats = CandidatePairStats {
+        local_candidate_id: "local".to_owned(),
+        remote_candidate_id: "remote".to_owned(),
+        state: CandidatePairState::InProgress,
+        timestamp: SystemTime::now(),
+        ..CandidatePairStats::default()
+    };
+    let json = serde_json::to_value(&stats).unwrap();
+    assert_eq!(json["localCandidateId"], "local");
+    assert_eq!(json["state"], "in-progress");
+    assert!(json["timestamp"]["secs_since_epoch"].as_u64().unwrap() > 0);
+    let parsed: CandidatePairStats = serde_json::from_value(json).unwrap();
+    assert_eq!(parsed.timestamp, stats.timestamp);
+
+    let stats = CandidateStats {
+        candidate_type: CandidateType::ServerReflexive,
+        network_type: NetworkType::Udp4,
+        interface_type: InterfaceType::Wifi,
+        ..CandidateStats::default()
+    };
+    let json = serde_json::to_value(&stats).unwrap();
+    assert_eq!(json["candidateType"], "srflx");
+    assert_eq!(json["networkType"], "udp4");
+    assert_eq!(json["interfaceType"], "wifi");
+    assert_eq!(json["relayProtocol"], "");
+
+    assert_eq!(
+        serde_json::to_value(ConnectionState::Checking).unwrap(),
+        "checking"
+    );
+
+    let a = Agent::new(AgentConfig::default()).await?;
+    let json = serde_json::to_value(a.get_stats().await).unwrap();
+    assert_eq!(json["transport"]["id"], TRANSPORT_STATS_ID);
+    assert_eq!(json["transport"]["iceRole"], "unspecified");
+    assert_eq!(json["transport"]["iceState"], "new");
+    assert_eq!(json["transport"]["iceGatheringState"], "new");
+    assert!(json["transport"]["connectionStateDurations"]["new"].is_number());
+    a.close().await?;
+
+    Ok(())
+}
@@ -1,5 +1,6 @@
 use super::*;
 use crate::errors::*;
+use crate::sdp::IceNegotiation;
 
 use async_trait::async_trait;
 use std::io;
@@ -11,42 +12,63 @@ impl Agent {
     /// The method blocks until at least one ice candidate pair has successfully connected.
     pub async fn dial(
         &self,
-        mut cancel_rx: mpsc::Receiver<()>,
+        cancel_rx: mpsc::Receiver<()>,
         remote_ufrag: String,
         remote_pwd: String,
     ) -> Result<Arc<impl Conn>, Error> {
-        let (on_connected_rx, agent_conn) = {
-            let agent_internal = Arc::clone(&self.agent_internal);
-            let mut ai = self.agent_internal.lock().await;
-            ai.start_connectivity_checks(agent_internal, true, remote_ufrag, remote_pwd)
-                .await?;
-            (ai.on_connected_rx.take(), Arc::clone(&ai.agent_conn))
-        };
-
-        if let Some(mut on_connected_rx) = on_connected_rx {
-            // block until pair selected
-            tokio::select! {
-                _ = on_connected_rx.recv() => {},
-                _ = cancel_rx.recv() => {
-                    return Err(ERR_CANCELED_BY_CALLER.to_owned());
-                }
-            }
-        }
-        Ok(agent_conn)
+        self.connect_with_role(true, cancel_rx, remote_ufrag, remote_pwd)
+            .await
     }
 
     /// Connects to the remote agent, acting as the controlled ice agent.
     /// The method blocks until at least one ice candidate pair has successfully connected.
     pub async fn accept(
         &self,
-        mut cancel_rx: mpsc::Receiver<()>,
+        cancel_rx: mpsc::Receiver<()>,
+        remote_ufrag: String,
+        remote_pwd: String,
+    ) -> Result<Arc<impl Conn>, Error> {
+        self.connect_with_role(false, cancel_rx, remote_ufrag, remote_pwd)
+            .await
+    }
+
+    /// Connects to the remote agent in the role negotiated from the SDP offer and answer, with
+    /// the credentials of the remote `a=ice-ufrag` and `a=ice-pwd` attributes.
+    /// The method blocks until at least one ice candidate pair has successfully connected.
+    pub async fn connect(
+        &self,
+        negotiation: &IceNegotiation,
+        cancel_rx: mpsc::Receiver<()>,
         remote_ufrag: String,
         remote_pwd: String,
     ) -> Result<Arc<impl Conn>, Error> {
+        // RFC 8445 section 6.1.1, a full agent controls a lite one
+        if negotiation.is_controlling
+            && !negotiation.remote_lite
+            && self.agent_internal.lock().await.lite
+        {
+            return Err(ERR_LITE_AGENT_CONTROLLING.to_owned());
+        }
+        self.connect_with_role(
+            negotiation.is_controlling,
+            cancel_rx,
+            remote_ufrag,
+            remote_pwd,
+        )
+        .await
+    }
+
+    async fn connect_with_role(
+        &self,
+        is_controlling: bool,
+        mut cancel_rx: mpsc::Receiver<()>,
+        remote_ufrag: String,
+        remote_pwd: String,
+    ) -> Result<Arc<AgentConn>, Error> {
         let (on_connected_rx, agent_conn) = {
             let agent_internal = Arc::clone(&self.agent_internal);
             let mut ai = self.agent_internal.lock().await;
-            ai.start_connectivity_checks(agent_internal, false, remote_ufrag, remote_pwd)
+            ai.start_connectivity_checks(agent_internal, is_controlling, remote_ufrag, remote_pwd)
                 .await?;
             (ai.on_connected_rx.take(), Arc::clone(&ai.agent_conn))
         };
@@ -125,7 +147,8 @@ impl AgentConn {
             }
 
             if let Some(b) = &mut best {
-                if b.priority() < p.priority() {
+                // A cheaper pair wins over a higher priority one, e.g. Wi-Fi over cellular
+                if (p.network_cost(), b.priority()) < (b.network_cost(), p.priority()) {
                     *b = p;
                 }
             } else {
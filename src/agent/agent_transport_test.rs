@@ -1,7 +1,15 @@
+use super::agent_test::gathered_candidates;
 use super::agent_vnet_test::*;
 use super::*;
 
+use crate::candidate::candidate_base::CandidateBaseConfig;
+use crate::candidate::candidate_host::CandidateHostConfig;
+use crate::control::Role;
+use crate::network_policy::{NETWORK_COST_CELLULAR, NETWORK_COST_LOW};
+use crate::sdp::{negotiate, IceAttributes, IceNegotiation};
 use defer::defer;
+use std::time::UNIX_EPOCH;
+use tokio_stream::StreamExt;
 use util::{vnet::*, Conn, Error};
 use waitgroup::WaitGroup;
 
@@ -132,3 +140,407 @@ async fn test_conn_stats() -> Result<(), Error> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_candidate_pair_stats_counters() -> Result<(), Error> {
+    let (ca, cb, a_agent, b_agent) = pipe(None, None).await?;
+
+    ca.send(&[0u8; 10]).await?;
+    ca.send(&[0u8; 20]).await?;
+    let mut buf = vec![0u8; 100];
+    assert_eq!(cb.recv(&mut buf).await?, 10);
+    assert_eq!(cb.recv(&mut buf).await?, 20);
+
+    let a_stats = a_agent.get_candidate_pairs_stats().await;
+    let sent = a_stats
+        .iter()
+        .find(|stat| stat.packets_sent > 0)
+        .expect("a pair should have sent data");
+    assert_eq!(sent.packets_sent, 2);
+    assert_eq!(sent.bytes_sent, 30);
+    assert!(sent.last_packet_sent_timestamp > UNIX_EPOCH);
+    assert!(sent.requests_sent > 0);
+    assert!(sent.responses_received > 0);
+    assert!(sent.responses_sent > 0);
+    assert!(sent.requests_received > 0);
+    assert!(sent.current_round_trip_time > 0.0);
+    assert!(sent.total_round_trip_time >= sent.current_round_trip_time);
+    assert!(sent.first_request_timestamp > UNIX_EPOCH);
+    assert!(sent.first_request_timestamp <= sent.last_request_timestamp);
+    assert!(sent.last_response_timestamp > UNIX_EPOCH);
+
+    let b_stats = b_agent.get_candidate_pairs_stats().await;
+    let received = b_stats
+        .iter()
+        .find(|stat| stat.packets_received > 0)
+        .expect("a pair should have received data");
+    assert_eq!(received.packets_received, 2);
+    assert_eq!(received.bytes_received, 30);
+    assert!(received.last_packet_received_timestamp > UNIX_EPOCH);
+    assert_eq!(received.packets_sent, 0);
+    assert_eq!(received.last_packet_sent_timestamp, UNIX_EPOCH);
+
+    // what never happened is at the epoch, not at the time of the report
+    let default_stats = CandidatePairStats::default();
+    assert_eq!(default_stats.timestamp, UNIX_EPOCH);
+    assert_eq!(default_stats.last_response_timestamp, UNIX_EPOCH);
+    assert_eq!(default_stats.consent_expired_timestamp, UNIX_EPOCH);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_agent_get_stats() -> Result<(), Error> {
+    let (ca, cb, a_agent, b_agent) = pipe(None, None).await?;
+
+    ca.send(&[0u8; 10]).await?;
+    let mut buf = vec![0u8; 100];
+    assert_eq!(cb.recv(&mut buf).await?, 10);
+
+    let a_report = a_agent.get_stats().await;
+    let b_report = b_agent.get_stats().await;
+
+    let transport = &a_report.transport;
+    assert_eq!(transport.id, TRANSPORT_STATS_ID);
+    assert_eq!(transport.timestamp, a_report.timestamp);
+    assert_eq!(transport.ice_role, Role::Controlled, "a accepted");
+    assert_eq!(b_report.transport.ice_role, Role::Controlling, "b dialed");
+    assert_eq!(
+        transport.ice_local_username_fragment,
+        b_report.transport.ice_remote_username_fragment
+    );
+    assert_eq!(
+        transport.ice_remote_username_fragment,
+        b_report.transport.ice_local_username_fragment
+    );
+    assert_eq!(transport.ice_state, ConnectionState::Connected);
+    assert!(transport.connection_state_durations[&ConnectionState::Checking] > 0.0);
+    assert!(transport
+        .connection_state_durations
+        .contains_key(&ConnectionState::Connected));
+    assert_eq!(transport.bytes_sent, 10);
+    assert_eq!(b_report.transport.bytes_received, 10);
+    assert!(transport.selected_candidate_pair_changes >= 1);
+
+    // every id refers to an entry of the report
+    let selected = a_report
+        .candidate_pairs
+        .iter()
+        .find(|pair| pair.id == transport.selected_candidate_pair_id)
+        .expect("the selected pair should be in the report");
+    assert!(selected.nominated);
+    assert_eq!(selected.bytes_sent, 10);
+    assert!(a_report
+        .local_candidates
+        .iter()
+        .any(|c| c.id == selected.local_candidate_id));
+    assert!(a_report
+        .remote_candidates
+        .iter()
+        .any(|c| c.id == selected.remote_candidate_id));
+
+    for pair in &a_report.candidate_pairs {
+        assert_eq!(pair.transport_id, TRANSPORT_STATS_ID);
+        assert_eq!(pair.timestamp, a_report.timestamp);
+    }
+    for c in a_report
+        .local_candidates
+        .iter()
+        .chain(&a_report.remote_candidates)
+    {
+        assert_eq!(c.transport_id, TRANSPORT_STATS_ID);
+        assert_eq!(c.timestamp, a_report.timestamp);
+    }
+
+    Ok(())
+}
+
+/// Reads `events` until the gathering completes, returning the gathered candidates.
+#[tokio::test]
+async fn test_agent_events() -> Result<(), Error> {
+    let new_agent = || async {
+        Agent::new(AgentConfig {
+            network_types: supported_network_types(),
+            ..Default::default()
+        })
+        .await
+        .map(Arc::new)
+    };
+    let a_agent = new_agent().await?;
+    let b_agent = new_agent().await?;
+    let mut a_events = Box::pin(a_agent.events());
+    let mut b_events = Box::pin(b_agent.events());
+
+    // the events stream stands in for the on_candidate handler
+    a_agent.gather_candidates().await?;
+    b_agent.gather_candidates().await?;
+    assert!(matches!(
+        a_events.next().await,
+        Some(AgentEvent::GatheringStateChange(GatheringState::Gathering))
+    ));
+    let a_candidates = gathered_candidates(&mut a_events).await;
+    let b_candidates = gathered_candidates(&mut b_events).await;
+    assert!(!a_candidates.is_empty());
+    assert_eq!(
+        a_candidates.len(),
+        a_agent.get_local_candidates().await?.len()
+    );
+    for c in a_candidates {
+        let c: Arc<dyn Candidate + Send + Sync> =
+            Arc::new(b_agent.unmarshal_remote_candidate(c.marshal()).await?);
+        b_agent.add_remote_candidate(&c).await?;
+    }
+    for c in b_candidates {
+        let c: Arc<dyn Candidate + Send + Sync> =
+            Arc::new(a_agent.unmarshal_remote_candidate(c.marshal()).await?);
+        a_agent.add_remote_candidate(&c).await?;
+    }
+
+    let (a_ufrag, a_pwd) = a_agent.get_local_user_credentials().await;
+    let (b_ufrag, b_pwd) = b_agent.get_local_user_credentials().await;
+    let agent = Arc::clone(&a_agent);
+    let accepted = tokio::spawn(async move {
+        let (_cancel_tx, cancel_rx) = mpsc::channel(1);
+        agent.accept(cancel_rx, b_ufrag, b_pwd).await
+    });
+    let (_cancel_tx, cancel_rx) = mpsc::channel(1);
+    let _b_conn = b_agent.dial(cancel_rx, a_ufrag, a_pwd).await?;
+    let _a_conn = accepted.await.expect("accept should not panic")?;
+
+    a_agent.close().await?;
+    b_agent.close().await?;
+
+    // the stream ends once the agent is closed
+    let mut states = vec![];
+    let mut selected_pair_changes = 0;
+    while let Some(event) = a_events.next().await {
+        match event {
+            AgentEvent::ConnectionStateChange(state) => states.push(state),
+            AgentEvent::SelectedCandidatePairChange { .. } => selected_pair_changes += 1,
+            _ => {}
+        }
+    }
+    assert_eq!(
+        states,
+        vec![
+            ConnectionState::Checking,
+            ConnectionState::Connected,
+            ConnectionState::Closed
+        ]
+    );
+    assert!(selected_pair_changes >= 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_handler_can_call_into_agent() -> Result<(), Error> {
+    let a_agent = Arc::new(
+        Agent::new(AgentConfig {
+            network_types: supported_network_types(),
+            ..Default::default()
+        })
+        .await?,
+    );
+    let mut events = Box::pin(a_agent.events());
+
+    // the handler calls back into the agent, it waits for the agent lock
+    let (states_tx, mut states_rx) = mpsc::unbounded_channel();
+    let agent = Arc::downgrade(&a_agent);
+    a_agent
+        .on_connection_state_change(Box::new(move |state: ConnectionState| {
+            let agent = agent.clone();
+            let states_tx = states_tx.clone();
+            Box::pin(async move {
+                if let Some(agent) = agent.upgrade() {
+                    let _ = agent.get_stats().await;
+                }
+                let _ = states_tx.send(state);
+            })
+        }))
+        .await;
+
+    let states = [
+        ConnectionState::Checking,
+        ConnectionState::Connected,
+        ConnectionState::Disconnected,
+        ConnectionState::Checking,
+    ];
+    tokio::time::timeout(Duration::from_secs(5), async {
+        let mut ai = a_agent.agent_internal.lock().await;
+        // several changes with the lock held used to wait on the handler, which waits on the lock
+        for state in states {
+            ai.update_connection_state(state).await;
+        }
+        // and the stream doesn't wait on the handler either
+        for state in states {
+            assert!(matches!(
+                events.next().await,
+                Some(AgentEvent::ConnectionStateChange(s)) if s == state
+            ));
+        }
+    })
+    .await
+    .expect("the agent should not wait on the handler");
+
+    for state in states {
+        let handled = tokio::time::timeout(Duration::from_secs(5), states_rx.recv())
+            .await
+            .expect("the handler should run once the lock is released");
+        assert_eq!(handled, Some(state));
+    }
+
+    a_agent.close().await?;
+
+    Ok(())
+}
+
+#[cfg(feature = "metrics")]
+#[tokio::test]
+async fn test_agent_metrics() -> Result<(), Error> {
+    // the metrics are shared with the other tests, which may be running
+    let sample = |name: &str| -> u64 {
+        crate::metrics::render()
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(' ')?.parse().ok())
+            .unwrap_or_default()
+    };
+    let binding_requests_sent = sample("ice_binding_requests_sent_total");
+    let binding_requests_received = sample("ice_binding_requests_received_total");
+    let connections = sample("ice_time_to_connected_seconds_count");
+
+    let (_ca, _cb, a_agent, b_agent) = pipe(None, None).await?;
+
+    assert!(sample("ice_binding_requests_sent_total") > binding_requests_sent);
+    assert!(sample("ice_binding_requests_received_total") > binding_requests_received);
+    assert!(sample("ice_time_to_connected_seconds_count") >= connections + 2);
+    assert!(sample("ice_agents{state=\"connected\"}") >= 2);
+    assert!(crate::metrics::render()
+        .contains("ice_selected_candidate_pairs_total{local_candidate_type=\"host\""));
+
+    a_agent.close().await?;
+    b_agent.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_best_valid_candidate_pair_prefers_cheaper_network() -> Result<(), Error> {
+    let host = |address: &str, network_cost: u16| CandidateHostConfig {
+        base_config: CandidateBaseConfig {
+            network: "udp".to_owned(),
+            address: address.to_owned(),
+            component: 1,
+            network_cost,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    // the cellular candidate has the higher priority, but the Wi-Fi one is cheaper
+    let mut cellular = host("10.0.0.1", NETWORK_COST_CELLULAR);
+    cellular.base_config.local_preference_rank = 0;
+    let mut wifi = host("10.0.0.2", NETWORK_COST_LOW);
+    wifi.base_config.local_preference_rank = 1;
+
+    let cellular: Arc<dyn Candidate + Send + Sync> =
+        Arc::new(cellular.new_candidate_host(None).await?);
+    let wifi: Arc<dyn Candidate + Send + Sync> = Arc::new(wifi.new_candidate_host(None).await?);
+    let remote: Arc<dyn Candidate + Send + Sync> =
+        Arc::new(host("10.0.0.3", 0).new_candidate_host(None).await?);
+
+    let agent_conn = AgentConn::new();
+    {
+        let mut checklist = agent_conn.checklist.lock().await;
+        for local in [&cellular, &wifi] {
+            let p = CandidatePair::new(Arc::clone(local), Arc::clone(&remote), true);
+            p.state
+                .store(CandidatePairState::Succeeded as u8, Ordering::SeqCst);
+            checklist.push(Arc::new(p));
+        }
+    }
+
+    let best = agent_conn
+        .get_best_valid_candidate_pair()
+        .await
+        .expect("should have a valid pair");
+    assert!(best.local.equal(&*wifi), "cheaper pair should be selected");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_connect_negotiated_roles() -> Result<(), Error> {
+    let full_agent = Arc::new(
+        Agent::new(AgentConfig {
+            network_types: supported_network_types(),
+            multicast_dns_mode: MulticastDnsMode::Disabled,
+            ..Default::default()
+        })
+        .await?,
+    );
+    let lite_agent = Arc::new(
+        Agent::new(AgentConfig {
+            lite: true,
+            candidate_types: vec![CandidateType::Host],
+            network_types: supported_network_types(),
+            multicast_dns_mode: MulticastDnsMode::Disabled,
+            ..Default::default()
+        })
+        .await?,
+    );
+    gather_and_exchange_candidates(&full_agent, &lite_agent).await?;
+
+    // the lite agent offers, the full agent still controls
+    let full = IceAttributes::from_agent(&full_agent).await?;
+    let lite = IceAttributes::from_agent(&lite_agent).await?;
+    let full_negotiation = negotiate(&full, &lite, false);
+    let lite_negotiation = negotiate(&lite, &full, true);
+    assert!(full_negotiation.is_controlling);
+    assert!(!lite_negotiation.is_controlling);
+
+    // a lite agent refuses to control a full one
+    let (_cancel_tx, cancel_rx) = mpsc::channel(1);
+    let result = lite_agent
+        .connect(
+            &IceNegotiation {
+                is_controlling: true,
+                ..lite_negotiation
+            },
+            cancel_rx,
+            full.ufrag.clone(),
+            full.pwd.clone(),
+        )
+        .await;
+    assert_eq!(result.err(), Some(ERR_LITE_AGENT_CONTROLLING.to_owned()));
+
+    let (connected_tx, mut connected_rx) = mpsc::channel(1);
+    let agent = Arc::clone(&lite_agent);
+    tokio::spawn(async move {
+        let (_cancel_tx, cancel_rx) = mpsc::channel(1);
+        let conn = agent
+            .connect(&lite_negotiation, cancel_rx, full.ufrag, full.pwd)
+            .await?;
+        let _ = connected_tx.send(conn).await;
+        Ok::<(), Error>(())
+    });
+
+    let (_cancel_tx, cancel_rx) = mpsc::channel(1);
+    let full_conn = full_agent
+        .connect(&full_negotiation, cancel_rx, lite.ufrag, lite.pwd)
+        .await?;
+    let lite_conn = tokio::time::timeout(Duration::from_secs(10), connected_rx.recv())
+        .await
+        .expect("the lite agent should connect")
+        .expect("connect should succeed");
+
+    full_conn.send(b"offer").await?;
+    let mut buf = vec![0u8; 16];
+    let n = lite_conn.recv(&mut buf).await?;
+    assert_eq!(&buf[..n], b"offer");
+
+    full_agent.close().await?;
+    lite_agent.close().await?;
+
+    Ok(())
+}
@@ -528,6 +528,7 @@ async fn test_connectivity_vnet_full_cone_nats_on_both_ends() -> Result<(), Erro
         username: "user".to_owned(),
         password: "pass".to_owned(),
         proto: ProtoType::Udp,
+        explicit_port: true,
     };
 
     // buildVNet with a Full-cone NATs both LANs
@@ -590,6 +591,7 @@ async fn test_connectivity_vnet_symmetric_nats_on_both_ends() -> Result<(), Erro
         username: "user".to_owned(),
         password: "pass".to_owned(),
         proto: ProtoType::Udp,
+        explicit_port: true,
     };
 
     // buildVNet with a Symmetric NATs for both LANs
@@ -1,13 +1,14 @@
 #[cfg(test)]
 mod agent_gather_test;
 #[cfg(test)]
-mod agent_test;
+pub(crate) mod agent_test;
 #[cfg(test)]
 mod agent_transport_test;
 #[cfg(test)]
 pub(crate) mod agent_vnet_test;
 
 pub mod agent_config;
+pub mod agent_events;
 pub mod agent_gather;
 pub(crate) mod agent_internal;
 pub mod agent_selector;
@@ -15,32 +16,37 @@ pub mod agent_stats;
 pub mod agent_transport;
 
 use crate::candidate::*;
+use crate::credential_provider::*;
+use crate::dns_srv::*;
 use crate::errors::*;
 use crate::external_ip_mapper::*;
-use crate::mdns::*;
+use crate::mdns::{mdns_conn::*, *};
+#[cfg(feature = "metrics")]
+use crate::metrics::METRICS;
+use crate::nat_discovery::*;
+use crate::network_policy::*;
 use crate::network_type::*;
+use crate::port_mapping::*;
+use crate::proxy::*;
+use crate::socket_factory::*;
 use crate::state::*;
 use crate::url::*;
 use agent_config::*;
+use agent_events::*;
 use agent_internal::*;
 use agent_stats::*;
 
-use mdns::conn::*;
 use stun::{agent::*, attributes::*, fingerprint::*, integrity::*, message::*, xoraddr::*};
 use util::{vnet::net::*, Buffer, Error};
 
-use std::collections::HashMap;
-use std::net::{Ipv4Addr, SocketAddr};
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 
 use crate::rand::*;
 
 use crate::agent::agent_gather::GatherCandidatesInternalParams;
 use crate::agent::agent_transport::AgentConn;
-use crate::candidate::candidate_base::CandidateBaseConfig;
-use crate::candidate::candidate_host::CandidateHostConfig;
-use crate::candidate::candidate_peer_reflexive::CandidatePeerReflexiveConfig;
-use crate::candidate::candidate_relay::CandidateRelayConfig;
-use crate::candidate::candidate_server_reflexive::CandidateServerReflexiveConfig;
+use crate::candidate::candidate_descriptor::CandidateDescriptor;
 use crate::tcp_type::TcpType;
 use std::future::Future;
 use std::pin::Pin;
@@ -49,6 +55,7 @@ use std::sync::Arc;
 use std::time::SystemTime;
 use tokio::sync::{broadcast, mpsc, Mutex};
 use tokio::time::{Duration, Instant};
+use tokio_stream::Stream;
 
 #[derive(Debug, Clone)]
 pub(crate) struct BindingRequest {
@@ -69,6 +76,32 @@ impl Default for BindingRequest {
     }
 }
 
+/// The W3C error code reported when a STUN or TURN server could not be reached.
+pub(crate) const CANDIDATE_ERROR_CODE_UNREACHABLE: u16 = 701;
+
+/// Describes a failure to gather or keep a candidate from a STUN or TURN server, or to resolve
+/// the mDNS name of a remote candidate, modeled after the W3C `RTCPeerConnectionIceErrorEvent`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CandidateError {
+    /// The local address used to communicate with the server, empty if there is none. For
+    /// remote mDNS candidates, the name that could not be resolved.
+    pub address: String,
+
+    /// The local port used to communicate with the server, 0 if there is none. For remote mDNS
+    /// candidates, the port of the candidate.
+    pub port: u16,
+
+    /// The url of the STUN or TURN server the error occurred with, empty for remote mDNS
+    /// candidates.
+    pub url: String,
+
+    /// The STUN error code returned by the server, or 701 if the server could not be reached.
+    pub error_code: u16,
+
+    /// The STUN reason text returned by the server, or a description of the failure.
+    pub error_text: String,
+}
+
 pub type OnConnectionStateChangeHdlrFn = Box<
     dyn (FnMut(ConnectionState) -> Pin<Box<dyn Future<Output = ()> + Send + 'static>>)
         + Send
@@ -89,6 +122,16 @@ pub type OnCandidateHdlrFn = Box<
         + Send
         + Sync,
 >;
+pub type OnCandidateRemovedHdlrFn = Box<
+    dyn (FnMut(
+            Arc<dyn Candidate + Send + Sync>,
+        ) -> Pin<Box<dyn Future<Output = ()> + Send + 'static>>)
+        + Send
+        + Sync,
+>;
+pub type OnCandidateErrorHdlrFn = Box<
+    dyn (FnMut(CandidateError) -> Pin<Box<dyn Future<Output = ()> + Send + 'static>>) + Send + Sync,
+>;
 pub type GatherCandidateCancelFn = Box<dyn Fn() + Send + Sync>;
 
 /// Represents the ICE agent.
@@ -98,19 +141,40 @@ pub struct Agent {
     pub(crate) port_min: u16,
     pub(crate) port_max: u16,
     pub(crate) interface_filter: Arc<Option<InterfaceFilterFn>>,
+    pub(crate) ip_filter: Arc<Option<IpFilterFn>>,
+    pub(crate) bind_addresses: Vec<IpAddr>,
+    pub(crate) include_loopback: bool,
+    pub(crate) network_policy: Arc<dyn NetworkPolicy + Send + Sync>,
+    pub(crate) socket_factory: Arc<dyn SocketFactory + Send + Sync>,
     pub(crate) mdns_mode: MulticastDnsMode,
     pub(crate) mdns_name: String,
-    pub(crate) mdns_conn: Option<Arc<DnsConn>>,
+    pub(crate) mdns_conn: Option<Arc<MulticastDnsConn>>,
+    pub(crate) mdns_config: MulticastDnsConfig,
+    pub(crate) mdns_query_timeout: Duration,
     pub(crate) net: Arc<Net>,
 
     // 1:1 D-NAT IP address mapping
     pub(crate) ext_ip_mapper: Arc<Option<ExternalIpMapper>>,
+    pub(crate) port_mapping: Option<PortMappingConfig>,
+    pub(crate) dns_srv: DnsSrvConfig,
+    pub(crate) credential_provider: Option<Arc<dyn CredentialProvider + Send + Sync>>,
+    pub(crate) turn_access_token: Option<TurnAccessToken>,
+    pub(crate) proxy_dialer: Option<Arc<dyn ProxyDialer + Send + Sync>>,
     pub(crate) gathering_state: Arc<AtomicU8>, //GatheringState,
     pub(crate) candidate_types: Vec<CandidateType>,
     pub(crate) urls: Vec<Url>,
     pub(crate) network_types: Vec<NetworkType>,
 
     pub(crate) gather_candidate_cancel: Option<GatherCandidateCancelFn>,
+
+    // The handlers are called without the agent lock held, they can call back into the agent
+    pub(crate) on_connection_state_change_hdlr: Arc<Mutex<Option<OnConnectionStateChangeHdlrFn>>>,
+    pub(crate) on_selected_candidate_pair_change_hdlr:
+        Arc<Mutex<Option<OnSelectedCandidatePairChangeHdlrFn>>>,
+    pub(crate) on_candidate_hdlr: Arc<Mutex<Option<OnCandidateHdlrFn>>>,
+    pub(crate) on_candidate_removed_hdlr: Arc<Mutex<Option<OnCandidateRemovedHdlrFn>>>,
+    pub(crate) on_candidate_error_hdlr: Arc<Mutex<Option<OnCandidateErrorHdlrFn>>>,
+    pub(crate) events: AgentEventSenders,
 }
 
 impl Agent {
@@ -120,12 +184,10 @@ impl Agent {
             return Err(ERR_PORT.to_owned());
         }
 
-        let mut mdns_name = config.multicast_dns_host_name.clone();
-        if mdns_name.is_empty() {
-            mdns_name = generate_multicast_dns_name();
-        }
-
-        if !mdns_name.ends_with(".local") || mdns_name.split('.').count() != 2 {
+        let mdns_name = config.multicast_dns_host_name.clone();
+        if !mdns_name.is_empty()
+            && (!mdns_name.ends_with(".local") || mdns_name.split('.').count() != 2)
+        {
             return Err(ERR_INVALID_MULTICAST_DNSHOST_NAME.to_owned());
         }
 
@@ -134,19 +196,24 @@ impl Agent {
             mdns_mode = MulticastDnsMode::QueryOnly;
         }
 
-        let mdns_conn = match create_multicast_dns(mdns_mode, &mdns_name) {
+        let mdns_config = config.multicast_dns_config.clone();
+        let mdns_conn = match create_multicast_dns(mdns_mode, &mdns_config) {
             Ok(c) => c,
             Err(err) => {
                 // Opportunistic mDNS: If we can't open the connection, that's ok: we
                 // can continue without it.
-                log::warn!("Failed to initialize mDNS {}: {}", mdns_name, err);
+                log::warn!("Failed to initialize mDNS: {}", err);
                 None
             }
         };
 
-        let (chan_state_tx, chan_state_rx) = mpsc::channel(1);
-        let (chan_candidate_tx, chan_candidate_rx) = mpsc::channel(1);
-        let (chan_candidate_pair_tx, chan_candidate_pair_rx) = mpsc::channel(1);
+        // Unbounded, the agent sends to the handlers with its lock held and must not wait on them
+        let (chan_state_tx, chan_state_rx) = mpsc::unbounded_channel();
+        let (chan_candidate_tx, chan_candidate_rx) = mpsc::unbounded_channel();
+        let (chan_candidate_pair_tx, chan_candidate_pair_rx) = mpsc::unbounded_channel();
+        let (chan_candidate_removed_tx, chan_candidate_removed_rx) = mpsc::unbounded_channel();
+        let (chan_candidate_error_tx, chan_candidate_error_rx) = mpsc::unbounded_channel();
+        let events = AgentEventSenders::default();
         let (on_connected_tx, on_connected_rx) = mpsc::channel(1);
         let (done_tx, done_rx) = mpsc::channel(1);
         let (force_candidate_contact_tx, force_candidate_contact_rx) = mpsc::channel(1);
@@ -166,10 +233,9 @@ impl Agent {
             chan_state_tx: Some(chan_state_tx),
             chan_candidate_tx: Some(Arc::new(chan_candidate_tx)),
             chan_candidate_pair_tx: Some(chan_candidate_pair_tx),
-
-            on_connection_state_change_hdlr: None,
-            on_selected_candidate_pair_change_hdlr: None,
-            on_candidate_hdlr: None,
+            chan_candidate_removed_tx: Some(chan_candidate_removed_tx),
+            chan_candidate_error_tx: Some(chan_candidate_error_tx),
+            events: events.clone(),
 
             tie_breaker: rand::random::<u64>(),
 
@@ -179,10 +245,17 @@ impl Agent {
             nominated_pair: None,
 
             connection_state: ConnectionState::New,
+            connection_state_changed_at: Instant::now(),
+            connection_state_durations: HashMap::new(),
+            selected_candidate_pair_changes: 0,
             local_candidates: HashMap::new(),
             remote_candidates: HashMap::new(),
+            removed_candidates_stats: VecDeque::new(),
 
             insecure_skip_verify: config.insecure_skip_verify,
+            mdns_conn: mdns_conn.clone(),
+            remote_resolve_cancel_tx: Some(broadcast::channel(1).0),
+            port_mapping_close_tx: Some(broadcast::channel(1).0),
 
             started_ch_tx: Some(started_ch_tx),
 
@@ -220,6 +293,8 @@ impl Agent {
             // AgentConn
             agent_conn: Arc::new(AgentConn::new()),
         };
+        #[cfg(feature = "metrics")]
+        METRICS.on_agent_created();
 
         config.init_with_defaults(&mut ai);
 
@@ -230,7 +305,7 @@ impl Agent {
         };
 
         if ai.lite && (candidate_types.len() != 1 || candidate_types[0] != CandidateType::Host) {
-            Self::close_multicast_conn(&mdns_conn).await;
+            Self::close_multicast_conn(&mdns_conn, &mdns_config).await;
             return Err(ERR_LITE_USING_NON_HOST_CANDIDATES.to_owned());
         }
 
@@ -238,14 +313,14 @@ impl Agent {
             && !contains_candidate_type(CandidateType::ServerReflexive, &candidate_types)
             && !contains_candidate_type(CandidateType::Relay, &candidate_types)
         {
-            Self::close_multicast_conn(&mdns_conn).await;
+            Self::close_multicast_conn(&mdns_conn, &mdns_config).await;
             return Err(ERR_USELESS_URLS_PROVIDED.to_owned());
         }
 
         let ext_ip_mapper = match config.init_ext_ip_mapping(mdns_mode, &candidate_types) {
             Ok(ext_ip_mapper) => ext_ip_mapper,
             Err(err) => {
-                Self::close_multicast_conn(&mdns_conn).await;
+                Self::close_multicast_conn(&mdns_conn, &mdns_config).await;
                 return Err(err);
             }
         };
@@ -263,37 +338,63 @@ impl Agent {
             Arc::new(Net::new(None))
         };
 
+        let socket_factory = config
+            .socket_factory
+            .take()
+            .unwrap_or_else(|| Arc::new(NetSocketFactory::new(Arc::clone(&net))));
+
         let a = Self {
             port_min: config.port_min,
             port_max: config.port_max,
             agent_internal: Arc::new(Mutex::new(ai)),
             interface_filter: Arc::new(config.interface_filter.take()),
+            ip_filter: Arc::new(config.ip_filter.take()),
+            bind_addresses: config.bind_addresses.clone(),
+            include_loopback: config.include_loopback,
+            network_policy: config
+                .network_policy
+                .take()
+                .unwrap_or_else(|| Arc::new(DefaultNetworkPolicy)),
+            socket_factory,
             mdns_mode,
             mdns_name,
             mdns_conn,
+            mdns_config,
+            mdns_query_timeout: config
+                .multicast_dns_query_timeout
+                .unwrap_or(DEFAULT_MULTICAST_DNS_QUERY_TIMEOUT),
             net,
             ext_ip_mapper: Arc::new(ext_ip_mapper),
+            port_mapping: config.port_mapping.clone(),
+            dns_srv: config.dns_srv.clone(),
+            credential_provider: config.credential_provider.take(),
+            turn_access_token: config.turn_access_token.take(),
+            proxy_dialer: config.proxy_dialer.take(),
             gathering_state: Arc::new(AtomicU8::new(0)), //GatheringState::New,
             candidate_types,
             urls: config.urls.clone(),
             network_types: config.network_types.clone(),
 
             gather_candidate_cancel: None,
-        };
 
-        let agent_internal = Arc::clone(&a.agent_internal);
+            on_connection_state_change_hdlr: Arc::new(Mutex::new(None)),
+            on_selected_candidate_pair_change_hdlr: Arc::new(Mutex::new(None)),
+            on_candidate_hdlr: Arc::new(Mutex::new(None)),
+            on_candidate_removed_hdlr: Arc::new(Mutex::new(None)),
+            on_candidate_error_hdlr: Arc::new(Mutex::new(None)),
+            events,
+        };
 
-        Self::start_on_connection_state_change_routine(
-            agent_internal,
+        a.start_on_connection_state_change_routine(
             chan_state_rx,
             chan_candidate_rx,
             chan_candidate_pair_rx,
-        )
-        .await;
+        );
+        a.start_on_candidate_event_routine(chan_candidate_removed_rx, chan_candidate_error_rx);
 
         // Restart is also used to initialize the agent for the first time
         if let Err(err) = a.restart(config.local_ufrag, config.local_pwd).await {
-            Self::close_multicast_conn(&a.mdns_conn).await;
+            // also releases the mDNS conn
             let _ = a.close().await;
             return Err(err);
         }
@@ -303,78 +404,123 @@ impl Agent {
 
     /// Sets a handler that is fired when the connection state changes.
     pub async fn on_connection_state_change(&self, f: OnConnectionStateChangeHdlrFn) {
-        let mut ai = self.agent_internal.lock().await;
-        ai.on_connection_state_change_hdlr = Some(f);
+        *self.on_connection_state_change_hdlr.lock().await = Some(f);
     }
 
     /// Sets a handler that is fired when the final candidate pair is selected.
     pub async fn on_selected_candidate_pair_change(&self, f: OnSelectedCandidatePairChangeHdlrFn) {
-        let mut ai = self.agent_internal.lock().await;
-        ai.on_selected_candidate_pair_change_hdlr = Some(f);
+        *self.on_selected_candidate_pair_change_hdlr.lock().await = Some(f);
     }
 
     /// Sets a handler that is fired when new candidates gathered. When the gathering process
     /// complete the last candidate is nil.
     pub async fn on_candidate(&self, f: OnCandidateHdlrFn) {
-        let mut ai = self.agent_internal.lock().await;
-        ai.on_candidate_hdlr = Some(f);
+        *self.on_candidate_hdlr.lock().await = Some(f);
+    }
+
+    /// Sets a handler that is fired when a gathered candidate is removed, e.g. because its TURN
+    /// allocation could not be kept alive.
+    pub async fn on_candidate_removed(&self, f: OnCandidateRemovedHdlrFn) {
+        *self.on_candidate_removed_hdlr.lock().await = Some(f);
+    }
+
+    /// Sets a handler that is fired when a candidate can't be gathered or kept alive because of a
+    /// STUN or TURN server error.
+    pub async fn on_candidate_error(&self, f: OnCandidateErrorHdlrFn) {
+        *self.on_candidate_error_hdlr.lock().await = Some(f);
+    }
+
+    /// Returns a stream of the events of the agent from now on, an alternative to the handlers.
+    /// The stream ends once the agent is closed, after the `Closed` connection state.
+    ///
+    /// Events are buffered until they are read, the stream should be read or dropped.
+    pub fn events(&self) -> impl Stream<Item = AgentEvent> {
+        self.events.subscribe()
+    }
+
+    fn start_on_candidate_event_routine(
+        &self,
+        mut chan_candidate_removed_rx: mpsc::UnboundedReceiver<Arc<dyn Candidate + Send + Sync>>,
+        mut chan_candidate_error_rx: mpsc::UnboundedReceiver<CandidateError>,
+    ) {
+        let on_candidate_removed_hdlr = Arc::clone(&self.on_candidate_removed_hdlr);
+        tokio::spawn(async move {
+            while let Some(c) = chan_candidate_removed_rx.recv().await {
+                if let Some(on_candidate_removed) = &mut *on_candidate_removed_hdlr.lock().await {
+                    on_candidate_removed(c).await;
+                }
+            }
+        });
+
+        let on_candidate_error_hdlr = Arc::clone(&self.on_candidate_error_hdlr);
+        tokio::spawn(async move {
+            while let Some(err) = chan_candidate_error_rx.recv().await {
+                if let Some(on_candidate_error) = &mut *on_candidate_error_hdlr.lock().await {
+                    on_candidate_error(err).await;
+                }
+            }
+        });
     }
 
-    async fn start_on_connection_state_change_routine(
-        agent_internal: Arc<Mutex<AgentInternal>>,
-        mut chan_state_rx: mpsc::Receiver<ConnectionState>,
-        mut chan_candidate_rx: mpsc::Receiver<Option<Arc<dyn Candidate + Send + Sync>>>,
-        mut chan_candidate_pair_rx: mpsc::Receiver<()>,
+    fn start_on_connection_state_change_routine(
+        &self,
+        mut chan_state_rx: mpsc::UnboundedReceiver<ConnectionState>,
+        mut chan_candidate_rx: mpsc::UnboundedReceiver<Option<Arc<dyn Candidate + Send + Sync>>>,
+        mut chan_candidate_pair_rx: mpsc::UnboundedReceiver<Arc<CandidatePair>>,
     ) {
-        let agent_internal_pair = Arc::clone(&agent_internal);
+        let on_selected_candidate_pair_change_hdlr =
+            Arc::clone(&self.on_selected_candidate_pair_change_hdlr);
         tokio::spawn(async move {
             // CandidatePair and ConnectionState are usually changed at once.
             // Blocking one by the other one causes deadlock.
-            while chan_candidate_pair_rx.recv().await.is_some() {
-                let mut ai = agent_internal_pair.lock().await;
-                let selected_pair = {
-                    let selected_pair = ai.agent_conn.selected_pair.lock().await;
-                    selected_pair.clone()
-                };
-
-                if let (Some(on_selected_candidate_pair_change), Some(p)) = (
-                    &mut ai.on_selected_candidate_pair_change_hdlr,
-                    &selected_pair,
-                ) {
+            while let Some(p) = chan_candidate_pair_rx.recv().await {
+                if let Some(on_selected_candidate_pair_change) =
+                    &mut *on_selected_candidate_pair_change_hdlr.lock().await
+                {
                     on_selected_candidate_pair_change(&*p.local, &*p.remote).await;
                 }
             }
         });
 
+        let on_connection_state_change_hdlr = Arc::clone(&self.on_connection_state_change_hdlr);
+        let on_candidate_hdlr = Arc::clone(&self.on_candidate_hdlr);
+        let on_state = move |s: ConnectionState| {
+            let on_connection_state_change_hdlr = Arc::clone(&on_connection_state_change_hdlr);
+            async move {
+                if let Some(on_connection_state_change) =
+                    &mut *on_connection_state_change_hdlr.lock().await
+                {
+                    on_connection_state_change(s).await;
+                }
+            }
+        };
+        let on_candidate = move |c: Option<Arc<dyn Candidate + Send + Sync>>| {
+            let on_candidate_hdlr = Arc::clone(&on_candidate_hdlr);
+            async move {
+                if let Some(on_candidate) = &mut *on_candidate_hdlr.lock().await {
+                    on_candidate(c).await;
+                }
+            }
+        };
         tokio::spawn(async move {
             loop {
                 tokio::select! {
                     opt_state = chan_state_rx.recv() => {
-                        let mut ai = agent_internal.lock().await;
                         if let Some(s) = opt_state {
-                            if let Some(on_connection_state_change) = &mut ai.on_connection_state_change_hdlr{
-                                on_connection_state_change(s).await;
-                            }
+                            on_state(s).await;
                         } else {
                             while let Some(c) = chan_candidate_rx.recv().await {
-                                if let Some(on_candidate) = &mut ai.on_candidate_hdlr {
-                                    on_candidate(c).await;
-                                }
+                                on_candidate(c).await;
                             }
                             break;
                         }
                     },
                     opt_cand = chan_candidate_rx.recv() => {
-                        let mut ai = agent_internal.lock().await;
                         if let Some(c) = opt_cand {
-                            if let Some(on_candidate) = &mut ai.on_candidate_hdlr{
-                                on_candidate(c).await;
-                            }
+                            on_candidate(c).await;
                         } else {
                             while let Some(s) = chan_state_rx.recv().await {
-                                if let Some(on_connection_state_change) = &mut ai.on_connection_state_change_hdlr{
-                                    on_connection_state_change(s).await;
-                                }
+                                on_state(s).await;
                             }
                             break;
                         }
@@ -384,6 +530,19 @@ impl Agent {
         });
     }
 
+    /// Adds the remote candidate described by `descriptor`.
+    pub async fn add_remote_candidate_descriptor(
+        &self,
+        descriptor: &CandidateDescriptor,
+    ) -> Result<(), Error> {
+        let c: Arc<dyn Candidate + Send + Sync> = Arc::new(
+            descriptor
+                .new_candidate(Some(Arc::clone(&self.agent_internal)))
+                .await?,
+        );
+        self.add_remote_candidate(&c).await
+    }
+
     /// Adds a new remote candidate.
     pub async fn add_remote_candidate(
         &self,
@@ -399,7 +558,7 @@ impl Agent {
         }
 
         // If we have a mDNS Candidate lets fully resolve it before adding it locally
-        if c.candidate_type() == CandidateType::Host && c.address().ends_with(".local") {
+        if c.address().ends_with(".local") {
             if self.mdns_mode == MulticastDnsMode::Disabled {
                 log::warn!(
                     "remote mDNS candidate added, but mDNS is disabled: ({})",
@@ -412,17 +571,81 @@ impl Agent {
                 return Err(ERR_ADDRESS_PARSE_FAILED.to_owned());
             }
 
+            let mdns_conn = match &self.mdns_conn {
+                Some(mdns_conn) => Arc::clone(mdns_conn),
+                None => return Ok(()),
+            };
+            let close_query_signal_rx = self.subscribe_remote_resolve_cancel().await?;
+
             let agent_internal = Arc::clone(&self.agent_internal);
             let host_candidate = Arc::clone(c);
-            let mdns_conn = self.mdns_conn.clone();
+            let mdns_query_timeout = self.mdns_query_timeout;
             tokio::spawn(async move {
-                if let Some(mdns_conn) = mdns_conn {
-                    if let Ok(candidate) =
-                        Self::resolve_and_add_multicast_candidate(mdns_conn, host_candidate).await
-                    {
+                match Self::resolve_and_add_multicast_candidate(
+                    mdns_conn,
+                    Arc::clone(&host_candidate),
+                    mdns_query_timeout,
+                    close_query_signal_rx,
+                )
+                .await
+                {
+                    Ok(candidate) => {
                         let mut ai = agent_internal.lock().await;
                         ai.add_remote_candidate(&candidate).await;
                     }
+                    Err(err)
+                        if err == *ERR_MULTICAST_DNS_QUERY_CANCELED
+                            || err == *ERR_MULTICAST_DNS_CLOSED => {}
+                    Err(err) => {
+                        let ai = agent_internal.lock().await;
+                        ai.candidate_error(CandidateError {
+                            address: host_candidate.address(),
+                            port: host_candidate.port(),
+                            url: String::new(),
+                            error_code: CANDIDATE_ERROR_CODE_UNREACHABLE,
+                            error_text: err.to_string(),
+                        });
+                    }
+                }
+            });
+        } else if c.address().parse::<IpAddr>().is_err() {
+            // A DNS name, every address it resolves to becomes a candidate of the same type
+            let close_resolve_signal_rx = self.subscribe_remote_resolve_cancel().await?;
+
+            let agent_internal = Arc::clone(&self.agent_internal);
+            let host_candidate = Arc::clone(c);
+            let net = Arc::clone(&self.net);
+            tokio::spawn(async move {
+                match Self::resolve_dns_candidate(
+                    &net,
+                    &host_candidate,
+                    &agent_internal,
+                    close_resolve_signal_rx,
+                )
+                .await
+                {
+                    Ok(candidates) => {
+                        let mut ai = agent_internal.lock().await;
+                        for candidate in &candidates {
+                            ai.add_remote_candidate(candidate).await;
+                        }
+                    }
+                    Err(err) if err == *ERR_DNS_RESOLVE_CANCELED => {}
+                    Err(err) => {
+                        log::warn!(
+                            "Failed to resolve remote candidate {}: {}",
+                            host_candidate.address(),
+                            err
+                        );
+                        let ai = agent_internal.lock().await;
+                        ai.candidate_error(CandidateError {
+                            address: host_candidate.address(),
+                            port: host_candidate.port(),
+                            url: String::new(),
+                            error_code: CANDIDATE_ERROR_CODE_UNREACHABLE,
+                            error_text: err.to_string(),
+                        });
+                    }
                 }
             });
         } else {
@@ -437,6 +660,36 @@ impl Agent {
         Ok(())
     }
 
+    /// Lookups of remote candidate addresses are canceled when the agent closes or restarts.
+    async fn subscribe_remote_resolve_cancel(&self) -> Result<broadcast::Receiver<()>, Error> {
+        self.agent_internal
+            .lock()
+            .await
+            .remote_resolve_cancel_tx
+            .as_ref()
+            .map(broadcast::Sender::subscribe)
+            .ok_or_else(|| ERR_CLOSED.to_owned())
+    }
+
+    /// Runs the RFC 5780 NAT behavior tests against the first UDP STUN server of the agent, which
+    /// tells why the connectivity checks may only succeed through a relay. It can be called
+    /// before gathering.
+    pub async fn discover_nat_behavior(&self) -> Result<NatBehavior, Error> {
+        let url = self
+            .urls
+            .iter()
+            .find(|url| url.scheme == SchemeType::Stun && url.proto == ProtoType::Udp)
+            .ok_or_else(|| ERR_NAT_DISCOVERY_NO_URL.to_owned())?;
+        let use_ipv4 =
+            self.network_types.contains(&NetworkType::Udp4) || self.network_types.is_empty();
+        let server_addr = self
+            .net
+            .resolve_addr(use_ipv4, &format!("{}:{}", url.host, url.port))
+            .await?;
+
+        discover_nat_behavior(&self.net, server_addr, DEFAULT_NAT_DISCOVERY_TIMEOUT).await
+    }
+
     /// Returns the local candidates.
     pub async fn get_local_candidates(
         &self,
@@ -473,8 +726,14 @@ impl Agent {
             gather_candidate_cancel();
         }
 
-        let mut ai = self.agent_internal.lock().await;
-        ai.close().await
+        {
+            let mut ai = self.agent_internal.lock().await;
+            ai.close().await?;
+        }
+
+        Self::close_multicast_conn(&self.mdns_conn, &self.mdns_config).await;
+
+        Ok(())
     }
 
     /// Sets the credentials of the remote agent.
@@ -512,8 +771,14 @@ impl Agent {
         {
             return Err(ERR_RESTART_WHEN_GATHERING.to_owned());
         }
-        self.gathering_state
-            .store(GatheringState::New as u8, Ordering::SeqCst);
+        if self
+            .gathering_state
+            .swap(GatheringState::New as u8, Ordering::SeqCst)
+            != GatheringState::New as u8
+        {
+            self.events
+                .send(&AgentEvent::GatheringStateChange(GatheringState::New));
+        }
 
         let mut ai = self.agent_internal.lock().await;
 
@@ -527,6 +792,9 @@ impl Agent {
         ai.remote_ufrag = String::new();
         ai.remote_pwd = String::new();
         ai.pending_binding_requests = vec![];
+        // the stats of the candidates removed before are not kept across restarts, only the ones
+        // of the candidates this restart removes
+        ai.removed_candidates_stats.clear();
 
         {
             let mut checklist = ai.agent_conn.checklist.lock().await;
@@ -535,6 +803,10 @@ impl Agent {
 
         ai.set_selected_pair(None).await;
         ai.delete_all_candidates().await;
+        // cancels the pending mDNS and DNS lookups of the remote candidates
+        ai.remote_resolve_cancel_tx = Some(broadcast::channel(1).0);
+        // releases the port mappings of the deleted local candidates
+        ai.port_mapping_close_tx = Some(broadcast::channel(1).0);
         ai.start();
 
         // Restart is used by NewAgent. Accept/Connect should be used to move to checking
@@ -552,12 +824,12 @@ impl Agent {
             return Err(ERR_MULTIPLE_GATHER_ATTEMPTED.to_owned());
         }
 
-        let chan_candidate_tx = {
+        if self.on_candidate_hdlr.lock().await.is_none() && self.events.is_empty() {
+            return Err(ERR_NO_ON_CANDIDATE_HANDLER.to_owned());
+        }
+        let (chan_candidate_tx, insecure_skip_verify) = {
             let ai = self.agent_internal.lock().await;
-            if ai.on_candidate_hdlr.is_none() {
-                return Err(ERR_NO_ON_CANDIDATE_HANDLER.to_owned());
-            }
-            ai.chan_candidate_tx.clone()
+            (ai.chan_candidate_tx.clone(), ai.insecure_skip_verify)
         };
 
         if let Some(gather_candidate_cancel) = &self.gather_candidate_cancel {
@@ -574,12 +846,25 @@ impl Agent {
             port_min: self.port_min,
             mdns_mode: self.mdns_mode,
             mdns_name: self.mdns_name.clone(),
+            mdns_conn: self.mdns_conn.clone(),
             net: Arc::clone(&self.net),
             interface_filter: self.interface_filter.clone(),
+            ip_filter: Arc::clone(&self.ip_filter),
+            bind_addresses: self.bind_addresses.clone(),
+            include_loopback: self.include_loopback,
+            network_policy: Arc::clone(&self.network_policy),
+            socket_factory: Arc::clone(&self.socket_factory),
             ext_ip_mapper: Arc::clone(&self.ext_ip_mapper),
+            port_mapping: self.port_mapping.clone(),
+            dns_srv: self.dns_srv.clone(),
+            credential_provider: self.credential_provider.clone(),
+            turn_access_token: self.turn_access_token.clone(),
+            proxy_dialer: self.proxy_dialer.clone(),
+            insecure_skip_verify,
             agent_internal: Arc::clone(&self.agent_internal),
             gathering_state: Arc::clone(&self.gathering_state),
             chan_candidate_tx,
+            events: self.events.clone(),
         };
         tokio::spawn(async move {
             Self::gather_candidates_internal(params).await;
@@ -588,6 +873,14 @@ impl Agent {
         Ok(())
     }
 
+    /// Returns a report of the stats of the agent and of its candidates and candidate pairs, taken
+    /// at once.
+    pub async fn get_stats(&self) -> StatsReport {
+        let gathering_state = GatheringState::from(self.gathering_state.load(Ordering::SeqCst));
+        let ai = self.agent_internal.lock().await;
+        ai.get_stats(gathering_state).await
+    }
+
     /// Returns a list of candidate pair stats.
     pub async fn get_candidate_pairs_stats(&self) -> Vec<CandidatePairStats> {
         let ai = self.agent_internal.lock().await;
@@ -597,178 +890,124 @@ impl Agent {
     /// Returns a list of local candidates stats.
     pub async fn get_local_candidates_stats(&self) -> Vec<CandidateStats> {
         let ai = self.agent_internal.lock().await;
-        ai.get_local_candidates_stats()
+        ai.get_local_candidates_stats().await
     }
 
     /// Returns a list of remote candidates stats.
     pub async fn get_remote_candidates_stats(&self) -> Vec<CandidateStats> {
         let ai = self.agent_internal.lock().await;
-        ai.get_remote_candidates_stats()
+        ai.get_remote_candidates_stats().await
     }
 
     /// Creates a Remote Candidate from its string representation.
     pub async fn unmarshal_remote_candidate(&self, raw: String) -> Result<impl Candidate, Error> {
-        let split: Vec<&str> = raw.split_whitespace().collect();
-        if split.len() < 8 {
-            return Err(Error::new(format!(
-                "{} ({})",
-                *ERR_ATTRIBUTE_TOO_SHORT_ICE_CANDIDATE,
-                split.len()
-            )));
-        }
-
-        // Foundation
-        let foundation = split[0].to_owned();
-
-        // Component
-        let component: u16 = split[1].parse()?;
-
-        // Network
-        let network = split[2].to_owned();
-
-        // Priority
-        let priority: u32 = split[3].parse()?;
-
-        // Address
-        let address = split[4].to_owned();
-
-        // Port
-        let port: u16 = split[5].parse()?;
-
-        let typ = split[7];
-
-        let mut rel_addr = String::new();
-        let mut rel_port = 0;
-        let mut tcp_type = TcpType::Unspecified;
-
-        if split.len() > 8 {
-            let split2 = &split[8..];
-
-            if split2[0] == "raddr" {
-                if split2.len() < 4 {
-                    return Err(Error::new(format!(
-                        "{}: incorrect length",
-                        *ERR_PARSE_RELATED_ADDR
-                    )));
-                }
-
-                // RelatedAddress
-                rel_addr = split2[1].to_owned();
-
-                // RelatedPort
-                rel_port = split2[3].parse()?;
-            } else if split2[0] == "tcptype" {
-                if split2.len() < 2 {
-                    return Err(Error::new(format!("{}: incorrect length", *ERR_PARSE_TYPE)));
-                }
-
-                tcp_type = TcpType::from(split2[1]);
-            }
-        }
-
-        match typ {
-            "host" => {
-                let config = CandidateHostConfig {
-                    base_config: CandidateBaseConfig {
-                        network,
-                        address,
-                        port,
-                        component,
-                        priority,
-                        foundation,
-                        ..CandidateBaseConfig::default()
-                    },
-                    tcp_type,
-                };
-                config
-                    .new_candidate_host(Some(Arc::clone(&self.agent_internal)))
-                    .await
-            }
-            "srflx" => {
-                let config = CandidateServerReflexiveConfig {
-                    base_config: CandidateBaseConfig {
-                        network,
-                        address,
-                        port,
-                        component,
-                        priority,
-                        foundation,
-                        ..CandidateBaseConfig::default()
-                    },
-                    rel_addr,
-                    rel_port,
-                };
-                config
-                    .new_candidate_server_reflexive(Some(Arc::clone(&self.agent_internal)))
-                    .await
-            }
-            "prflx" => {
-                let config = CandidatePeerReflexiveConfig {
-                    base_config: CandidateBaseConfig {
-                        network,
-                        address,
-                        port,
-                        component,
-                        priority,
-                        foundation,
-                        ..CandidateBaseConfig::default()
-                    },
-                    rel_addr,
-                    rel_port,
-                };
-
-                config
-                    .new_candidate_peer_reflexive(Some(Arc::clone(&self.agent_internal)))
-                    .await
-            }
-            "relay" => {
-                let config = CandidateRelayConfig {
-                    base_config: CandidateBaseConfig {
-                        network,
-                        address,
-                        port,
-                        component,
-                        priority,
-                        foundation,
-                        ..CandidateBaseConfig::default()
-                    },
-                    rel_addr,
-                    rel_port,
-                    ..CandidateRelayConfig::default()
-                };
-                config
-                    .new_candidate_relay(Some(Arc::clone(&self.agent_internal)))
-                    .await
-            }
-            _ => Err(Error::new(format!(
-                "{} ({})",
-                *ERR_UNKNOWN_CANDIDATE_TYPE, typ
-            ))),
-        }
+        CandidateDescriptor::unmarshal(&raw)?
+            .new_candidate(Some(Arc::clone(&self.agent_internal)))
+            .await
     }
 
     async fn resolve_and_add_multicast_candidate(
-        mdns_conn: Arc<DnsConn>,
+        mdns_conn: Arc<MulticastDnsConn>,
         c: Arc<dyn Candidate + Send + Sync>,
+        timeout: Duration,
+        close_query_signal_rx: broadcast::Receiver<()>,
     ) -> Result<Arc<dyn Candidate + Send + Sync>, Error> {
-        //TODO: hook up _close_query_signal_tx to Agent or Candidate's Close signal?
-        let (_close_query_signal_tx, close_query_signal_rx) = mpsc::channel(1);
-        let src = match mdns_conn.query(&c.address(), close_query_signal_rx).await {
-            Ok((_, src)) => src,
+        let ip = match mdns_conn
+            .query(&c.address(), timeout, close_query_signal_rx)
+            .await
+        {
+            Ok(ip) => ip,
             Err(err) => {
                 log::warn!("Failed to discover mDNS candidate {}: {}", c.address(), err);
                 return Err(err);
             }
         };
 
-        c.set_ip(&src.ip()).await?;
+        c.set_ip(&ip).await?;
 
         Ok(c)
     }
 
-    async fn close_multicast_conn(mdns_conn: &Option<Arc<DnsConn>>) {
+    /// Resolves the A and AAAA records of a candidate's DNS name into one candidate of the same
+    /// type per distinct address, IPv6 first.
+    async fn resolve_dns_candidate(
+        net: &Arc<Net>,
+        c: &Arc<dyn Candidate + Send + Sync>,
+        agent_internal: &Arc<Mutex<AgentInternal>>,
+        mut close_resolve_signal_rx: broadcast::Receiver<()>,
+    ) -> Result<Vec<Arc<dyn Candidate + Send + Sync>>, Error> {
+        let host_port = format!("{}:{}", c.address(), c.port());
+        let mut ips = tokio::select! {
+            result = tokio::time::timeout(DEFAULT_DNS_RESOLVE_TIMEOUT, Self::lookup_ips(net, &host_port)) => {
+                result.map_err(|_| ERR_DNS_RESOLVE_TIMEOUT.to_owned())?
+            }
+            _ = close_resolve_signal_rx.recv() => {
+                return Err(ERR_DNS_RESOLVE_CANCELED.to_owned());
+            }
+        };
+        if ips.is_empty() {
+            return Err(ERR_DNS_NO_ADDRESS.to_owned());
+        }
+        ips.sort_by_key(IpAddr::is_ipv4);
+
+        let mut candidates = vec![];
+        for ip in ips {
+            let descriptor = CandidateDescriptor {
+                address: ip.to_string(),
+                ..CandidateDescriptor::from_candidate(&**c)
+            };
+            let candidate: Arc<dyn Candidate + Send + Sync> = Arc::new(
+                descriptor
+                    .new_candidate(Some(Arc::clone(agent_internal)))
+                    .await?,
+            );
+            candidates.push(candidate);
+        }
+
+        Ok(candidates)
+    }
+
+    /// Returns every address of the A and AAAA records of `host_port`. The virtual network only
+    /// knows one address per name and family.
+    async fn lookup_ips(net: &Arc<Net>, host_port: &str) -> Vec<IpAddr> {
+        let mut ips: Vec<IpAddr> = vec![];
+        if net.is_virtual() {
+            for use_ipv4 in [false, true] {
+                match net.resolve_addr(use_ipv4, host_port).await {
+                    Ok(addr) => {
+                        if !ips.contains(&addr.ip()) {
+                            ips.push(addr.ip());
+                        }
+                    }
+                    Err(err) => log::debug!(
+                        "no {} address for {}: {}",
+                        if use_ipv4 { "IPv4" } else { "IPv6" },
+                        host_port,
+                        err
+                    ),
+                }
+            }
+        } else {
+            match tokio::net::lookup_host(host_port).await {
+                Ok(addrs) => {
+                    for addr in addrs {
+                        if !ips.contains(&addr.ip()) {
+                            ips.push(addr.ip());
+                        }
+                    }
+                }
+                Err(err) => log::debug!("no address for {}: {}", host_port, err),
+            }
+        }
+        ips
+    }
+
+    async fn close_multicast_conn(
+        mdns_conn: &Option<Arc<MulticastDnsConn>>,
+        mdns_config: &MulticastDnsConfig,
+    ) {
         if let Some(conn) = mdns_conn {
-            if let Err(err) = conn.close().await {
+            if let Err(err) = close_multicast_dns(conn, mdns_config).await {
                 log::warn!("failed to close mDNS Conn: {}", err);
             }
         }
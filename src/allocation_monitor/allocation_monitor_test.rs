@@ -0,0 +1,165 @@
+use super::*;
+
+use tokio::net::UdpSocket;
+use util::Error;
+
+const TEST_REQUEST_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// Returns a monitored conn, its monitor, and the socket standing in for the TURN server.
+async fn monitored_pair() -> Result<(AllocationMonitorConn, AllocationMonitor, UdpSocket), Error> {
+    let client = UdpSocket::bind("127.0.0.1:0").await?;
+    let server = UdpSocket::bind("127.0.0.1:0").await?;
+    client.connect(server.local_addr()?).await?;
+    server.connect(client.local_addr()?).await?;
+    let (conn, monitor) = AllocationMonitorConn::new(Arc::new(client), TEST_REQUEST_TIMEOUT);
+    Ok((conn, monitor, server))
+}
+
+fn request(method: Method, lifetime: Option<Duration>) -> Result<Message, Error> {
+    let mut setters: Vec<Box<dyn Setter>> = vec![
+        Box::new(TransactionId::new()),
+        Box::new(MessageType::new(method, CLASS_REQUEST)),
+    ];
+    if let Some(lifetime) = lifetime {
+        setters.push(Box::new(Lifetime(lifetime)));
+    }
+    let mut m = Message::new();
+    m.build(&setters)?;
+    Ok(m)
+}
+
+fn response(request: &Message, error_code: Option<ErrorCode>) -> Result<Message, Error> {
+    let mut m = Message::new();
+    match error_code {
+        Some(code) => m.build(&[
+            Box::new(request.transaction_id),
+            Box::new(MessageType::new(request.typ.method, CLASS_ERROR_RESPONSE)),
+            Box::new(code),
+        ])?,
+        None => m.build(&[
+            Box::new(request.transaction_id),
+            Box::new(MessageType::new(request.typ.method, CLASS_SUCCESS_RESPONSE)),
+        ])?,
+    }
+    Ok(m)
+}
+
+/// Sends `response` from the server side and lets the monitored conn read it.
+async fn answer(
+    conn: &AllocationMonitorConn,
+    server: &UdpSocket,
+    response: &Message,
+) -> Result<(), Error> {
+    server.send(&response.raw).await?;
+    let mut buf = vec![0u8; 1500];
+    conn.recv(&mut buf).await?;
+    Ok(())
+}
+
+async fn assert_not_lost(monitor: &mut AllocationMonitor) {
+    assert!(
+        tokio::time::timeout(TEST_REQUEST_TIMEOUT * 3, monitor.lost())
+            .await
+            .is_err(),
+        "allocation should not be lost"
+    );
+}
+
+#[tokio::test]
+async fn test_allocation_monitor_unanswered_refresh() -> Result<(), Error> {
+    let (conn, mut monitor, _server) = monitored_pair().await?;
+
+    let refresh = request(METHOD_REFRESH, Some(Duration::from_secs(600)))?;
+    conn.send(&refresh.raw).await?;
+
+    // a retransmission doesn't restart the timer
+    tokio::time::sleep(TEST_REQUEST_TIMEOUT / 2).await;
+    conn.send(&refresh.raw).await?;
+
+    let reason = tokio::time::timeout(TEST_REQUEST_TIMEOUT, monitor.lost())
+        .await
+        .expect("allocation should be lost");
+    assert_eq!(reason, "Refresh request timed out");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_allocation_monitor_answered_requests() -> Result<(), Error> {
+    let (conn, mut monitor, server) = monitored_pair().await?;
+
+    let refresh = request(METHOD_REFRESH, Some(Duration::from_secs(600)))?;
+    conn.send(&refresh.raw).await?;
+    answer(&conn, &server, &response(&refresh, None)?).await?;
+
+    // the client gets a new nonce and retries, the stale one is not a loss
+    let permission = request(METHOD_CREATE_PERMISSION, None)?;
+    conn.send(&permission.raw).await?;
+    answer(
+        &conn,
+        &server,
+        &response(&permission, Some(CODE_STALE_NONCE))?,
+    )
+    .await?;
+
+    // the deallocation on close is not watched
+    conn.send(&request(METHOD_REFRESH, Some(Duration::from_secs(0)))?.raw)
+        .await?;
+
+    assert_not_lost(&mut monitor).await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_allocation_monitor_rejected_permission() -> Result<(), Error> {
+    let (conn, mut monitor, server) = monitored_pair().await?;
+
+    let permission = request(METHOD_CREATE_PERMISSION, None)?;
+    conn.send(&permission.raw).await?;
+    answer(
+        &conn,
+        &server,
+        &response(&permission, Some(CODE_ALLOC_MISMATCH))?,
+    )
+    .await?;
+
+    let reason = tokio::time::timeout(TEST_REQUEST_TIMEOUT, monitor.lost())
+        .await
+        .expect("allocation should be lost");
+    assert_eq!(reason, "CreatePermission failed: Allocation Mismatch");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_allocation_monitor_failed_permission() -> Result<(), Error> {
+    let (conn, mut monitor, server) = monitored_pair().await?;
+
+    // the server refuses the peer, the allocation stays
+    let permission = request(METHOD_CREATE_PERMISSION, None)?;
+    conn.send(&permission.raw).await?;
+    answer(
+        &conn,
+        &server,
+        &response(&permission, Some(CODE_FORBIDDEN))?,
+    )
+    .await?;
+
+    // nor is an unanswered permission a loss, only the Refresh tells
+    conn.send(&request(METHOD_CREATE_PERMISSION, None)?.raw)
+        .await?;
+
+    assert_not_lost(&mut monitor).await;
+
+    let refresh = request(METHOD_REFRESH, Some(Duration::from_secs(600)))?;
+    conn.send(&refresh.raw).await?;
+    answer(&conn, &server, &response(&refresh, Some(CODE_FORBIDDEN))?).await?;
+
+    let reason = tokio::time::timeout(TEST_REQUEST_TIMEOUT, monitor.lost())
+        .await
+        .expect("allocation should be lost");
+    assert_eq!(reason, "Refresh failed: Forbidden");
+
+    Ok(())
+}
@@ -0,0 +1,183 @@
+#[cfg(test)]
+mod allocation_monitor_test;
+
+use crate::util::decode_stun_message;
+
+use util::Conn;
+
+use async_trait::async_trait;
+use std::collections::HashSet;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use stun::agent::TransactionId;
+use stun::error_code::*;
+use stun::message::*;
+use tokio::sync::watch;
+use turn::proto::lifetime::Lifetime;
+
+/// How long a Refresh or `CreatePermission` may stay unanswered. The turn client gives up on a
+/// request after about 9.4 seconds of retransmissions with its default timeouts.
+pub const ALLOCATION_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Tells when the allocation a `turn::client::Client` keeps alive is lost.
+pub struct AllocationMonitor {
+    lost_rx: watch::Receiver<Option<String>>,
+}
+
+impl AllocationMonitor {
+    /// Waits until the allocation is lost and returns why. Never returns if the client is
+    /// closed first.
+    pub async fn lost(&mut self) -> String {
+        loop {
+            if let Some(reason) = &*self.lost_rx.borrow() {
+                return reason.clone();
+            }
+            if self.lost_rx.changed().await.is_err() {
+                return std::future::pending().await;
+            }
+        }
+    }
+}
+
+/// Wraps the conn of a turn client and watches the Refresh and `CreatePermission` transactions
+/// that keep its allocation alive. The client only logs when these fail, so the monitor reads the
+/// messages going through: a Refresh left unanswered or answered with an error other than the
+/// stale nonce the client retries with, or an Allocation Mismatch (437) to either, means the
+/// allocation is lost. Other `CreatePermission` failures only keep the client from one peer and
+/// are logged.
+pub struct AllocationMonitorConn {
+    conn: Arc<dyn Conn + Send + Sync>,
+    pending: Arc<Mutex<HashSet<TransactionId>>>,
+    lost_tx: Arc<watch::Sender<Option<String>>>,
+    request_timeout: Duration,
+}
+
+impl AllocationMonitorConn {
+    pub fn new(
+        conn: Arc<dyn Conn + Send + Sync>,
+        request_timeout: Duration,
+    ) -> (Self, AllocationMonitor) {
+        let (lost_tx, lost_rx) = watch::channel(None);
+        (
+            Self {
+                conn,
+                pending: Arc::new(Mutex::new(HashSet::new())),
+                lost_tx: Arc::new(lost_tx),
+                request_timeout,
+            },
+            AllocationMonitor { lost_rx },
+        )
+    }
+
+    fn on_send(&self, buf: &[u8]) {
+        let m = match decode_stun_message(buf) {
+            Some(m) if m.typ.class == CLASS_REQUEST => m,
+            _ => return,
+        };
+        if m.typ.method == METHOD_REFRESH {
+            let mut lifetime = Lifetime::default();
+            if lifetime.get_from(&m).is_ok() && lifetime.0 == Duration::from_secs(0) {
+                // the client deallocates on close
+                return;
+            }
+        } else if m.typ.method != METHOD_CREATE_PERMISSION {
+            return;
+        }
+
+        {
+            let mut pending = self.pending.lock().unwrap();
+            if !pending.insert(m.transaction_id) {
+                // a retransmission, the timer already runs
+                return;
+            }
+        }
+
+        let pending = Arc::clone(&self.pending);
+        let lost_tx = Arc::clone(&self.lost_tx);
+        let request_timeout = self.request_timeout;
+        tokio::spawn(async move {
+            tokio::time::sleep(request_timeout).await;
+            if pending.lock().unwrap().remove(&m.transaction_id) {
+                let reason = format!("{} request timed out", m.typ.method);
+                if m.typ.method == METHOD_REFRESH {
+                    report_lost(&lost_tx, reason);
+                } else {
+                    log::warn!("{}", reason);
+                }
+            }
+        });
+    }
+
+    fn on_recv(&self, buf: &[u8]) {
+        let m = match decode_stun_message(buf) {
+            Some(m)
+                if m.typ.class == CLASS_SUCCESS_RESPONSE || m.typ.class == CLASS_ERROR_RESPONSE =>
+            {
+                m
+            }
+            _ => return,
+        };
+        if !self.pending.lock().unwrap().remove(&m.transaction_id) {
+            return;
+        }
+        if m.typ.class == CLASS_ERROR_RESPONSE {
+            let mut code = ErrorCodeAttribute::default();
+            let (reason, mismatch) = match code.get_from(&m) {
+                // retried with the new nonce under a new transaction
+                Ok(()) if code.code == CODE_STALE_NONCE => return,
+                Ok(()) => (
+                    String::from_utf8_lossy(&code.reason).into_owned(),
+                    code.code == CODE_ALLOC_MISMATCH,
+                ),
+                Err(err) => (err.to_string(), false),
+            };
+            let reason = format!("{} failed: {}", m.typ.method, reason);
+            if m.typ.method == METHOD_REFRESH || mismatch {
+                report_lost(&self.lost_tx, reason);
+            } else {
+                log::warn!("{}", reason);
+            }
+        }
+    }
+}
+
+fn report_lost(lost_tx: &watch::Sender<Option<String>>, reason: String) {
+    if lost_tx.borrow().is_none() {
+        let _ = lost_tx.send(Some(reason));
+    }
+}
+
+#[async_trait]
+impl Conn for AllocationMonitorConn {
+    async fn connect(&self, addr: SocketAddr) -> io::Result<()> {
+        self.conn.connect(addr).await
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.conn.recv(buf).await?;
+        self.on_recv(&buf[..n]);
+        Ok(n)
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let (n, addr) = self.conn.recv_from(buf).await?;
+        self.on_recv(&buf[..n]);
+        Ok((n, addr))
+    }
+
+    async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.on_send(buf);
+        self.conn.send(buf).await
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        self.on_send(buf);
+        self.conn.send_to(buf, target).await
+    }
+
+    async fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.conn.local_addr().await
+    }
+}
@@ -1,5 +1,7 @@
+use super::candidate_descriptor::CandidateDescriptor;
 use super::*;
 use crate::errors::*;
+use crate::url::Url;
 use crate::util::*;
 
 use stun::message::*;
@@ -22,6 +24,14 @@ pub struct CandidateBaseConfig {
     pub component: u16,
     pub priority: u32,
     pub foundation: String,
+    /// Position of the candidate among the gathered candidates of the same type, 0 being the
+    /// most preferred. Keeps local preferences unique on multi-homed hosts.
+    pub local_preference_rank: u16,
+    pub network_id: u16,
+    pub network_cost: u16,
+    pub interface_type: InterfaceType,
+    /// The STUN or TURN server the candidate was gathered from.
+    pub url: Option<Url>,
     pub conn: Option<Arc<dyn util::Conn + Send + Sync>>,
     pub initialized_ch: Option<broadcast::Receiver<()>>,
 }
@@ -50,10 +60,17 @@ pub struct CandidateBase {
 
     pub(crate) foundation_override: String,
     pub(crate) priority_override: u32,
+    pub(crate) local_preference_rank: u16,
+
+    pub(crate) network_id: u16,
+    pub(crate) network_cost: u16,
+    pub(crate) interface_type: InterfaceType,
+    pub(crate) url: Option<Url>,
 
     //CandidateHost
     pub(crate) network: String,
     //CandidateRelay
+    pub(crate) relay_protocol: String,
     pub(crate) relay_client: Option<Arc<turn::client::Client>>,
 }
 
@@ -81,7 +98,13 @@ impl Default for CandidateBase {
 
             foundation_override: String::new(),
             priority_override: 0,
+            local_preference_rank: 0,
+            network_id: 0,
+            network_cost: 0,
+            interface_type: InterfaceType::default(),
+            url: None,
             network: String::new(),
+            relay_protocol: String::new(),
             relay_client: None,
         }
     }
@@ -188,6 +211,26 @@ impl Candidate for CandidateBase {
             + (256 - u32::from(self.component()))
     }
 
+    /// Returns the id of the network the candidate was gathered on, 0 if unknown.
+    fn network_id(&self) -> u16 {
+        self.network_id
+    }
+
+    /// Returns the cost of sending over the candidate's network, 0 if unknown.
+    fn network_cost(&self) -> u16 {
+        self.network_cost
+    }
+
+    /// Returns the type of the interface the candidate was gathered on.
+    fn interface_type(&self) -> InterfaceType {
+        self.interface_type
+    }
+
+    /// Returns the STUN or TURN server the candidate was gathered from.
+    fn url(&self) -> Option<Url> {
+        self.url.clone()
+    }
+
     /// Returns `Option<CandidateRelatedAddress>`.
     fn related_address(&self) -> Option<CandidateRelatedAddress> {
         self.related_address.as_ref().cloned()
@@ -202,32 +245,14 @@ impl Candidate for CandidateBase {
         self.tcp_type
     }
 
+    /// Returns the transport to the TURN server of a relay candidate.
+    fn relay_protocol(&self) -> String {
+        self.relay_protocol.clone()
+    }
+
     /// Returns the string representation of the ICECandidate.
     fn marshal(&self) -> String {
-        let mut val = format!(
-            "{} {} {} {} {} {} typ {}",
-            self.foundation(),
-            self.component(),
-            self.network_type().network_short(),
-            self.priority(),
-            self.address(),
-            self.port(),
-            self.candidate_type()
-        );
-
-        if self.tcp_type != TcpType::Unspecified {
-            val += format!(" tcptype {}", self.tcp_type()).as_str();
-        }
-
-        if let Some(related_address) = self.related_address() {
-            val += format!(
-                " raddr {} rport {}",
-                related_address.address, related_address.port,
-            )
-            .as_str();
-        }
-
-        val
+        CandidateDescriptor::from_candidate(self).marshal()
     }
 
     async fn addr(&self) -> SocketAddr {
@@ -365,7 +390,7 @@ impl CandidateBase {
             // other-pref is the preference for the particular IP address from which
             // the candidate was obtained.  When there is only a single IP address,
             // this value SHOULD be set to the maximum allowed value (8191).
-            let other_pref: u16 = 8191;
+            let other_pref: u16 = 8191_u16.saturating_sub(self.local_preference_rank);
 
             let direction_pref: u16 = match self.candidate_type() {
                 CandidateType::Host | CandidateType::Relay => match self.tcp_type() {
@@ -387,7 +412,7 @@ impl CandidateBase {
 
             (1 << 13) * direction_pref + other_pref
         } else {
-            DEFAULT_LOCAL_PREFERENCE
+            DEFAULT_LOCAL_PREFERENCE.saturating_sub(self.local_preference_rank)
         }
     }
 
@@ -464,7 +489,7 @@ impl CandidateBase {
             }
         } else {
             let ai = agent_internal.lock().await;
-            if !ai.validate_non_stun_traffic(c, src_addr).await {
+            if !ai.validate_non_stun_traffic(c, src_addr, buf.len()).await {
                 log::warn!(
                     "Discarded message from {}, not a valid remote candidate",
                     c.addr().await
@@ -0,0 +1,403 @@
+use super::candidate_base::*;
+use super::candidate_host::*;
+use super::candidate_peer_reflexive::*;
+use super::candidate_relay::*;
+use super::candidate_server_reflexive::*;
+use super::*;
+use crate::errors::*;
+use crate::util::is_ice_char;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+
+const MAX_FOUNDATION_LEN: usize = 32;
+const MAX_COMPONENT: u16 = 256;
+
+/// The `candidate` attribute of SDP (RFC 8839 section 5.1), parsed on its own, without an agent.
+///
+/// Parsing and marshaling round-trip: the extension attributes this crate doesn't know are kept,
+/// in order, and written back after the known ones.
+///
+/// With the `serde` feature, it is serialized as a [`CandidateInit`].
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(into = "CandidateInit", try_from = "CandidateInit")
+)]
+pub struct CandidateDescriptor {
+    pub foundation: String,
+    pub component: u16,
+    /// The transport protocol, in lower case, `udp` or `tcp` for the candidates the agent uses.
+    pub transport: String,
+    pub priority: u32,
+    /// An IP address, or a host name such as an mDNS one.
+    pub address: String,
+    pub port: u16,
+    pub candidate_type: CandidateType,
+    pub related_address: Option<CandidateRelatedAddress>,
+    /// The `tcptype` extension of RFC 6544.
+    pub tcp_type: TcpType,
+    pub generation: Option<u32>,
+    pub ufrag: Option<String>,
+    pub network_id: Option<u16>,
+    pub network_cost: Option<u16>,
+    /// The other extension attributes, as name and value pairs.
+    pub extensions: Vec<(String, String)>,
+}
+
+impl Default for CandidateDescriptor {
+    fn default() -> Self {
+        Self {
+            foundation: String::new(),
+            component: COMPONENT_RTP,
+            transport: UDP.to_owned(),
+            priority: 0,
+            address: String::new(),
+            port: 0,
+            candidate_type: CandidateType::default(),
+            related_address: None,
+            tcp_type: TcpType::default(),
+            generation: None,
+            ufrag: None,
+            network_id: None,
+            network_cost: None,
+            extensions: vec![],
+        }
+    }
+}
+
+impl fmt::Display for CandidateDescriptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.marshal())
+    }
+}
+
+impl CandidateDescriptor {
+    /// Parses the value of a `candidate` attribute, with or without the `candidate:` or
+    /// `a=candidate:` prefix.
+    pub fn unmarshal(raw: &str) -> Result<Self, Error> {
+        let raw = raw.trim();
+        let raw = raw.strip_prefix("a=").unwrap_or(raw);
+        let raw = raw.strip_prefix("candidate:").unwrap_or(raw);
+
+        let fields: Vec<&str> = raw.split_whitespace().collect();
+        if fields.len() < 8 {
+            return Err(Error::new(format!(
+                "{} ({})",
+                *ERR_ATTRIBUTE_TOO_SHORT_ICE_CANDIDATE,
+                fields.len()
+            )));
+        }
+
+        let foundation = fields[0];
+        if foundation.len() > MAX_FOUNDATION_LEN || !foundation.chars().all(is_ice_char) {
+            return Err(parse_error(&ERR_PARSE_FOUNDATION, foundation));
+        }
+
+        let component = match fields[1].parse::<u16>() {
+            Ok(component) if (1..=MAX_COMPONENT).contains(&component) => component,
+            _ => return Err(parse_error(&ERR_PARSE_COMPONENT, fields[1])),
+        };
+
+        let transport = fields[2];
+        if !transport.chars().all(is_token_char) {
+            return Err(parse_error(&ERR_PARSE_TRANSPORT, transport));
+        }
+
+        let priority = fields[3]
+            .parse::<u32>()
+            .map_err(|_| parse_error(&ERR_PARSE_PRIORITY, fields[3]))?;
+        let address = fields[4];
+        let port = parse_port(fields[5])?;
+
+        if fields[6] != "typ" {
+            return Err(parse_error(&ERR_PARSE_TYPE, fields[6]));
+        }
+        let candidate_type = match fields[7] {
+            "host" => CandidateType::Host,
+            "srflx" => CandidateType::ServerReflexive,
+            "prflx" => CandidateType::PeerReflexive,
+            "relay" => CandidateType::Relay,
+            typ => {
+                return Err(Error::new(format!(
+                    "{} ({})",
+                    *ERR_UNKNOWN_CANDIDATE_TYPE, typ
+                )))
+            }
+        };
+
+        let mut descriptor = Self {
+            foundation: foundation.to_owned(),
+            component,
+            transport: transport.to_lowercase(),
+            priority,
+            address: address.to_owned(),
+            port,
+            candidate_type,
+            ..Self::default()
+        };
+
+        let mut rest = &fields[8..];
+        if rest.first() == Some(&"raddr") {
+            descriptor.related_address = Some(parse_related_address(rest)?);
+            rest = &rest[4..];
+        }
+
+        while let Some(name) = rest.first().copied() {
+            let value = match rest.get(1).copied() {
+                Some(value) => value,
+                None => return Err(parse_error(&ERR_PARSE_EXTENSION, name)),
+            };
+            match name {
+                // older versions of this crate wrote tcptype before the related address
+                "raddr" if descriptor.related_address.is_none() => {
+                    descriptor.related_address = Some(parse_related_address(rest)?);
+                    rest = &rest[4..];
+                    continue;
+                }
+                "tcptype" => {
+                    descriptor.tcp_type = match TcpType::from(value) {
+                        TcpType::Unspecified => {
+                            return Err(parse_error(&ERR_PARSE_TCP_TYPE, value))
+                        }
+                        tcp_type => tcp_type,
+                    };
+                }
+                "generation" => {
+                    descriptor.generation = Some(
+                        value
+                            .parse()
+                            .map_err(|_| parse_error(&ERR_PARSE_GENERATION, value))?,
+                    );
+                }
+                "ufrag" => descriptor.ufrag = Some(value.to_owned()),
+                "network-id" => {
+                    descriptor.network_id = Some(
+                        value
+                            .parse()
+                            .map_err(|_| parse_error(&ERR_PARSE_NETWORK_ID, value))?,
+                    );
+                }
+                "network-cost" => {
+                    descriptor.network_cost = Some(
+                        value
+                            .parse()
+                            .map_err(|_| parse_error(&ERR_PARSE_NETWORK_COST, value))?,
+                    );
+                }
+                _ => descriptor
+                    .extensions
+                    .push((name.to_owned(), value.to_owned())),
+            }
+            rest = &rest[2..];
+        }
+
+        Ok(descriptor)
+    }
+
+    /// Returns the value of the `candidate` attribute, without the `candidate:` prefix.
+    #[must_use]
+    pub fn marshal(&self) -> String {
+        let mut fields = vec![
+            self.foundation.clone(),
+            self.component.to_string(),
+            self.transport.clone(),
+            self.priority.to_string(),
+            self.address.clone(),
+            self.port.to_string(),
+            "typ".to_owned(),
+            self.candidate_type.to_string(),
+        ];
+
+        if let Some(related_address) = &self.related_address {
+            fields.push("raddr".to_owned());
+            fields.push(related_address.address.clone());
+            fields.push("rport".to_owned());
+            fields.push(related_address.port.to_string());
+        }
+
+        let mut extensions = vec![];
+        if self.tcp_type != TcpType::Unspecified {
+            extensions.push(("tcptype", self.tcp_type.to_string()));
+        }
+        if let Some(generation) = self.generation {
+            extensions.push(("generation", generation.to_string()));
+        }
+        if let Some(ufrag) = &self.ufrag {
+            extensions.push(("ufrag", ufrag.clone()));
+        }
+        if let Some(network_id) = self.network_id {
+            extensions.push(("network-id", network_id.to_string()));
+        }
+        if let Some(network_cost) = self.network_cost {
+            extensions.push(("network-cost", network_cost.to_string()));
+        }
+        extensions.extend(
+            self.extensions
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.clone())),
+        );
+        for (name, value) in extensions {
+            fields.push(name.to_owned());
+            fields.push(value);
+        }
+
+        fields.join(" ")
+    }
+
+    /// Describes `c`, leaving out the network id and cost when they are unknown.
+    #[must_use]
+    pub fn from_candidate(c: &dyn Candidate) -> Self {
+        Self {
+            foundation: c.foundation(),
+            component: c.component(),
+            transport: c.network_type().network_short(),
+            priority: c.priority(),
+            address: c.address(),
+            port: c.port(),
+            candidate_type: c.candidate_type(),
+            related_address: c.related_address(),
+            tcp_type: c.tcp_type(),
+            network_id: Some(c.network_id()).filter(|network_id| *network_id != 0),
+            network_cost: Some(c.network_cost()).filter(|network_cost| *network_cost != 0),
+            ..Self::default()
+        }
+    }
+
+    /// Creates the candidate described, as a remote candidate of the agent of `agent_internal`.
+    pub async fn new_candidate(
+        &self,
+        agent_internal: Option<Arc<Mutex<AgentInternal>>>,
+    ) -> Result<CandidateBase, Error> {
+        let base_config = CandidateBaseConfig {
+            network: self.transport.clone(),
+            address: self.address.clone(),
+            port: self.port,
+            component: self.component,
+            priority: self.priority,
+            foundation: self.foundation.clone(),
+            network_id: self.network_id.unwrap_or_default(),
+            network_cost: self.network_cost.unwrap_or_default(),
+            ..CandidateBaseConfig::default()
+        };
+        let (rel_addr, rel_port) = self
+            .related_address
+            .as_ref()
+            .map_or((String::new(), 0), |related_address| {
+                (related_address.address.clone(), related_address.port)
+            });
+
+        match self.candidate_type {
+            CandidateType::Host => {
+                CandidateHostConfig {
+                    base_config,
+                    tcp_type: self.tcp_type,
+                }
+                .new_candidate_host(agent_internal)
+                .await
+            }
+            CandidateType::ServerReflexive => {
+                CandidateServerReflexiveConfig {
+                    base_config,
+                    rel_addr,
+                    rel_port,
+                    tcp_type: self.tcp_type,
+                }
+                .new_candidate_server_reflexive(agent_internal)
+                .await
+            }
+            CandidateType::PeerReflexive => {
+                CandidatePeerReflexiveConfig {
+                    base_config,
+                    rel_addr,
+                    rel_port,
+                }
+                .new_candidate_peer_reflexive(agent_internal)
+                .await
+            }
+            CandidateType::Relay => {
+                CandidateRelayConfig {
+                    base_config,
+                    rel_addr,
+                    rel_port,
+                    ..CandidateRelayConfig::default()
+                }
+                .new_candidate_relay(agent_internal)
+                .await
+            }
+            CandidateType::Unspecified => Err(Error::new(format!(
+                "{} ({})",
+                *ERR_UNKNOWN_CANDIDATE_TYPE, self.candidate_type
+            ))),
+        }
+    }
+}
+
+/// A candidate as signaled to browsers, in the shape of the W3C `RTCIceCandidateInit` dictionary,
+/// with the `candidate`, `sdpMid`, `sdpMLineIndex` and `usernameFragment` members.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct CandidateInit {
+    /// The `candidate` attribute, with the `candidate:` prefix.
+    pub candidate: String,
+    #[cfg_attr(feature = "serde", serde(rename = "sdpMid", default))]
+    pub sdp_mid: Option<String>,
+    #[cfg_attr(feature = "serde", serde(rename = "sdpMLineIndex", default))]
+    pub sdp_mline_index: Option<u16>,
+    #[cfg_attr(feature = "serde", serde(rename = "usernameFragment", default))]
+    pub username_fragment: Option<String>,
+}
+
+impl From<CandidateDescriptor> for CandidateInit {
+    fn from(descriptor: CandidateDescriptor) -> Self {
+        Self {
+            candidate: format!("candidate:{}", descriptor.marshal()),
+            sdp_mid: None,
+            sdp_mline_index: None,
+            username_fragment: descriptor.ufrag,
+        }
+    }
+}
+
+impl TryFrom<CandidateInit> for CandidateDescriptor {
+    type Error = Error;
+
+    /// Parses the `candidate` attribute, whose `ufrag` extension defaults to the
+    /// `usernameFragment`.
+    fn try_from(init: CandidateInit) -> Result<Self, Self::Error> {
+        let mut descriptor = Self::unmarshal(&init.candidate)?;
+        if descriptor.ufrag.is_none() {
+            descriptor.ufrag = init.username_fragment;
+        }
+        Ok(descriptor)
+    }
+}
+
+fn parse_error(err: &Error, token: &str) -> Error {
+    Error::new(format!("{}: {:?}", err, token))
+}
+
+fn parse_port(raw: &str) -> Result<u16, Error> {
+    raw.parse().map_err(|_| parse_error(&ERR_PARSE_PORT, raw))
+}
+
+/// Parses `raddr <address> rport <port>` at the start of `fields`.
+fn parse_related_address(fields: &[&str]) -> Result<CandidateRelatedAddress, Error> {
+    match fields {
+        ["raddr", address, "rport", port, ..] => Ok(CandidateRelatedAddress {
+            address: (*address).to_owned(),
+            port: parse_port(port)?,
+        }),
+        _ => Err(Error::new(format!(
+            "{}: expected raddr <address> rport <port>",
+            *ERR_PARSE_RELATED_ADDR
+        ))),
+    }
+}
+
+/// Whether `c` may appear in a token (RFC 4566 section 9), which is what a transport is.
+fn is_token_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`{|}~".contains(c)
+}
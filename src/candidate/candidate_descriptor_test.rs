@@ -0,0 +1,267 @@
+use super::candidate_descriptor::*;
+use super::candidate_host::*;
+use super::*;
+use crate::agent::agent_config::AgentConfig;
+use crate::agent::Agent;
+use crate::errors::*;
+
+use std::convert::TryFrom;
+use std::time::Duration;
+
+#[test]
+fn test_candidate_descriptor_unmarshal() -> Result<(), Error> {
+    let raw = "candidate:842163049 1 UDP 1677729535 203.0.113.7 50123 typ srflx raddr 10.0.1.1 rport 8998 generation 2 ufrag EsAw network-id 3 network-cost 10 x-custom abc";
+    let descriptor = CandidateDescriptor::unmarshal(raw)?;
+    assert_eq!(
+        descriptor,
+        CandidateDescriptor {
+            foundation: "842163049".to_owned(),
+            component: 1,
+            transport: "udp".to_owned(),
+            priority: 1677729535,
+            address: "203.0.113.7".to_owned(),
+            port: 50123,
+            candidate_type: CandidateType::ServerReflexive,
+            related_address: Some(CandidateRelatedAddress {
+                address: "10.0.1.1".to_owned(),
+                port: 8998,
+            }),
+            tcp_type: TcpType::Unspecified,
+            generation: Some(2),
+            ufrag: Some("EsAw".to_owned()),
+            network_id: Some(3),
+            network_cost: Some(10),
+            extensions: vec![("x-custom".to_owned(), "abc".to_owned())],
+        }
+    );
+    assert_eq!(
+        descriptor.marshal(),
+        "842163049 1 udp 1677729535 203.0.113.7 50123 typ srflx raddr 10.0.1.1 rport 8998 generation 2 ufrag EsAw network-id 3 network-cost 10 x-custom abc"
+    );
+    assert_eq!(
+        CandidateDescriptor::unmarshal(&descriptor.marshal())?,
+        descriptor
+    );
+
+    let with_attribute_prefix = CandidateDescriptor::unmarshal(
+        "a=candidate:1 1 tcp 2128609279 192.168.0.196 9 typ host tcptype active",
+    )?;
+    assert_eq!(with_attribute_prefix.tcp_type, TcpType::Active);
+    assert_eq!(with_attribute_prefix.related_address, None);
+
+    // older versions of this crate put tcptype before the related address
+    let descriptor = CandidateDescriptor::unmarshal(
+        "1 1 tcp 1694498815 191.228.238.68 9 typ srflx tcptype passive raddr 10.0.0.1 rport 9",
+    )?;
+    assert_eq!(descriptor.tcp_type, TcpType::Passive);
+    assert_eq!(
+        descriptor.marshal(),
+        "1 1 tcp 1694498815 191.228.238.68 9 typ srflx raddr 10.0.0.1 rport 9 tcptype passive"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_candidate_descriptor_unmarshal_errors() {
+    let tests = vec![
+        (
+            "1 1 udp 2130706431 10.0.75.1 53634 typ",
+            &*ERR_ATTRIBUTE_TOO_SHORT_ICE_CANDIDATE,
+        ),
+        (
+            "1-2 1 udp 2130706431 10.0.75.1 53634 typ host",
+            &*ERR_PARSE_FOUNDATION,
+        ),
+        (
+            "123456789012345678901234567890123 1 udp 2130706431 10.0.75.1 53634 typ host",
+            &*ERR_PARSE_FOUNDATION,
+        ),
+        (
+            "1 0 udp 2130706431 10.0.75.1 53634 typ host",
+            &*ERR_PARSE_COMPONENT,
+        ),
+        (
+            "1 257 udp 2130706431 10.0.75.1 53634 typ host",
+            &*ERR_PARSE_COMPONENT,
+        ),
+        (
+            "1 1 ud\"p 2130706431 10.0.75.1 53634 typ host",
+            &*ERR_PARSE_TRANSPORT,
+        ),
+        (
+            "1 1 udp 99999999999 10.0.75.1 53634 typ host",
+            &*ERR_PARSE_PRIORITY,
+        ),
+        (
+            "1 1 udp 2130706431 10.0.75.1 99999 typ host",
+            &*ERR_PARSE_PORT,
+        ),
+        (
+            "1 1 udp 2130706431 10.0.75.1 53634 type host",
+            &*ERR_PARSE_TYPE,
+        ),
+        (
+            "1 1 udp 2130706431 10.0.75.1 53634 typ other",
+            &*ERR_UNKNOWN_CANDIDATE_TYPE,
+        ),
+        (
+            "1 1 udp 1694498815 191.228.238.68 53991 typ srflx raddr 10.0.0.1",
+            &*ERR_PARSE_RELATED_ADDR,
+        ),
+        (
+            "1 1 udp 1694498815 191.228.238.68 53991 typ srflx raddr 10.0.0.1 rport x",
+            &*ERR_PARSE_PORT,
+        ),
+        (
+            "1 1 tcp 2128609279 10.0.75.1 9 typ host tcptype both",
+            &*ERR_PARSE_TCP_TYPE,
+        ),
+        (
+            "1 1 udp 2130706431 10.0.75.1 53634 typ host generation x",
+            &*ERR_PARSE_GENERATION,
+        ),
+        (
+            "1 1 udp 2130706431 10.0.75.1 53634 typ host network-id x",
+            &*ERR_PARSE_NETWORK_ID,
+        ),
+        (
+            "1 1 udp 2130706431 10.0.75.1 53634 typ host network-cost x",
+            &*ERR_PARSE_NETWORK_COST,
+        ),
+        (
+            "1 1 udp 2130706431 10.0.75.1 53634 typ host generation",
+            &*ERR_PARSE_EXTENSION,
+        ),
+    ];
+
+    for (raw, expected) in tests {
+        let err = CandidateDescriptor::unmarshal(raw).unwrap_err();
+        assert!(
+            err.to_string().starts_with(&expected.to_string()),
+            "{}: {}",
+            raw,
+            err
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_candidate_descriptor_new_candidate() -> Result<(), Error> {
+    let host = CandidateHostConfig {
+        base_config: CandidateBaseConfig {
+            network: "udp".to_owned(),
+            address: "10.0.75.1".to_owned(),
+            port: 53634,
+            component: COMPONENT_RTP,
+            network_id: 2,
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+    .new_candidate_host(None)
+    .await?;
+
+    let descriptor = CandidateDescriptor::from_candidate(&host);
+    assert_eq!(descriptor.network_id, Some(2));
+    assert_eq!(descriptor.network_cost, None);
+    assert_eq!(descriptor.marshal(), host.marshal());
+
+    let candidate = descriptor.new_candidate(None).await?;
+    assert!(candidate.equal(&host));
+    assert_eq!(candidate.network_type(), NetworkType::Udp4);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_agent_add_remote_candidate_descriptor() -> Result<(), Error> {
+    let a = Agent::new(AgentConfig::default()).await?;
+
+    let descriptor = CandidateDescriptor::unmarshal(
+        "candidate:1 1 udp 2130706431 192.168.1.2 5000 typ host generation 0",
+    )?;
+    a.add_remote_candidate_descriptor(&descriptor).await?;
+
+    let mut added = false;
+    for _ in 0..50 {
+        {
+            let ai = a.agent_internal.lock().await;
+            if let Some(candidates) = ai.remote_candidates.get(&NetworkType::Udp4) {
+                assert_eq!(candidates.len(), 1);
+                assert_eq!(candidates[0].address(), "192.168.1.2");
+                assert_eq!(candidates[0].port(), 5000);
+                added = true;
+                break;
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(added, "the remote candidate should have been added");
+
+    a.close().await?;
+
+    Ok(())
+}
+
+#[test]
+fn test_candidate_init() -> Result<(), Error> {
+    let descriptor = CandidateDescriptor::unmarshal("1 1 udp 2130706431 10.0.75.1 53634 typ host")?;
+
+    let init = CandidateInit::from(descriptor.clone());
+    assert_eq!(
+        init.candidate,
+        "candidate:1 1 udp 2130706431 10.0.75.1 53634 typ host"
+    );
+    assert_eq!(init.username_fragment, None);
+
+    let with_ufrag = CandidateDescriptor::try_from(CandidateInit {
+        username_fragment: Some("EsAw".to_owned()),
+        ..init
+    })?;
+    assert_eq!(with_ufrag.ufrag, Some("EsAw".to_owned()));
+    assert_eq!(
+        CandidateInit::from(with_ufrag).username_fragment,
+        Some("EsAw".to_owned())
+    );
+
+    assert!(CandidateDescriptor::try_from(CandidateInit::default()).is_err());
+
+    Ok(())
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_candidate_descriptor_serde() -> Result<(), Error> {
+    let descriptor = CandidateDescriptor::unmarshal(
+        "1 1 udp 1694498815 191.228.238.68 53991 typ srflx raddr 10.0.0.1 rport 53991 ufrag EsAw",
+    )?;
+
+    let json = serde_json::to_value(&descriptor).unwrap();
+    assert_eq!(
+        json,
+        serde_json::json!({
+            "candidate": "candidate:1 1 udp 1694498815 191.228.238.68 53991 typ srflx raddr 10.0.0.1 rport 53991 ufrag EsAw",
+            "sdpMid": null,
+            "sdpMLineIndex": null,
+            "usernameFragment": "EsAw",
+        })
+    );
+    let parsed: CandidateDescriptor = serde_json::from_value(json).unwrap();
+    assert_eq!(parsed, descriptor);
+
+    // as sent by browsers
+    let init: CandidateInit = serde_json::from_str(
+        r#"{"candidate":"candidate:1 1 UDP 2130706431 10.0.75.1 53634 typ host","sdpMid":"0","sdpMLineIndex":0}"#,
+    )
+    .unwrap();
+    assert_eq!(init.sdp_mid, Some("0".to_owned()));
+    assert_eq!(init.sdp_mline_index, Some(0));
+    assert_eq!(init.username_fragment, None);
+
+    assert!(
+        serde_json::from_str::<CandidateDescriptor>(r#"{"candidate":"candidate:1 1"}"#).is_err()
+    );
+
+    Ok(())
+}
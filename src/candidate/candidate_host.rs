@@ -1,7 +1,10 @@
 use super::candidate_base::*;
 use super::*;
+use crate::errors::*;
+use crate::network_type::determine_network_type;
 use crate::rand::generate_cand_id;
-use std::sync::atomic::{AtomicU16, AtomicU8};
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicU16, AtomicU8, Ordering};
 use std::sync::Arc;
 
 /// The config required to create a new `CandidateHost`.
@@ -32,6 +35,11 @@ impl CandidateHostConfig {
             tcp_type: self.tcp_type,
             foundation_override: self.base_config.foundation,
             priority_override: self.base_config.priority,
+            local_preference_rank: self.base_config.local_preference_rank,
+            network_id: self.base_config.network_id,
+            network_cost: self.base_config.network_cost,
+            interface_type: self.base_config.interface_type,
+            url: self.base_config.url,
             network: self.base_config.network,
             network_type: AtomicU8::new(NetworkType::Udp4 as u8),
             conn: self.base_config.conn,
@@ -39,10 +47,16 @@ impl CandidateHostConfig {
             ..CandidateBase::default()
         };
 
-        if !self.base_config.address.ends_with(".local") {
-            let ip = self.base_config.address.parse()?;
+        if let Ok(ip) = self.base_config.address.parse() {
             c.set_ip(&ip).await?;
-        };
+        } else if is_host_name(&self.base_config.address) {
+            // mDNS and DNS names are resolved later by the agent, the network
+            // family is only known once they are
+            let network_type = determine_network_type(&c.network, &Ipv4Addr::UNSPECIFIED.into())?;
+            c.network_type.store(network_type as u8, Ordering::SeqCst);
+        } else {
+            return Err(ERR_ADDRESS_PARSE_FAILED.to_owned());
+        }
 
         Ok(c)
     }
@@ -42,6 +42,11 @@ impl CandidatePeerReflexiveConfig {
             component: AtomicU16::new(self.base_config.component),
             foundation_override: self.base_config.foundation,
             priority_override: self.base_config.priority,
+            local_preference_rank: self.base_config.local_preference_rank,
+            network_id: self.base_config.network_id,
+            network_cost: self.base_config.network_cost,
+            interface_type: self.base_config.interface_type,
+            url: self.base_config.url,
             related_address: Some(CandidateRelatedAddress {
                 address: self.rel_addr,
                 port: self.rel_port,
@@ -3,6 +3,7 @@ use super::*;
 use crate::errors::*;
 use crate::rand::generate_cand_id;
 use crate::util::*;
+use std::net::Ipv4Addr;
 use std::sync::atomic::{AtomicU16, AtomicU8};
 use std::sync::Arc;
 
@@ -13,6 +14,8 @@ pub struct CandidateRelayConfig {
 
     pub rel_addr: String,
     pub rel_port: u16,
+    /// The transport to the TURN server: udp, tcp or tls.
+    pub relay_protocol: String,
     pub relay_client: Option<Arc<turn::client::Client>>,
 }
 
@@ -29,6 +32,8 @@ impl CandidateRelayConfig {
 
         let ip: IpAddr = match self.base_config.address.parse() {
             Ok(ip) => ip,
+            // a DNS name of a remote candidate, the agent resolves it before pairing
+            Err(_) if is_host_name(&self.base_config.address) => Ipv4Addr::UNSPECIFIED.into(),
             Err(_) => return Err(ERR_ADDRESS_PARSE_FAILED.to_owned()),
         };
         let network_type = determine_network_type(&self.base_config.network, &ip)?;
@@ -43,12 +48,18 @@ impl CandidateRelayConfig {
             component: AtomicU16::new(self.base_config.component),
             foundation_override: self.base_config.foundation,
             priority_override: self.base_config.priority,
+            local_preference_rank: self.base_config.local_preference_rank,
+            network_id: self.base_config.network_id,
+            network_cost: self.base_config.network_cost,
+            interface_type: self.base_config.interface_type,
+            url: self.base_config.url,
             related_address: Some(CandidateRelatedAddress {
                 address: self.rel_addr,
                 port: self.rel_port,
             }),
             conn: self.base_config.conn,
             agent_internal,
+            relay_protocol: self.relay_protocol,
             relay_client: self.relay_client.clone(),
             ..CandidateBase::default()
         };
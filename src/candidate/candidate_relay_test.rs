@@ -71,6 +71,7 @@ async fn test_relay_only_connection() -> Result<(), Error> {
             password: "password".to_owned(),
             port: server_port,
             proto: ProtoType::Udp,
+            explicit_port: true,
         }],
         candidate_types: vec![CandidateType::Relay],
         ..Default::default()
@@ -89,6 +90,7 @@ async fn test_relay_only_connection() -> Result<(), Error> {
             password: "password".to_owned(),
             port: server_port,
             proto: ProtoType::Udp,
+            explicit_port: true,
         }],
         candidate_types: vec![CandidateType::Relay],
         ..Default::default()
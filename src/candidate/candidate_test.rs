@@ -16,6 +16,15 @@ fn test_candidate_priority() -> Result<(), Error> {
             },
             2130706431,
         ),
+        (
+            CandidateBase {
+                candidate_type: CandidateType::Host,
+                component: AtomicU16::new(COMPONENT_RTP as u16),
+                local_preference_rank: 1,
+                ..Default::default()
+            },
+            2130706175,
+        ),
         (
             CandidateBase {
                 candidate_type: CandidateType::Host,
@@ -26,6 +35,17 @@ fn test_candidate_priority() -> Result<(), Error> {
             },
             2128609279,
         ),
+        (
+            CandidateBase {
+                candidate_type: CandidateType::Host,
+                component: AtomicU16::new(COMPONENT_RTP as u16),
+                network_type: AtomicU8::new(NetworkType::Tcp4 as u8),
+                tcp_type: TcpType::Active,
+                local_preference_rank: 1,
+                ..Default::default()
+            },
+            2128609023,
+        ),
         (
             CandidateBase {
                 candidate_type: CandidateType::Host,
@@ -312,6 +332,18 @@ async fn test_candidate_marshal() -> Result<(), Error> {
             }),
             "1380287402 1 udp 2130706431 e2494022-4d9a-4c1e-a750-cc48d4f8d6ee.local 60542 typ host", 
         ),
+        (
+            Some(CandidateBase{
+                    network_type:   AtomicU8::new(NetworkType::Udp4 as u8),
+                    candidate_type: CandidateType::Host,
+                    address:       "10.0.75.1".to_owned(),
+                    port:          53634,
+                    network_id:    2,
+                    network_cost:  10,
+                ..Default::default()
+            }),
+            "1986380506 1 udp 2130706431 10.0.75.1 53634 typ host network-id 2 network-cost 10",
+        ),
         // Invalid candidates
         (None, ""),
         (None, "1938809241"),
@@ -324,6 +356,7 @@ async fn test_candidate_marshal() -> Result<(), Error> {
         (None, "4207374051 1 udp INVALID 10.0.75.1 53634 typ host"),
         (None, "4207374051 INVALID udp 2130706431 10.0.75.1 INVALID typ host"),
         (None, "4207374051 1 udp 2130706431 10.0.75.1 53634 typ INVALID"),
+        (None, "4207374051 1 udp 2130706431 10.0.75.1 53634 typ host network-cost INVALID"),
     ];
 
     let agent = Agent::new(AgentConfig::default()).await?;
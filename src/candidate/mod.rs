@@ -1,4 +1,6 @@
 #[cfg(test)]
+mod candidate_descriptor_test;
+#[cfg(test)]
 mod candidate_pair_test;
 #[cfg(test)]
 mod candidate_relay_test;
@@ -8,24 +10,30 @@ mod candidate_server_reflexive_test;
 mod candidate_test;
 
 pub mod candidate_base;
+pub mod candidate_descriptor;
 pub mod candidate_host;
 pub mod candidate_peer_reflexive;
 pub mod candidate_relay;
 pub mod candidate_server_reflexive;
 
+use crate::network_policy::*;
 use crate::network_type::*;
 use crate::tcp_type::*;
+use crate::url::Url;
 use candidate_base::*;
 
 use util::Error;
 
 use crate::agent::agent_internal::AgentInternal;
 use async_trait::async_trait;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
 use std::fmt;
 use std::net::{IpAddr, SocketAddr};
-use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU8, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU32, AtomicU64, AtomicU8, Ordering};
 use std::sync::Arc;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::sync::{broadcast, Mutex};
 
 pub(crate) const RECEIVE_MTU: usize = 8192;
@@ -66,6 +74,20 @@ pub trait Candidate: fmt::Display {
 
     fn priority(&self) -> u32;
 
+    /// The id of the network the candidate was gathered on, from the `network-id` extension
+    /// for remote candidates. 0 if unknown.
+    fn network_id(&self) -> u16;
+
+    /// The cost of sending over the candidate's network, from the `network-cost` extension
+    /// for remote candidates. 0 if unknown.
+    fn network_cost(&self) -> u16;
+
+    /// The type of the network interface of a local candidate.
+    fn interface_type(&self) -> InterfaceType;
+
+    /// The STUN or TURN server a local server reflexive or relay candidate was gathered from.
+    fn url(&self) -> Option<Url>;
+
     /// A transport address related to candidate,
     /// which is useful for diagnostics and other purposes.
     fn related_address(&self) -> Option<CandidateRelatedAddress>;
@@ -73,6 +95,10 @@ pub trait Candidate: fmt::Display {
     fn candidate_type(&self) -> CandidateType;
     fn tcp_type(&self) -> TcpType;
 
+    /// The transport to the TURN server of a relay candidate (udp, tcp or tls), empty for the
+    /// other types.
+    fn relay_protocol(&self) -> String;
+
     fn marshal(&self) -> String;
 
     async fn addr(&self) -> SocketAddr;
@@ -94,10 +120,17 @@ pub trait Candidate: fmt::Display {
 
 /// Represents the type of candidate `CandidateType` enum.
 #[derive(PartialEq, Debug, Copy, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "lowercase")
+)]
 pub enum CandidateType {
     Unspecified,
     Host,
+    #[cfg_attr(feature = "serde", serde(rename = "srflx"))]
     ServerReflexive,
+    #[cfg_attr(feature = "serde", serde(rename = "prflx"))]
     PeerReflexive,
     Relay,
 }
@@ -155,8 +188,22 @@ pub(crate) fn contains_candidate_type(
     false
 }
 
+/// Whether `address` looks like a DNS host name, e.g. `example.org` or `host.local`.
+pub(crate) fn is_host_name(address: &str) -> bool {
+    !address.is_empty()
+        && address.len() <= 253
+        && address.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && label
+                    .chars()
+                    .all(|ch| ch.is_ascii_alphanumeric() || ch == '-' || ch == '_')
+        })
+}
+
 /// Convey transport addresses related to the candidate, useful for diagnostics and other purposes.
 #[derive(PartialEq, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct CandidateRelatedAddress {
     pub address: String,
     pub port: u16,
@@ -171,6 +218,11 @@ impl fmt::Display for CandidateRelatedAddress {
 
 /// Represent the ICE candidate pair state.
 #[derive(PartialEq, Debug, Copy, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "kebab-case")
+)]
 pub enum CandidatePairState {
     Unspecified = 0,
 
@@ -220,6 +272,42 @@ impl fmt::Display for CandidatePairState {
     }
 }
 
+/// The traffic of a candidate pair, reported by `CandidatePairStats`. The timestamps are in
+/// nanoseconds since the UNIX epoch, 0 until it happens.
+#[derive(Default)]
+pub(crate) struct CandidatePairCounters {
+    pub(crate) packets_sent: AtomicU32,
+    pub(crate) packets_received: AtomicU32,
+    pub(crate) bytes_sent: AtomicU64,
+    pub(crate) bytes_received: AtomicU64,
+    pub(crate) last_packet_sent_timestamp: AtomicU64,
+    pub(crate) last_packet_received_timestamp: AtomicU64,
+
+    pub(crate) requests_sent: AtomicU64,
+    pub(crate) requests_received: AtomicU64,
+    pub(crate) responses_sent: AtomicU64,
+    pub(crate) responses_received: AtomicU64,
+    pub(crate) consent_requests_sent: AtomicU64,
+    pub(crate) first_request_timestamp: AtomicU64,
+    pub(crate) last_request_timestamp: AtomicU64,
+    pub(crate) last_response_timestamp: AtomicU64,
+
+    /// In nanoseconds, of the responses received.
+    pub(crate) total_round_trip_time: AtomicU64,
+    pub(crate) current_round_trip_time: AtomicU64,
+}
+
+pub(crate) fn unix_nanos_now() -> u64 {
+    let d = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    u64::try_from(d.as_nanos()).unwrap_or(u64::MAX)
+}
+
+pub(crate) fn system_time_from_unix_nanos(nanos: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_nanos(nanos)
+}
+
 /// Represents a combination of a local and remote candidate.
 pub(crate) struct CandidatePair {
     pub(crate) ice_role_controlling: AtomicBool,
@@ -228,6 +316,7 @@ pub(crate) struct CandidatePair {
     pub(crate) binding_request_count: AtomicU16,
     pub(crate) state: AtomicU8, // convert it to CandidatePairState,
     pub(crate) nominated: AtomicBool,
+    pub(crate) counters: CandidatePairCounters,
 }
 
 impl Default for CandidatePair {
@@ -239,6 +328,7 @@ impl Default for CandidatePair {
             state: AtomicU8::new(CandidatePairState::Waiting as u8),
             binding_request_count: AtomicU16::new(0),
             nominated: AtomicBool::new(false),
+            counters: CandidatePairCounters::default(),
         }
     }
 }
@@ -290,6 +380,7 @@ impl CandidatePair {
             state: AtomicU8::new(CandidatePairState::Waiting as u8),
             binding_request_count: AtomicU16::new(0),
             nominated: AtomicBool::new(false),
+            counters: CandidatePairCounters::default(),
         }
     }
 
@@ -312,7 +403,75 @@ impl CandidatePair {
             + if g > d { 1 } else { 0 }
     }
 
+    /// The id of the pair in the stats, made of the ids of its candidates.
+    pub fn id(&self) -> String {
+        format!("{}-{}", self.local.id(), self.remote.id())
+    }
+
+    /// The cost of sending over the pair, the sum of the network costs of both candidates.
+    pub fn network_cost(&self) -> u32 {
+        u32::from(self.local.network_cost()) + u32::from(self.remote.network_cost())
+    }
+
     pub async fn write(&self, b: &[u8]) -> Result<usize, Error> {
-        self.local.write_to(b, &*self.remote).await
+        let n = self.local.write_to(b, &*self.remote).await?;
+        self.counters.packets_sent.fetch_add(1, Ordering::SeqCst);
+        self.counters
+            .bytes_sent
+            .fetch_add(b.len() as u64, Ordering::SeqCst);
+        self.counters
+            .last_packet_sent_timestamp
+            .store(unix_nanos_now(), Ordering::SeqCst);
+        Ok(n)
+    }
+
+    /// Counts a packet of `n` bytes of data received, STUN excluded.
+    pub(crate) fn on_packet_received(&self, n: usize) {
+        self.counters
+            .packets_received
+            .fetch_add(1, Ordering::SeqCst);
+        self.counters
+            .bytes_received
+            .fetch_add(n as u64, Ordering::SeqCst);
+        self.counters
+            .last_packet_received_timestamp
+            .store(unix_nanos_now(), Ordering::SeqCst);
+    }
+
+    /// Counts a binding request sent, `consent` when it checks the selected pair.
+    pub(crate) fn on_request_sent(&self, consent: bool) {
+        let now = unix_nanos_now();
+        self.counters.requests_sent.fetch_add(1, Ordering::SeqCst);
+        if consent {
+            self.counters
+                .consent_requests_sent
+                .fetch_add(1, Ordering::SeqCst);
+        }
+        let _ = self.counters.first_request_timestamp.compare_exchange(
+            0,
+            now,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        );
+        self.counters
+            .last_request_timestamp
+            .store(now, Ordering::SeqCst);
+    }
+
+    /// Counts a success response to one of our binding requests, sent `rtt` ago.
+    pub(crate) fn on_response_received(&self, rtt: Duration) {
+        let rtt = u64::try_from(rtt.as_nanos()).unwrap_or(u64::MAX);
+        self.counters
+            .responses_received
+            .fetch_add(1, Ordering::SeqCst);
+        self.counters
+            .last_response_timestamp
+            .store(unix_nanos_now(), Ordering::SeqCst);
+        self.counters
+            .total_round_trip_time
+            .fetch_add(rtt, Ordering::SeqCst);
+        self.counters
+            .current_round_trip_time
+            .store(rtt, Ordering::SeqCst);
     }
 }
@@ -8,6 +8,8 @@ use stun::message::*;
 
 use util::Error;
 
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 use std::fmt;
 
 /// Common helper for ICE-{CONTROLLED,CONTROLLING} and represents the so-called Tiebreaker number.
@@ -112,6 +114,11 @@ impl Getter for AttrControl {
 /// Represents ICE agent role, which can be controlling or controlled.
 /// Possible ICE agent roles.
 #[derive(PartialEq, Copy, Clone, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "lowercase")
+)]
 pub enum Role {
     Controlling,
     Controlled,
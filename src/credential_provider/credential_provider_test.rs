@@ -0,0 +1,366 @@
+use super::*;
+use crate::agent::{agent_config::AgentConfig, agent_test::*, Agent, CandidateError};
+use crate::candidate::*;
+use crate::network_type::*;
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use turn::auth::{generate_auth_key, AuthHandler, LongTermAuthHandler};
+use util::vnet::*;
+
+const SHARED_SECRET: &str = "secret";
+const TURN_SERVER_IP: &str = "1.2.3.4";
+const TURN_SERVER_PORT: u16 = 3478;
+const REALM: &str = "webrtc.rs";
+
+/// Checks TURN REST API credentials the way a TURN server configured with the shared secret does.
+struct RestAuthHandler;
+
+impl AuthHandler for RestAuthHandler {
+    fn auth_handle(
+        &self,
+        username: &str,
+        realm: &str,
+        _src_addr: SocketAddr,
+    ) -> Result<Vec<u8>, Error> {
+        let expiry = username.split(':').next().unwrap_or_default();
+        let expiry = UNIX_EPOCH + Duration::from_secs(expiry.parse::<u64>()?);
+        if expiry < SystemTime::now() {
+            return Err(Error::new(format!("expired username {}", username)));
+        }
+        let user = username.split_once(':').map_or("", |(_, user)| user);
+        let credentials = turn_rest_credentials(SHARED_SECRET, user, expiry)?;
+        Ok(generate_auth_key(username, realm, &credentials.password))
+    }
+}
+
+/// Counts the credentials it hands out.
+struct CountingProvider {
+    inner: TurnRestCredentialProvider,
+    calls: Arc<AtomicUsize>,
+}
+
+#[async_trait]
+impl CredentialProvider for CountingProvider {
+    async fn credentials(&self, url: &Url) -> Result<TurnCredentials, Error> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        self.inner.credentials(url).await
+    }
+}
+
+struct FailingProvider;
+
+#[async_trait]
+impl CredentialProvider for FailingProvider {
+    async fn credentials(&self, _url: &Url) -> Result<TurnCredentials, Error> {
+        Err(Error::new("credential service unavailable".to_owned()))
+    }
+}
+
+/// Knows the session key of `KID`, as a TURN server that decrypted its token would, unless the
+/// token is rejected, and the long-term credentials of "user". Records who authenticated.
+struct TokenAuthHandler {
+    accept_token: bool,
+    usernames: Arc<std::sync::Mutex<Vec<String>>>,
+}
+
+impl AuthHandler for TokenAuthHandler {
+    fn auth_handle(
+        &self,
+        username: &str,
+        realm: &str,
+        _src_addr: SocketAddr,
+    ) -> Result<Vec<u8>, Error> {
+        self.usernames.lock().unwrap().push(username.to_owned());
+        match username {
+            KID if self.accept_token => Ok(test_access_token().mac_key),
+            "user" => Ok(generate_auth_key(username, realm, "pass")),
+            _ => Err(Error::new(format!("unknown user {}", username))),
+        }
+    }
+}
+
+const KID: &str = "north-1";
+
+fn test_access_token() -> TurnAccessToken {
+    TurnAccessToken {
+        kid: KID.to_owned(),
+        mac_key: vec![0x5A; 32],
+        access_token: b"opaque token".to_vec(),
+    }
+}
+
+/// Returns the net of the agent, on a started WAN with a TURN server checking credentials with
+/// `auth_handler`.
+/// Runs a TURN server at `TURN_SERVER_IP` checking credentials with `auth_handler`, returns the
+/// net of the agent and the server.
+async fn start_server(
+    auth_handler: Box<dyn AuthHandler + Send + Sync>,
+) -> Result<(Arc<net::Net>, turn::server::Server), Error> {
+    let (agent_net, server_net) = build_wan(&[TURN_SERVER_IP], None).await?;
+    let conn = server_net
+        .bind(SocketAddr::new(
+            TURN_SERVER_IP.parse().unwrap(),
+            TURN_SERVER_PORT,
+        ))
+        .await?;
+    let server = start_turn_server(server_net, conn, auth_handler).await?;
+
+    Ok((agent_net, server))
+}
+
+#[test]
+fn test_turn_rest_credentials() -> Result<(), Error> {
+    let expiry = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+    let credentials = turn_rest_credentials(SHARED_SECRET, "alice", expiry)?;
+    assert_eq!(credentials.username, "1700000000:alice");
+    assert_eq!(credentials.password, "d8soP47RbdIKLDUOpnJPVQyq5Ts=");
+    assert_eq!(credentials.expiry, Some(expiry));
+
+    // without a user, they are the time-windowed credentials the turn crate checks
+    let expiry = SystemTime::now() + Duration::from_secs(60);
+    let credentials = turn_rest_credentials(SHARED_SECRET, "", expiry)?;
+    assert!(!credentials.username.contains(':'));
+    let handler = LongTermAuthHandler::new(SHARED_SECRET.to_owned());
+    assert_eq!(
+        handler.auth_handle(
+            &credentials.username,
+            REALM,
+            "1.2.3.10:5000".parse().unwrap()
+        )?,
+        generate_auth_key(&credentials.username, REALM, &credentials.password)
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_turn_rest_credential_provider() -> Result<(), Error> {
+    let provider = TurnRestCredentialProvider::new(
+        SHARED_SECRET.to_owned(),
+        "alice".to_owned(),
+        Duration::from_secs(600),
+    );
+    let url = Url::parse_url("turn:1.2.3.4:3478")?;
+
+    let credentials = provider.credentials(&url).await?;
+    let (expiry, user) = credentials.username.split_once(':').unwrap();
+    assert_eq!(user, "alice");
+    let expiry = UNIX_EPOCH + Duration::from_secs(expiry.parse::<u64>()?);
+    let ttl = expiry.duration_since(SystemTime::now())?;
+    assert!(ttl > Duration::from_secs(590) && ttl <= Duration::from_secs(600));
+    assert_eq!(
+        credentials,
+        turn_rest_credentials(SHARED_SECRET, "alice", expiry)?
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_agent_relay_with_credential_provider() -> Result<(), Error> {
+    let (agent_net, server) = start_server(Box::new(RestAuthHandler)).await?;
+    let calls = Arc::new(AtomicUsize::new(0));
+
+    let a = Agent::new(AgentConfig {
+        // no static credentials, they all come from the provider
+        urls: vec![Url::parse_url("turn:1.2.3.4:3478")?],
+        network_types: vec![NetworkType::Udp4],
+        candidate_types: vec![CandidateType::Relay],
+        credential_provider: Some(Arc::new(CountingProvider {
+            inner: TurnRestCredentialProvider::new(
+                SHARED_SECRET.to_owned(),
+                "alice".to_owned(),
+                Duration::from_secs(600),
+            ),
+            calls: Arc::clone(&calls),
+        })),
+        net: Some(agent_net),
+        ..Default::default()
+    })
+    .await?;
+
+    gather_and_wait(&a).await?;
+
+    let candidates = a.get_local_candidates().await?;
+    assert_eq!(candidates.len(), 1, "there must be one relay candidate");
+    assert_eq!(candidates[0].candidate_type(), CandidateType::Relay);
+    assert_eq!(candidates[0].address(), TURN_SERVER_IP);
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+    a.close().await?;
+    server.close()?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_agent_relay_credentials_renewed_before_expiry() -> Result<(), Error> {
+    let (agent_net, server) = start_server(Box::new(RestAuthHandler)).await?;
+    let calls = Arc::new(AtomicUsize::new(0));
+
+    let a = Agent::new(AgentConfig {
+        urls: vec![Url::parse_url("turn:1.2.3.4:3478")?],
+        network_types: vec![NetworkType::Udp4],
+        candidate_types: vec![CandidateType::Relay],
+        credential_provider: Some(Arc::new(CountingProvider {
+            // the server rejects them after two to three seconds
+            inner: TurnRestCredentialProvider::new(
+                SHARED_SECRET.to_owned(),
+                "alice".to_owned(),
+                Duration::from_secs(3),
+            ),
+            calls: Arc::clone(&calls),
+        })),
+        net: Some(agent_net),
+        ..Default::default()
+    })
+    .await?;
+
+    let (removed_tx, mut removed_rx) = mpsc::unbounded_channel();
+    a.on_candidate_removed(Box::new(move |c: Arc<dyn Candidate + Send + Sync>| {
+        let _ = removed_tx.send(c);
+        Box::pin(async {})
+    }))
+    .await;
+
+    gather_and_wait(&a).await?;
+    let candidates = a.get_local_candidates().await?;
+    assert_eq!(candidates.len(), 1, "there must be one relay candidate");
+    let first = Arc::clone(&candidates[0]);
+
+    let removed = tokio::time::timeout(Duration::from_secs(5), removed_rx.recv())
+        .await
+        .expect("relay candidate should have been replaced before its credentials expire")
+        .unwrap();
+    assert_eq!(removed.id(), first.id());
+
+    // the new allocation was made before the old one was removed
+    let candidates = a.get_local_candidates().await?;
+    assert_eq!(candidates.len(), 1);
+    assert_eq!(candidates[0].candidate_type(), CandidateType::Relay);
+    assert_ne!(candidates[0].id(), first.id());
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+    a.close().await?;
+    server.close()?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_agent_relay_credential_provider_error() -> Result<(), Error> {
+    let (agent_net, server) = start_server(Box::new(RestAuthHandler)).await?;
+
+    let a = Agent::new(AgentConfig {
+        urls: vec![Url::parse_url("turn:1.2.3.4:3478")?],
+        network_types: vec![NetworkType::Udp4],
+        candidate_types: vec![CandidateType::Relay],
+        credential_provider: Some(Arc::new(FailingProvider)),
+        net: Some(agent_net),
+        ..Default::default()
+    })
+    .await?;
+
+    let (error_tx, mut error_rx) = mpsc::channel::<CandidateError>(1);
+    a.on_candidate_error(Box::new(move |err: CandidateError| {
+        let error_tx = error_tx.clone();
+        Box::pin(async move {
+            let _ = error_tx.send(err).await;
+        })
+    }))
+    .await;
+
+    gather_and_wait(&a).await?;
+
+    assert!(a.get_local_candidates().await?.is_empty());
+    let err = error_rx.recv().await.unwrap();
+    assert_eq!(err.url, "turn:1.2.3.4:3478?transport=udp");
+    assert!(
+        err.error_text.contains("credential service unavailable"),
+        "{}",
+        err.error_text
+    );
+
+    a.close().await?;
+    server.close()?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_agent_relay_with_access_token() -> Result<(), Error> {
+    let usernames = Arc::new(std::sync::Mutex::new(vec![]));
+    let (agent_net, server) = start_server(Box::new(TokenAuthHandler {
+        accept_token: true,
+        usernames: Arc::clone(&usernames),
+    }))
+    .await?;
+
+    let a = Agent::new(AgentConfig {
+        // no shared credentials, only the token
+        urls: vec![Url::parse_url("turn:1.2.3.4:3478")?],
+        network_types: vec![NetworkType::Udp4],
+        candidate_types: vec![CandidateType::Relay],
+        turn_access_token: Some(test_access_token()),
+        net: Some(agent_net),
+        ..Default::default()
+    })
+    .await?;
+
+    gather_and_wait(&a).await?;
+
+    let candidates = a.get_local_candidates().await?;
+    assert_eq!(candidates.len(), 1, "there must be one relay candidate");
+    assert_eq!(candidates[0].candidate_type(), CandidateType::Relay);
+    assert!(usernames.lock().unwrap().iter().all(|u| u == KID));
+
+    a.close().await?;
+    server.close()?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_agent_relay_access_token_fallback() -> Result<(), Error> {
+    let usernames = Arc::new(std::sync::Mutex::new(vec![]));
+    let (agent_net, server) = start_server(Box::new(TokenAuthHandler {
+        accept_token: false,
+        usernames: Arc::clone(&usernames),
+    }))
+    .await?;
+
+    let a = Agent::new(AgentConfig {
+        urls: vec![Url {
+            username: "user".to_owned(),
+            password: "pass".to_owned(),
+            ..Url::parse_url("turn:1.2.3.4:3478")?
+        }],
+        network_types: vec![NetworkType::Udp4],
+        candidate_types: vec![CandidateType::Relay],
+        turn_access_token: Some(test_access_token()),
+        net: Some(agent_net),
+        ..Default::default()
+    })
+    .await?;
+
+    gather_and_wait(&a).await?;
+
+    let candidates = a.get_local_candidates().await?;
+    assert_eq!(
+        candidates.len(),
+        1,
+        "the long-term credentials should be used after the token was rejected"
+    );
+    let usernames = usernames.lock().unwrap().clone();
+    assert_eq!(usernames.first().map(String::as_str), Some(KID));
+    assert_eq!(usernames.last().map(String::as_str), Some("user"));
+
+    a.close().await?;
+    server.close()?;
+
+    Ok(())
+}
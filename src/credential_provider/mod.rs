@@ -0,0 +1,108 @@
+#[cfg(test)]
+mod credential_provider_test;
+
+use crate::url::*;
+
+use async_trait::async_trait;
+use ring::hmac;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use util::Error;
+
+/// How long the credentials minted by `TurnRestCredentialProvider` are valid by default.
+pub const DEFAULT_TURN_REST_CREDENTIALS_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// A username and password to authenticate to a TURN server with, or an access token.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TurnCredentials {
+    pub username: String,
+    pub password: String,
+    /// Authenticates the allocations instead of the username and password, which are only used
+    /// when the server rejects the token and they are not empty.
+    pub access_token: Option<TurnAccessToken>,
+    /// When the credentials stop being accepted. The allocations made with them are made again
+    /// with fresh credentials shortly before.
+    pub expiry: Option<SystemTime>,
+}
+
+/// An OAuth access token for third-party authorization to TURN servers (RFC 7635), as issued by
+/// the authorization server along with its session key.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TurnAccessToken {
+    /// The id of the session key, sent as the username.
+    pub kid: String,
+    /// The session key, which keys MESSAGE-INTEGRITY. The TURN server gets it from the token.
+    pub mac_key: Vec<u8>,
+    /// The self-contained token, opaque to the client and sent in the ACCESS-TOKEN attribute.
+    pub access_token: Vec<u8>,
+}
+
+/// Supplies the credentials of TURN servers, in place of the static ones of the URLs.
+///
+/// It is asked before each allocation, including the ones made again after an allocation is
+/// lost or before its credentials expire, so credentials can be minted or fetched fresh every
+/// time.
+#[async_trait]
+pub trait CredentialProvider {
+    /// Returns the credentials for the TURN server of `url`.
+    async fn credentials(&self, url: &Url) -> Result<TurnCredentials, Error>;
+}
+
+/// Mints time-limited credentials in the style of the TURN REST API
+/// (draft-uberti-behave-turn-rest-00), which TURN servers check against the shared secret.
+pub struct TurnRestCredentialProvider {
+    shared_secret: String,
+    user: String,
+    ttl: Duration,
+}
+
+impl TurnRestCredentialProvider {
+    /// Creates a provider whose credentials are signed with `shared_secret`, name `user`, which
+    /// may be empty, and are valid for `ttl`.
+    #[must_use]
+    pub const fn new(shared_secret: String, user: String, ttl: Duration) -> Self {
+        Self {
+            shared_secret,
+            user,
+            ttl,
+        }
+    }
+}
+
+#[async_trait]
+impl CredentialProvider for TurnRestCredentialProvider {
+    async fn credentials(&self, _url: &Url) -> Result<TurnCredentials, Error> {
+        let expiry = SystemTime::now() + self.ttl;
+        turn_rest_credentials(&self.shared_secret, &self.user, expiry)
+    }
+}
+
+/// Returns the TURN REST API credentials of `user` that expire at `expiry`.
+///
+/// The username is `expiry:user`, with the expiry in seconds since the Unix epoch, and the
+/// password is the base64 HMAC-SHA1 of the username keyed with `shared_secret`.
+pub fn turn_rest_credentials(
+    shared_secret: &str,
+    user: &str,
+    expiry: SystemTime,
+) -> Result<TurnCredentials, Error> {
+    let expiry_secs = expiry.duration_since(UNIX_EPOCH)?.as_secs();
+    let username = if user.is_empty() {
+        expiry_secs.to_string()
+    } else {
+        format!("{}:{}", expiry_secs, user)
+    };
+
+    let key = hmac::Key::new(
+        hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY,
+        shared_secret.as_bytes(),
+    );
+    let password = base64::encode(hmac::sign(&key, username.as_bytes()).as_ref());
+
+    Ok(TurnCredentials {
+        username,
+        password,
+        access_token: None,
+        // what the server checks, without the fraction of a second
+        expiry: Some(UNIX_EPOCH + Duration::from_secs(expiry_secs)),
+    })
+}
@@ -0,0 +1,352 @@
+use super::*;
+use crate::agent::{agent_config::AgentConfig, agent_vnet_test::*, Agent};
+use crate::candidate::*;
+use crate::network_type::*;
+
+use mdns::message::resource::{srv::*, *};
+use std::collections::HashMap;
+use tokio::sync::{mpsc, Mutex};
+use util::vnet::*;
+
+const DNS_SERVER_IP: &str = "192.168.0.2";
+
+fn srv_record(priority: u16, weight: u16, port: u16, target: &str) -> SrvRecord {
+    SrvRecord {
+        priority,
+        weight,
+        port,
+        target: target.to_owned(),
+    }
+}
+
+/// Answers the SRV query in `raw_query` with the records of `zone`, or with a name error for
+/// the names it doesn't have.
+fn answer_srv_query(raw_query: &[u8], zone: &HashMap<String, Vec<SrvRecord>>) -> Option<Vec<u8>> {
+    let mut query = Message::default();
+    query.unpack(raw_query).ok()?;
+    let question = query.questions.first()?.clone();
+    let records = zone.get(question.name.data.trim_end_matches('.'));
+
+    let answers = records
+        .into_iter()
+        .flatten()
+        .map(|record| Resource {
+            header: ResourceHeader {
+                name: question.name.clone(),
+                class: DNSCLASS_INET,
+                ttl: 60,
+                ..ResourceHeader::default()
+            },
+            body: Some(Box::new(SrvResource {
+                priority: record.priority,
+                weight: record.weight,
+                port: record.port,
+                target: Name::new(&format!("{}.", record.target)).unwrap(),
+            })),
+        })
+        .collect();
+    let mut response = Message {
+        header: Header {
+            id: query.header.id,
+            response: true,
+            recursion_available: true,
+            rcode: if records.is_some() {
+                RCode::Success
+            } else {
+                RCode::NameError
+            },
+            ..Header::default()
+        },
+        questions: vec![question],
+        answers,
+        ..Message::default()
+    };
+    response.pack().ok()
+}
+
+/// Stands in for a DNS server with the SRV records of `zone`, and returns the queried names.
+async fn start_dns_server(
+    net: &Arc<net::Net>,
+    zone: HashMap<String, Vec<SrvRecord>>,
+) -> Result<mpsc::UnboundedReceiver<String>, Error> {
+    let conn = net
+        .bind(SocketAddr::new(DNS_SERVER_IP.parse().unwrap(), DNS_PORT))
+        .await?;
+    let (queries_tx, queries_rx) = mpsc::unbounded_channel();
+
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; 1500];
+        while let Ok((n, from)) = conn.recv_from(&mut buf).await {
+            {
+                let mut query = Message::default();
+                if query.unpack(&buf[..n]).is_ok() {
+                    for question in &query.questions {
+                        let _ = queries_tx.send(question.name.data.clone());
+                    }
+                }
+            }
+            let response = match answer_srv_query(&buf[..n], &zone) {
+                Some(response) => response,
+                None => continue,
+            };
+            if conn.send_to(&response, from).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    Ok(queries_rx)
+}
+
+fn dns_server_addr() -> SocketAddr {
+    SocketAddr::new(DNS_SERVER_IP.parse().unwrap(), DNS_PORT)
+}
+
+#[test]
+fn test_srv_name() -> Result<(), Error> {
+    let tests = vec![
+        ("stun:example.com", Some("_stun._udp.example.com")),
+        ("stuns:example.com", Some("_stuns._tcp.example.com")),
+        ("turn:example.com", Some("_turn._udp.example.com")),
+        (
+            "turn:example.com?transport=tcp",
+            Some("_turn._tcp.example.com"),
+        ),
+        ("turns:example.com", Some("_turns._tcp.example.com")),
+        ("stun:example.com:3478", None),
+        ("stun:1.2.3.4", None),
+        ("turn:[::1]", None),
+    ];
+
+    for (raw, expected) in tests {
+        let url = Url::parse_url(raw)?;
+        assert_eq!(srv_name(&url).as_deref(), expected, "{}", raw);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_parse_resolv_conf() {
+    let resolv_conf = "# generated\nsearch example.com\nnameserver 10.0.0.53\nnameserver 8.8.8.8\n";
+    assert_eq!(
+        parse_resolv_conf(resolv_conf),
+        Some("10.0.0.53:53".parse().unwrap())
+    );
+    assert_eq!(parse_resolv_conf("search example.com\n"), None);
+}
+
+#[test]
+fn test_order_srv_records() {
+    let records = vec![
+        srv_record(20, 0, 3478, "backup.example.com"),
+        srv_record(10, 1, 3478, "b.example.com"),
+        srv_record(10, 3, 3478, "a.example.com"),
+        srv_record(10, 0, 3478, "c.example.com"),
+    ];
+
+    let mut firsts = HashMap::new();
+    for _ in 0..400 {
+        let ordered = order_srv_records(records.clone());
+        assert_eq!(ordered.len(), records.len());
+        assert!(
+            ordered
+                .windows(2)
+                .all(|pair| pair[0].priority <= pair[1].priority),
+            "lower priorities must be tried first"
+        );
+        assert_eq!(ordered[3].target, "backup.example.com");
+        *firsts.entry(ordered[0].target.clone()).or_insert(0) += 1;
+    }
+
+    // weights 3 and 1 out of 4, with a zero weight only picked on a zero draw
+    let a = firsts.get("a.example.com").copied().unwrap_or(0);
+    let b = firsts.get("b.example.com").copied().unwrap_or(0);
+    assert!(a > b, "heavier records should be tried first more often");
+    assert!(b > 0, "lighter records should get a chance too");
+}
+
+#[tokio::test]
+async fn test_lookup_srv() -> Result<(), Error> {
+    let v = build_simple_vnet(nat::NatType::default(), nat::NatType::default()).await?;
+    let mut zone = HashMap::new();
+    zone.insert(
+        "_turn._udp.example.com".to_owned(),
+        vec![
+            srv_record(10, 5, 3478, "turn1.example.com"),
+            srv_record(20, 0, 3479, "turn2.example.com"),
+        ],
+    );
+    let mut queries_rx = start_dns_server(&v.net1, zone).await?;
+
+    let records = lookup_srv(
+        &v.net0,
+        dns_server_addr(),
+        "_turn._udp.example.com",
+        Duration::from_secs(1),
+    )
+    .await?;
+    assert_eq!(
+        records,
+        vec![
+            srv_record(10, 5, 3478, "turn1.example.com"),
+            srv_record(20, 0, 3479, "turn2.example.com"),
+        ]
+    );
+    assert_eq!(
+        queries_rx.recv().await.as_deref(),
+        Some("_turn._udp.example.com.")
+    );
+
+    let records = lookup_srv(
+        &v.net0,
+        dns_server_addr(),
+        "_stun._udp.example.com",
+        Duration::from_secs(1),
+    )
+    .await?;
+    assert!(records.is_empty(), "a missing name has no records");
+
+    let result = lookup_srv(
+        &v.net0,
+        "192.168.0.3:53".parse().unwrap(),
+        "_turn._udp.example.com",
+        Duration::from_millis(200),
+    )
+    .await;
+    assert_eq!(result.unwrap_err(), *ERR_DNS_SRV_TIMEOUT);
+
+    v.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_resolve_url_targets() -> Result<(), Error> {
+    let v = build_simple_vnet(nat::NatType::default(), nat::NatType::default()).await?;
+    let mut zone = HashMap::new();
+    zone.insert(
+        "_stun._udp.example.com".to_owned(),
+        vec![srv_record(10, 0, 3479, "stun.example.com")],
+    );
+    zone.insert(
+        "_stun._udp.gone.example.com".to_owned(),
+        vec![srv_record(0, 0, 0, "")],
+    );
+    let _queries_rx = start_dns_server(&v.net1, zone).await?;
+    let config = DnsSrvConfig {
+        server: Some(dns_server_addr()),
+        timeout: Some(Duration::from_secs(1)),
+        ..Default::default()
+    };
+
+    let url = Url::parse_url("stun:example.com")?;
+    assert_eq!(
+        resolve_url_targets(&v.net0, &config, &url).await,
+        vec![("stun.example.com".to_owned(), 3479)]
+    );
+
+    let url = Url::parse_url("stun:example.com:3478")?;
+    assert_eq!(
+        resolve_url_targets(&v.net0, &config, &url).await,
+        vec![("example.com".to_owned(), 3478)],
+        "an explicit port skips the lookup"
+    );
+
+    let url = Url::parse_url("stun:other.example.com")?;
+    assert_eq!(
+        resolve_url_targets(&v.net0, &config, &url).await,
+        vec![("other.example.com".to_owned(), 3478)],
+        "names without records fall back to the default port"
+    );
+
+    let url = Url::parse_url("stun:gone.example.com")?;
+    assert!(
+        resolve_url_targets(&v.net0, &config, &url).await.is_empty(),
+        "a \".\" target means there is no service"
+    );
+
+    let disabled = DnsSrvConfig {
+        disabled: true,
+        ..config
+    };
+    let url = Url::parse_url("stun:example.com")?;
+    assert_eq!(
+        resolve_url_targets(&v.net0, &disabled, &url).await,
+        vec![("example.com".to_owned(), 3478)]
+    );
+
+    v.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_gather_srflx_from_srv_targets() -> Result<(), Error> {
+    let v = build_simple_vnet(nat::NatType::default(), nat::NatType::default()).await?;
+    {
+        let mut w = v.wan.lock().await;
+        w.add_host(
+            "stun.example.com".to_owned(),
+            VNET_STUN_SERVER_IP.to_owned(),
+        )
+        .await?;
+    }
+    // the first target doesn't resolve, the gatherer must move on to the next one
+    let mut zone = HashMap::new();
+    zone.insert(
+        "_stun._udp.example.com".to_owned(),
+        vec![
+            srv_record(10, 0, VNET_STUN_SERVER_PORT, "missing.example.com"),
+            srv_record(20, 0, VNET_STUN_SERVER_PORT, "stun.example.com"),
+        ],
+    );
+    let mut queries_rx = start_dns_server(&v.net1, zone).await?;
+
+    let a = Agent::new(AgentConfig {
+        urls: vec![Url::parse_url("stun:example.com")?],
+        network_types: vec![NetworkType::Udp4],
+        candidate_types: vec![CandidateType::ServerReflexive],
+        dns_srv: DnsSrvConfig {
+            server: Some(dns_server_addr()),
+            ..Default::default()
+        },
+        net: Some(Arc::clone(&v.net0)),
+        ..Default::default()
+    })
+    .await?;
+
+    let (done_tx, mut done_rx) = mpsc::channel::<()>(1);
+    let done_tx = Arc::new(Mutex::new(Some(done_tx)));
+    a.on_candidate(Box::new(
+        move |c: Option<Arc<dyn Candidate + Send + Sync>>| {
+            let done_tx_clone = Arc::clone(&done_tx);
+            Box::pin(async move {
+                if c.is_none() {
+                    let mut tx = done_tx_clone.lock().await;
+                    tx.take();
+                }
+            })
+        },
+    ))
+    .await;
+
+    a.gather_candidates().await?;
+    let _ = done_rx.recv().await;
+
+    assert_eq!(
+        queries_rx.recv().await.as_deref(),
+        Some("_stun._udp.example.com.")
+    );
+    let candidates = a.get_local_candidates().await?;
+    assert_eq!(candidates.len(), 1);
+    assert_eq!(
+        candidates[0].candidate_type(),
+        CandidateType::ServerReflexive
+    );
+
+    a.close().await?;
+    v.close().await?;
+
+    Ok(())
+}
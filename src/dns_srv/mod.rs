@@ -0,0 +1,255 @@
+#[cfg(test)]
+mod dns_srv_test;
+
+use crate::errors::*;
+use crate::url::*;
+
+use mdns::message::header::*;
+use mdns::message::name::*;
+use mdns::message::parser::*;
+use mdns::message::question::*;
+use mdns::message::*;
+use rand::{thread_rng, Rng};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use util::{vnet::net::*, Error};
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::time::{Duration, Instant};
+
+/// How long SRV lookups wait for the DNS server by default.
+pub const DEFAULT_DNS_SRV_TIMEOUT: Duration = Duration::from_secs(2);
+
+const DNS_PORT: u16 = 53;
+const RETRANSMISSION_INTERVAL: Duration = Duration::from_millis(500);
+const MAX_MESSAGE_SIZE: usize = 1500;
+
+/// How the agent looks up the DNS SRV records of STUN and TURN URLs without a port
+/// (RFC 5389 section 9, RFC 5928 section 3).
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(default))]
+pub struct DnsSrvConfig {
+    /// Uses the host and default port of the URLs as they are.
+    pub disabled: bool,
+    /// The DNS server to query, the first name server of `/etc/resolv.conf` by default.
+    pub server: Option<SocketAddr>,
+    /// How long to wait for the DNS server, `DEFAULT_DNS_SRV_TIMEOUT` by default.
+    pub timeout: Option<Duration>,
+}
+
+/// An SRV record (RFC 2782).
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SrvRecord {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    /// The host name of the target, without the trailing dot. It is empty when the record says
+    /// the service isn't available at the domain.
+    pub target: String,
+}
+
+/// Returns the name of the SRV records to look up for `url`, which is `None` when the URL has an
+/// explicit port or an IP address.
+#[must_use]
+pub fn srv_name(url: &Url) -> Option<String> {
+    if url.explicit_port
+        || url.scheme == SchemeType::Unknown
+        || url.proto == ProtoType::Unknown
+        || url.host.parse::<IpAddr>().is_ok()
+    {
+        return None;
+    }
+    Some(format!(
+        "_{}._{}.{}",
+        url.scheme,
+        url.proto,
+        url.host.trim_end_matches('.')
+    ))
+}
+
+/// Asks the DNS server at `server` for the SRV records of `name`, retransmitting the query until
+/// it is answered or `timeout` passes. A name that doesn't exist has no records.
+pub async fn lookup_srv(
+    net: &Arc<Net>,
+    server: SocketAddr,
+    name: &str,
+    timeout: Duration,
+) -> Result<Vec<SrvRecord>, Error> {
+    let id: u16 = thread_rng().gen();
+    // packed right away, the message isn't Send
+    let raw_query = Message {
+        header: Header {
+            id,
+            recursion_desired: true,
+            ..Header::default()
+        },
+        questions: vec![Question {
+            name: Name::new(&format!("{}.", name.trim_end_matches('.')))?,
+            typ: DnsType::Srv,
+            class: DNSCLASS_INET,
+        }],
+        ..Message::default()
+    }
+    .pack()?;
+
+    let local_ip = if server.is_ipv4() {
+        IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+    } else {
+        IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+    };
+    let conn = net.bind(SocketAddr::new(local_ip, 0)).await?;
+
+    let deadline = Instant::now() + timeout;
+    let mut buf = vec![0u8; MAX_MESSAGE_SIZE];
+    while Instant::now() < deadline {
+        conn.send_to(&raw_query, server).await?;
+
+        let retransmit_at = (Instant::now() + RETRANSMISSION_INTERVAL).min(deadline);
+        while let Ok(result) =
+            tokio::time::timeout_at(retransmit_at, conn.recv_from(&mut buf)).await
+        {
+            let (n, from) = result?;
+            if from != server {
+                continue;
+            }
+            let mut p = Parser::default();
+            let header = match p.start(&buf[..n]) {
+                Ok(header) if header.response && header.id == id => header,
+                _ => continue,
+            };
+            return match header.rcode {
+                RCode::Success => parse_srv_records(&mut p),
+                RCode::NameError => Ok(vec![]),
+                _ => Err(ERR_DNS_SRV_FAILED.to_owned()),
+            };
+        }
+    }
+
+    Err(ERR_DNS_SRV_TIMEOUT.to_owned())
+}
+
+fn parse_srv_records(p: &mut Parser<'_>) -> Result<Vec<SrvRecord>, Error> {
+    p.skip_all_questions()?;
+
+    let mut records = vec![];
+    while let Ok(header) = p.answer_header() {
+        if header.class != DNSCLASS_INET || header.typ != DnsType::Srv {
+            p.skip_answer()?;
+            continue;
+        }
+
+        // the bodies are opaque, but SRV records pack to their wire format with the target
+        // uncompressed
+        let raw = p.resource_body()?.pack(vec![], &mut None, 0)?;
+        if raw.len() < 7 {
+            continue;
+        }
+        let mut target = Name::default();
+        target.unpack(&raw, 6)?;
+        records.push(SrvRecord {
+            priority: u16::from_be_bytes([raw[0], raw[1]]),
+            weight: u16::from_be_bytes([raw[2], raw[3]]),
+            port: u16::from_be_bytes([raw[4], raw[5]]),
+            target: target.data.trim_end_matches('.').to_owned(),
+        });
+    }
+
+    Ok(records)
+}
+
+/// Orders SRV records the way RFC 2782 says to try them: by priority, and at random weighted
+/// by their weights within the same priority.
+#[must_use]
+pub fn order_srv_records(mut records: Vec<SrvRecord>) -> Vec<SrvRecord> {
+    // zero weights go first, so they only win when the draw is zero
+    records.sort_by_key(|record| (record.priority, record.weight != 0));
+
+    let mut rng = thread_rng();
+    let mut ordered = Vec::with_capacity(records.len());
+    while !records.is_empty() {
+        let priority = records[0].priority;
+        let group_len = records
+            .iter()
+            .take_while(|record| record.priority == priority)
+            .count();
+        let mut group: Vec<SrvRecord> = records.drain(..group_len).collect();
+
+        while !group.is_empty() {
+            let total: u32 = group.iter().map(|record| u32::from(record.weight)).sum();
+            let draw = rng.gen_range(0..=total);
+            let mut running_sum = 0;
+            let index = group
+                .iter()
+                .position(|record| {
+                    running_sum += u32::from(record.weight);
+                    running_sum >= draw
+                })
+                .unwrap_or(0);
+            ordered.push(group.remove(index));
+        }
+    }
+
+    ordered
+}
+
+/// Returns the hosts and ports to try for `url`, in order. They come from its SRV records when it
+/// has some, and are its own host and port otherwise.
+pub(crate) async fn resolve_url_targets(
+    net: &Arc<Net>,
+    config: &DnsSrvConfig,
+    url: &Url,
+) -> Vec<(String, u16)> {
+    let fallback = vec![(url.host.clone(), url.port)];
+    if config.disabled {
+        return fallback;
+    }
+    let name = match srv_name(url) {
+        Some(name) => name,
+        None => return fallback,
+    };
+    let server = if let Some(server) = config.server.or_else(default_dns_server) {
+        server
+    } else {
+        log::debug!("Not looking up {}: {}", name, *ERR_NO_DNS_SERVER);
+        return fallback;
+    };
+
+    let timeout = config.timeout.unwrap_or(DEFAULT_DNS_SRV_TIMEOUT);
+    let records = match lookup_srv(net, server, &name, timeout).await {
+        Ok(records) if !records.is_empty() => records,
+        Ok(_) => return fallback,
+        Err(err) => {
+            log::debug!("Failed to look up {}: {}", name, err);
+            return fallback;
+        }
+    };
+
+    if records.len() == 1 && records[0].target.is_empty() {
+        log::warn!("{} is not available at {}", url.scheme, url.host);
+        return vec![];
+    }
+
+    order_srv_records(records)
+        .into_iter()
+        .filter(|record| !record.target.is_empty())
+        .map(|record| (record.target, record.port))
+        .collect()
+}
+
+/// Returns the first name server of `/etc/resolv.conf`.
+pub(crate) fn default_dns_server() -> Option<SocketAddr> {
+    let resolv_conf = std::fs::read_to_string("/etc/resolv.conf").ok()?;
+    parse_resolv_conf(&resolv_conf)
+}
+
+fn parse_resolv_conf(resolv_conf: &str) -> Option<SocketAddr> {
+    resolv_conf.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        if fields.next() != Some("nameserver") {
+            return None;
+        }
+        let ip: IpAddr = fields.next()?.parse().ok()?;
+        Some(SocketAddr::new(ip, DNS_PORT))
+    })
+}
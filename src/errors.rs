@@ -78,6 +78,15 @@ lazy_static! {
     /// IPNotFound in NAT1To1IPMapping.
     pub static ref ERR_EXTERNAL_MAPPED_IP_NOT_FOUND:Error = Error::new("external mapped IP not found".to_owned());
 
+    /// Indicates that a local port is outside the port range of its 1:1 NAT mapping rule.
+    pub static ref ERR_EXTERNAL_MAPPED_PORT_NOT_FOUND:Error = Error::new("local port is not forwarded by the 1:1 NAT mapping".to_owned());
+
+    /// Indicates an empty, reversed or overflowing port range in a 1:1 NAT mapping rule.
+    pub static ref ERR_INVALID_NAT_PORT_RANGE:Error = Error::new("invalid 1:1 NAT port range".to_owned());
+
+    /// Indicates that both `nat_mapping` and `nat_1to1_ips` are configured.
+    pub static ref ERR_NAT_MAPPING_WITH_NAT_1TO1_IPS:Error = Error::new("nat_mapping cannot be used along with nat_1to1_ips".to_owned());
+
     /// Indicates that the mDNS gathering cannot be used along with 1:1 NAT IP mapping for host
     /// candidate.
     pub static ref ERR_MULTICAST_DNS_WITH_NAT_1TO1_IP_MAPPING:Error = Error::new("mDNS gathering cannot be used with 1:1 NAT IP mapping for host candidate".to_owned());
@@ -104,7 +113,11 @@ lazy_static! {
 
     /// Indicates we already have the connection with same remote addr.
     pub static ref ERR_TCP_REMOTE_ADDR_ALREADY_EXISTS:Error = Error::new("conn with same remote addr already exists".to_owned());
+}
 
+// lazy_static! recurses once per item, a single block would reach the default recursion limit
+#[rustfmt::skip]
+lazy_static! {
     pub static ref ERR_SEND_PACKET                      :Error = Error::new("failed to send packet".to_owned());
     pub static ref ERR_ATTRIBUTE_TOO_SHORT_ICE_CANDIDATE:Error = Error::new("attribute not long enough to be ICE candidate".to_owned());
     pub static ref ERR_PARSE_COMPONENT                  :Error = Error::new("could not parse component".to_owned());
@@ -112,6 +125,13 @@ lazy_static! {
     pub static ref ERR_PARSE_PORT                       :Error = Error::new("could not parse port".to_owned());
     pub static ref ERR_PARSE_RELATED_ADDR               :Error = Error::new("could not parse related addresses".to_owned());
     pub static ref ERR_PARSE_TYPE                       :Error = Error::new("could not parse type".to_owned());
+    pub static ref ERR_PARSE_FOUNDATION                 :Error = Error::new("could not parse foundation".to_owned());
+    pub static ref ERR_PARSE_TRANSPORT                  :Error = Error::new("could not parse transport".to_owned());
+    pub static ref ERR_PARSE_TCP_TYPE                   :Error = Error::new("could not parse tcptype".to_owned());
+    pub static ref ERR_PARSE_GENERATION                 :Error = Error::new("could not parse generation".to_owned());
+    pub static ref ERR_PARSE_NETWORK_ID                 :Error = Error::new("could not parse network-id".to_owned());
+    pub static ref ERR_PARSE_NETWORK_COST               :Error = Error::new("could not parse network-cost".to_owned());
+    pub static ref ERR_PARSE_EXTENSION                  :Error = Error::new("extension attribute without a value".to_owned());
     pub static ref ERR_UNKNOWN_CANDIDATE_TYPE           :Error = Error::new("unknown candidate type".to_owned());
     pub static ref ERR_GET_XOR_MAPPED_ADDR_RESPONSE     :Error = Error::new("failed to get XOR-MAPPED-ADDRESS response".to_owned());
     pub static ref ERR_CONNECTION_ADDR_ALREADY_EXIST    :Error = Error::new("connection with same remote address already exists".to_owned());
@@ -127,4 +147,33 @@ lazy_static! {
     pub static ref ERR_ICE_WRITE_STUN_MESSAGE           :Error = Error::new("the ICE conn can't write STUN messages".to_owned());
     pub static ref ERR_INVALID_URL                      :Error = Error::new("invalid url".to_owned());
     pub static ref ERR_URL_PARSE_ERROR                  :Error = Error::new("relative URL without a base".to_owned());
+    pub static ref ERR_SHORT_BUFFER                     :Error = Error::new("buffer is too short for the message".to_owned());
+    pub static ref ERR_CLOSED_STREAM                    :Error = Error::new("stream closed by the remote end".to_owned());
+    pub static ref ERR_TCP_NOT_SUPPORTED_BY_VNET        :Error = Error::new("vnet does not support TCP".to_owned());
+    pub static ref ERR_MULTICAST_DNS_CLOSED             :Error = Error::new("mDNS connection is closed".to_owned());
+    pub static ref ERR_MULTICAST_DNS_JOIN_FAILED        :Error = Error::new("failed to join the mDNS multicast group on any interface".to_owned());
+    pub static ref ERR_MULTICAST_DNS_QUERY_TIMEOUT      :Error = Error::new("mDNS query timed out".to_owned());
+    pub static ref ERR_MULTICAST_DNS_QUERY_CANCELED     :Error = Error::new("mDNS query was canceled".to_owned());
+    pub static ref ERR_DNS_RESOLVE_TIMEOUT              :Error = Error::new("DNS resolution of the candidate address timed out".to_owned());
+    pub static ref ERR_DNS_RESOLVE_CANCELED             :Error = Error::new("DNS resolution of the candidate address was canceled".to_owned());
+    pub static ref ERR_DNS_NO_ADDRESS                   :Error = Error::new("candidate host name did not resolve to any address".to_owned());
+    pub static ref ERR_PORT_MAPPING_TIMEOUT             :Error = Error::new("gateway did not answer the port mapping request".to_owned());
+    pub static ref ERR_PORT_MAPPING_UNSUPPORTED_VERSION :Error = Error::new("gateway does not support the port mapping protocol version".to_owned());
+    pub static ref ERR_PORT_MAPPING_REJECTED            :Error = Error::new("gateway rejected the port mapping request".to_owned());
+    pub static ref ERR_PORT_MAPPING_MALFORMED_RESPONSE  :Error = Error::new("malformed port mapping response".to_owned());
+    pub static ref ERR_NO_DEFAULT_GATEWAY               :Error = Error::new("no default gateway to request port mappings from".to_owned());
+    pub static ref ERR_NAT_DISCOVERY_NO_RESPONSE        :Error = Error::new("STUN server did not answer the NAT discovery binding request".to_owned());
+    pub static ref ERR_NAT_DISCOVERY_NO_URL             :Error = Error::new("no STUN URL to discover the NAT behavior with".to_owned());
+    pub static ref ERR_DNS_SRV_TIMEOUT                  :Error = Error::new("DNS server did not answer the SRV query".to_owned());
+    pub static ref ERR_DNS_SRV_FAILED                   :Error = Error::new("DNS server failed the SRV query".to_owned());
+    pub static ref ERR_NO_DNS_SERVER                    :Error = Error::new("no DNS server to look up SRV records with".to_owned());
+    pub static ref ERR_PROXY_REJECTED                   :Error = Error::new("proxy refused to connect".to_owned());
+    pub static ref ERR_PROXY_AUTH_FAILED                :Error = Error::new("proxy authentication failed".to_owned());
+    pub static ref ERR_PROXY_MALFORMED_RESPONSE         :Error = Error::new("malformed response from the proxy".to_owned());
+    pub static ref ERR_PROXY_CREDENTIALS_TOO_LONG       :Error = Error::new("proxy username or password is longer than 255 bytes".to_owned());
+    pub static ref ERR_MISSING_ICE_UFRAG                :Error = Error::new("missing ice-ufrag attribute".to_owned());
+    pub static ref ERR_MISSING_ICE_PWD                  :Error = Error::new("missing ice-pwd attribute".to_owned());
+    pub static ref ERR_INVALID_ICE_UFRAG                :Error = Error::new("ice-ufrag must be 4 to 256 ice-chars".to_owned());
+    pub static ref ERR_INVALID_ICE_PWD                  :Error = Error::new("ice-pwd must be 22 to 256 ice-chars".to_owned());
+    pub static ref ERR_LITE_AGENT_CONTROLLING           :Error = Error::new("a lite agent can't control a full one".to_owned());
 }
@@ -249,3 +249,200 @@ fn test_external_ip_mapper_find_external_ip_with_empty_map() -> Result<(), Error
 
     Ok(())
 }
+
+#[test]
+fn test_external_ip_mapper_from_nat_1to1_ips() -> Result<(), Error> {
+    let config = NatMappingConfig::from_nat_1to1_ips(
+        CandidateType::ServerReflexive,
+        &["1.2.3.4/10.0.0.1".to_owned(), "2200::1".to_owned()],
+    )?;
+    assert_eq!(config.candidates, NatMappingCandidates::ServerReflexive);
+    assert_eq!(
+        config.rules,
+        vec![
+            NatMappingRule {
+                external_ip: "1.2.3.4".parse().unwrap(),
+                local_ip: Some("10.0.0.1".parse().unwrap()),
+                ..NatMappingRule::default()
+            },
+            NatMappingRule {
+                external_ip: "2200::1".parse().unwrap(),
+                ..NatMappingRule::default()
+            },
+        ]
+    );
+
+    let result = NatMappingConfig::from_nat_1to1_ips(CandidateType::Relay, &[]);
+    assert!(result.is_err(), "should fail");
+
+    Ok(())
+}
+
+#[test]
+fn test_external_ip_mapper_port_range() -> Result<(), Error> {
+    let m = ExternalIpMapper::from_config(&NatMappingConfig {
+        rules: vec![NatMappingRule {
+            external_ip: "1.2.3.4".parse().unwrap(),
+            local_ip: Some("10.0.0.1".parse().unwrap()),
+            port_range: Some(NatPortRange {
+                local_min: 5000,
+                local_max: 5099,
+                external_min: 40000,
+            }),
+            ..NatMappingRule::default()
+        }],
+        ..NatMappingConfig::default()
+    })?
+    .unwrap();
+
+    let ext_addr = m.find_external_addr("10.0.0.1:5000".parse().unwrap(), "")?;
+    assert_eq!(ext_addr, "1.2.3.4:40000".parse().unwrap());
+    let ext_addr = m.find_external_addr("10.0.0.1:5099".parse().unwrap(), "")?;
+    assert_eq!(ext_addr, "1.2.3.4:40099".parse().unwrap());
+
+    let result = m.find_external_addr("10.0.0.1:5100".parse().unwrap(), "");
+    assert_eq!(result, Err(ERR_EXTERNAL_MAPPED_PORT_NOT_FOUND.to_owned()));
+    assert_eq!(
+        m.local_port_range("10.0.0.1".parse().unwrap(), ""),
+        Some((5000, 5099))
+    );
+    assert_eq!(m.local_port_range("10.0.0.2".parse().unwrap(), ""), None);
+
+    // the external ports can't go past 65535
+    for port_range in [
+        NatPortRange {
+            local_min: 5000,
+            local_max: 5099,
+            external_min: 65500,
+        },
+        NatPortRange {
+            local_min: 5099,
+            local_max: 5000,
+            external_min: 40000,
+        },
+    ] {
+        let result = ExternalIpMapper::from_config(&NatMappingConfig {
+            rules: vec![NatMappingRule {
+                external_ip: "1.2.3.4".parse().unwrap(),
+                port_range: Some(port_range),
+                ..NatMappingRule::default()
+            }],
+            ..NatMappingConfig::default()
+        });
+        assert!(result.is_err(), "should fail");
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_external_ip_mapper_interface_rules() -> Result<(), Error> {
+    let m = ExternalIpMapper::from_config(&NatMappingConfig {
+        rules: vec![
+            NatMappingRule {
+                external_ip: "1.2.3.4".parse().unwrap(),
+                interface: Some("eth0".to_owned()),
+                ..NatMappingRule::default()
+            },
+            NatMappingRule {
+                external_ip: "1.2.3.5".parse().unwrap(),
+                interface: Some("eth1".to_owned()),
+                ..NatMappingRule::default()
+            },
+            NatMappingRule {
+                external_ip: "1.2.3.6".parse().unwrap(),
+                local_ip: Some("10.0.1.1".parse().unwrap()),
+                ..NatMappingRule::default()
+            },
+        ],
+        ..NatMappingConfig::default()
+    })?
+    .unwrap();
+
+    let ext_addr = m.find_external_addr("10.0.0.1:5000".parse().unwrap(), "eth0")?;
+    assert_eq!(ext_addr, "1.2.3.4:5000".parse().unwrap());
+    let ext_addr = m.find_external_addr("10.0.0.1:5000".parse().unwrap(), "eth1")?;
+    assert_eq!(ext_addr, "1.2.3.5:5000".parse().unwrap());
+    // an explicit local IP wins over the interface
+    let ext_addr = m.find_external_addr("10.0.1.1:5000".parse().unwrap(), "eth1")?;
+    assert_eq!(ext_addr, "1.2.3.6:5000".parse().unwrap());
+
+    let result = m.find_external_addr("10.0.0.1:5000".parse().unwrap(), "eth2");
+    assert!(result.is_err(), "should fail");
+
+    Ok(())
+}
+
+#[test]
+fn test_external_ip_mapper_ipv6_prefix() -> Result<(), Error> {
+    let m = ExternalIpMapper::from_config(&NatMappingConfig {
+        rules: vec![NatMappingRule {
+            external_ip: "2001:db8:1::".parse().unwrap(),
+            local_ip: Some("fd01:203:405::".parse().unwrap()),
+            prefix_len: Some(48),
+            ..NatMappingRule::default()
+        }],
+        ..NatMappingConfig::default()
+    })?
+    .unwrap();
+
+    let ext_ip = m.find_external_ip("fd01:203:405:1::1234")?;
+    assert_eq!(ext_ip, "2001:db8:1:1::1234".parse::<IpAddr>().unwrap());
+
+    let result = m.find_external_ip("fd01:203:406::1");
+    assert!(result.is_err(), "should fail");
+
+    // prefixes are IPv6 only, and need a local prefix to translate
+    for rule in [
+        NatMappingRule {
+            external_ip: "1.2.3.0".parse().unwrap(),
+            local_ip: Some("10.0.0.0".parse().unwrap()),
+            prefix_len: Some(24),
+            ..NatMappingRule::default()
+        },
+        NatMappingRule {
+            external_ip: "2001:db8:1::".parse().unwrap(),
+            prefix_len: Some(48),
+            ..NatMappingRule::default()
+        },
+    ] {
+        let result = ExternalIpMapper::from_config(&NatMappingConfig {
+            rules: vec![rule],
+            ..NatMappingConfig::default()
+        });
+        assert!(result.is_err(), "should fail");
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_external_ip_mapper_rule_index() -> Result<(), Error> {
+    let m = ExternalIpMapper::from_config(&NatMappingConfig {
+        rules: vec![
+            NatMappingRule {
+                external_ip: "2200::1".parse().unwrap(),
+                ..NatMappingRule::default()
+            },
+            NatMappingRule {
+                external_ip: "1.2.3.4".parse().unwrap(),
+                local_ip: Some("10.0.0.1".parse().unwrap()),
+                ..NatMappingRule::default()
+            },
+            NatMappingRule {
+                external_ip: "1.2.3.5".parse().unwrap(),
+                local_ip: Some("10.0.0.2".parse().unwrap()),
+                ..NatMappingRule::default()
+            },
+        ],
+        ..NatMappingConfig::default()
+    })?
+    .unwrap();
+
+    assert_eq!(m.rule_index("fe80::1".parse().unwrap(), ""), Some(0));
+    assert_eq!(m.rule_index("10.0.0.1".parse().unwrap(), ""), Some(1));
+    assert_eq!(m.rule_index("10.0.0.2".parse().unwrap(), ""), Some(2));
+    assert_eq!(m.rule_index("10.0.0.3".parse().unwrap(), ""), None);
+
+    Ok(())
+}
@@ -4,10 +4,12 @@ mod external_ip_mapper_test;
 use crate::candidate::*;
 use crate::errors::*;
 
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 use util::Error;
 
 use std::collections::HashMap;
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
 pub(crate) fn validate_ip_string(ip_str: &str) -> Result<IpAddr, Error> {
     match ip_str.parse() {
@@ -16,25 +18,256 @@ pub(crate) fn validate_ip_string(ip_str: &str) -> Result<IpAddr, Error> {
     }
 }
 
+/// Which candidates carry the external addresses of a [`NatMappingConfig`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "kebab-case")
+)]
+pub enum NatMappingCandidates {
+    /// Host candidates are advertised with the external address instead of the local one.
+    Host,
+    /// A server reflexive candidate with the external address is gathered alongside the STUN
+    /// ones, from a socket of its own bound to the unspecified address.
+    ServerReflexive,
+    /// Host candidates keep their local address, and each gets a server reflexive sibling with
+    /// the external address, sharing its socket.
+    HostAndServerReflexive,
+}
+
+impl Default for NatMappingCandidates {
+    fn default() -> Self {
+        Self::Host
+    }
+}
+
+/// Forwards the local ports `local_min..=local_max` from the external ports starting at
+/// `external_min`, keeping their offset.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct NatPortRange {
+    pub local_min: u16,
+    pub local_max: u16,
+    pub external_min: u16,
+}
+
+impl NatPortRange {
+    const fn external_port(self, local_port: u16) -> Option<u16> {
+        if self.local_min <= local_port && local_port <= self.local_max {
+            self.external_min.checked_add(local_port - self.local_min)
+        } else {
+            None
+        }
+    }
+}
+
+/// One translation of a 1:1 NAT.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(default))]
+pub struct NatMappingRule {
+    /// The address the peers reach the local address at. With `prefix_len`, the external
+    /// IPv6 prefix.
+    pub external_ip: IpAddr,
+    /// The translated local address. Without it, the rule applies to every local address of the
+    /// family (of `interface` when given), and it can't be combined with other rules of the
+    /// family that lack an interface.
+    pub local_ip: Option<IpAddr>,
+    /// Translates the IPv6 prefix of this length of `local_ip` to the one of `external_ip`,
+    /// keeping the interface identifier.
+    pub prefix_len: Option<u8>,
+    /// Without it the external port is the local one.
+    pub port_range: Option<NatPortRange>,
+    /// Only applies to the addresses of the interface with this name.
+    pub interface: Option<String>,
+}
+
+impl Default for NatMappingRule {
+    fn default() -> Self {
+        Self {
+            external_ip: Ipv4Addr::UNSPECIFIED.into(),
+            local_ip: None,
+            prefix_len: None,
+            port_range: None,
+            interface: None,
+        }
+    }
+}
+
+impl NatMappingRule {
+    fn validate(&self) -> Result<(), Error> {
+        if self.external_ip.is_unspecified() {
+            return Err(ERR_INVALID_NAT_1TO1_IP_MAPPING.to_owned());
+        }
+        if let Some(local_ip) = self.local_ip {
+            if local_ip.is_ipv4() != self.external_ip.is_ipv4() {
+                return Err(ERR_INVALID_NAT_1TO1_IP_MAPPING.to_owned());
+            }
+        }
+        if let Some(prefix_len) = self.prefix_len {
+            if self.external_ip.is_ipv4()
+                || self.local_ip.is_none()
+                || prefix_len == 0
+                || prefix_len > 128
+            {
+                return Err(ERR_INVALID_NAT_1TO1_IP_MAPPING.to_owned());
+            }
+        }
+        if let Some(port_range) = &self.port_range {
+            if port_range.local_min == 0
+                || port_range.local_min > port_range.local_max
+                || port_range.external_min == 0
+                || port_range
+                    .external_min
+                    .checked_add(port_range.local_max - port_range.local_min)
+                    .is_none()
+            {
+                return Err(ERR_INVALID_NAT_PORT_RANGE.to_owned());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn matches_interface(&self, interface: &str) -> bool {
+        self.interface
+            .as_ref()
+            .map_or(true, |rule_interface| rule_interface == interface)
+    }
+
+    fn matches_prefix(&self, ip: IpAddr) -> bool {
+        match (self.local_ip, self.prefix_len, ip) {
+            (Some(IpAddr::V6(local_ip)), Some(prefix_len), IpAddr::V6(ip)) => {
+                let mask = prefix_mask(prefix_len);
+                u128::from(local_ip) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+
+    fn external_ip_for(&self, loc_ip: IpAddr) -> IpAddr {
+        match (self.prefix_len, self.external_ip, loc_ip) {
+            (Some(prefix_len), IpAddr::V6(external_ip), IpAddr::V6(loc_ip)) => {
+                let mask = prefix_mask(prefix_len);
+                IpAddr::V6(Ipv6Addr::from(
+                    (u128::from(external_ip) & mask) | (u128::from(loc_ip) & !mask),
+                ))
+            }
+            _ => self.external_ip,
+        }
+    }
+
+    fn external_addr_for(&self, local_addr: SocketAddr) -> Result<SocketAddr, Error> {
+        let port = match &self.port_range {
+            Some(port_range) => port_range
+                .external_port(local_addr.port())
+                .ok_or_else(|| ERR_EXTERNAL_MAPPED_PORT_NOT_FOUND.to_owned())?,
+            None => local_addr.port(),
+        };
+
+        Ok(SocketAddr::new(self.external_ip_for(local_addr.ip()), port))
+    }
+}
+
+const fn prefix_mask(prefix_len: u8) -> u128 {
+    if prefix_len >= 128 {
+        u128::MAX
+    } else {
+        !(u128::MAX >> prefix_len)
+    }
+}
+
+/// The typed configuration of a 1:1 NAT in front of the agent, e.g. a cloud load balancer
+/// forwarding a public address to the private one of the host.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(default))]
+pub struct NatMappingConfig {
+    pub candidates: NatMappingCandidates,
+    pub rules: Vec<NatMappingRule>,
+}
+
+impl NatMappingConfig {
+    /// Converts the `ext` and `ext/local` strings of `AgentConfig::nat_1to1_ips`.
+    pub fn from_nat_1to1_ips(candidate_type: CandidateType, ips: &[String]) -> Result<Self, Error> {
+        let candidates = match candidate_type {
+            CandidateType::Unspecified | CandidateType::Host => NatMappingCandidates::Host,
+            CandidateType::ServerReflexive => NatMappingCandidates::ServerReflexive,
+            _ => return Err(ERR_UNSUPPORTED_NAT_1TO1_IP_CANDIDATE_TYPE.to_owned()),
+        };
+
+        let mut rules = vec![];
+        for ext_ip_str in ips {
+            let ip_pair: Vec<&str> = ext_ip_str.split('/').collect();
+            if ip_pair.is_empty() || ip_pair.len() > 2 {
+                return Err(ERR_INVALID_NAT_1TO1_IP_MAPPING.clone());
+            }
+
+            rules.push(NatMappingRule {
+                external_ip: validate_ip_string(ip_pair[0])?,
+                local_ip: match ip_pair.get(1) {
+                    Some(loc_ip_str) => Some(validate_ip_string(loc_ip_str)?),
+                    None => None,
+                },
+                ..NatMappingRule::default()
+            });
+        }
+
+        Ok(Self { candidates, rules })
+    }
+}
+
 /// Holds the mapping of local and external IP address for a particular IP family.
 #[derive(Default, PartialEq, Debug)]
 pub(crate) struct IpMapping {
-    ip_sole: Option<IpAddr>, // when non-nil, this is the sole external IP for one local IP assumed
-    ip_map: HashMap<String, IpAddr>, // local-to-external IP mapping (k: local, v: external)
+    ip_sole: Option<NatMappingRule>, // when set, this is the sole external IP for all local IPs
+    ip_map: HashMap<String, NatMappingRule>, // local-to-external IP mapping (k: local)
+    prefix_rules: Vec<NatMappingRule>, // longest prefix first
+    interface_map: HashMap<String, NatMappingRule>, // rules without a local IP (k: interface)
 }
 
 impl IpMapping {
-    pub(crate) fn set_sole_ip(&mut self, ip: IpAddr) -> Result<(), Error> {
-        if self.ip_sole.is_some() || !self.ip_map.is_empty() {
+    fn add_rule(&mut self, rule: NatMappingRule) -> Result<(), Error> {
+        match (&rule.local_ip, &rule.interface) {
+            (None, Some(interface)) => {
+                if self.interface_map.contains_key(interface) {
+                    return Err(ERR_INVALID_NAT_1TO1_IP_MAPPING.to_owned());
+                }
+                self.interface_map.insert(interface.clone(), rule);
+                Ok(())
+            }
+            (None, None) => self.set_sole_ip(rule),
+            (Some(loc_ip), _) => {
+                if self.ip_sole.is_some() {
+                    return Err(ERR_INVALID_NAT_1TO1_IP_MAPPING.to_owned());
+                }
+                if rule.prefix_len.is_some() {
+                    self.prefix_rules.push(rule);
+                    self.prefix_rules
+                        .sort_by_key(|rule| std::cmp::Reverse(rule.prefix_len));
+                    Ok(())
+                } else {
+                    let loc_ip = *loc_ip;
+                    self.add_ip_mapping(loc_ip, rule)
+                }
+            }
+        }
+    }
+
+    pub(crate) fn set_sole_ip(&mut self, rule: NatMappingRule) -> Result<(), Error> {
+        if self.ip_sole.is_some() || !self.ip_map.is_empty() || !self.prefix_rules.is_empty() {
             return Err(ERR_INVALID_NAT_1TO1_IP_MAPPING.to_owned());
         }
 
-        self.ip_sole = Some(ip);
+        self.ip_sole = Some(rule);
 
         Ok(())
     }
 
-    pub(crate) fn add_ip_mapping(&mut self, loc_ip: IpAddr, ext_ip: IpAddr) -> Result<(), Error> {
+    pub(crate) fn add_ip_mapping(
+        &mut self,
+        loc_ip: IpAddr,
+        rule: NatMappingRule,
+    ) -> Result<(), Error> {
         if self.ip_sole.is_some() {
             return Err(ERR_INVALID_NAT_1TO1_IP_MAPPING.to_owned());
         }
@@ -46,19 +279,30 @@ impl IpMapping {
             return Err(ERR_INVALID_NAT_1TO1_IP_MAPPING.to_owned());
         }
 
-        self.ip_map.insert(loc_ip_str, ext_ip);
+        self.ip_map.insert(loc_ip_str, rule);
 
         Ok(())
     }
 
-    pub(crate) fn find_external_ip(&self, loc_ip: IpAddr) -> Result<IpAddr, Error> {
-        if let Some(ip_sole) = &self.ip_sole {
-            return Ok(*ip_sole);
-        }
+    /// Returns the most specific rule for the local IP: an explicit local IP, then the longest
+    /// matching prefix, then the interface, then the sole external IP.
+    fn find_rule(&self, loc_ip: IpAddr, interface: &str) -> Option<&NatMappingRule> {
+        self.ip_map
+            .get(&loc_ip.to_string())
+            .filter(|rule| rule.matches_interface(interface))
+            .or_else(|| {
+                self.prefix_rules
+                    .iter()
+                    .find(|rule| rule.matches_interface(interface) && rule.matches_prefix(loc_ip))
+            })
+            .or_else(|| self.interface_map.get(interface))
+            .or(self.ip_sole.as_ref())
+    }
 
-        self.ip_map.get(&loc_ip.to_string()).map_or_else(
+    pub(crate) fn find_external_ip(&self, loc_ip: IpAddr) -> Result<IpAddr, Error> {
+        self.find_rule(loc_ip, "").map_or_else(
             || Err(ERR_EXTERNAL_MAPPED_IP_NOT_FOUND.to_owned()),
-            |ext_ip| Ok(*ext_ip),
+            |rule| Ok(rule.external_ip_for(loc_ip)),
         )
     }
 }
@@ -67,72 +311,96 @@ impl IpMapping {
 pub(crate) struct ExternalIpMapper {
     pub(crate) ipv4_mapping: IpMapping,
     pub(crate) ipv6_mapping: IpMapping,
+    /// The type of the candidates carrying the external addresses.
     pub(crate) candidate_type: CandidateType,
+    pub(crate) candidates: NatMappingCandidates,
+    /// The rules, in the configured order.
+    pub(crate) rules: Vec<NatMappingRule>,
 }
 
 impl ExternalIpMapper {
     pub(crate) fn new(
-        mut candidate_type: CandidateType,
+        candidate_type: CandidateType,
         ips: &[String],
     ) -> Result<Option<Self>, Error> {
         if ips.is_empty() {
             return Ok(None);
         }
-        if candidate_type == CandidateType::Unspecified {
-            candidate_type = CandidateType::Host; // defaults to host
-        } else if candidate_type != CandidateType::Host
-            && candidate_type != CandidateType::ServerReflexive
-        {
-            return Err(ERR_UNSUPPORTED_NAT_1TO1_IP_CANDIDATE_TYPE.to_owned());
+
+        Self::from_config(&NatMappingConfig::from_nat_1to1_ips(candidate_type, ips)?)
+    }
+
+    pub(crate) fn from_config(config: &NatMappingConfig) -> Result<Option<Self>, Error> {
+        if config.rules.is_empty() {
+            return Ok(None);
         }
 
         let mut m = Self {
             ipv4_mapping: IpMapping::default(),
             ipv6_mapping: IpMapping::default(),
-            candidate_type,
+            candidate_type: if config.candidates == NatMappingCandidates::Host {
+                CandidateType::Host
+            } else {
+                CandidateType::ServerReflexive
+            },
+            candidates: config.candidates,
+            rules: config.rules.clone(),
         };
 
-        for ext_ip_str in ips {
-            let ip_pair: Vec<&str> = ext_ip_str.split('/').collect();
-            if ip_pair.is_empty() || ip_pair.len() > 2 {
-                return Err(ERR_INVALID_NAT_1TO1_IP_MAPPING.clone());
-            }
-
-            let ext_ip = validate_ip_string(ip_pair[0])?;
-            if ip_pair.len() == 1 {
-                if ext_ip.is_ipv4() {
-                    m.ipv4_mapping.set_sole_ip(ext_ip)?;
-                } else {
-                    m.ipv6_mapping.set_sole_ip(ext_ip)?;
-                }
+        for rule in &config.rules {
+            rule.validate()?;
+            if rule.external_ip.is_ipv4() {
+                m.ipv4_mapping.add_rule(rule.clone())?;
             } else {
-                let loc_ip = validate_ip_string(ip_pair[1])?;
-                if ext_ip.is_ipv4() {
-                    if !loc_ip.is_ipv4() {
-                        return Err(ERR_INVALID_NAT_1TO1_IP_MAPPING.clone());
-                    }
-
-                    m.ipv4_mapping.add_ip_mapping(loc_ip, ext_ip)?;
-                } else {
-                    if loc_ip.is_ipv4() {
-                        return Err(ERR_INVALID_NAT_1TO1_IP_MAPPING.clone());
-                    }
-
-                    m.ipv6_mapping.add_ip_mapping(loc_ip, ext_ip)?;
-                }
+                m.ipv6_mapping.add_rule(rule.clone())?;
             }
         }
 
         Ok(Some(m))
     }
 
-    pub(crate) fn find_external_ip(&self, local_ip_str: &str) -> Result<IpAddr, Error> {
-        let loc_ip = validate_ip_string(local_ip_str)?;
-
+    const fn mapping(&self, loc_ip: IpAddr) -> &IpMapping {
         if loc_ip.is_ipv4() {
-            self.ipv4_mapping.find_external_ip(loc_ip)
+            &self.ipv4_mapping
         } else {
-            self.ipv6_mapping.find_external_ip(loc_ip)
+            &self.ipv6_mapping
         }
     }
+
+    pub(crate) fn find_external_ip(&self, local_ip_str: &str) -> Result<IpAddr, Error> {
+        let loc_ip = validate_ip_string(local_ip_str)?;
+
+        self.mapping(loc_ip).find_external_ip(loc_ip)
+    }
+
+    /// Returns the external address of a local address of the named interface, empty when it
+    /// isn't known.
+    pub(crate) fn find_external_addr(
+        &self,
+        local_addr: SocketAddr,
+        interface: &str,
+    ) -> Result<SocketAddr, Error> {
+        self.mapping(local_addr.ip())
+            .find_rule(local_addr.ip(), interface)
+            .map_or_else(
+                || Err(ERR_EXTERNAL_MAPPED_IP_NOT_FOUND.to_owned()),
+                |rule| rule.external_addr_for(local_addr),
+            )
+    }
+
+    /// Returns the position in the configuration of the rule that translates the local IP of
+    /// the named interface.
+    pub(crate) fn rule_index(&self, loc_ip: IpAddr, interface: &str) -> Option<usize> {
+        let rule = self.mapping(loc_ip).find_rule(loc_ip, interface)?;
+        self.rules.iter().position(|r| r == rule)
+    }
+
+    /// Returns the `(min, max)` local ports that are forwarded for the local IP, if the rule
+    /// translates ports.
+    pub(crate) fn local_port_range(&self, loc_ip: IpAddr, interface: &str) -> Option<(u16, u16)> {
+        self.mapping(loc_ip)
+            .find_rule(loc_ip, interface)
+            .and_then(|rule| rule.port_range)
+            .map(|port_range| (port_range.local_min, port_range.local_max))
+    }
 }
@@ -5,6 +5,8 @@
     allow(
         clippy::case_sensitive_file_extension_comparisons,
         clippy::let_underscore_drop,
+        // let-else needs Rust 1.65
+        clippy::manual_let_else,
         clippy::missing_errors_doc,
         clippy::missing_panics_doc,
         clippy::module_name_repetitions,
@@ -18,17 +20,30 @@
 #[macro_use]
 extern crate lazy_static;
 
+mod access_token_conn;
 pub mod agent;
+mod allocation_monitor;
 pub mod candidate;
 pub mod control;
+pub mod credential_provider;
+pub mod dns_srv;
 pub mod errors;
 pub mod external_ip_mapper;
 pub mod mdns;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod nat_discovery;
+pub mod network_policy;
 pub mod network_type;
+pub mod port_mapping;
 pub mod priority;
+pub mod proxy;
 mod rand;
+pub mod sdp;
+pub mod socket_factory;
 pub mod state;
 pub mod stats;
+mod stream_conn;
 pub mod tcp_type;
 pub mod url;
 pub mod use_candidate;
@@ -0,0 +1,572 @@
+use super::MulticastDnsConfig;
+use crate::errors::*;
+
+use mdns::message::header::*;
+use mdns::message::name::*;
+use mdns::message::parser::*;
+use mdns::message::question::*;
+use mdns::message::resource::{a::*, aaaa::*, *};
+use mdns::message::*;
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::{broadcast, mpsc, Mutex};
+use util::Error;
+
+/// The port of mDNS (RFC 6762).
+pub const MULTICAST_DNS_PORT: u16 = 5353;
+/// The IPv4 mDNS multicast group and port.
+pub const MULTICAST_DNS_DEST_ADDR_V4: &str = "224.0.0.251:5353";
+/// The IPv6 mDNS multicast group and port.
+pub const MULTICAST_DNS_DEST_ADDR_V6: &str = "[ff02::fb]:5353";
+
+const INBOUND_BUFFER_SIZE: usize = 1500;
+const QUERY_INTERVAL: Duration = Duration::from_secs(1);
+const RESPONSE_TTL: u32 = 120;
+pub const MAX_CACHED_NAMES: usize = 256;
+
+/// How long the read loop waits after a failed read, doubled for each failure in a row.
+const READ_ERROR_BACKOFF_MIN: Duration = Duration::from_millis(10);
+const READ_ERROR_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+/// The top bit of the class of questions asks for a unicast response, and the one of answers
+/// flushes the caches of the receivers (RFC 6762, sections 5.4 and 10.2).
+const CLASS_FLAG_MASK: u16 = 0x8000;
+
+/// Returns how long to wait before reading again after `failures` failed reads in a row.
+pub fn read_error_backoff(failures: u32) -> Duration {
+    READ_ERROR_BACKOFF_MIN
+        .saturating_mul(1 << failures.saturating_sub(1).min(16))
+        .min(READ_ERROR_BACKOFF_MAX)
+}
+
+/// The addresses of the names resolved through any query, kept for the TTL of their records, so
+/// that candidates sharing a name don't each need a query.
+#[derive(Default)]
+pub struct NameCache {
+    entries: HashMap<String, (IpAddr, Instant)>,
+}
+
+impl NameCache {
+    pub fn get(&mut self, name_with_suffix: &str) -> Option<IpAddr> {
+        let (ip, expires) = *self.entries.get(name_with_suffix)?;
+        if expires <= Instant::now() {
+            self.entries.remove(name_with_suffix);
+            return None;
+        }
+        Some(ip)
+    }
+
+    pub fn insert(&mut self, name_with_suffix: String, ip: IpAddr, ttl: u32) {
+        // a TTL of zero announces that the record is gone (RFC 6762, section 10.1)
+        if ttl == 0 {
+            self.entries.remove(&name_with_suffix);
+            return;
+        }
+
+        let now = Instant::now();
+        if self.entries.len() >= MAX_CACHED_NAMES {
+            self.entries.retain(|_, (_, expires)| *expires > now);
+        }
+        if self.entries.len() >= MAX_CACHED_NAMES {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, expires))| *expires)
+                .map(|(name, _)| name.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+
+        let expires = now + Duration::from_secs(u64::from(ttl));
+        self.entries.insert(name_with_suffix, (ip, expires));
+    }
+}
+
+struct Query {
+    id: u64,
+    name_with_suffix: String,
+    result_tx: mpsc::Sender<IpAddr>,
+}
+
+/// A mDNS responder and querier.
+///
+/// Unlike `mdns::conn::DnsConn`, which answers a fixed set of names with the address of the
+/// receiving interface, the names can be registered and unregistered at any time and every name
+/// is answered with its own address. This lets the agent give each local IP its own name.
+pub struct MulticastDnsConn {
+    // the sockets and the multicast groups they send to
+    sockets: Vec<(Arc<UdpSocket>, SocketAddr)>,
+
+    local_names: Arc<Mutex<HashMap<String, IpAddr>>>,
+    queries: Arc<Mutex<Vec<Query>>>,
+    next_query_id: AtomicU64,
+    cache: Arc<Mutex<NameCache>>,
+
+    closed: AtomicBool,
+    close_tx: Mutex<Option<broadcast::Sender<()>>>,
+}
+
+impl MulticastDnsConn {
+    /// Binds the sockets of `config` and joins the mDNS groups on the selected interfaces.
+    pub fn server(config: &MulticastDnsConfig) -> Result<Self, Error> {
+        let interfaces: Vec<util::ifaces::Interface> = util::ifaces::ifaces()
+            .map_err(|err| Error::new(err.to_string()))?
+            .into_iter()
+            .filter(|iface| config.interfaces.is_empty() || config.interfaces.contains(&iface.name))
+            .collect();
+
+        let mut sockets = vec![Self::bind_v4(
+            config.bind_addr_v4.unwrap_or_else(|| {
+                SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), MULTICAST_DNS_PORT)
+            }),
+            &interfaces,
+            !config.interfaces.is_empty(),
+        )?];
+        if config.enable_ipv6 {
+            sockets.push(Self::bind_v6(
+                config.bind_addr_v6.unwrap_or_else(|| {
+                    SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), MULTICAST_DNS_PORT)
+                }),
+                &interfaces,
+                !config.interfaces.is_empty(),
+            )?);
+        }
+
+        Ok(Self::from_sockets(sockets))
+    }
+
+    fn new_socket(domain: socket2::Domain, addr: SocketAddr) -> Result<socket2::Socket, Error> {
+        let socket =
+            socket2::Socket::new(domain, socket2::Type::DGRAM, Some(socket2::Protocol::UDP))?;
+
+        #[cfg(target_family = "unix")]
+        socket.set_reuse_port(true)?;
+        socket.set_reuse_address(true)?;
+        if domain == socket2::Domain::IPV6 {
+            socket.set_only_v6(true)?;
+        }
+        socket.set_nonblocking(true)?;
+        socket.bind(&socket2::SockAddr::from(addr))?;
+
+        Ok(socket)
+    }
+
+    fn bind_v4(
+        addr: SocketAddr,
+        interfaces: &[util::ifaces::Interface],
+        selected: bool,
+    ) -> Result<(UdpSocket, SocketAddr), Error> {
+        let socket = Self::new_socket(socket2::Domain::IPV4, addr)?;
+        socket.set_broadcast(true)?;
+
+        let group = Ipv4Addr::new(224, 0, 0, 251);
+        let mut joined = vec![];
+        for interface in interfaces {
+            if let Some(SocketAddr::V4(iface_addr)) = interface.addr {
+                match socket.join_multicast_v4(&group, iface_addr.ip()) {
+                    Ok(()) => joined.push(*iface_addr.ip()),
+                    Err(err) => log::trace!("failed to join mDNS group on {}: {}", iface_addr, err),
+                }
+            }
+        }
+        if joined.is_empty() {
+            return Err(ERR_MULTICAST_DNS_JOIN_FAILED.to_owned());
+        }
+        // otherwise the route to the group picks the interface
+        if selected {
+            socket.set_multicast_if_v4(&joined[0])?;
+        }
+
+        let socket = UdpSocket::from_std(socket.into())?;
+        Ok((socket, MULTICAST_DNS_DEST_ADDR_V4.parse()?))
+    }
+
+    fn bind_v6(
+        addr: SocketAddr,
+        interfaces: &[util::ifaces::Interface],
+        selected: bool,
+    ) -> Result<(UdpSocket, SocketAddr), Error> {
+        let socket = Self::new_socket(socket2::Domain::IPV6, addr)?;
+
+        // the scope of the link-local addresses is the index of their interface
+        let mut indexes: Vec<u32> = interfaces
+            .iter()
+            .filter_map(|iface| match iface.addr {
+                Some(SocketAddr::V6(iface_addr)) if iface_addr.scope_id() != 0 => {
+                    Some(iface_addr.scope_id())
+                }
+                _ => None,
+            })
+            .collect();
+        indexes.dedup();
+        if indexes.is_empty() && !selected {
+            // lets the system choose the interface
+            indexes.push(0);
+        }
+
+        let group = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb);
+        let mut joined = vec![];
+        for index in indexes {
+            match socket.join_multicast_v6(&group, index) {
+                Ok(()) => joined.push(index),
+                Err(err) => log::trace!("failed to join mDNS group on {}: {}", index, err),
+            }
+        }
+        if joined.is_empty() {
+            return Err(ERR_MULTICAST_DNS_JOIN_FAILED.to_owned());
+        }
+        if selected {
+            socket.set_multicast_if_v6(joined[0])?;
+        }
+
+        let socket = UdpSocket::from_std(socket.into())?;
+        Ok((socket, MULTICAST_DNS_DEST_ADDR_V6.parse()?))
+    }
+
+    /// Serves on an already bound socket, sending questions and answers to `dst_addr`.
+    pub fn new(socket: UdpSocket, dst_addr: SocketAddr) -> Self {
+        Self::from_sockets(vec![(socket, dst_addr)])
+    }
+
+    fn from_sockets(sockets: Vec<(UdpSocket, SocketAddr)>) -> Self {
+        let (close_tx, _) = broadcast::channel(1);
+        let c = Self {
+            sockets: sockets
+                .into_iter()
+                .map(|(socket, dst_addr)| (Arc::new(socket), dst_addr))
+                .collect(),
+            local_names: Arc::new(Mutex::new(HashMap::new())),
+            queries: Arc::new(Mutex::new(vec![])),
+            next_query_id: AtomicU64::new(0),
+            cache: Arc::new(Mutex::new(NameCache::default())),
+            closed: AtomicBool::new(false),
+            close_tx: Mutex::new(Some(close_tx.clone())),
+        };
+
+        for (socket, dst_addr) in &c.sockets {
+            let close_rx = close_tx.subscribe();
+            let socket = Arc::clone(socket);
+            let dst_addr = *dst_addr;
+            let local_names = Arc::clone(&c.local_names);
+            let queries = Arc::clone(&c.queries);
+            let cache = Arc::clone(&c.cache);
+            tokio::spawn(async move {
+                Self::read_loop(close_rx, socket, dst_addr, local_names, queries, cache).await;
+            });
+        }
+
+        c
+    }
+
+    /// Answers queries for `name` with `ip` until the name is unregistered. A name maps to a
+    /// single address; registering it again replaces the address.
+    pub async fn register(&self, name: &str, ip: IpAddr) {
+        let mut local_names = self.local_names.lock().await;
+        local_names.insert(Self::name_with_suffix(name), ip);
+    }
+
+    /// Stops answering queries for `name`. Returns false if the name was not registered.
+    pub async fn unregister(&self, name: &str) -> bool {
+        let mut local_names = self.local_names.lock().await;
+        local_names.remove(&Self::name_with_suffix(name)).is_some()
+    }
+
+    /// Resolves `name`, from the cache if possible. Otherwise sends mDNS queries until it is
+    /// answered, `timeout` passes or `close_query_signal` fires.
+    pub async fn query(
+        &self,
+        name: &str,
+        timeout: Duration,
+        mut close_query_signal: broadcast::Receiver<()>,
+    ) -> Result<IpAddr, Error> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(ERR_MULTICAST_DNS_CLOSED.to_owned());
+        }
+
+        let name_with_suffix = Self::name_with_suffix(name);
+        let cached = self.cache.lock().await.get(&name_with_suffix);
+        if let Some(ip) = cached {
+            return Ok(ip);
+        }
+
+        let id = self.next_query_id.fetch_add(1, Ordering::SeqCst);
+        let (result_tx, mut result_rx) = mpsc::channel(1);
+        {
+            let mut queries = self.queries.lock().await;
+            queries.push(Query {
+                id,
+                name_with_suffix: name_with_suffix.clone(),
+                result_tx,
+            });
+        }
+
+        let deadline = Instant::now() + timeout;
+        let result = loop {
+            let now = Instant::now();
+            if now >= deadline {
+                break Err(ERR_MULTICAST_DNS_QUERY_TIMEOUT.to_owned());
+            }
+            self.send_question(&name_with_suffix).await;
+
+            tokio::select! {
+                () = tokio::time::sleep(QUERY_INTERVAL.min(deadline - now)) => {}
+                _ = close_query_signal.recv() => break Err(ERR_MULTICAST_DNS_QUERY_CANCELED.to_owned()),
+                ip = result_rx.recv() => {
+                    break ip.ok_or_else(|| ERR_MULTICAST_DNS_CLOSED.to_owned());
+                }
+            }
+        };
+
+        // drop the query in case it was not answered
+        {
+            let mut queries = self.queries.lock().await;
+            queries.retain(|q| q.id != id);
+        }
+
+        result
+    }
+
+    /// Returns true once the connection is closed.
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    /// Stops answering and querying.
+    pub async fn close(&self) -> Result<(), Error> {
+        if self.closed.swap(true, Ordering::SeqCst) {
+            return Err(ERR_MULTICAST_DNS_CLOSED.to_owned());
+        }
+
+        self.close_tx.lock().await.take();
+        self.local_names.lock().await.clear();
+        *self.cache.lock().await = NameCache::default();
+        // dropping the senders ends the pending queries
+        self.queries.lock().await.clear();
+
+        Ok(())
+    }
+
+    fn name_with_suffix(name: &str) -> String {
+        let mut name = name.to_ascii_lowercase();
+        if !name.ends_with('.') {
+            name.push('.');
+        }
+        name
+    }
+
+    async fn send_question(&self, name_with_suffix: &str) {
+        let raw_query = match Self::pack_question(name_with_suffix) {
+            Ok(raw_query) => raw_query,
+            Err(err) => {
+                log::warn!(
+                    "Failed to construct mDNS query for {}: {}",
+                    name_with_suffix,
+                    err
+                );
+                return;
+            }
+        };
+
+        for (socket, dst_addr) in &self.sockets {
+            if let Err(err) = socket.send_to(&raw_query, dst_addr).await {
+                log::warn!("Failed to send mDNS query to {}: {}", dst_addr, err);
+            }
+        }
+    }
+
+    fn pack_question(name_with_suffix: &str) -> Result<Vec<u8>, Error> {
+        let name = Name::new(name_with_suffix)?;
+        let mut msg = Message {
+            header: Header::default(),
+            questions: vec![
+                Question {
+                    name: name.clone(),
+                    typ: DnsType::A,
+                    class: DNSCLASS_INET,
+                },
+                Question {
+                    name,
+                    typ: DnsType::Aaaa,
+                    class: DNSCLASS_INET,
+                },
+            ],
+            ..Message::default()
+        };
+        msg.pack()
+    }
+
+    fn pack_answer(name_with_suffix: &str, ip: IpAddr) -> Result<Vec<u8>, Error> {
+        let header = ResourceHeader {
+            name: Name::new(name_with_suffix)?,
+            class: DnsClass(DNSCLASS_INET.0 | CLASS_FLAG_MASK),
+            ttl: RESPONSE_TTL,
+            ..ResourceHeader::default()
+        };
+        let body: Box<dyn ResourceBody> = match ip {
+            IpAddr::V4(ip) => Box::new(AResource { a: ip.octets() }),
+            IpAddr::V6(ip) => Box::new(AaaaResource { aaaa: ip.octets() }),
+        };
+
+        let mut msg = Message {
+            header: Header {
+                response: true,
+                authoritative: true,
+                ..Header::default()
+            },
+            answers: vec![Resource {
+                header,
+                body: Some(body),
+            }],
+            ..Message::default()
+        };
+        msg.pack()
+    }
+
+    async fn read_loop(
+        mut close_rx: broadcast::Receiver<()>,
+        socket: Arc<UdpSocket>,
+        dst_addr: SocketAddr,
+        local_names: Arc<Mutex<HashMap<String, IpAddr>>>,
+        queries: Arc<Mutex<Vec<Query>>>,
+        cache: Arc<Mutex<NameCache>>,
+    ) {
+        let mut b = vec![0u8; INBOUND_BUFFER_SIZE];
+        let mut read_failures = 0;
+        loop {
+            let result = tokio::select! {
+                _ = close_rx.recv() => return,
+                result = socket.recv_from(&mut b) => result,
+            };
+            let (n, src) = match result {
+                Ok(result) => {
+                    read_failures = 0;
+                    result
+                }
+                Err(err) => {
+                    // the socket may keep failing, e.g. when its interface is gone, and the
+                    // pending queries still time out on their own
+                    read_failures += 1;
+                    let backoff = read_error_backoff(read_failures);
+                    log::warn!(
+                        "Failed to read mDNS packet, retrying in {:?}: {}",
+                        backoff,
+                        err
+                    );
+                    tokio::select! {
+                        _ = close_rx.recv() => return,
+                        () = tokio::time::sleep(backoff) => continue,
+                    }
+                }
+            };
+
+            let mut p = Parser::default();
+            let header = match p.start(&b[..n]) {
+                Ok(header) => header,
+                Err(err) => {
+                    log::trace!("Failed to parse mDNS packet from {}: {}", src, err);
+                    continue;
+                }
+            };
+
+            let answers = if header.response {
+                if let Err(err) = p.skip_all_questions() {
+                    log::trace!("Failed to parse mDNS packet from {}: {}", src, err);
+                    continue;
+                }
+                Self::parse_answers(&mut p)
+            } else {
+                let questions = p.all_questions().unwrap_or_default();
+                let to_answer = {
+                    let local_names = local_names.lock().await;
+                    questions
+                        .iter()
+                        .filter(|q| q.class.0 & !CLASS_FLAG_MASK == DNSCLASS_INET.0)
+                        .filter_map(|q| {
+                            let ip = *local_names.get(&q.name.data.to_ascii_lowercase())?;
+                            let wanted = match q.typ {
+                                DnsType::A => ip.is_ipv4(),
+                                DnsType::Aaaa => ip.is_ipv6(),
+                                DnsType::All => true,
+                                _ => false,
+                            };
+                            wanted.then(|| (q.name.data.clone(), ip))
+                        })
+                        .collect::<Vec<_>>()
+                };
+
+                for (name, ip) in to_answer {
+                    match Self::pack_answer(&name, ip) {
+                        Ok(raw_answer) => {
+                            if let Err(err) = socket.send_to(&raw_answer, dst_addr).await {
+                                log::warn!("Failed to send mDNS answer: {}", err);
+                            }
+                        }
+                        Err(err) => log::warn!("Failed to construct mDNS answer: {}", err),
+                    }
+                }
+                continue;
+            };
+
+            if answers.is_empty() {
+                continue;
+            }
+            {
+                let mut cache = cache.lock().await;
+                for (name, ip, ttl) in &answers {
+                    cache.insert(name.clone(), *ip, *ttl);
+                }
+            }
+            let mut queries = queries.lock().await;
+            for (name, ip, _) in answers {
+                queries.retain(|q| {
+                    if q.name_with_suffix == name {
+                        let _ = q.result_tx.try_send(ip);
+                        false
+                    } else {
+                        true
+                    }
+                });
+            }
+        }
+    }
+
+    /// Returns the names, addresses and TTLs of the A and AAAA records of a response.
+    fn parse_answers(p: &mut Parser<'_>) -> Vec<(String, IpAddr, u32)> {
+        let mut answers = vec![];
+        while let Ok(header) = p.answer_header() {
+            if header.class.0 & !CLASS_FLAG_MASK != DNSCLASS_INET.0
+                || (header.typ != DnsType::A && header.typ != DnsType::Aaaa)
+            {
+                if p.skip_answer().is_err() {
+                    break;
+                }
+                continue;
+            }
+
+            let body = match p.resource_body() {
+                Ok(body) => body,
+                Err(_) => break,
+            };
+            // the bodies are opaque, but A and AAAA records pack to the bare address
+            let ip = match body.pack(vec![], &mut None, 0) {
+                Ok(raw) if raw.len() == 4 => {
+                    IpAddr::V4(Ipv4Addr::new(raw[0], raw[1], raw[2], raw[3]))
+                }
+                Ok(raw) if raw.len() == 16 => {
+                    let mut octets = [0u8; 16];
+                    octets.copy_from_slice(&raw);
+                    IpAddr::V6(Ipv6Addr::from(octets))
+                }
+                _ => continue,
+            };
+            answers.push((header.name.data.to_ascii_lowercase(), ip, header.ttl));
+        }
+        answers
+    }
+}
@@ -0,0 +1,224 @@
+use super::mdns_conn::*;
+use super::*;
+use crate::errors::*;
+
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::broadcast;
+
+/// Creates two connections on loopback which send their multicast traffic to each other.
+async fn create_conn_pair() -> Result<(MulticastDnsConn, MulticastDnsConn), Error> {
+    let socket0 = UdpSocket::bind("127.0.0.1:0").await?;
+    let socket1 = UdpSocket::bind("127.0.0.1:0").await?;
+    let addr0 = socket0.local_addr()?;
+    let addr1 = socket1.local_addr()?;
+
+    Ok((
+        MulticastDnsConn::new(socket0, addr1),
+        MulticastDnsConn::new(socket1, addr0),
+    ))
+}
+
+async fn query_with_timeout(
+    conn: &MulticastDnsConn,
+    name: &str,
+    timeout: Duration,
+) -> Result<IpAddr, Error> {
+    let (_close_query_signal_tx, close_query_signal_rx) = broadcast::channel(1);
+    conn.query(name, timeout, close_query_signal_rx).await
+}
+
+#[tokio::test]
+async fn test_multicast_dns_conn_answers_registered_names() -> Result<(), Error> {
+    let (querier, responder) = create_conn_pair().await?;
+
+    let ipv4: IpAddr = "192.168.0.2".parse().unwrap();
+    let ipv6: IpAddr = "fd00::2".parse().unwrap();
+    let name4 = generate_multicast_dns_name();
+    let name6 = generate_multicast_dns_name();
+    responder.register(&name4, ipv4).await;
+    responder.register(&name6, ipv6).await;
+
+    let ip = query_with_timeout(&querier, &name4, Duration::from_secs(3)).await?;
+    assert_eq!(ip, ipv4);
+    let ip = query_with_timeout(&querier, &name6, Duration::from_secs(3)).await?;
+    assert_eq!(ip, ipv6);
+
+    // names are case insensitive
+    let ip = query_with_timeout(&querier, &name4.to_uppercase(), Duration::from_secs(3)).await?;
+    assert_eq!(ip, ipv4);
+
+    querier.close().await?;
+    responder.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_multicast_dns_conn_unregister() -> Result<(), Error> {
+    let (querier, responder) = create_conn_pair().await?;
+
+    let name = generate_multicast_dns_name();
+    responder
+        .register(&name, "192.168.0.2".parse().unwrap())
+        .await;
+    assert!(responder.unregister(&name).await);
+    assert!(
+        !responder.unregister(&name).await,
+        "the name should be gone already"
+    );
+
+    let result = query_with_timeout(&querier, &name, Duration::from_millis(1500)).await;
+    assert_eq!(result, Err(ERR_MULTICAST_DNS_QUERY_TIMEOUT.to_owned()));
+
+    querier.close().await?;
+    responder.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_multicast_dns_conn_close_ends_queries() -> Result<(), Error> {
+    let (querier, responder) = create_conn_pair().await?;
+    let querier = Arc::new(querier);
+
+    let querier2 = Arc::clone(&querier);
+    let query = tokio::spawn(async move {
+        query_with_timeout(&querier2, "unknown.local", Duration::from_secs(30)).await
+    });
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    querier.close().await?;
+
+    let result = tokio::time::timeout(Duration::from_secs(1), query)
+        .await
+        .expect("the query should end when the connection closes")
+        .unwrap();
+    assert!(result.is_err());
+    assert!(querier.close().await.is_err(), "should be closed already");
+
+    responder.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_multicast_dns_conn_over_ipv6() -> Result<(), Error> {
+    let socket0 = UdpSocket::bind("[::1]:0").await?;
+    let socket1 = UdpSocket::bind("[::1]:0").await?;
+    let addr0 = socket0.local_addr()?;
+    let addr1 = socket1.local_addr()?;
+    let querier = MulticastDnsConn::new(socket0, addr1);
+    let responder = MulticastDnsConn::new(socket1, addr0);
+
+    let name = generate_multicast_dns_name();
+    let ipv6: IpAddr = "fd00::2".parse().unwrap();
+    responder.register(&name, ipv6).await;
+
+    let ip = query_with_timeout(&querier, &name, Duration::from_secs(3)).await?;
+    assert_eq!(ip, ipv6);
+
+    querier.close().await?;
+    responder.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_multicast_dns_conn_unknown_interface() {
+    let result = MulticastDnsConn::server(&MulticastDnsConfig {
+        bind_addr_v4: Some("0.0.0.0:0".parse().unwrap()),
+        interfaces: vec!["no-such-interface".to_owned()],
+        ..Default::default()
+    });
+    assert!(
+        matches!(result, Err(err) if err == *ERR_MULTICAST_DNS_JOIN_FAILED),
+        "no interface should be left to join the group on"
+    );
+}
+
+#[tokio::test]
+async fn test_multicast_dns_conn_query_canceled() -> Result<(), Error> {
+    let (querier, responder) = create_conn_pair().await?;
+    let querier = Arc::new(querier);
+
+    let (close_query_signal_tx, close_query_signal_rx) = broadcast::channel(1);
+    let querier2 = Arc::clone(&querier);
+    let query = tokio::spawn(async move {
+        querier2
+            .query(
+                "unknown.local",
+                Duration::from_secs(30),
+                close_query_signal_rx,
+            )
+            .await
+    });
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    drop(close_query_signal_tx);
+
+    let result = tokio::time::timeout(Duration::from_secs(1), query)
+        .await
+        .expect("the query should end when canceled")
+        .unwrap();
+    assert_eq!(result, Err(ERR_MULTICAST_DNS_QUERY_CANCELED.to_owned()));
+
+    querier.close().await?;
+    responder.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_multicast_dns_conn_caches_answers() -> Result<(), Error> {
+    let (querier, responder) = create_conn_pair().await?;
+
+    let name = generate_multicast_dns_name();
+    let ipv4: IpAddr = "192.168.0.2".parse().unwrap();
+    responder.register(&name, ipv4).await;
+
+    let ip = query_with_timeout(&querier, &name, Duration::from_secs(3)).await?;
+    assert_eq!(ip, ipv4);
+
+    // the responder is gone, so the address can only come from the cache
+    responder.close().await?;
+    let ip = query_with_timeout(&querier, &name, Duration::from_millis(100)).await?;
+    assert_eq!(ip, ipv4);
+
+    querier.close().await?;
+
+    Ok(())
+}
+
+#[test]
+fn test_name_cache() {
+    let mut cache = NameCache::default();
+    let ip: IpAddr = "192.168.0.2".parse().unwrap();
+
+    cache.insert("a.local.".to_owned(), ip, 120);
+    assert_eq!(cache.get("a.local."), Some(ip));
+
+    // goodbye packets remove the record
+    cache.insert("a.local.".to_owned(), ip, 0);
+    assert_eq!(cache.get("a.local."), None);
+
+    for i in 0..MAX_CACHED_NAMES + 1 {
+        cache.insert(format!("{}.local.", i), ip, 120 + i as u32);
+    }
+    assert_eq!(
+        cache.get("0.local."),
+        None,
+        "the oldest entry should be evicted"
+    );
+    assert_eq!(cache.get(&format!("{}.local.", MAX_CACHED_NAMES)), Some(ip));
+}
+
+#[test]
+fn test_read_error_backoff() {
+    assert_eq!(read_error_backoff(1), Duration::from_millis(10));
+    assert_eq!(read_error_backoff(2), Duration::from_millis(20));
+    assert_eq!(read_error_backoff(4), Duration::from_millis(80));
+    assert_eq!(read_error_backoff(8), Duration::from_secs(1));
+    assert_eq!(read_error_backoff(u32::MAX), Duration::from_secs(1));
+}
@@ -3,8 +3,10 @@ use crate::agent::{agent_config::*, agent_vnet_test::*, *};
 use crate::candidate::*;
 use crate::errors::*;
 use crate::network_type::*;
+use util::vnet::*;
 
 use regex::Regex;
+use std::time::Duration;
 use tokio::sync::{mpsc, Mutex};
 
 #[tokio::test]
@@ -144,3 +146,258 @@ fn test_generate_multicast_dnsname() -> Result<(), Error> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_multicast_dns_unique_host_names() -> Result<(), Error> {
+    let r = Arc::new(Mutex::new(router::Router::new(router::RouterConfig {
+        cidr: "1.2.3.0/24".to_owned(),
+        ..Default::default()
+    })?));
+    let nw = Arc::new(net::Net::new(Some(net::NetConfig {
+        static_ips: vec!["1.2.3.4".to_owned(), "1.2.3.5".to_owned()],
+        ..Default::default()
+    })));
+    connect_net2router(&nw, &r).await?;
+
+    let a = Agent::new(AgentConfig {
+        net: Some(Arc::clone(&nw)),
+        network_types: vec![NetworkType::Udp4],
+        candidate_types: vec![CandidateType::Host],
+        multicast_dns_mode: MulticastDnsMode::QueryAndGather,
+        multicast_dns_host_name: "validName.local".to_owned(),
+        ..Default::default()
+    })
+    .await?;
+
+    let (done_tx, mut done_rx) = mpsc::channel::<()>(1);
+    let done_tx = Arc::new(Mutex::new(Some(done_tx)));
+    a.on_candidate(Box::new(
+        move |c: Option<Arc<dyn Candidate + Send + Sync>>| {
+            let done_tx_clone = Arc::clone(&done_tx);
+            Box::pin(async move {
+                if c.is_none() {
+                    let mut tx = done_tx_clone.lock().await;
+                    tx.take();
+                }
+            })
+        },
+    ))
+    .await;
+
+    a.gather_candidates().await?;
+    let _ = done_rx.recv().await;
+
+    let candidates = a.get_local_candidates().await?;
+    assert_eq!(candidates.len(), 2);
+    let mut names: Vec<String> = candidates.iter().map(|c| c.address()).collect();
+    assert!(names.contains(&"validName.local".to_owned()));
+    names.dedup();
+    assert_eq!(names.len(), 2, "each local IP should have its own name");
+
+    a.restart(String::new(), String::new()).await?;
+
+    // mDNS is opportunistic, so the responder may be missing in restricted environments
+    if let Some(mdns_conn) = &a.mdns_conn {
+        for name in &names {
+            assert!(
+                !mdns_conn.unregister(name).await,
+                "{} should have been unregistered on restart",
+                name
+            );
+        }
+    }
+
+    a.close().await?;
+
+    Ok(())
+}
+
+fn shared_multicast_dns_agent_config() -> AgentConfig {
+    AgentConfig {
+        network_types: vec![NetworkType::Udp4],
+        multicast_dns_mode: MulticastDnsMode::QueryOnly,
+        multicast_dns_config: MulticastDnsConfig {
+            // keeps this test apart from other agents sharing the default configuration
+            bind_addr_v4: Some("0.0.0.0:5353".parse().unwrap()),
+            shared: true,
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn test_multicast_dns_shared_conn() -> Result<(), Error> {
+    let a = Agent::new(shared_multicast_dns_agent_config()).await?;
+    let b = Agent::new(shared_multicast_dns_agent_config()).await?;
+
+    // mDNS is opportunistic, so the responder may be missing in restricted environments
+    let (a_conn, b_conn) = match (&a.mdns_conn, &b.mdns_conn) {
+        (Some(a_conn), Some(b_conn)) => (Arc::clone(a_conn), Arc::clone(b_conn)),
+        _ => return Ok(()),
+    };
+    assert!(
+        Arc::ptr_eq(&a_conn, &b_conn),
+        "the agents should share the conn"
+    );
+
+    a.close().await?;
+    assert!(!b_conn.is_closed(), "b still uses the conn");
+
+    b.close().await?;
+    assert!(b_conn.is_closed(), "the last user should close the conn");
+
+    let c = Agent::new(shared_multicast_dns_agent_config()).await?;
+    if let Some(c_conn) = &c.mdns_conn {
+        assert!(
+            !Arc::ptr_eq(c_conn, &b_conn),
+            "a closed conn must not be reused"
+        );
+    }
+    c.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_multicast_dns_shared_conn_failed_agent() -> Result<(), Error> {
+    let config = || {
+        let mut config = shared_multicast_dns_agent_config();
+        // apart from test_multicast_dns_shared_conn, which counts the users of its conn
+        config.multicast_dns_config.bind_addr_v6 = Some("[::]:5353".parse().unwrap());
+        config
+    };
+    let a = Agent::new(config()).await?;
+    let a_conn = match &a.mdns_conn {
+        Some(a_conn) => Arc::clone(a_conn),
+        None => return a.close().await,
+    };
+
+    let result = Agent::new(AgentConfig {
+        local_ufrag: "a".to_owned(),
+        ..config()
+    })
+    .await;
+    assert!(result.is_err(), "the ufrag is too short");
+    assert!(!a_conn.is_closed(), "a still uses the conn");
+
+    let b = Agent::new(config()).await?;
+    if let Some(b_conn) = &b.mdns_conn {
+        assert!(
+            Arc::ptr_eq(&a_conn, b_conn),
+            "the agents should share the conn"
+        );
+    }
+    a.close().await?;
+    assert!(!a_conn.is_closed(), "b still uses the conn");
+    b.close().await?;
+    assert!(a_conn.is_closed(), "the last user should close the conn");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_multicast_dns_unresolved_remote_candidate() -> Result<(), Error> {
+    let a = Agent::new(AgentConfig {
+        network_types: vec![NetworkType::Udp4],
+        multicast_dns_mode: MulticastDnsMode::QueryOnly,
+        multicast_dns_query_timeout: Some(Duration::from_millis(200)),
+        ..Default::default()
+    })
+    .await?;
+    // mDNS is opportunistic, so the responder may be missing in restricted environments
+    if a.mdns_conn.is_none() {
+        return a.close().await;
+    }
+
+    let (error_tx, mut error_rx) = mpsc::channel::<CandidateError>(1);
+    a.on_candidate_error(Box::new(move |err: CandidateError| {
+        let error_tx = error_tx.clone();
+        Box::pin(async move {
+            let _ = error_tx.send(err).await;
+        })
+    }))
+    .await;
+
+    let name = generate_multicast_dns_name();
+    let c: Arc<dyn Candidate + Send + Sync> = Arc::new(
+        a.unmarshal_remote_candidate(format!("1 1 udp 2130706431 {} 5000 typ host", name))
+            .await?,
+    );
+    a.add_remote_candidate(&c).await?;
+
+    let err = tokio::time::timeout(Duration::from_secs(3), error_rx.recv())
+        .await
+        .expect("the query should have timed out")
+        .unwrap();
+    assert_eq!(err.address, name);
+    assert_eq!(err.port, 5000);
+    assert_eq!(err.error_text, ERR_MULTICAST_DNS_QUERY_TIMEOUT.to_string());
+    {
+        let ai = a.agent_internal.lock().await;
+        assert!(ai.remote_candidates.is_empty());
+    }
+
+    a.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_multicast_dns_close_cancels_queries() -> Result<(), Error> {
+    let a = Agent::new(AgentConfig {
+        network_types: vec![NetworkType::Udp4],
+        multicast_dns_mode: MulticastDnsMode::QueryOnly,
+        multicast_dns_query_timeout: Some(Duration::from_secs(30)),
+        ..Default::default()
+    })
+    .await?;
+    if a.mdns_conn.is_none() {
+        return a.close().await;
+    }
+
+    let (error_tx, mut error_rx) = mpsc::channel::<CandidateError>(1);
+    a.on_candidate_error(Box::new(move |err: CandidateError| {
+        let error_tx = error_tx.clone();
+        Box::pin(async move {
+            let _ = error_tx.send(err).await;
+        })
+    }))
+    .await;
+
+    let c: Arc<dyn Candidate + Send + Sync> = Arc::new(
+        a.unmarshal_remote_candidate(format!(
+            "1 1 udp 2130706431 {} 5000 typ host",
+            generate_multicast_dns_name()
+        ))
+        .await?,
+    );
+    a.add_remote_candidate(&c).await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let mut close_query_signal_rx = {
+        let ai = a.agent_internal.lock().await;
+        let remote_resolve_cancel_tx = ai.remote_resolve_cancel_tx.as_ref().unwrap();
+        assert_eq!(
+            remote_resolve_cancel_tx.receiver_count(),
+            1,
+            "one query should be pending"
+        );
+        remote_resolve_cancel_tx.subscribe()
+    };
+
+    a.close().await?;
+    assert!(
+        tokio::time::timeout(Duration::from_secs(1), close_query_signal_rx.recv())
+            .await
+            .is_ok(),
+        "closing the agent should cancel the query"
+    );
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(
+        error_rx.try_recv().is_err(),
+        "canceled queries are not errors"
+    );
+
+    Ok(())
+}
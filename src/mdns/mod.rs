@@ -1,18 +1,28 @@
 #[cfg(test)]
+mod mdns_conn_test;
+#[cfg(test)]
 mod mdns_test;
 
-use mdns::config::*;
-use mdns::conn::*;
+pub(crate) mod mdns_conn;
+
+use mdns_conn::*;
 
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::{Arc, PoisonError};
 use util::Error;
 
 /// Represents the different Multicast modes that ICE can run.
 #[derive(PartialEq, Debug, Copy, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "kebab-case")
+)]
 pub enum MulticastDnsMode {
     Unspecified,
 
@@ -39,33 +49,80 @@ pub(crate) fn generate_multicast_dns_name() -> String {
     format!("{}.local", u)
 }
 
+/// Configures the sockets used for mDNS.
+#[derive(Default, Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(default))]
+pub struct MulticastDnsConfig {
+    /// The address of the IPv4 socket. Defaults to `0.0.0.0:5353` when this is nil.
+    pub bind_addr_v4: Option<SocketAddr>,
+
+    /// Also answers and queries over IPv6, via the `ff02::fb` group.
+    pub enable_ipv6: bool,
+
+    /// The address of the IPv6 socket. Defaults to `[::]:5353` when this is nil.
+    pub bind_addr_v6: Option<SocketAddr>,
+
+    /// The names of the interfaces to join the mDNS groups on. All interfaces are used when this
+    /// is empty.
+    pub interfaces: Vec<String>,
+
+    /// Shares a single responder and querier among all agents of the process that use the same
+    /// configuration, instead of opening sockets per agent.
+    pub shared: bool,
+}
+
+lazy_static! {
+    /// The shared connections and the number of agents using them.
+    static ref SHARED_MULTICAST_DNS_CONNS: std::sync::Mutex<HashMap<MulticastDnsConfig, (Arc<MulticastDnsConn>, usize)>> =
+        std::sync::Mutex::new(HashMap::new());
+}
+
 pub(crate) fn create_multicast_dns(
     mdns_mode: MulticastDnsMode,
-    mdns_name: &str,
-) -> Result<Option<Arc<DnsConn>>, Error> {
-    if mdns_mode == MulticastDnsMode::Disabled {
+    config: &MulticastDnsConfig,
+) -> Result<Option<Arc<MulticastDnsConn>>, Error> {
+    if mdns_mode == MulticastDnsMode::Disabled || mdns_mode == MulticastDnsMode::Unspecified {
         return Ok(None);
     }
 
-    //TODO: make it configurable
-    //TODO: why DEFAULT_DEST_ADDR doesn't work on Mac/Win?
-    let addr = SocketAddr::from_str("0.0.0.0:5353")?;
+    // the host names are registered by the gatherer, one per local IP
+    if !config.shared {
+        return Ok(Some(Arc::new(MulticastDnsConn::server(config)?)));
+    }
 
-    match mdns_mode {
-        MulticastDnsMode::QueryOnly => {
-            let conn = DnsConn::server(addr, Config::default())?;
-            Ok(Some(Arc::new(conn)))
-        }
-        MulticastDnsMode::QueryAndGather => {
-            let conn = DnsConn::server(
-                addr,
-                Config {
-                    local_names: vec![mdns_name.to_owned()],
-                    ..Config::default()
-                },
-            )?;
-            Ok(Some(Arc::new(conn)))
+    let mut conns = SHARED_MULTICAST_DNS_CONNS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    if let Some((conn, users)) = conns.get_mut(config) {
+        *users += 1;
+        return Ok(Some(Arc::clone(conn)));
+    }
+
+    let conn = Arc::new(MulticastDnsConn::server(config)?);
+    conns.insert(config.clone(), (Arc::clone(&conn), 1));
+    Ok(Some(conn))
+}
+
+/// Closes a connection of `create_multicast_dns`, unless it is shared and other agents still use
+/// it.
+pub(crate) async fn close_multicast_dns(
+    conn: &Arc<MulticastDnsConn>,
+    config: &MulticastDnsConfig,
+) -> Result<(), Error> {
+    if config.shared {
+        let mut conns = SHARED_MULTICAST_DNS_CONNS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some((shared_conn, users)) = conns.get_mut(config) {
+            if Arc::ptr_eq(shared_conn, conn) {
+                *users -= 1;
+                if *users > 0 {
+                    return Ok(());
+                }
+                conns.remove(config);
+            }
         }
-        _ => Ok(None),
     }
+
+    conn.close().await
 }
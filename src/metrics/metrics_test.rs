@@ -0,0 +1,70 @@
+use super::*;
+
+#[test]
+fn test_metrics_render() {
+    let metrics = Metrics::default();
+    metrics.on_agent_created();
+    metrics.on_agent_created();
+    metrics.on_agent_created();
+    metrics.on_connection_state_change(ConnectionState::New, ConnectionState::Checking);
+    metrics.on_connection_state_change(ConnectionState::New, ConnectionState::Checking);
+    metrics.on_connection_state_change(ConnectionState::Checking, ConnectionState::Connected);
+    metrics.on_connection_state_change(ConnectionState::Checking, ConnectionState::Closed);
+    metrics.on_agent_dropped(ConnectionState::New);
+    metrics.on_connected(Duration::from_millis(300));
+    metrics.on_connected(Duration::from_secs(60));
+    metrics.on_selected_candidate_pair(CandidateType::Host, CandidateType::ServerReflexive);
+    metrics.on_selected_candidate_pair(CandidateType::Relay, CandidateType::Host);
+    metrics.on_selected_candidate_pair(CandidateType::Host, CandidateType::ServerReflexive);
+    metrics.on_binding_request_sent();
+    metrics.on_binding_request_sent();
+    metrics.on_binding_request_received();
+    metrics.on_gather_failure("turn:turn.example.com:3478?transport=udp");
+    metrics.on_gather_failure("stun:\"stun\".example.com:3478");
+    metrics.on_consent_expired();
+
+    assert_eq!(
+        metrics.render(),
+        r#"# TYPE ice_agents gauge
+# HELP ice_agents Agents by connection state.
+ice_agents{state="new"} 0
+ice_agents{state="checking"} 0
+ice_agents{state="connected"} 1
+ice_agents{state="completed"} 0
+ice_agents{state="disconnected"} 0
+ice_agents{state="failed"} 0
+# TYPE ice_time_to_connected_seconds histogram
+# HELP ice_time_to_connected_seconds Time from the start of the connectivity checks to the first selected pair.
+ice_time_to_connected_seconds_bucket{le="0.05"} 0
+ice_time_to_connected_seconds_bucket{le="0.1"} 0
+ice_time_to_connected_seconds_bucket{le="0.25"} 0
+ice_time_to_connected_seconds_bucket{le="0.5"} 1
+ice_time_to_connected_seconds_bucket{le="1.0"} 1
+ice_time_to_connected_seconds_bucket{le="2.5"} 1
+ice_time_to_connected_seconds_bucket{le="5.0"} 1
+ice_time_to_connected_seconds_bucket{le="10.0"} 1
+ice_time_to_connected_seconds_bucket{le="30.0"} 1
+ice_time_to_connected_seconds_bucket{le="+Inf"} 2
+ice_time_to_connected_seconds_count 2
+ice_time_to_connected_seconds_sum 60.3
+# TYPE ice_selected_candidate_pairs counter
+# HELP ice_selected_candidate_pairs Selected candidate pairs by local and remote candidate type.
+ice_selected_candidate_pairs_total{local_candidate_type="host",remote_candidate_type="srflx"} 2
+ice_selected_candidate_pairs_total{local_candidate_type="relay",remote_candidate_type="host"} 1
+# TYPE ice_binding_requests_sent counter
+# HELP ice_binding_requests_sent STUN binding requests sent, connectivity checks and consent checks.
+ice_binding_requests_sent_total 2
+# TYPE ice_binding_requests_received counter
+# HELP ice_binding_requests_received STUN binding requests received.
+ice_binding_requests_received_total 1
+# TYPE ice_gather_failures counter
+# HELP ice_gather_failures Server reflexive and relay candidates that couldn't be gathered, by STUN or TURN URL.
+ice_gather_failures_total{url="stun:\"stun\".example.com:3478"} 1
+ice_gather_failures_total{url="turn:turn.example.com:3478?transport=udp"} 1
+# TYPE ice_consent_expirations counter
+# HELP ice_consent_expirations Selected pairs the remote agent stopped answering on, failing the agent.
+ice_consent_expirations_total 1
+# EOF
+"#
+    );
+}
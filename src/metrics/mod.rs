@@ -0,0 +1,254 @@
+#[cfg(test)]
+mod metrics_test;
+
+use crate::candidate::CandidateType;
+use crate::state::ConnectionState;
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+/// The upper bounds of the buckets of the time to connected histogram, in seconds.
+const TIME_TO_CONNECTED_BUCKETS: [f64; 9] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+/// The states the agents are counted in. Closed agents are gone and aren't counted.
+const AGENT_STATES: [ConnectionState; 6] = [
+    ConnectionState::New,
+    ConnectionState::Checking,
+    ConnectionState::Connected,
+    ConnectionState::Completed,
+    ConnectionState::Disconnected,
+    ConnectionState::Failed,
+];
+
+lazy_static! {
+    /// The metrics of all the agents of the process.
+    pub(crate) static ref METRICS: Metrics = Metrics::default();
+}
+
+/// Returns the metrics of all the agents of the process in the `OpenMetrics` text format.
+///
+/// A Prometheus scrape endpoint serves it with the `application/openmetrics-text; version=1.0.0;
+/// charset=utf-8` content type.
+#[must_use]
+pub fn render() -> String {
+    METRICS.render()
+}
+
+#[derive(Default, Clone)]
+struct Histogram {
+    /// The number of observations of each bucket, not cumulative, the last one is `+Inf`.
+    buckets: [u64; TIME_TO_CONNECTED_BUCKETS.len() + 1],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        let bucket = TIME_TO_CONNECTED_BUCKETS
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(TIME_TO_CONNECTED_BUCKETS.len());
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+/// Process-wide counters, updated by the agents as things happen.
+#[derive(Default)]
+pub(crate) struct Metrics {
+    agents: Mutex<HashMap<ConnectionState, u64>>,
+    time_to_connected: Mutex<Histogram>,
+    /// By local and remote candidate type.
+    selected_candidate_pairs: Mutex<BTreeMap<(String, String), u64>>,
+    binding_requests_sent: AtomicU64,
+    binding_requests_received: AtomicU64,
+    /// By STUN or TURN URL.
+    gather_failures: Mutex<BTreeMap<String, u64>>,
+    consent_expirations: AtomicU64,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl Metrics {
+    pub(crate) fn on_agent_created(&self) {
+        *lock(&self.agents).entry(ConnectionState::New).or_default() += 1;
+    }
+
+    pub(crate) fn on_agent_dropped(&self, state: ConnectionState) {
+        if let Some(count) = lock(&self.agents).get_mut(&state) {
+            *count = count.saturating_sub(1);
+        }
+    }
+
+    pub(crate) fn on_connection_state_change(&self, from: ConnectionState, to: ConnectionState) {
+        let mut agents = lock(&self.agents);
+        if let Some(count) = agents.get_mut(&from) {
+            *count = count.saturating_sub(1);
+        }
+        if to != ConnectionState::Closed {
+            *agents.entry(to).or_default() += 1;
+        }
+    }
+
+    /// Observes the time from the start of the connectivity checks to the first selected pair.
+    pub(crate) fn on_connected(&self, time_to_connected: Duration) {
+        lock(&self.time_to_connected).observe(time_to_connected.as_secs_f64());
+    }
+
+    pub(crate) fn on_selected_candidate_pair(&self, local: CandidateType, remote: CandidateType) {
+        *lock(&self.selected_candidate_pairs)
+            .entry((local.to_string(), remote.to_string()))
+            .or_default() += 1;
+    }
+
+    pub(crate) fn on_binding_request_sent(&self) {
+        self.binding_requests_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn on_binding_request_received(&self) {
+        self.binding_requests_received
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a server reflexive or relay candidate that couldn't be gathered from `url`.
+    pub(crate) fn on_gather_failure(&self, url: &str) {
+        *lock(&self.gather_failures)
+            .entry(url.to_owned())
+            .or_default() += 1;
+    }
+
+    pub(crate) fn on_consent_expired(&self) {
+        self.consent_expirations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn render(&self) -> String {
+        let mut out = String::new();
+
+        family(
+            &mut out,
+            "ice_agents",
+            "gauge",
+            "Agents by connection state.",
+        );
+        let agents = lock(&self.agents).clone();
+        for state in AGENT_STATES {
+            let state_label = state.to_string().to_lowercase();
+            let count = agents.get(&state).copied().unwrap_or_default();
+            let _ = writeln!(out, "ice_agents{{state=\"{}\"}} {}", state_label, count);
+        }
+
+        family(
+            &mut out,
+            "ice_time_to_connected_seconds",
+            "histogram",
+            "Time from the start of the connectivity checks to the first selected pair.",
+        );
+        let histogram = lock(&self.time_to_connected).clone();
+        let mut cumulative = 0;
+        for (i, count) in histogram.buckets.iter().enumerate() {
+            cumulative += count;
+            let le = TIME_TO_CONNECTED_BUCKETS
+                .get(i)
+                .map_or_else(|| "+Inf".to_owned(), |bound| format!("{:?}", bound));
+            let _ = writeln!(
+                out,
+                "ice_time_to_connected_seconds_bucket{{le=\"{}\"}} {}",
+                le, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "ice_time_to_connected_seconds_count {}",
+            histogram.count
+        );
+        let _ = writeln!(out, "ice_time_to_connected_seconds_sum {}", histogram.sum);
+
+        family(
+            &mut out,
+            "ice_selected_candidate_pairs",
+            "counter",
+            "Selected candidate pairs by local and remote candidate type.",
+        );
+        for ((local, remote), count) in lock(&self.selected_candidate_pairs).iter() {
+            let _ = writeln!(
+                out,
+                "ice_selected_candidate_pairs_total{{local_candidate_type=\"{}\",remote_candidate_type=\"{}\"}} {}",
+                local,
+                remote,
+                count
+            );
+        }
+
+        family(
+            &mut out,
+            "ice_binding_requests_sent",
+            "counter",
+            "STUN binding requests sent, connectivity checks and consent checks.",
+        );
+        let _ = writeln!(
+            out,
+            "ice_binding_requests_sent_total {}",
+            self.binding_requests_sent.load(Ordering::Relaxed)
+        );
+
+        family(
+            &mut out,
+            "ice_binding_requests_received",
+            "counter",
+            "STUN binding requests received.",
+        );
+        let _ = writeln!(
+            out,
+            "ice_binding_requests_received_total {}",
+            self.binding_requests_received.load(Ordering::Relaxed)
+        );
+
+        family(
+            &mut out,
+            "ice_gather_failures",
+            "counter",
+            "Server reflexive and relay candidates that couldn't be gathered, by STUN or TURN URL.",
+        );
+        for (url, count) in lock(&self.gather_failures).iter() {
+            let _ = writeln!(
+                out,
+                "ice_gather_failures_total{{url=\"{}\"}} {}",
+                escape_label_value(url),
+                count
+            );
+        }
+
+        family(
+            &mut out,
+            "ice_consent_expirations",
+            "counter",
+            "Selected pairs the remote agent stopped answering on, failing the agent.",
+        );
+        let _ = writeln!(
+            out,
+            "ice_consent_expirations_total {}",
+            self.consent_expirations.load(Ordering::Relaxed)
+        );
+
+        out.push_str("# EOF\n");
+        out
+    }
+}
+
+fn family(out: &mut String, name: &str, metric_type: &str, help: &str) {
+    let _ = writeln!(out, "# TYPE {} {}", name, metric_type);
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
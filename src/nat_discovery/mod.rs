@@ -0,0 +1,305 @@
+#[cfg(test)]
+mod nat_discovery_test;
+
+use crate::errors::*;
+use crate::util::local_ip_towards;
+
+use stun::{addr::*, agent::*, attributes::*, message::*, xoraddr::*};
+use util::{vnet::net::*, Conn, Error};
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::time::{Duration, Instant};
+
+/// How long each test waits for the server when `discover_nat_behavior` is called through the
+/// agent.
+pub const DEFAULT_NAT_DISCOVERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+const RETRANSMISSION_INTERVAL: Duration = Duration::from_millis(500);
+const MAX_MESSAGE_SIZE: usize = 1280;
+
+const CHANGE_IP: u8 = 0x04;
+const CHANGE_PORT: u8 = 0x02;
+
+/// How the NAT maps the local address to the external one (RFC 4787 section 4.1).
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum NatMappingBehavior {
+    /// The server sees the local address, there is no NAT.
+    NoNat,
+    /// The same mapping is used for every destination, which hole punching relies on.
+    EndpointIndependent,
+    /// A new mapping is used for every destination IP.
+    AddressDependent,
+    /// A new mapping is used for every destination IP and port, i.e. a symmetric NAT.
+    AddressAndPortDependent,
+    /// The server doesn't support RFC 5780, or didn't answer all the tests.
+    Unknown,
+}
+
+/// Which sources the NAT lets reach a mapping (RFC 4787 section 5).
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum NatFilteringBehavior {
+    /// Anyone can send to the mapping.
+    EndpointIndependent,
+    /// Only the IPs the mapping has sent to can.
+    AddressDependent,
+    /// Only the IPs and ports the mapping has sent to can.
+    AddressAndPortDependent,
+    /// The server doesn't support RFC 5780.
+    Unknown,
+}
+
+impl fmt::Display for NatMappingBehavior {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match *self {
+            Self::NoNat => "no NAT",
+            Self::EndpointIndependent => "endpoint-independent",
+            Self::AddressDependent => "address-dependent",
+            Self::AddressAndPortDependent => "address and port-dependent",
+            Self::Unknown => "unknown",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl fmt::Display for NatFilteringBehavior {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match *self {
+            Self::EndpointIndependent => "endpoint-independent",
+            Self::AddressDependent => "address-dependent",
+            Self::AddressAndPortDependent => "address and port-dependent",
+            Self::Unknown => "unknown",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// What the RFC 5780 tests found out about the NAT between the host and a STUN server.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct NatBehavior {
+    /// The address the tests were sent from.
+    pub local_addr: SocketAddr,
+    /// The address the server saw the first request from.
+    pub mapped_addr: SocketAddr,
+    /// The alternate address of the server, `None` when it doesn't support RFC 5780.
+    pub other_addr: Option<SocketAddr>,
+    pub mapping: NatMappingBehavior,
+    pub filtering: NatFilteringBehavior,
+}
+
+impl NatBehavior {
+    /// Whether a peer can reach the server reflexive candidates without a relay.
+    ///
+    /// That takes a mapping that doesn't depend on the destination, so the peer reaches the
+    /// address it was given.
+    #[must_use]
+    pub const fn allows_direct_connectivity(&self) -> bool {
+        matches!(
+            self.mapping,
+            NatMappingBehavior::NoNat | NatMappingBehavior::EndpointIndependent
+        )
+    }
+}
+
+impl fmt::Display for NatBehavior {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} mapped to {}: mapping {}, filtering {}",
+            self.local_addr, self.mapped_addr, self.mapping, self.filtering
+        )
+    }
+}
+
+/// The CHANGE-REQUEST attribute (RFC 5780 section 7.2).
+struct ChangeRequest(u8);
+
+impl Setter for ChangeRequest {
+    fn add_to(&self, m: &mut Message) -> Result<(), Error> {
+        m.add(ATTR_CHANGE_REQUEST, &[0, 0, 0, self.0]);
+        Ok(())
+    }
+}
+
+/// The answer to a binding request.
+struct BindingResponse {
+    mapped_addr: SocketAddr,
+    other_addr: Option<SocketAddr>,
+}
+
+/// Runs the mapping and filtering behavior tests of RFC 5780 against a STUN server.
+///
+/// The server at `server_addr` must have an alternate address for the tests to tell anything.
+/// Each test waits up to `timeout` for the server.
+pub async fn discover_nat_behavior(
+    net: &Arc<Net>,
+    server_addr: SocketAddr,
+    timeout: Duration,
+) -> Result<NatBehavior, Error> {
+    let local_ip = local_ip_towards(net, server_addr).await?;
+
+    // Mapping test I: the mapping and the alternate address of the server
+    let conn = net.bind(SocketAddr::new(local_ip, 0)).await?;
+    let local_addr = conn.local_addr().await?;
+    let response = binding_request(&*conn, server_addr, None, timeout)
+        .await?
+        .ok_or_else(|| ERR_NAT_DISCOVERY_NO_RESPONSE.to_owned())?;
+
+    let mut behavior = NatBehavior {
+        local_addr,
+        mapped_addr: response.mapped_addr,
+        other_addr: response.other_addr,
+        mapping: NatMappingBehavior::Unknown,
+        filtering: NatFilteringBehavior::Unknown,
+    };
+    let other_addr = if let Some(other_addr) = response.other_addr {
+        other_addr
+    } else {
+        log::debug!("{} does not support RFC 5780", server_addr);
+        return Ok(behavior);
+    };
+
+    behavior.mapping = if response.mapped_addr == local_addr {
+        NatMappingBehavior::NoNat
+    } else {
+        discover_mapping(
+            &*conn,
+            server_addr,
+            other_addr,
+            response.mapped_addr,
+            timeout,
+        )
+        .await?
+    };
+
+    // the mapping tests opened the filter to the alternate address, so the filtering ones go
+    // through a fresh mapping
+    let conn = net.bind(SocketAddr::new(local_ip, 0)).await?;
+    behavior.filtering = discover_filtering(&*conn, server_addr, timeout).await?;
+
+    Ok(behavior)
+}
+
+async fn discover_mapping(
+    conn: &(dyn Conn + Send + Sync),
+    server_addr: SocketAddr,
+    other_addr: SocketAddr,
+    mapped_addr: SocketAddr,
+    timeout: Duration,
+) -> Result<NatMappingBehavior, Error> {
+    // Mapping test II: the alternate IP, the primary port
+    let response = match binding_request(
+        conn,
+        SocketAddr::new(other_addr.ip(), server_addr.port()),
+        None,
+        timeout,
+    )
+    .await?
+    {
+        Some(response) => response,
+        None => return Ok(NatMappingBehavior::Unknown),
+    };
+    if response.mapped_addr == mapped_addr {
+        return Ok(NatMappingBehavior::EndpointIndependent);
+    }
+    let mapped_addr = response.mapped_addr;
+
+    // Mapping test III: the alternate IP and port
+    let response = match binding_request(conn, other_addr, None, timeout).await? {
+        Some(response) => response,
+        None => return Ok(NatMappingBehavior::Unknown),
+    };
+    if response.mapped_addr == mapped_addr {
+        Ok(NatMappingBehavior::AddressDependent)
+    } else {
+        Ok(NatMappingBehavior::AddressAndPortDependent)
+    }
+}
+
+async fn discover_filtering(
+    conn: &(dyn Conn + Send + Sync),
+    server_addr: SocketAddr,
+    timeout: Duration,
+) -> Result<NatFilteringBehavior, Error> {
+    // Filtering test I: a mapping to the primary address
+    if binding_request(conn, server_addr, None, timeout)
+        .await?
+        .is_none()
+    {
+        return Ok(NatFilteringBehavior::Unknown);
+    }
+
+    // Filtering test II: answered from the alternate IP and port
+    let change = ChangeRequest(CHANGE_IP | CHANGE_PORT);
+    if binding_request(conn, server_addr, Some(change), timeout)
+        .await?
+        .is_some()
+    {
+        return Ok(NatFilteringBehavior::EndpointIndependent);
+    }
+
+    // Filtering test III: answered from the primary IP and the alternate port
+    let change = ChangeRequest(CHANGE_PORT);
+    if binding_request(conn, server_addr, Some(change), timeout)
+        .await?
+        .is_some()
+    {
+        Ok(NatFilteringBehavior::AddressDependent)
+    } else {
+        Ok(NatFilteringBehavior::AddressAndPortDependent)
+    }
+}
+
+/// Sends a binding request until it is answered, from whichever address, or `timeout` passes,
+/// in which case there is no response.
+async fn binding_request(
+    conn: &(dyn Conn + Send + Sync),
+    server_addr: SocketAddr,
+    change_request: Option<ChangeRequest>,
+    timeout: Duration,
+) -> Result<Option<BindingResponse>, Error> {
+    let transaction_id = TransactionId::new();
+    let mut request = Message::new();
+    request.build(&[Box::new(BINDING_REQUEST), Box::new(transaction_id)])?;
+    if let Some(change_request) = change_request {
+        change_request.add_to(&mut request)?;
+    }
+
+    let deadline = Instant::now() + timeout;
+    let mut buf = vec![0_u8; MAX_MESSAGE_SIZE];
+    while Instant::now() < deadline {
+        conn.send_to(&request.raw, server_addr).await?;
+
+        let retransmit_at = (Instant::now() + RETRANSMISSION_INTERVAL).min(deadline);
+        while let Ok(result) =
+            tokio::time::timeout_at(retransmit_at, conn.recv_from(&mut buf)).await
+        {
+            let (n, _) = result?;
+            let mut response = Message::new();
+            response.raw = buf[..n].to_vec();
+            if response.decode().is_err()
+                || response.transaction_id != transaction_id
+                || response.typ != BINDING_SUCCESS
+            {
+                continue;
+            }
+
+            let mut mapped_addr = XorMappedAddress::default();
+            mapped_addr.get_from(&response)?;
+            let mut other_addr = MappedAddress::default();
+            let other_addr = other_addr
+                .get_from_as(&response, ATTR_OTHER_ADDRESS)
+                .ok()
+                .map(|()| SocketAddr::new(other_addr.ip, other_addr.port))
+                .filter(|addr| !addr.ip().is_unspecified());
+
+            return Ok(Some(BindingResponse {
+                mapped_addr: SocketAddr::new(mapped_addr.ip, mapped_addr.port),
+                other_addr,
+            }));
+        }
+    }
+
+    Ok(None)
+}
@@ -0,0 +1,225 @@
+use super::*;
+use crate::agent::{agent_config::AgentConfig, agent_test::build_wan, Agent};
+use crate::url::Url;
+use util::vnet::*;
+
+use std::net::IpAddr;
+
+const SERVER_IPS: [&str; 2] = ["1.2.3.4", "1.2.3.5"];
+const SERVER_PORTS: [u16; 2] = [3478, 3479];
+const TEST_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Stands in for an RFC 5780 STUN server listening on both `SERVER_IPS` and both `SERVER_PORTS`.
+/// Without `supports_rfc5780` it leaves out OTHER-ADDRESS and ignores CHANGE-REQUEST.
+async fn start_server(net: &Arc<net::Net>, supports_rfc5780: bool) -> Result<(), Error> {
+    let mut conns = vec![];
+    for ip in SERVER_IPS {
+        for port in SERVER_PORTS {
+            conns.push(net.bind(SocketAddr::new(ip.parse().unwrap(), port)).await?);
+        }
+    }
+    let conns = Arc::new(conns);
+
+    for index in 0..conns.len() {
+        let conns = Arc::clone(&conns);
+        tokio::spawn(async move {
+            let (ip_index, port_index) = (index / 2, index % 2);
+            let mut buf = vec![0u8; 1500];
+            while let Ok((n, from)) = conns[index].recv_from(&mut buf).await {
+                let mut request = Message::new();
+                request.raw = buf[..n].to_vec();
+                if request.decode().is_err() || request.typ != BINDING_REQUEST {
+                    continue;
+                }
+
+                let change = match request.get(ATTR_CHANGE_REQUEST) {
+                    Ok(v) if supports_rfc5780 && v.len() == 4 => v[3],
+                    _ => 0,
+                };
+                let response_ip_index = if change & CHANGE_IP != 0 {
+                    1 - ip_index
+                } else {
+                    ip_index
+                };
+                let response_port_index = if change & CHANGE_PORT != 0 {
+                    1 - port_index
+                } else {
+                    port_index
+                };
+
+                let mut response = Message::new();
+                if response
+                    .build(&[
+                        Box::new(request),
+                        Box::new(BINDING_SUCCESS),
+                        Box::new(XorMappedAddress {
+                            ip: from.ip(),
+                            port: from.port(),
+                        }),
+                    ])
+                    .is_err()
+                {
+                    continue;
+                }
+                if supports_rfc5780 {
+                    let other_addr = MappedAddress {
+                        ip: SERVER_IPS[1 - ip_index].parse().unwrap(),
+                        port: SERVER_PORTS[1 - port_index],
+                    };
+                    if other_addr
+                        .add_to_as(&mut response, ATTR_OTHER_ADDRESS)
+                        .is_err()
+                    {
+                        continue;
+                    }
+                }
+
+                let conn = &conns[response_ip_index * 2 + response_port_index];
+                if conn.send_to(&response.raw, from).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    Ok(())
+}
+
+/// Returns the net of the client, behind a NAT of `nat_type` unless it is `None`, on a started
+/// WAN with the server on it.
+fn server_addr() -> SocketAddr {
+    SocketAddr::new(SERVER_IPS[0].parse().unwrap(), SERVER_PORTS[0])
+}
+
+#[tokio::test]
+async fn test_nat_discovery_no_nat() -> Result<(), Error> {
+    let (client_net, server_net) = build_wan(&SERVER_IPS, None).await?;
+    start_server(&server_net, true).await?;
+
+    let behavior = discover_nat_behavior(&client_net, server_addr(), TEST_TIMEOUT).await?;
+    assert_eq!(behavior.mapped_addr, behavior.local_addr);
+    assert_eq!(behavior.other_addr, Some("1.2.3.5:3479".parse().unwrap()));
+    assert_eq!(behavior.mapping, NatMappingBehavior::NoNat);
+    assert_eq!(
+        behavior.filtering,
+        NatFilteringBehavior::EndpointIndependent
+    );
+    assert!(behavior.allows_direct_connectivity());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_nat_discovery_port_restricted_cone_nat() -> Result<(), Error> {
+    let (client_net, server_net) = build_wan(
+        &SERVER_IPS,
+        Some(nat::NatType {
+            mapping_behavior: nat::EndpointDependencyType::EndpointIndependent,
+            filtering_behavior: nat::EndpointDependencyType::EndpointAddrPortDependent,
+            ..Default::default()
+        }),
+    )
+    .await?;
+    start_server(&server_net, true).await?;
+
+    let behavior = discover_nat_behavior(&client_net, server_addr(), TEST_TIMEOUT).await?;
+    assert_eq!(
+        behavior.mapped_addr.ip(),
+        "1.2.3.10".parse::<IpAddr>().unwrap()
+    );
+    assert_eq!(behavior.mapping, NatMappingBehavior::EndpointIndependent);
+    assert_eq!(
+        behavior.filtering,
+        NatFilteringBehavior::AddressAndPortDependent
+    );
+    assert!(behavior.allows_direct_connectivity());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_nat_discovery_symmetric_nat() -> Result<(), Error> {
+    let (client_net, server_net) = build_wan(
+        &SERVER_IPS,
+        Some(nat::NatType {
+            mapping_behavior: nat::EndpointDependencyType::EndpointAddrPortDependent,
+            filtering_behavior: nat::EndpointDependencyType::EndpointAddrPortDependent,
+            ..Default::default()
+        }),
+    )
+    .await?;
+    start_server(&server_net, true).await?;
+
+    let behavior = discover_nat_behavior(&client_net, server_addr(), TEST_TIMEOUT).await?;
+    assert_eq!(
+        behavior.mapping,
+        NatMappingBehavior::AddressAndPortDependent
+    );
+    assert_eq!(
+        behavior.filtering,
+        NatFilteringBehavior::AddressAndPortDependent
+    );
+    assert!(!behavior.allows_direct_connectivity());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_nat_discovery_without_rfc5780() -> Result<(), Error> {
+    let (client_net, server_net) = build_wan(
+        &SERVER_IPS,
+        Some(nat::NatType {
+            mapping_behavior: nat::EndpointDependencyType::EndpointIndependent,
+            filtering_behavior: nat::EndpointDependencyType::EndpointIndependent,
+            ..Default::default()
+        }),
+    )
+    .await?;
+    start_server(&server_net, false).await?;
+
+    let behavior = discover_nat_behavior(&client_net, server_addr(), TEST_TIMEOUT).await?;
+    assert_eq!(behavior.other_addr, None);
+    assert_eq!(behavior.mapping, NatMappingBehavior::Unknown);
+    assert_eq!(behavior.filtering, NatFilteringBehavior::Unknown);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_nat_discovery_no_response() -> Result<(), Error> {
+    let (client_net, _) = build_wan(&SERVER_IPS, None).await?;
+
+    let result = discover_nat_behavior(&client_net, server_addr(), TEST_TIMEOUT).await;
+    assert_eq!(result.unwrap_err(), *ERR_NAT_DISCOVERY_NO_RESPONSE);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_agent_discover_nat_behavior() -> Result<(), Error> {
+    let (client_net, server_net) = build_wan(&SERVER_IPS, None).await?;
+    start_server(&server_net, true).await?;
+
+    let a = Agent::new(AgentConfig {
+        net: Some(Arc::clone(&client_net)),
+        ..Default::default()
+    })
+    .await?;
+    assert_eq!(
+        a.discover_nat_behavior().await.unwrap_err(),
+        *ERR_NAT_DISCOVERY_NO_URL
+    );
+    a.close().await?;
+
+    let a = Agent::new(AgentConfig {
+        urls: vec![Url::parse_url("stun:1.2.3.4:3478")?],
+        net: Some(client_net),
+        ..Default::default()
+    })
+    .await?;
+    let behavior = a.discover_nat_behavior().await?;
+    assert_eq!(behavior.mapping, NatMappingBehavior::NoNat);
+    a.close().await?;
+
+    Ok(())
+}
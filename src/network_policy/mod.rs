@@ -0,0 +1,118 @@
+#[cfg(test)]
+mod network_policy_test;
+
+use crate::util::is_vpn_interface;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// The cost of sending over ethernet or loopback interfaces.
+pub const NETWORK_COST_MIN: u16 = 0;
+/// The cost of sending over Wi-Fi interfaces.
+pub const NETWORK_COST_LOW: u16 = 10;
+/// The cost of sending over interfaces of unknown type.
+pub const NETWORK_COST_UNKNOWN: u16 = 50;
+/// The cost of sending over VPN interfaces.
+pub const NETWORK_COST_VPN: u16 = 60;
+/// The cost of sending over cellular, usually metered, interfaces.
+pub const NETWORK_COST_CELLULAR: u16 = 900;
+/// The highest allowed cost.
+pub const NETWORK_COST_MAX: u16 = 999;
+
+/// Interface name prefixes of common cellular modem drivers (Android, iOS and Linux).
+const CELLULAR_INTERFACE_PREFIXES: [&str; 5] = ["rmnet", "ccmni", "pdp_ip", "wwan", "wwp"];
+
+/// Interface name prefixes of common Wi-Fi drivers.
+const WIFI_INTERFACE_PREFIXES: [&str; 4] = ["wlan", "wlp", "wifi", "ath"];
+
+/// Interface name prefixes of common wired drivers.
+const ETHERNET_INTERFACE_PREFIXES: [&str; 4] = ["eth", "en", "em", "bond"];
+
+/// Represents the type of network interface a local candidate is gathered from, as reported by
+/// the W3C `RTCIceCandidateStats::networkType`.
+#[derive(PartialEq, Debug, Copy, Clone, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum InterfaceType {
+    Unknown,
+    Ethernet,
+    Wifi,
+    Cellular,
+    Vpn,
+    Loopback,
+}
+
+impl fmt::Display for InterfaceType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match *self {
+            Self::Ethernet => "ethernet",
+            Self::Wifi => "wifi",
+            Self::Cellular => "cellular",
+            Self::Vpn => "vpn",
+            Self::Loopback => "loopback",
+            Self::Unknown => "unknown",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl Default for InterfaceType {
+    fn default() -> Self {
+        Self::Unknown
+    }
+}
+
+impl InterfaceType {
+    /// Returns the cost `DefaultNetworkPolicy` assigns to this type of interface.
+    #[must_use]
+    pub const fn default_cost(self) -> u16 {
+        match self {
+            Self::Ethernet | Self::Loopback => NETWORK_COST_MIN,
+            Self::Wifi => NETWORK_COST_LOW,
+            Self::Unknown => NETWORK_COST_UNKNOWN,
+            Self::Vpn => NETWORK_COST_VPN,
+            Self::Cellular => NETWORK_COST_CELLULAR,
+        }
+    }
+}
+
+/// Decides how the agent sees the local network interfaces. Host candidates from cheaper
+/// interfaces get higher local preferences, and the nominated pair is the cheapest valid one.
+pub trait NetworkPolicy {
+    /// Returns the type of the interface with the given name.
+    fn classify(&self, interface_name: &str) -> InterfaceType;
+
+    /// Returns the cost of sending over the interface, from 0 (cheapest) to `NETWORK_COST_MAX`.
+    fn cost(&self, _interface_name: &str, interface_type: InterfaceType) -> u16 {
+        interface_type.default_cost()
+    }
+}
+
+/// Classifies interfaces from the naming conventions of the common operating systems.
+#[derive(Default, Debug, Copy, Clone)]
+pub struct DefaultNetworkPolicy;
+
+impl NetworkPolicy for DefaultNetworkPolicy {
+    fn classify(&self, interface_name: &str) -> InterfaceType {
+        let name = interface_name.to_lowercase();
+        let has_prefix = |prefixes: &[&str]| prefixes.iter().any(|p| name.starts_with(p));
+
+        if name == "lo" || name.starts_with("lo0") || name.starts_with("loopback") {
+            InterfaceType::Loopback
+        } else if is_vpn_interface(&name) {
+            InterfaceType::Vpn
+        } else if has_prefix(&CELLULAR_INTERFACE_PREFIXES) {
+            InterfaceType::Cellular
+        } else if has_prefix(&WIFI_INTERFACE_PREFIXES) {
+            InterfaceType::Wifi
+        } else if has_prefix(&ETHERNET_INTERFACE_PREFIXES) {
+            InterfaceType::Ethernet
+        } else {
+            InterfaceType::Unknown
+        }
+    }
+}
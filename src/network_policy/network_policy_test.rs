@@ -0,0 +1,58 @@
+use super::*;
+
+#[test]
+fn test_default_network_policy_classify() {
+    let tests = vec![
+        ("lo", InterfaceType::Loopback),
+        ("lo0", InterfaceType::Loopback),
+        ("eth0", InterfaceType::Ethernet),
+        ("enp3s0", InterfaceType::Ethernet),
+        ("wlan0", InterfaceType::Wifi),
+        ("wlp2s0", InterfaceType::Wifi),
+        ("rmnet_data0", InterfaceType::Cellular),
+        ("pdp_ip0", InterfaceType::Cellular),
+        ("wwan0", InterfaceType::Cellular),
+        ("tun0", InterfaceType::Vpn),
+        ("utun3", InterfaceType::Vpn),
+        ("wg0", InterfaceType::Vpn),
+        ("TAP-Windows", InterfaceType::Vpn),
+        ("docker0", InterfaceType::Unknown),
+    ];
+
+    for (name, expected) in tests {
+        assert_eq!(
+            DefaultNetworkPolicy.classify(name),
+            expected,
+            "{} should be classified as {}",
+            name,
+            expected
+        );
+    }
+}
+
+#[test]
+fn test_default_network_cost_ordering() {
+    let policy = DefaultNetworkPolicy;
+    let cost = |name: &str| policy.cost(name, policy.classify(name));
+
+    assert!(cost("eth0") < cost("wlan0"), "ethernet before wifi");
+    assert!(cost("wlan0") < cost("tun0"), "wifi before vpn");
+    assert!(cost("tun0") < cost("rmnet0"), "vpn before cellular");
+    assert!(cost("rmnet0") <= NETWORK_COST_MAX);
+}
+
+#[test]
+fn test_interface_type_string() {
+    let tests = vec![
+        (InterfaceType::Unknown, "unknown"),
+        (InterfaceType::Ethernet, "ethernet"),
+        (InterfaceType::Wifi, "wifi"),
+        (InterfaceType::Cellular, "cellular"),
+        (InterfaceType::Vpn, "vpn"),
+        (InterfaceType::Loopback, "loopback"),
+    ];
+
+    for (interface_type, expected) in tests {
+        assert_eq!(interface_type.to_string(), expected);
+    }
+}
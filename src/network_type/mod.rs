@@ -5,6 +5,8 @@ use crate::errors::*;
 
 use util::Error;
 
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 use std::fmt;
 use std::net::IpAddr;
 
@@ -23,6 +25,11 @@ pub fn supported_network_types() -> Vec<NetworkType> {
 
 /// Represents the type of network.
 #[derive(PartialEq, Debug, Copy, Clone, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "lowercase")
+)]
 pub enum NetworkType {
     Unspecified,
 
@@ -0,0 +1,314 @@
+#[cfg(test)]
+mod port_mapping_test;
+
+use crate::errors::*;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use util::{vnet::net::*, Conn, Error};
+
+use std::convert::TryFrom;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The port PCP and NAT-PMP servers listen on.
+pub const PORT_MAPPING_SERVER_PORT: u16 = 5351;
+
+/// The lifetime requested when `PortMappingConfig::lifetime` isn't set.
+pub(crate) const DEFAULT_PORT_MAPPING_LIFETIME: Duration = Duration::from_secs(7200);
+
+const PCP_VERSION: u8 = 2;
+const NAT_PMP_VERSION: u8 = 0;
+const PCP_OPCODE_MAP: u8 = 1;
+const NAT_PMP_OPCODE_EXTERNAL_ADDRESS: u8 = 0;
+const NAT_PMP_OPCODE_MAP_UDP: u8 = 1;
+const OPCODE_RESPONSE: u8 = 0x80;
+const PROTOCOL_UDP: u8 = 17;
+const RESULT_SUCCESS: u8 = 0;
+const RESULT_UNSUPPORTED_VERSION: u8 = 1;
+
+const PCP_MAP_LEN: usize = 60;
+const NAT_PMP_EXTERNAL_ADDRESS_RESPONSE_LEN: usize = 12;
+const NAT_PMP_MAP_LEN: usize = 12;
+const NAT_PMP_MAP_RESPONSE_LEN: usize = 16;
+
+/// The request is resent after each of these, as RFC 6887 and RFC 6886 have clients back off.
+const RETRANSMISSION_TIMEOUTS: [Duration; 3] = [
+    Duration::from_millis(250),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+];
+
+/// Asks the local gateway for port mappings with PCP (RFC 6887), falling back to NAT-PMP
+/// (RFC 6886), and advertises them as server reflexive candidates.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(default))]
+pub struct PortMappingConfig {
+    /// The gateway the mappings are requested from. When `None`, the gateway of the default
+    /// IPv4 route, which is only known on Linux.
+    pub gateway: Option<IpAddr>,
+    /// The requested lifetime of the mappings, two hours when `None`. They are renewed halfway
+    /// through the one the gateway grants.
+    pub lifetime: Option<Duration>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum PortMappingProtocol {
+    Pcp,
+    NatPmp,
+}
+
+/// A mapping granted by the gateway.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PortMapping {
+    pub(crate) protocol: PortMappingProtocol,
+    pub(crate) gateway: IpAddr,
+    pub(crate) internal: SocketAddr,
+    pub(crate) external: SocketAddr,
+    pub(crate) lifetime: Duration,
+    nonce: [u8; 12],
+}
+
+impl PortMapping {
+    /// Requests a mapping of the UDP port of `internal`, with PCP first and NAT-PMP if the gateway
+    /// doesn't speak PCP.
+    pub(crate) async fn request(
+        net: &Arc<Net>,
+        gateway: IpAddr,
+        internal: SocketAddr,
+        lifetime: Duration,
+    ) -> Result<Self, Error> {
+        let mut mapping = Self {
+            protocol: PortMappingProtocol::Pcp,
+            gateway,
+            internal,
+            external: SocketAddr::new(internal.ip(), 0),
+            lifetime,
+            nonce: rand::random(),
+        };
+
+        match mapping.exchange(net, lifetime).await {
+            Err(err) if err == *ERR_PORT_MAPPING_UNSUPPORTED_VERSION && gateway.is_ipv4() => {
+                log::debug!("{} does not speak PCP, falling back to NAT-PMP", gateway);
+                mapping.protocol = PortMappingProtocol::NatPmp;
+                mapping.exchange(net, lifetime).await?;
+            }
+            result => result?,
+        }
+
+        Ok(mapping)
+    }
+
+    /// Renews the mapping, with the protocol and nonce it was created with.
+    pub(crate) async fn renew(&mut self, net: &Arc<Net>, lifetime: Duration) -> Result<(), Error> {
+        self.exchange(net, lifetime).await
+    }
+
+    /// Asks the gateway to delete the mapping.
+    pub(crate) async fn release(&mut self, net: &Arc<Net>) -> Result<(), Error> {
+        self.exchange(net, Duration::from_secs(0)).await
+    }
+
+    async fn exchange(&mut self, net: &Arc<Net>, lifetime: Duration) -> Result<(), Error> {
+        let conn = net.bind(SocketAddr::new(self.internal.ip(), 0)).await?;
+        let server = SocketAddr::new(self.gateway, PORT_MAPPING_SERVER_PORT);
+        let lifetime_secs = u32::try_from(lifetime.as_secs()).unwrap_or(u32::MAX);
+
+        let result = match self.protocol {
+            PortMappingProtocol::Pcp => {
+                let request = self.marshal_pcp_map(lifetime_secs);
+                let response = transact(&*conn, server, &request).await?;
+                self.unmarshal_pcp_map(&response)
+            }
+            PortMappingProtocol::NatPmp => {
+                let request = self.marshal_nat_pmp_map(lifetime_secs);
+                let response = transact(&*conn, server, &request).await?;
+                match self.unmarshal_nat_pmp_map(&response) {
+                    Ok(()) if lifetime_secs != 0 => {
+                        let response = transact(
+                            &*conn,
+                            server,
+                            &[NAT_PMP_VERSION, NAT_PMP_OPCODE_EXTERNAL_ADDRESS],
+                        )
+                        .await?;
+                        self.unmarshal_nat_pmp_external_address(&response)
+                    }
+                    result => result,
+                }
+            }
+        };
+
+        result
+    }
+
+    fn marshal_pcp_map(&self, lifetime_secs: u32) -> Vec<u8> {
+        let mut b = vec![0u8; PCP_MAP_LEN];
+        b[0] = PCP_VERSION;
+        b[1] = PCP_OPCODE_MAP;
+        b[4..8].copy_from_slice(&lifetime_secs.to_be_bytes());
+        b[8..24].copy_from_slice(&to_pcp_ip(self.internal.ip()).octets());
+        b[24..36].copy_from_slice(&self.nonce);
+        b[36] = PROTOCOL_UDP;
+        b[40..42].copy_from_slice(&self.internal.port().to_be_bytes());
+        // the previous external address is suggested on renewals
+        if self.external.port() != 0 {
+            b[42..44].copy_from_slice(&self.external.port().to_be_bytes());
+            b[44..60].copy_from_slice(&to_pcp_ip(self.external.ip()).octets());
+        } else if self.internal.is_ipv4() {
+            b[44..60].copy_from_slice(&to_pcp_ip(Ipv4Addr::UNSPECIFIED.into()).octets());
+        }
+        b
+    }
+
+    fn unmarshal_pcp_map(&mut self, b: &[u8]) -> Result<(), Error> {
+        if b.len() >= 4 && b[0] == NAT_PMP_VERSION {
+            return Err(ERR_PORT_MAPPING_UNSUPPORTED_VERSION.to_owned());
+        }
+        if b.len() < 4 || b[0] != PCP_VERSION || b[1] != OPCODE_RESPONSE | PCP_OPCODE_MAP {
+            return Err(ERR_PORT_MAPPING_MALFORMED_RESPONSE.to_owned());
+        }
+        check_result_code(b[3])?;
+        if b.len() < PCP_MAP_LEN || b[24..36] != self.nonce || b[36] != PROTOCOL_UDP {
+            return Err(ERR_PORT_MAPPING_MALFORMED_RESPONSE.to_owned());
+        }
+
+        let lifetime = u32::from_be_bytes([b[4], b[5], b[6], b[7]]);
+        let port = u16::from_be_bytes([b[42], b[43]]);
+        let mut octets = [0u8; 16];
+        octets.copy_from_slice(&b[44..60]);
+        let ip = from_pcp_ip(Ipv6Addr::from(octets));
+
+        self.lifetime = Duration::from_secs(u64::from(lifetime));
+        self.external = SocketAddr::new(ip, port);
+
+        Ok(())
+    }
+
+    fn marshal_nat_pmp_map(&self, lifetime_secs: u32) -> Vec<u8> {
+        let mut b = vec![0u8; NAT_PMP_MAP_LEN];
+        b[0] = NAT_PMP_VERSION;
+        b[1] = NAT_PMP_OPCODE_MAP_UDP;
+        b[4..6].copy_from_slice(&self.internal.port().to_be_bytes());
+        if lifetime_secs != 0 {
+            b[6..8].copy_from_slice(&self.external.port().to_be_bytes());
+        }
+        b[8..12].copy_from_slice(&lifetime_secs.to_be_bytes());
+        b
+    }
+
+    fn unmarshal_nat_pmp_map(&mut self, b: &[u8]) -> Result<(), Error> {
+        if b.len() < 4
+            || b[0] != NAT_PMP_VERSION
+            || b[1] != OPCODE_RESPONSE | NAT_PMP_OPCODE_MAP_UDP
+        {
+            return Err(ERR_PORT_MAPPING_MALFORMED_RESPONSE.to_owned());
+        }
+        check_nat_pmp_result_code(u16::from_be_bytes([b[2], b[3]]))?;
+        if b.len() < NAT_PMP_MAP_RESPONSE_LEN
+            || u16::from_be_bytes([b[8], b[9]]) != self.internal.port()
+        {
+            return Err(ERR_PORT_MAPPING_MALFORMED_RESPONSE.to_owned());
+        }
+
+        let port = u16::from_be_bytes([b[10], b[11]]);
+        let lifetime = u32::from_be_bytes([b[12], b[13], b[14], b[15]]);
+        self.lifetime = Duration::from_secs(u64::from(lifetime));
+        self.external = SocketAddr::new(self.external.ip(), port);
+
+        Ok(())
+    }
+
+    fn unmarshal_nat_pmp_external_address(&mut self, b: &[u8]) -> Result<(), Error> {
+        if b.len() < 4
+            || b[0] != NAT_PMP_VERSION
+            || b[1] != OPCODE_RESPONSE | NAT_PMP_OPCODE_EXTERNAL_ADDRESS
+        {
+            return Err(ERR_PORT_MAPPING_MALFORMED_RESPONSE.to_owned());
+        }
+        check_nat_pmp_result_code(u16::from_be_bytes([b[2], b[3]]))?;
+        if b.len() < NAT_PMP_EXTERNAL_ADDRESS_RESPONSE_LEN {
+            return Err(ERR_PORT_MAPPING_MALFORMED_RESPONSE.to_owned());
+        }
+
+        let ip = Ipv4Addr::new(b[8], b[9], b[10], b[11]);
+        self.external = SocketAddr::new(ip.into(), self.external.port());
+
+        Ok(())
+    }
+}
+
+/// Sends the request until the server answers, backing off between attempts.
+async fn transact(
+    conn: &(dyn Conn + Send + Sync),
+    server: SocketAddr,
+    request: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let mut buf = vec![0u8; 1100];
+    for timeout in RETRANSMISSION_TIMEOUTS {
+        conn.send_to(request, server).await?;
+
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            match tokio::time::timeout_at(deadline, conn.recv_from(&mut buf)).await {
+                Ok(Ok((n, from))) if from == server => return Ok(buf[..n].to_vec()),
+                Ok(Ok(_)) => {}
+                Ok(Err(err)) => return Err(err.into()),
+                Err(_) => break,
+            }
+        }
+    }
+
+    Err(ERR_PORT_MAPPING_TIMEOUT.to_owned())
+}
+
+fn check_result_code(result_code: u8) -> Result<(), Error> {
+    match result_code {
+        RESULT_SUCCESS => Ok(()),
+        RESULT_UNSUPPORTED_VERSION => Err(ERR_PORT_MAPPING_UNSUPPORTED_VERSION.to_owned()),
+        _ => Err(Error::new(format!(
+            "{}: result code {}",
+            *ERR_PORT_MAPPING_REJECTED, result_code
+        ))),
+    }
+}
+
+fn check_nat_pmp_result_code(result_code: u16) -> Result<(), Error> {
+    check_result_code(u8::try_from(result_code).unwrap_or(u8::MAX))
+}
+
+/// PCP carries IPv4 addresses as IPv4-mapped IPv6 addresses.
+const fn to_pcp_ip(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+const fn from_pcp_ip(ip: Ipv6Addr) -> IpAddr {
+    match ip.octets() {
+        [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, a, b, c, d] => {
+            IpAddr::V4(Ipv4Addr::new(a, b, c, d))
+        }
+        _ => IpAddr::V6(ip),
+    }
+}
+
+/// Returns the gateway of the default IPv4 route from `/proc/net/route`.
+pub(crate) fn default_gateway() -> Option<IpAddr> {
+    let routes = std::fs::read_to_string("/proc/net/route").ok()?;
+    parse_default_gateway(&routes)
+}
+
+fn parse_default_gateway(routes: &str) -> Option<IpAddr> {
+    routes.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 3 || fields[1] != "00000000" {
+            return None;
+        }
+        // the address as printed from a native integer, so its bytes are in native order
+        let gateway = u32::from_str_radix(fields[2], 16).ok()?;
+        Some(IpAddr::V4(Ipv4Addr::from(gateway.to_ne_bytes())))
+            .filter(|gateway| !gateway.is_unspecified())
+    })
+}
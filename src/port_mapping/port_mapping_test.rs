@@ -0,0 +1,399 @@
+use super::*;
+use crate::agent::{agent_config::*, agent_test::gather_and_wait, agent_vnet_test::*, *};
+use crate::candidate::*;
+use crate::network_type::*;
+use util::vnet::*;
+
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::Instant;
+
+const GATEWAY_IP: &str = "10.0.0.254";
+const EXTERNAL_IP: &str = "1.2.3.4";
+const EXTERNAL_PORT_OFFSET: u16 = 10000;
+
+/// Stands in for the PCP server of a gateway, or for a NAT-PMP only one. It maps the internal
+/// ports to `EXTERNAL_IP` and the port `EXTERNAL_PORT_OFFSET` higher, and passes on the requests.
+async fn start_gateway(
+    net: Arc<net::Net>,
+    speaks_pcp: bool,
+) -> Result<mpsc::UnboundedReceiver<Vec<u8>>, Error> {
+    start_gateway_with(net, speaks_pcp, |_| Some(EXTERNAL_IP.parse().unwrap())).await
+}
+
+/// Like `start_gateway`, but maps the ports to the address `external_ip` returns for the index of
+/// each map request, retransmissions included, and leaves the request unanswered for `None`.
+async fn start_gateway_with(
+    net: Arc<net::Net>,
+    speaks_pcp: bool,
+    external_ip: impl Fn(usize) -> Option<Ipv4Addr> + Send + 'static,
+) -> Result<mpsc::UnboundedReceiver<Vec<u8>>, Error> {
+    let conn = net
+        .bind(SocketAddr::new(
+            GATEWAY_IP.parse().unwrap(),
+            PORT_MAPPING_SERVER_PORT,
+        ))
+        .await?;
+    let (requests_tx, requests_rx) = mpsc::unbounded_channel();
+
+    tokio::spawn(async move {
+        let mut map_requests = 0;
+        let mut buf = vec![0u8; 1500];
+        while let Ok((n, from)) = conn.recv_from(&mut buf).await {
+            let b = buf[..n].to_vec();
+            let mapped_ip = if (b[0], b[1]) == (PCP_VERSION, PCP_OPCODE_MAP)
+                || (b[0], b[1]) == (NAT_PMP_VERSION, NAT_PMP_OPCODE_MAP_UDP)
+            {
+                map_requests += 1;
+                match external_ip(map_requests - 1) {
+                    Some(ip) => ip,
+                    None => continue,
+                }
+            } else {
+                EXTERNAL_IP.parse().unwrap()
+            };
+            let response = match (b[0], b[1]) {
+                (PCP_VERSION, PCP_OPCODE_MAP) if speaks_pcp => {
+                    let internal_port = u16::from_be_bytes([b[40], b[41]]);
+                    let mut r = vec![0u8; PCP_MAP_LEN];
+                    r[0] = PCP_VERSION;
+                    r[1] = OPCODE_RESPONSE | PCP_OPCODE_MAP;
+                    r[4..8].copy_from_slice(&b[4..8]);
+                    r[24..42].copy_from_slice(&b[24..42]);
+                    r[42..44]
+                        .copy_from_slice(&(internal_port + EXTERNAL_PORT_OFFSET).to_be_bytes());
+                    r[44..60].copy_from_slice(&mapped_ip.to_ipv6_mapped().octets());
+                    r
+                }
+                (PCP_VERSION, opcode) => {
+                    vec![NAT_PMP_VERSION, OPCODE_RESPONSE | opcode, 0, 1, 0, 0, 0, 0]
+                }
+                (NAT_PMP_VERSION, NAT_PMP_OPCODE_EXTERNAL_ADDRESS) => {
+                    let mut r = vec![NAT_PMP_VERSION, OPCODE_RESPONSE, 0, 0, 0, 0, 0, 0];
+                    r.extend_from_slice(&mapped_ip.octets());
+                    r
+                }
+                (NAT_PMP_VERSION, NAT_PMP_OPCODE_MAP_UDP) => {
+                    let internal_port = u16::from_be_bytes([b[4], b[5]]);
+                    let mut r = vec![0u8; NAT_PMP_MAP_RESPONSE_LEN];
+                    r[1] = OPCODE_RESPONSE | NAT_PMP_OPCODE_MAP_UDP;
+                    r[8..10].copy_from_slice(&b[4..6]);
+                    r[10..12]
+                        .copy_from_slice(&(internal_port + EXTERNAL_PORT_OFFSET).to_be_bytes());
+                    r[12..16].copy_from_slice(&b[8..12]);
+                    r
+                }
+                _ => continue,
+            };
+            let _ = requests_tx.send(b);
+            if conn.send_to(&response, from).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    Ok(requests_rx)
+}
+
+/// Returns the net of the agent, and the one of the gateway, on a started LAN.
+async fn build_lan() -> Result<(Arc<net::Net>, Arc<net::Net>), Error> {
+    let lan = Arc::new(Mutex::new(router::Router::new(router::RouterConfig {
+        cidr: "10.0.0.0/24".to_owned(),
+        ..Default::default()
+    })?));
+    let host_net = Arc::new(net::Net::new(Some(net::NetConfig {
+        static_ips: vec!["10.0.0.1".to_owned()],
+        ..Default::default()
+    })));
+    let gateway_net = Arc::new(net::Net::new(Some(net::NetConfig {
+        static_ips: vec![GATEWAY_IP.to_owned()],
+        ..Default::default()
+    })));
+    connect_net2router(&host_net, &lan).await?;
+    connect_net2router(&gateway_net, &lan).await?;
+    start_router(&lan).await?;
+
+    Ok((host_net, gateway_net))
+}
+
+fn requested_pcp_lifetime(request: &[u8]) -> u32 {
+    u32::from_be_bytes([request[4], request[5], request[6], request[7]])
+}
+
+#[tokio::test]
+async fn test_port_mapping_pcp() -> Result<(), Error> {
+    let (host_net, gateway_net) = build_lan().await?;
+    let mut requests_rx = start_gateway(gateway_net, true).await?;
+
+    let internal: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+    let mut mapping = PortMapping::request(
+        &host_net,
+        GATEWAY_IP.parse().unwrap(),
+        internal,
+        Duration::from_secs(600),
+    )
+    .await?;
+    assert_eq!(mapping.protocol, PortMappingProtocol::Pcp);
+    assert_eq!(mapping.external, "1.2.3.4:15000".parse().unwrap());
+    assert_eq!(mapping.lifetime, Duration::from_secs(600));
+
+    let request = requests_rx.recv().await.unwrap();
+    assert_eq!(request.len(), PCP_MAP_LEN);
+    assert_eq!(requested_pcp_lifetime(&request), 600);
+    assert_eq!(
+        &request[8..24],
+        &"10.0.0.1"
+            .parse::<Ipv4Addr>()
+            .unwrap()
+            .to_ipv6_mapped()
+            .octets(),
+        "the client address must be the one the request comes from"
+    );
+    let nonce = request[24..36].to_vec();
+
+    // renewals suggest the mapping they renew
+    mapping.renew(&host_net, Duration::from_secs(600)).await?;
+    let request = requests_rx.recv().await.unwrap();
+    assert_eq!(request[24..36], nonce[..]);
+    assert_eq!(u16::from_be_bytes([request[42], request[43]]), 15000);
+
+    mapping.release(&host_net).await?;
+    let request = requests_rx.recv().await.unwrap();
+    assert_eq!(requested_pcp_lifetime(&request), 0);
+    assert_eq!(request[24..36], nonce[..]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_port_mapping_nat_pmp_fallback() -> Result<(), Error> {
+    let (host_net, gateway_net) = build_lan().await?;
+    let mut requests_rx = start_gateway(gateway_net, false).await?;
+
+    let mut mapping = PortMapping::request(
+        &host_net,
+        GATEWAY_IP.parse().unwrap(),
+        "10.0.0.1:5000".parse().unwrap(),
+        Duration::from_secs(600),
+    )
+    .await?;
+    assert_eq!(mapping.protocol, PortMappingProtocol::NatPmp);
+    assert_eq!(mapping.external, "1.2.3.4:15000".parse().unwrap());
+    assert_eq!(mapping.lifetime, Duration::from_secs(600));
+
+    let versions: Vec<(u8, u8)> = [
+        requests_rx.recv().await.unwrap(),
+        requests_rx.recv().await.unwrap(),
+        requests_rx.recv().await.unwrap(),
+    ]
+    .iter()
+    .map(|request| (request[0], request[1]))
+    .collect();
+    assert_eq!(
+        versions,
+        vec![
+            (PCP_VERSION, PCP_OPCODE_MAP),
+            (NAT_PMP_VERSION, NAT_PMP_OPCODE_MAP_UDP),
+            (NAT_PMP_VERSION, NAT_PMP_OPCODE_EXTERNAL_ADDRESS),
+        ]
+    );
+
+    mapping.release(&host_net).await?;
+    let request = requests_rx.recv().await.unwrap();
+    assert_eq!(request.len(), NAT_PMP_MAP_LEN);
+    assert_eq!(&request[6..12], &[0, 0, 0, 0, 0, 0]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_port_mapping_timeout() -> Result<(), Error> {
+    let (host_net, _) = build_lan().await?;
+
+    let result = PortMapping::request(
+        &host_net,
+        GATEWAY_IP.parse().unwrap(),
+        "10.0.0.1:5000".parse().unwrap(),
+        Duration::from_secs(600),
+    )
+    .await;
+    assert_eq!(result.unwrap_err(), *ERR_PORT_MAPPING_TIMEOUT);
+
+    Ok(())
+}
+
+#[test]
+fn test_parse_default_gateway() {
+    // the kernel prints the addresses as native integers
+    let routes = format!(
+        "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT\n\
+         eth0\t{:08X}\t00000000\t0001\t0\t0\t0\t{:08X}\t0\t0\t0\n\
+         eth0\t00000000\t{:08X}\t0003\t0\t0\t0\t00000000\t0\t0\t0\n",
+        u32::from_ne_bytes([192, 168, 0, 0]),
+        u32::from_ne_bytes([255, 255, 255, 0]),
+        u32::from_ne_bytes([192, 168, 0, 1]),
+    );
+    assert_eq!(
+        parse_default_gateway(&routes),
+        Some("192.168.0.1".parse().unwrap())
+    );
+
+    let without_default_route: String = routes.lines().take(2).collect::<Vec<_>>().join("\n");
+    assert_eq!(parse_default_gateway(&without_default_route), None);
+}
+
+/// Returns an agent that gathered the candidate of a mapping with the given lifetime, and the
+/// receiver of the candidates it removes.
+async fn gather_port_mapping_candidate(
+    host_net: Arc<net::Net>,
+    lifetime: Duration,
+) -> Result<
+    (
+        Agent,
+        mpsc::UnboundedReceiver<Arc<dyn Candidate + Send + Sync>>,
+    ),
+    Error,
+> {
+    let a = Agent::new(AgentConfig {
+        network_types: vec![NetworkType::Udp4],
+        candidate_types: vec![CandidateType::ServerReflexive],
+        port_mapping: Some(PortMappingConfig {
+            gateway: Some(GATEWAY_IP.parse().unwrap()),
+            lifetime: Some(lifetime),
+        }),
+        net: Some(host_net),
+        ..Default::default()
+    })
+    .await?;
+
+    let (removed_tx, removed_rx) = mpsc::unbounded_channel();
+    a.on_candidate_removed(Box::new(move |c: Arc<dyn Candidate + Send + Sync>| {
+        let _ = removed_tx.send(c);
+        Box::pin(async {})
+    }))
+    .await;
+
+    gather_and_wait(&a).await?;
+
+    Ok((a, removed_rx))
+}
+
+#[tokio::test]
+async fn test_agent_port_mapping_candidate() -> Result<(), Error> {
+    let (host_net, gateway_net) = build_lan().await?;
+    let mut requests_rx = start_gateway(gateway_net, true).await?;
+
+    let (a, _) = gather_port_mapping_candidate(host_net, Duration::from_secs(600)).await?;
+
+    let candidates = a.get_local_candidates().await?;
+    assert_eq!(candidates.len(), 1, "the mapped address must be advertised");
+    let c = &candidates[0];
+    assert_eq!(c.candidate_type(), CandidateType::ServerReflexive);
+    assert_eq!(c.address(), EXTERNAL_IP);
+    let related_address = c.related_address().unwrap();
+    assert_eq!(related_address.address, "10.0.0.1");
+    assert_eq!(c.port(), related_address.port + EXTERNAL_PORT_OFFSET);
+    let request = requests_rx.recv().await.unwrap();
+    assert_eq!(requested_pcp_lifetime(&request), 600);
+
+    a.close().await?;
+
+    let request = tokio::time::timeout(Duration::from_secs(3), requests_rx.recv())
+        .await
+        .expect("the mapping should have been released")
+        .unwrap();
+    assert_eq!(requested_pcp_lifetime(&request), 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_agent_port_mapping_candidate_moves() -> Result<(), Error> {
+    let (host_net, gateway_net) = build_lan().await?;
+    let _requests_rx = start_gateway_with(gateway_net, true, |i| {
+        Some(
+            if i == 0 { EXTERNAL_IP } else { "5.6.7.8" }
+                .parse()
+                .unwrap(),
+        )
+    })
+    .await?;
+
+    let (a, mut removed_rx) =
+        gather_port_mapping_candidate(host_net, Duration::from_secs(2)).await?;
+    let mapped = a.get_local_candidates().await?[0].clone();
+
+    // the renewal halfway through the lifetime finds the port mapped to another address
+    let removed = tokio::time::timeout(Duration::from_secs(5), removed_rx.recv())
+        .await
+        .expect("the candidate of the old address should have been removed")
+        .unwrap();
+    assert!(removed.equal(&*mapped));
+
+    let candidates = a.get_local_candidates().await?;
+    assert_eq!(candidates.len(), 1, "the new address must be advertised");
+    assert_eq!(candidates[0].address(), "5.6.7.8");
+    assert_eq!(candidates[0].port(), mapped.port());
+    assert_eq!(candidates[0].related_address(), mapped.related_address());
+
+    a.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_agent_port_mapping_renewal_retried() -> Result<(), Error> {
+    let (host_net, gateway_net) = build_lan().await?;
+    // the first renewal and its retransmissions get lost
+    let mut requests_rx = start_gateway_with(gateway_net, true, |i| {
+        if (1..=3).contains(&i) {
+            None
+        } else {
+            Some(EXTERNAL_IP.parse().unwrap())
+        }
+    })
+    .await?;
+
+    let lifetime = Duration::from_secs(8);
+    let gathered_at = Instant::now();
+    let (a, mut removed_rx) = gather_port_mapping_candidate(host_net, lifetime).await?;
+    let _ = requests_rx.recv().await;
+
+    let renewal = tokio::time::timeout(lifetime, requests_rx.recv())
+        .await
+        .expect("the renewal should have been retried before the mapping expired")
+        .unwrap();
+    assert_eq!(requested_pcp_lifetime(&renewal), 8);
+    assert!(gathered_at.elapsed() < lifetime);
+    assert!(removed_rx.try_recv().is_err(), "the candidate must stay");
+    assert_eq!(a.get_local_candidates().await?.len(), 1);
+
+    a.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_agent_port_mapping_expires() -> Result<(), Error> {
+    let (host_net, gateway_net) = build_lan().await?;
+    let _requests_rx = start_gateway_with(gateway_net, true, |i| {
+        if i == 0 {
+            Some(EXTERNAL_IP.parse().unwrap())
+        } else {
+            None
+        }
+    })
+    .await?;
+
+    let (a, mut removed_rx) =
+        gather_port_mapping_candidate(host_net, Duration::from_secs(4)).await?;
+
+    tokio::time::timeout(Duration::from_secs(10), removed_rx.recv())
+        .await
+        .expect("the candidate should have been removed once the mapping expired")
+        .unwrap();
+    assert!(a.get_local_candidates().await?.is_empty());
+
+    a.close().await?;
+
+    Ok(())
+}
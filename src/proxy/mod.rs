@@ -0,0 +1,218 @@
+#[cfg(test)]
+mod proxy_test;
+
+use crate::errors::*;
+
+use async_trait::async_trait;
+use std::convert::TryFrom;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use util::Error;
+
+const MAX_HTTP_RESPONSE_HEADER_SIZE: usize = 8192;
+
+const SOCKS5_VERSION: u8 = 5;
+const SOCKS5_AUTH_NONE: u8 = 0;
+const SOCKS5_AUTH_USERNAME_PASSWORD: u8 = 2;
+const SOCKS5_AUTH_NO_ACCEPTABLE_METHODS: u8 = 0xFF;
+const SOCKS5_USERNAME_PASSWORD_VERSION: u8 = 1;
+const SOCKS5_CMD_CONNECT: u8 = 1;
+const SOCKS5_ATYP_IPV4: u8 = 1;
+const SOCKS5_ATYP_DOMAIN: u8 = 3;
+const SOCKS5_ATYP_IPV6: u8 = 4;
+const SOCKS5_REPLY_SUCCEEDED: u8 = 0;
+
+/// Opens TCP connections through a proxy, for networks where only the proxy can reach the
+/// outside. The agent dials TURN servers over TCP and TLS with it.
+#[async_trait]
+pub trait ProxyDialer {
+    /// Returns a connection to `host` and `port` tunneled through the proxy. The host is left for
+    /// the proxy to resolve when it is a name.
+    async fn dial(&self, host: &str, port: u16) -> Result<TcpStream, Error>;
+}
+
+/// Tunnels connections through an HTTP proxy with the CONNECT method (RFC 9110 section 9.3.6).
+#[derive(Debug, Clone)]
+pub struct HttpConnectProxy {
+    pub addr: SocketAddr,
+    /// The username of the basic authentication to the proxy, none when it is empty.
+    pub username: String,
+    pub password: String,
+}
+
+#[async_trait]
+impl ProxyDialer for HttpConnectProxy {
+    async fn dial(&self, host: &str, port: u16) -> Result<TcpStream, Error> {
+        let mut stream = TcpStream::connect(self.addr).await?;
+
+        let authority = authority(host, port);
+        let proxy_authorization = if self.username.is_empty() {
+            String::new()
+        } else {
+            let token = base64::encode(format!("{}:{}", self.username, self.password));
+            format!("Proxy-Authorization: Basic {}\r\n", token)
+        };
+        let request = format!(
+            "CONNECT {} HTTP/1.1\r\nHost: {}\r\n{}\r\n",
+            authority, authority, proxy_authorization
+        );
+        stream.write_all(request.as_bytes()).await?;
+
+        // read byte by byte, whatever follows the header already belongs to the tunnel
+        let mut header = Vec::with_capacity(256);
+        while !header.ends_with(b"\r\n\r\n") {
+            if header.len() >= MAX_HTTP_RESPONSE_HEADER_SIZE {
+                return Err(ERR_PROXY_MALFORMED_RESPONSE.to_owned());
+            }
+            header.push(stream.read_u8().await?);
+        }
+
+        match parse_http_status(&header)? {
+            200..=299 => Ok(stream),
+            407 => Err(ERR_PROXY_AUTH_FAILED.to_owned()),
+            status => Err(Error::new(format!(
+                "{}: HTTP status {}",
+                *ERR_PROXY_REJECTED, status
+            ))),
+        }
+    }
+}
+
+fn authority(host: &str, port: u16) -> String {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.parse::<Ipv6Addr>().is_ok() {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+fn parse_http_status(header: &[u8]) -> Result<u16, Error> {
+    let header =
+        std::str::from_utf8(header).map_err(|_| ERR_PROXY_MALFORMED_RESPONSE.to_owned())?;
+    let status_line = header.lines().next().unwrap_or_default();
+    let mut fields = status_line.split_whitespace();
+    match (fields.next(), fields.next()) {
+        (Some(version), Some(status)) if version.starts_with("HTTP/1.") => status
+            .parse()
+            .map_err(|_| ERR_PROXY_MALFORMED_RESPONSE.to_owned()),
+        _ => Err(ERR_PROXY_MALFORMED_RESPONSE.to_owned()),
+    }
+}
+
+/// Tunnels connections through a SOCKS5 proxy (RFC 1928), with the username and password
+/// authentication of RFC 1929.
+#[derive(Debug, Clone)]
+pub struct Socks5Proxy {
+    pub addr: SocketAddr,
+    /// The username to authenticate to the proxy with, none when it is empty.
+    pub username: String,
+    pub password: String,
+}
+
+#[async_trait]
+impl ProxyDialer for Socks5Proxy {
+    async fn dial(&self, host: &str, port: u16) -> Result<TcpStream, Error> {
+        let mut stream = TcpStream::connect(self.addr).await?;
+
+        if self.username.is_empty() {
+            stream
+                .write_all(&[SOCKS5_VERSION, 1, SOCKS5_AUTH_NONE])
+                .await?;
+        } else {
+            stream
+                .write_all(&[
+                    SOCKS5_VERSION,
+                    2,
+                    SOCKS5_AUTH_NONE,
+                    SOCKS5_AUTH_USERNAME_PASSWORD,
+                ])
+                .await?;
+        }
+        let mut choice = [0u8; 2];
+        stream.read_exact(&mut choice).await?;
+        if choice[0] != SOCKS5_VERSION {
+            return Err(ERR_PROXY_MALFORMED_RESPONSE.to_owned());
+        }
+        match choice[1] {
+            SOCKS5_AUTH_NONE => {}
+            SOCKS5_AUTH_USERNAME_PASSWORD if !self.username.is_empty() => {
+                self.authenticate(&mut stream).await?;
+            }
+            SOCKS5_AUTH_NO_ACCEPTABLE_METHODS => return Err(ERR_PROXY_AUTH_FAILED.to_owned()),
+            _ => return Err(ERR_PROXY_MALFORMED_RESPONSE.to_owned()),
+        }
+
+        // an IPv6 literal may come in brackets, as it is written next to a port
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let mut request = vec![SOCKS5_VERSION, SOCKS5_CMD_CONNECT, 0];
+        match host.parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => {
+                request.push(SOCKS5_ATYP_IPV4);
+                request.extend_from_slice(&ip.octets());
+            }
+            Ok(IpAddr::V6(ip)) => {
+                request.push(SOCKS5_ATYP_IPV6);
+                request.extend_from_slice(&ip.octets());
+            }
+            Err(_) => {
+                let len = u8::try_from(host.len()).map_err(|_| ERR_HOST.to_owned())?;
+                request.push(SOCKS5_ATYP_DOMAIN);
+                request.push(len);
+                request.extend_from_slice(host.as_bytes());
+            }
+        }
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request).await?;
+
+        let mut reply = [0u8; 4];
+        stream.read_exact(&mut reply).await?;
+        if reply[0] != SOCKS5_VERSION {
+            return Err(ERR_PROXY_MALFORMED_RESPONSE.to_owned());
+        }
+        if reply[1] != SOCKS5_REPLY_SUCCEEDED {
+            return Err(Error::new(format!(
+                "{}: SOCKS5 reply {}",
+                *ERR_PROXY_REJECTED, reply[1]
+            )));
+        }
+        // the bound address isn't needed, but it has to be read off the stream
+        let bound_addr_len = match reply[3] {
+            SOCKS5_ATYP_IPV4 => 4,
+            SOCKS5_ATYP_IPV6 => 16,
+            SOCKS5_ATYP_DOMAIN => usize::from(stream.read_u8().await?),
+            _ => return Err(ERR_PROXY_MALFORMED_RESPONSE.to_owned()),
+        };
+        let mut bound_addr = vec![0u8; bound_addr_len + 2];
+        stream.read_exact(&mut bound_addr).await?;
+
+        Ok(stream)
+    }
+}
+
+impl Socks5Proxy {
+    async fn authenticate(&self, stream: &mut TcpStream) -> Result<(), Error> {
+        let username_len = u8::try_from(self.username.len())
+            .map_err(|_| ERR_PROXY_CREDENTIALS_TOO_LONG.to_owned())?;
+        let password_len = u8::try_from(self.password.len())
+            .map_err(|_| ERR_PROXY_CREDENTIALS_TOO_LONG.to_owned())?;
+
+        let mut request = vec![SOCKS5_USERNAME_PASSWORD_VERSION, username_len];
+        request.extend_from_slice(self.username.as_bytes());
+        request.push(password_len);
+        request.extend_from_slice(self.password.as_bytes());
+        stream.write_all(&request).await?;
+
+        let mut status = [0u8; 2];
+        stream.read_exact(&mut status).await?;
+        if status[0] != SOCKS5_USERNAME_PASSWORD_VERSION {
+            return Err(ERR_PROXY_MALFORMED_RESPONSE.to_owned());
+        }
+        if status[1] != 0 {
+            return Err(ERR_PROXY_AUTH_FAILED.to_owned());
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,450 @@
+use super::*;
+use crate::agent::{
+    agent_config::AgentConfig, agent_test::gather_and_wait, agent_vnet_test::TestAuthHandler, Agent,
+};
+use crate::candidate::*;
+use crate::network_type::*;
+use crate::stream_conn::StreamConn;
+use crate::url::Url;
+
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use util::vnet::net;
+
+const PROXY_USERNAME: &str = "proxyuser";
+const PROXY_PASSWORD: &str = "proxypass";
+
+/// Stands in for an HTTP proxy that asks for `PROXY_USERNAME` and `PROXY_PASSWORD`, and returns
+/// its address along with the targets it connects to.
+async fn start_http_proxy() -> Result<(SocketAddr, mpsc::UnboundedReceiver<String>), Error> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let (targets_tx, targets_rx) = mpsc::unbounded_channel();
+
+    tokio::spawn(async move {
+        while let Ok((mut client, _)) = listener.accept().await {
+            let targets_tx = targets_tx.clone();
+            tokio::spawn(async move {
+                let mut header = vec![];
+                while !header.ends_with(b"\r\n\r\n") {
+                    header.push(client.read_u8().await?);
+                }
+                let header = String::from_utf8_lossy(&header).into_owned();
+                let target = header.split_whitespace().nth(1).unwrap_or_default();
+
+                let authorization = format!(
+                    "Proxy-Authorization: Basic {}\r\n",
+                    base64::encode(format!("{}:{}", PROXY_USERNAME, PROXY_PASSWORD))
+                );
+                if !header.contains(&authorization) {
+                    client
+                        .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n")
+                        .await?;
+                    return Ok(());
+                }
+
+                let _ = targets_tx.send(target.to_owned());
+                let mut server = match TcpStream::connect(target).await {
+                    Ok(server) => server,
+                    Err(_) => {
+                        client
+                            .write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n")
+                            .await?;
+                        return Ok(());
+                    }
+                };
+                client
+                    .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                    .await?;
+                tokio::io::copy_bidirectional(&mut client, &mut server).await?;
+
+                Ok::<(), Error>(())
+            });
+        }
+    });
+
+    Ok((addr, targets_rx))
+}
+
+/// Stands in for a SOCKS5 proxy, which asks for `PROXY_USERNAME` and `PROXY_PASSWORD` when
+/// `with_auth` is set, and returns its address along with the targets it connects to.
+async fn start_socks5_proxy(
+    with_auth: bool,
+) -> Result<(SocketAddr, mpsc::UnboundedReceiver<String>), Error> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let (targets_tx, targets_rx) = mpsc::unbounded_channel();
+
+    tokio::spawn(async move {
+        while let Ok((mut client, _)) = listener.accept().await {
+            let targets_tx = targets_tx.clone();
+            tokio::spawn(async move {
+                let mut greeting = [0u8; 2];
+                client.read_exact(&mut greeting).await?;
+                let mut methods = vec![0u8; usize::from(greeting[1])];
+                client.read_exact(&mut methods).await?;
+
+                let method = if with_auth {
+                    SOCKS5_AUTH_USERNAME_PASSWORD
+                } else {
+                    SOCKS5_AUTH_NONE
+                };
+                if !methods.contains(&method) {
+                    client
+                        .write_all(&[SOCKS5_VERSION, SOCKS5_AUTH_NO_ACCEPTABLE_METHODS])
+                        .await?;
+                    return Ok(());
+                }
+                client.write_all(&[SOCKS5_VERSION, method]).await?;
+
+                if with_auth {
+                    let _version = client.read_u8().await?;
+                    let mut username = vec![0u8; usize::from(client.read_u8().await?)];
+                    client.read_exact(&mut username).await?;
+                    let mut password = vec![0u8; usize::from(client.read_u8().await?)];
+                    client.read_exact(&mut password).await?;
+
+                    let authorized = username == PROXY_USERNAME.as_bytes()
+                        && password == PROXY_PASSWORD.as_bytes();
+                    client
+                        .write_all(&[SOCKS5_USERNAME_PASSWORD_VERSION, u8::from(!authorized)])
+                        .await?;
+                    if !authorized {
+                        return Ok(());
+                    }
+                }
+
+                let mut request = [0u8; 4];
+                client.read_exact(&mut request).await?;
+                let host = match request[3] {
+                    SOCKS5_ATYP_IPV4 => {
+                        let mut octets = [0u8; 4];
+                        client.read_exact(&mut octets).await?;
+                        Ipv4Addr::from(octets).to_string()
+                    }
+                    SOCKS5_ATYP_IPV6 => {
+                        let mut octets = [0u8; 16];
+                        client.read_exact(&mut octets).await?;
+                        Ipv6Addr::from(octets).to_string()
+                    }
+                    SOCKS5_ATYP_DOMAIN => {
+                        let mut name = vec![0u8; usize::from(client.read_u8().await?)];
+                        client.read_exact(&mut name).await?;
+                        String::from_utf8_lossy(&name).into_owned()
+                    }
+                    _ => return Ok(()),
+                };
+                let port = client.read_u16().await?;
+
+                // a name in brackets isn't an IPv6 address, it doesn't resolve
+                let _ = targets_tx.send(authority(&host, port));
+                let mut server = match TcpStream::connect((host.as_str(), port)).await {
+                    Ok(server) => server,
+                    Err(_) => {
+                        // connection refused
+                        client
+                            .write_all(&[SOCKS5_VERSION, 5, 0, SOCKS5_ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+                            .await?;
+                        return Ok(());
+                    }
+                };
+                let bound_addr = server.local_addr()?;
+                let mut reply = vec![
+                    SOCKS5_VERSION,
+                    SOCKS5_REPLY_SUCCEEDED,
+                    0,
+                    SOCKS5_ATYP_IPV4,
+                    127,
+                    0,
+                    0,
+                    1,
+                ];
+                reply.extend_from_slice(&bound_addr.port().to_be_bytes());
+                client.write_all(&reply).await?;
+                tokio::io::copy_bidirectional(&mut client, &mut server).await?;
+
+                Ok::<(), Error>(())
+            });
+        }
+    });
+
+    Ok((addr, targets_rx))
+}
+
+/// Echoes what it receives, and returns its address.
+async fn start_echo_server() -> Result<SocketAddr, Error> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+
+    Ok(addr)
+}
+
+async fn assert_echo(mut stream: TcpStream) -> Result<(), Error> {
+    stream.write_all(b"ping").await?;
+    let mut buf = [0u8; 4];
+    stream.read_exact(&mut buf).await?;
+    assert_eq!(&buf, b"ping");
+    Ok(())
+}
+
+/// Stands in for a TURN server accepting TURN over TCP on the loopback address `ip`, and returns
+/// its address.
+async fn start_turn_tcp_server(ip: IpAddr) -> Result<SocketAddr, Error> {
+    let listener = TcpListener::bind(SocketAddr::new(ip, 0)).await?;
+    let addr = listener.local_addr()?;
+
+    tokio::spawn(async move {
+        let mut servers = vec![];
+        while let Ok((stream, _)) = listener.accept().await {
+            let conn = match StreamConn::from_tcp(stream) {
+                Ok(conn) => conn,
+                Err(_) => continue,
+            };
+            let server = turn::server::Server::new(turn::server::config::ServerConfig {
+                conn_configs: vec![turn::server::config::ConnConfig {
+                    conn: Arc::new(conn),
+                    relay_addr_generator: Box::new(
+                        turn::relay::relay_static::RelayAddressGeneratorStatic {
+                            relay_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+                            address: "127.0.0.1".to_owned(),
+                            net: Arc::new(net::Net::new(None)),
+                        },
+                    ),
+                }],
+                realm: "webrtc.rs".to_owned(),
+                auth_handler: Arc::new(Box::new(TestAuthHandler::new())),
+                channel_bind_timeout: Duration::from_secs(0),
+            })
+            .await;
+            if let Ok(server) = server {
+                servers.push(server);
+            }
+        }
+    });
+
+    Ok(addr)
+}
+
+/// Gathers the relay candidates of a TURN over TCP server dialed with `proxy_dialer`.
+async fn gather_relay_candidates(
+    turn_server_addr: SocketAddr,
+    proxy_dialer: Arc<dyn ProxyDialer + Send + Sync>,
+) -> Result<Agent, Error> {
+    let mut url = Url::parse_url(&format!("turn:{}?transport=tcp", turn_server_addr))?;
+    url.username = "user".to_owned();
+    url.password = "pass".to_owned();
+
+    let a = Agent::new(AgentConfig {
+        urls: vec![url],
+        network_types: vec![NetworkType::Udp4],
+        candidate_types: vec![CandidateType::Relay],
+        proxy_dialer: Some(proxy_dialer),
+        ..Default::default()
+    })
+    .await?;
+
+    gather_and_wait(&a).await?;
+
+    Ok(a)
+}
+
+#[test]
+fn test_parse_http_status() -> Result<(), Error> {
+    assert_eq!(
+        parse_http_status(b"HTTP/1.1 200 Connection established\r\n\r\n")?,
+        200
+    );
+    assert_eq!(parse_http_status(b"HTTP/1.0 403 Forbidden\r\n\r\n")?, 403);
+    assert_eq!(
+        parse_http_status(b"SSH-2.0-OpenSSH\r\n\r\n").unwrap_err(),
+        *ERR_PROXY_MALFORMED_RESPONSE
+    );
+    assert_eq!(authority("::1", 3478), "[::1]:3478");
+    assert_eq!(authority("turn.example.com", 3478), "turn.example.com:3478");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_http_connect_proxy() -> Result<(), Error> {
+    let echo_addr = start_echo_server().await?;
+    let (proxy_addr, mut targets_rx) = start_http_proxy().await?;
+
+    let proxy = HttpConnectProxy {
+        addr: proxy_addr,
+        username: PROXY_USERNAME.to_owned(),
+        password: PROXY_PASSWORD.to_owned(),
+    };
+    let stream = proxy.dial("127.0.0.1", echo_addr.port()).await?;
+    assert_eq!(targets_rx.recv().await, Some(echo_addr.to_string()));
+    assert_echo(stream).await?;
+
+    let proxy = HttpConnectProxy {
+        password: "wrong".to_owned(),
+        ..proxy
+    };
+    assert_eq!(
+        proxy.dial("127.0.0.1", echo_addr.port()).await.unwrap_err(),
+        *ERR_PROXY_AUTH_FAILED
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_socks5_proxy() -> Result<(), Error> {
+    let echo_addr = start_echo_server().await?;
+
+    let (proxy_addr, mut targets_rx) = start_socks5_proxy(false).await?;
+    let proxy = Socks5Proxy {
+        addr: proxy_addr,
+        username: String::new(),
+        password: String::new(),
+    };
+    let stream = proxy.dial("127.0.0.1", echo_addr.port()).await?;
+    assert_eq!(targets_rx.recv().await, Some(echo_addr.to_string()));
+    assert_echo(stream).await?;
+
+    // names are left for the proxy to resolve
+    let stream = proxy.dial("localhost", echo_addr.port()).await?;
+    assert_eq!(
+        targets_rx.recv().await,
+        Some(format!("localhost:{}", echo_addr.port()))
+    );
+    assert_echo(stream).await?;
+
+    let (proxy_addr, _targets_rx) = start_socks5_proxy(true).await?;
+    let proxy = Socks5Proxy {
+        addr: proxy_addr,
+        username: PROXY_USERNAME.to_owned(),
+        password: PROXY_PASSWORD.to_owned(),
+    };
+    assert_echo(proxy.dial("127.0.0.1", echo_addr.port()).await?).await?;
+
+    let wrong_password = Socks5Proxy {
+        password: "wrong".to_owned(),
+        ..proxy.clone()
+    };
+    assert_eq!(
+        wrong_password
+            .dial("127.0.0.1", echo_addr.port())
+            .await
+            .unwrap_err(),
+        *ERR_PROXY_AUTH_FAILED
+    );
+    let no_credentials = Socks5Proxy {
+        username: String::new(),
+        ..proxy
+    };
+    assert_eq!(
+        no_credentials
+            .dial("127.0.0.1", echo_addr.port())
+            .await
+            .unwrap_err(),
+        *ERR_PROXY_AUTH_FAILED
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_agent_relay_through_http_connect_proxy() -> Result<(), Error> {
+    let turn_server_addr = start_turn_tcp_server(IpAddr::V4(Ipv4Addr::LOCALHOST)).await?;
+    let (proxy_addr, mut targets_rx) = start_http_proxy().await?;
+
+    let a = gather_relay_candidates(
+        turn_server_addr,
+        Arc::new(HttpConnectProxy {
+            addr: proxy_addr,
+            username: PROXY_USERNAME.to_owned(),
+            password: PROXY_PASSWORD.to_owned(),
+        }),
+    )
+    .await?;
+
+    assert_eq!(targets_rx.recv().await, Some(turn_server_addr.to_string()));
+    let candidates = a.get_local_candidates().await?;
+    assert_eq!(candidates.len(), 1, "there must be one relay candidate");
+    assert_eq!(candidates[0].candidate_type(), CandidateType::Relay);
+    assert_eq!(candidates[0].relay_protocol(), "tcp");
+
+    let url = candidates[0]
+        .url()
+        .expect("relay candidates know their TURN server");
+    assert!(url.username.is_empty() && url.password.is_empty());
+
+    let stats = a.get_local_candidates_stats().await;
+    assert_eq!(stats.len(), 1);
+    assert_eq!(stats[0].relay_protocol, "tcp");
+    assert_eq!(
+        stats[0].url,
+        format!("turn:{}?transport=tcp", turn_server_addr)
+    );
+
+    a.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_agent_relay_through_socks5_proxy() -> Result<(), Error> {
+    let turn_server_addr = start_turn_tcp_server(IpAddr::V4(Ipv4Addr::LOCALHOST)).await?;
+    let (proxy_addr, mut targets_rx) = start_socks5_proxy(true).await?;
+
+    let a = gather_relay_candidates(
+        turn_server_addr,
+        Arc::new(Socks5Proxy {
+            addr: proxy_addr,
+            username: PROXY_USERNAME.to_owned(),
+            password: PROXY_PASSWORD.to_owned(),
+        }),
+    )
+    .await?;
+
+    assert_eq!(targets_rx.recv().await, Some(turn_server_addr.to_string()));
+    let candidates = a.get_local_candidates().await?;
+    assert_eq!(candidates.len(), 1, "there must be one relay candidate");
+    assert_eq!(candidates[0].relay_protocol(), "tcp");
+
+    a.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_agent_relay_through_socks5_proxy_ipv6() -> Result<(), Error> {
+    let turn_server_addr = start_turn_tcp_server(IpAddr::V6(Ipv6Addr::LOCALHOST)).await?;
+    let (proxy_addr, mut targets_rx) = start_socks5_proxy(false).await?;
+    let proxy = Socks5Proxy {
+        addr: proxy_addr,
+        username: String::new(),
+        password: String::new(),
+    };
+
+    // a bracketed literal is sent as an IPv6 address, not as the name "[::1]"
+    proxy.dial("[::1]", turn_server_addr.port()).await?;
+    assert_eq!(targets_rx.recv().await, Some(turn_server_addr.to_string()));
+
+    let a = gather_relay_candidates(turn_server_addr, Arc::new(proxy)).await?;
+
+    assert_eq!(targets_rx.recv().await, Some(turn_server_addr.to_string()));
+    let candidates = a.get_local_candidates().await?;
+    assert_eq!(candidates.len(), 1, "there must be one relay candidate");
+    assert_eq!(candidates[0].relay_protocol(), "tcp");
+
+    a.close().await?;
+
+    Ok(())
+}
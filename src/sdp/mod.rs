@@ -0,0 +1,222 @@
+#[cfg(test)]
+mod sdp_test;
+
+use crate::agent::Agent;
+use crate::candidate::candidate_descriptor::CandidateDescriptor;
+use crate::errors::*;
+use crate::state::GatheringState;
+use crate::util::is_ice_char;
+
+use std::fmt;
+use std::sync::atomic::Ordering;
+use util::Error;
+
+const MIN_UFRAG_LEN: usize = 4;
+const MIN_PWD_LEN: usize = 22;
+const MAX_UFRAG_PWD_LEN: usize = 256;
+
+/// An ICE option of the `ice-options` attribute (RFC 8839 section 5.6).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IceOption {
+    /// Trickle ICE (RFC 8838): candidates may be sent as they are gathered.
+    Trickle,
+    /// The agent implements RFC 8445 rather than RFC 5245.
+    Ice2,
+    /// The controlling agent may nominate a pair again (draft-thatcher-ice-renomination).
+    Renomination,
+    Other(String),
+}
+
+impl From<&str> for IceOption {
+    fn from(raw: &str) -> Self {
+        match raw {
+            "trickle" => Self::Trickle,
+            "ice2" => Self::Ice2,
+            "renomination" => Self::Renomination,
+            _ => Self::Other(raw.to_owned()),
+        }
+    }
+}
+
+impl fmt::Display for IceOption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::Trickle => "trickle",
+            Self::Ice2 => "ice2",
+            Self::Renomination => "renomination",
+            Self::Other(option) => option,
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// The ICE attributes of an SDP media section (RFC 8839 section 5).
+///
+/// `ice-lite` is a session level attribute, `marshal_session` writes it for the session section
+/// and `marshal` leaves it out of the media section.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IceAttributes {
+    pub ufrag: String,
+    pub pwd: String,
+    pub options: Vec<IceOption>,
+    pub lite: bool,
+    pub candidates: Vec<CandidateDescriptor>,
+    /// Whether all the candidates have been sent (RFC 8838 section 8.2).
+    pub end_of_candidates: bool,
+}
+
+impl IceAttributes {
+    /// Returns the local attributes of `agent`, with the candidates gathered so far. Trickle ICE is
+    /// the only option, the agent always trickles its candidates.
+    pub async fn from_agent(agent: &Agent) -> Result<Self, Error> {
+        let (ufrag, pwd) = agent.get_local_user_credentials().await;
+        let lite = agent.agent_internal.lock().await.lite;
+        // read before the candidates, so that none are left out when it says gathering is over
+        let end_of_candidates = GatheringState::from(agent.gathering_state.load(Ordering::SeqCst))
+            == GatheringState::Complete;
+        let candidates = agent
+            .get_local_candidates()
+            .await?
+            .iter()
+            .map(|c| CandidateDescriptor::from_candidate(c.as_ref()))
+            .collect();
+
+        Ok(Self {
+            ufrag,
+            pwd,
+            options: vec![IceOption::Trickle],
+            lite,
+            candidates,
+            end_of_candidates,
+        })
+    }
+
+    /// Returns the session level attribute lines, each ending with CRLF: `a=ice-lite` for a lite
+    /// agent, nothing otherwise.
+    #[must_use]
+    pub fn marshal_session(&self) -> String {
+        if self.lite {
+            "a=ice-lite\r\n".to_owned()
+        } else {
+            String::new()
+        }
+    }
+
+    /// Returns the media level attribute lines, each ending with CRLF.
+    pub fn marshal(&self) -> Result<String, Error> {
+        validate_credentials(&self.ufrag, &self.pwd)?;
+
+        let mut lines = vec![
+            format!("a=ice-ufrag:{}", self.ufrag),
+            format!("a=ice-pwd:{}", self.pwd),
+        ];
+        if !self.options.is_empty() {
+            let options: Vec<String> = self.options.iter().map(ToString::to_string).collect();
+            lines.push(format!("a=ice-options:{}", options.join(" ")));
+        }
+        for candidate in &self.candidates {
+            lines.push(format!("a=candidate:{}", candidate.marshal()));
+        }
+        if self.end_of_candidates {
+            lines.push("a=end-of-candidates".to_owned());
+        }
+
+        let mut sdp = lines.join("\r\n");
+        sdp.push_str("\r\n");
+        Ok(sdp)
+    }
+
+    /// Parses the ICE attributes out of SDP lines, ignoring the other ones. To take the session
+    /// level attributes into account, pass the session section before the media section, the media
+    /// level values win.
+    pub fn unmarshal(sdp: &str) -> Result<Self, Error> {
+        let mut attributes = Self::default();
+        let mut ufrag = None;
+        let mut pwd = None;
+
+        for line in sdp.lines() {
+            let attribute = match line.trim_end_matches('\r').strip_prefix("a=") {
+                Some(attribute) => attribute,
+                None => continue,
+            };
+            let (name, value) = attribute.split_once(':').unwrap_or((attribute, ""));
+            match name {
+                "ice-ufrag" => ufrag = Some(value.to_owned()),
+                "ice-pwd" => pwd = Some(value.to_owned()),
+                "ice-options" => {
+                    // media level options replace the session level ones
+                    attributes.options = value.split_whitespace().map(IceOption::from).collect();
+                }
+                "ice-lite" => attributes.lite = true,
+                "candidate" => attributes
+                    .candidates
+                    .push(CandidateDescriptor::unmarshal(value)?),
+                "end-of-candidates" => attributes.end_of_candidates = true,
+                _ => {}
+            }
+        }
+
+        attributes.ufrag = ufrag.ok_or_else(|| ERR_MISSING_ICE_UFRAG.to_owned())?;
+        attributes.pwd = pwd.ok_or_else(|| ERR_MISSING_ICE_PWD.to_owned())?;
+        validate_credentials(&attributes.ufrag, &attributes.pwd)?;
+
+        Ok(attributes)
+    }
+
+    /// Whether `option` is in the `ice-options`.
+    #[must_use]
+    pub fn has_option(&self, option: &IceOption) -> bool {
+        self.options.contains(option)
+    }
+}
+
+/// Checks a username fragment and a password against the grammar of RFC 8839 section 5.4.
+pub fn validate_credentials(ufrag: &str, pwd: &str) -> Result<(), Error> {
+    if !(MIN_UFRAG_LEN..=MAX_UFRAG_PWD_LEN).contains(&ufrag.len())
+        || !ufrag.chars().all(is_ice_char)
+    {
+        return Err(Error::new(format!(
+            "{}: {:?}",
+            *ERR_INVALID_ICE_UFRAG, ufrag
+        )));
+    }
+    if !(MIN_PWD_LEN..=MAX_UFRAG_PWD_LEN).contains(&pwd.len()) || !pwd.chars().all(is_ice_char) {
+        return Err(ERR_INVALID_ICE_PWD.to_owned());
+    }
+    Ok(())
+}
+
+/// The outcome of the ICE offer/answer exchange. `Agent::connect` starts the connectivity checks
+/// in the negotiated role.
+///
+/// Whether to trickle the candidates is up to the signaling, which can check
+/// `IceOption::Trickle` in the attributes of both sides.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IceNegotiation {
+    pub ice2: bool,
+    pub remote_lite: bool,
+    /// Whether the local agent takes the controlling role or the controlled one.
+    pub is_controlling: bool,
+}
+
+/// Negotiates the options both sides support and the roles (RFC 8445 section 6.1.1): a full
+/// agent controls a lite one, otherwise the offerer controls.
+#[must_use]
+pub fn negotiate(
+    local: &IceAttributes,
+    remote: &IceAttributes,
+    is_offerer: bool,
+) -> IceNegotiation {
+    let both = |option: IceOption| local.has_option(&option) && remote.has_option(&option);
+    let is_controlling = if local.lite == remote.lite {
+        is_offerer
+    } else {
+        remote.lite
+    };
+
+    IceNegotiation {
+        ice2: both(IceOption::Ice2),
+        remote_lite: remote.lite,
+        is_controlling,
+    }
+}
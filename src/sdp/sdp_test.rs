@@ -0,0 +1,184 @@
+use super::*;
+use crate::agent::agent_config::AgentConfig;
+use crate::candidate::CandidateType;
+
+const UFRAG: &str = "8hhY";
+const PWD: &str = "asd88fgpdd777uzjYhagZg";
+
+fn attributes(options: Vec<IceOption>, lite: bool) -> IceAttributes {
+    IceAttributes {
+        ufrag: UFRAG.to_owned(),
+        pwd: PWD.to_owned(),
+        options,
+        lite,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_ice_attributes_marshal() -> Result<(), Error> {
+    let attributes = IceAttributes {
+        options: vec![IceOption::Trickle, IceOption::Ice2],
+        candidates: vec![CandidateDescriptor::unmarshal(
+            "1 1 udp 2130706431 192.168.1.2 5000 typ host",
+        )?],
+        end_of_candidates: true,
+        ..attributes(vec![], true)
+    };
+
+    // ice-lite only goes in the session section
+    let session = attributes.marshal_session();
+    assert_eq!(session, "a=ice-lite\r\n");
+    let marshaled = attributes.marshal()?;
+    assert_eq!(
+        marshaled,
+        "a=ice-ufrag:8hhY\r\n\
+         a=ice-pwd:asd88fgpdd777uzjYhagZg\r\n\
+         a=ice-options:trickle ice2\r\n\
+         a=candidate:1 1 udp 2130706431 192.168.1.2 5000 typ host\r\n\
+         a=end-of-candidates\r\n"
+    );
+    assert_eq!(
+        IceAttributes::unmarshal(&format!("{}{}", session, marshaled))?,
+        attributes
+    );
+    assert!(!IceAttributes::unmarshal(&marshaled)?.lite);
+    assert_eq!(
+        IceAttributes {
+            lite: false,
+            ..attributes
+        }
+        .marshal_session(),
+        ""
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_ice_attributes_unmarshal() -> Result<(), Error> {
+    let sdp = "v=0\r\n\
+               o=- 0 0 IN IP4 127.0.0.1\r\n\
+               s=-\r\n\
+               a=ice-lite\r\n\
+               a=ice-ufrag:sess\r\n\
+               a=ice-pwd:sessionpasswordsession\r\n\
+               a=ice-options:ice2\r\n\
+               m=audio 9 UDP/TLS/RTP/SAVPF 111\r\n\
+               a=ice-ufrag:8hhY\r\n\
+               a=ice-pwd:asd88fgpdd777uzjYhagZg\r\n\
+               a=ice-options:trickle renomination x-future\r\n\
+               a=mid:0\r\n\
+               a=candidate:1 1 UDP 2130706431 192.168.1.2 5000 typ host\r\n";
+
+    let attributes = IceAttributes::unmarshal(sdp)?;
+    assert_eq!(attributes.ufrag, UFRAG);
+    assert_eq!(attributes.pwd, PWD);
+    assert!(attributes.lite);
+    assert_eq!(
+        attributes.options,
+        vec![
+            IceOption::Trickle,
+            IceOption::Renomination,
+            IceOption::Other("x-future".to_owned())
+        ]
+    );
+    assert_eq!(attributes.candidates.len(), 1);
+    assert_eq!(attributes.candidates[0].transport, "udp");
+    assert!(!attributes.end_of_candidates);
+
+    Ok(())
+}
+
+#[test]
+fn test_ice_attributes_validation() {
+    let tests = vec![
+        (
+            "a=ice-pwd:asd88fgpdd777uzjYhagZg\r\n",
+            &*ERR_MISSING_ICE_UFRAG,
+        ),
+        ("a=ice-ufrag:8hhY\r\n", &*ERR_MISSING_ICE_PWD),
+        (
+            "a=ice-ufrag:8hh\r\na=ice-pwd:asd88fgpdd777uzjYhagZg\r\n",
+            &*ERR_INVALID_ICE_UFRAG,
+        ),
+        (
+            "a=ice-ufrag:8hh-Y\r\na=ice-pwd:asd88fgpdd777uzjYhagZg\r\n",
+            &*ERR_INVALID_ICE_UFRAG,
+        ),
+        (
+            "a=ice-ufrag:8hhY\r\na=ice-pwd:asd88fgpdd777uzjYhag\r\n",
+            &*ERR_INVALID_ICE_PWD,
+        ),
+        (
+            "a=ice-ufrag:8hhY\r\na=ice-pwd:asd88fgpdd777uzjYhagZ=\r\n",
+            &*ERR_INVALID_ICE_PWD,
+        ),
+    ];
+
+    for (sdp, expected) in tests {
+        let err = IceAttributes::unmarshal(sdp).unwrap_err();
+        assert!(
+            err.to_string().starts_with(&expected.to_string()),
+            "{:?}: {}",
+            sdp,
+            err
+        );
+    }
+
+    let invalid = IceAttributes {
+        ufrag: "8hh".to_owned(),
+        ..attributes(vec![], false)
+    };
+    assert!(invalid.marshal().is_err());
+}
+
+#[test]
+fn test_negotiate() {
+    let full = attributes(vec![IceOption::Trickle, IceOption::Ice2], false);
+    let lite = attributes(vec![IceOption::Ice2], true);
+
+    let negotiation = negotiate(&full, &full, true);
+    assert_eq!(
+        negotiation,
+        IceNegotiation {
+            ice2: true,
+            remote_lite: false,
+            is_controlling: true,
+        }
+    );
+    assert!(!negotiate(&full, &full, false).is_controlling);
+
+    // a full agent controls a lite one, whoever offers
+    let negotiation = negotiate(&full, &lite, false);
+    assert!(negotiation.ice2);
+    assert!(negotiation.remote_lite);
+    assert!(negotiation.is_controlling);
+    assert!(!negotiate(&lite, &full, true).is_controlling);
+    assert!(negotiate(&lite, &lite, true).is_controlling);
+}
+
+#[tokio::test]
+async fn test_ice_attributes_from_agent() -> Result<(), Error> {
+    let a = Agent::new(AgentConfig {
+        local_ufrag: UFRAG.to_owned(),
+        local_pwd: PWD.to_owned(),
+        lite: true,
+        candidate_types: vec![CandidateType::Host],
+        ..Default::default()
+    })
+    .await?;
+
+    let attributes = IceAttributes::from_agent(&a).await?;
+    assert_eq!(attributes.ufrag, UFRAG);
+    assert_eq!(attributes.pwd, PWD);
+    assert!(attributes.lite);
+    assert_eq!(attributes.options, vec![IceOption::Trickle]);
+    assert!(attributes.candidates.is_empty());
+    assert!(!attributes.end_of_candidates, "gathering hasn't started");
+    attributes.marshal()?;
+
+    a.close().await?;
+
+    Ok(())
+}
@@ -0,0 +1,40 @@
+#[cfg(test)]
+mod socket_factory_test;
+
+use async_trait::async_trait;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use util::{vnet::net::*, Conn, Error};
+
+/// Creates the sockets of host candidates.
+///
+/// Implement it to hand the agent sockets that are owned by the application, e.g. bound by a
+/// privileged helper, configured with special socket options or shared with another protocol.
+#[async_trait]
+pub trait SocketFactory {
+    /// Returns a UDP socket bound to `laddr`. The port is zero when any port will do; otherwise
+    /// the agent tries the ports of its configured range one after another, so an error should be
+    /// returned if the port is not available.
+    async fn bind_udp(&self, laddr: SocketAddr) -> Result<Arc<dyn Conn + Send + Sync>, Error>;
+}
+
+/// Binds sockets through a `Net`, which is either the host network or a virtual network.
+///
+/// This is what the agent uses unless a `SocketFactory` is configured.
+pub struct NetSocketFactory {
+    net: Arc<Net>,
+}
+
+impl NetSocketFactory {
+    #[must_use]
+    pub const fn new(net: Arc<Net>) -> Self {
+        Self { net }
+    }
+}
+
+#[async_trait]
+impl SocketFactory for NetSocketFactory {
+    async fn bind_udp(&self, laddr: SocketAddr) -> Result<Arc<dyn Conn + Send + Sync>, Error> {
+        self.net.bind(laddr).await
+    }
+}
@@ -0,0 +1,38 @@
+use super::*;
+
+use util::vnet::router::*;
+
+use std::net::IpAddr;
+use tokio::sync::Mutex;
+
+#[tokio::test]
+async fn test_net_socket_factory_binds_on_vnet() -> Result<(), Error> {
+    let r = Arc::new(Mutex::new(Router::new(RouterConfig {
+        cidr: "1.2.3.0/24".to_owned(),
+        ..Default::default()
+    })?));
+    let nw = Arc::new(Net::new(Some(NetConfig {
+        static_ips: vec!["1.2.3.4".to_owned()],
+        ..Default::default()
+    })));
+    {
+        let nic = nw.get_nic()?;
+        let mut w = r.lock().await;
+        w.add_net(Arc::clone(&nic)).await?;
+        let n = nic.lock().await;
+        n.set_router(Arc::clone(&r)).await?;
+    }
+
+    let ip: IpAddr = "1.2.3.4".parse().unwrap();
+    let factory = NetSocketFactory::new(Arc::clone(&nw));
+
+    let conn = factory.bind_udp(SocketAddr::new(ip, 5000)).await?;
+    assert_eq!(conn.local_addr().await?, SocketAddr::new(ip, 5000));
+
+    assert!(
+        factory.bind_udp(SocketAddr::new(ip, 5000)).await.is_err(),
+        "the port should be in use"
+    );
+
+    Ok(())
+}
@@ -1,10 +1,17 @@
 #[cfg(test)]
 mod state_test;
 
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 use std::fmt;
 
 /// An enum showing the state of a ICE Connection List of supported States.
-#[derive(Debug, Copy, Clone, PartialEq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "lowercase")
+)]
 pub enum ConnectionState {
     Unspecified,
 
@@ -53,7 +60,12 @@ impl fmt::Display for ConnectionState {
 }
 
 /// Describes the state of the candidate gathering process.
-#[derive(PartialEq, Copy, Clone)]
+#[derive(Debug, PartialEq, Copy, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "lowercase")
+)]
 pub enum GatheringState {
     Unspecified,
 
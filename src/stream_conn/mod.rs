@@ -0,0 +1,230 @@
+#[cfg(test)]
+mod stream_conn_test;
+
+use crate::errors::*;
+
+use util::{Conn, Error};
+
+use async_trait::async_trait;
+use std::convert::TryFrom;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio_rustls::rustls;
+
+const STUN_HEADER_SIZE: usize = 20;
+const CHANNEL_DATA_HEADER_SIZE: usize = 4;
+const READ_CHUNK_SIZE: usize = 2048;
+
+/// Returns the size of the first STUN message or `ChannelData` message in `buf`, or `None` if
+/// not enough bytes have been buffered yet to tell.
+///
+/// Over TCP, `ChannelData` messages are padded to a multiple of four bytes (RFC 5766 §11.5), so
+/// the returned size includes the padding while the second value is the unpadded size.
+fn frame_size(buf: &[u8]) -> Option<(usize, usize)> {
+    if buf.len() < CHANNEL_DATA_HEADER_SIZE {
+        return None;
+    }
+
+    let length = usize::from(u16::from_be_bytes([buf[2], buf[3]]));
+    if buf[0] & 0xC0 == 0x40 {
+        // ChannelData: the first two bits are 0b01.
+        let size = CHANNEL_DATA_HEADER_SIZE + length;
+        Some(((size + 3) & !3, size))
+    } else {
+        let size = STUN_HEADER_SIZE + length;
+        Some((size, size))
+    }
+}
+
+trait AsyncStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> AsyncStream for T {}
+
+struct StreamReader {
+    reader: ReadHalf<Box<dyn AsyncStream>>,
+    buf: Vec<u8>,
+}
+
+/// Carries STUN and TURN messages over a connected stream (TCP or TLS) and exposes them through
+/// the datagram oriented `util::Conn` trait, one message per read.
+pub struct StreamConn {
+    reader: Mutex<StreamReader>,
+    writer: Mutex<WriteHalf<Box<dyn AsyncStream>>>,
+    local_addr: SocketAddr,
+    remote_addr: SocketAddr,
+}
+
+impl StreamConn {
+    fn new<S>(stream: S, local_addr: SocketAddr, remote_addr: SocketAddr) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let stream: Box<dyn AsyncStream> = Box::new(stream);
+        let (reader, writer) = tokio::io::split(stream);
+        Self {
+            reader: Mutex::new(StreamReader {
+                reader,
+                buf: vec![],
+            }),
+            writer: Mutex::new(writer),
+            local_addr,
+            remote_addr,
+        }
+    }
+
+    /// Wraps an already established TCP connection.
+    pub fn from_tcp(stream: TcpStream) -> Result<Self, Error> {
+        let (local_addr, remote_addr) = (stream.local_addr()?, stream.peer_addr()?);
+        Ok(Self::new(stream, local_addr, remote_addr))
+    }
+
+    /// Upgrades an already established TCP connection to TLS, verifying the server certificate
+    /// against `server_name` unless `insecure_skip_verify` is set.
+    pub async fn from_tcp_with_tls(
+        stream: TcpStream,
+        server_name: &str,
+        insecure_skip_verify: bool,
+    ) -> Result<Self, Error> {
+        let (local_addr, remote_addr) = (stream.local_addr()?, stream.peer_addr()?);
+
+        let server_name = match rustls::ServerName::try_from(server_name) {
+            Ok(server_name) => server_name,
+            Err(err) => return Err(Error::new(format!("{}: {}", *ERR_HOST, err))),
+        };
+        let connector = tokio_rustls::TlsConnector::from(tls_client_config(insecure_skip_verify));
+        let stream = connector.connect(server_name, stream).await?;
+
+        Ok(Self::new(stream, local_addr, remote_addr))
+    }
+
+    /// Returns the address of the other end of the stream, which is the proxy for tunneled ones.
+    pub(crate) const fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+
+    async fn read_frame(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut r = self.reader.lock().await;
+        loop {
+            if let Some((size, unpadded)) = frame_size(&r.buf) {
+                if r.buf.len() >= size {
+                    if buf.len() < unpadded {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            ERR_SHORT_BUFFER.to_string(),
+                        ));
+                    }
+                    buf[..unpadded].copy_from_slice(&r.buf[..unpadded]);
+                    r.buf.drain(..size);
+                    return Ok(unpadded);
+                }
+            }
+
+            // read into a local chunk first, so that a cancelled read never loses buffered bytes
+            let mut chunk = [0_u8; READ_CHUNK_SIZE];
+            let n = r.reader.read(&mut chunk).await?;
+            if n == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    ERR_CLOSED_STREAM.to_string(),
+                ));
+            }
+            r.buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    async fn write_frame(&self, buf: &[u8]) -> io::Result<usize> {
+        let mut w = self.writer.lock().await;
+        w.write_all(buf).await?;
+
+        if let Some((size, unpadded)) = frame_size(buf) {
+            if unpadded == buf.len() && size > unpadded {
+                w.write_all(&[0_u8; 3][..size - unpadded]).await?;
+            }
+        }
+        w.flush().await?;
+
+        Ok(buf.len())
+    }
+}
+
+#[async_trait]
+impl Conn for StreamConn {
+    async fn connect(&self, _addr: SocketAddr) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Other, "Not applicable"))
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_frame(buf).await
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let n = self.read_frame(buf).await?;
+        Ok((n, self.remote_addr))
+    }
+
+    async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.write_frame(buf).await
+    }
+
+    /// The stream is already connected, so `target` is only checked for logging purposes.
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        if target != self.remote_addr {
+            log::trace!(
+                "stream conn to {} ignores send_to target {}",
+                self.remote_addr,
+                target
+            );
+        }
+        self.write_frame(buf).await
+    }
+
+    async fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+}
+
+/// Accepts any server certificate, used when `insecure_skip_verify` is set.
+struct SkipServerVerification;
+
+impl rustls::client::ServerCertVerifier for SkipServerVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::ServerCertVerified::assertion())
+    }
+}
+
+fn tls_client_config(insecure_skip_verify: bool) -> Arc<rustls::ClientConfig> {
+    let mut root_store = rustls::RootCertStore::empty();
+    root_store.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+        rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+
+    let mut config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(root_store)
+        .with_no_client_auth();
+
+    if insecure_skip_verify {
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(SkipServerVerification));
+    }
+
+    Arc::new(config)
+}
@@ -0,0 +1,162 @@
+use super::*;
+
+use stun::agent::*;
+use stun::message::*;
+use stun::xoraddr::*;
+use tokio::net::{TcpListener, TcpStream};
+
+#[test]
+fn test_frame_size() {
+    assert_eq!(frame_size(&[0x00, 0x01]), None, "header is incomplete");
+
+    // STUN binding request with 8 bytes of attributes
+    assert_eq!(frame_size(&[0x00, 0x01, 0x00, 0x08]), Some((28, 28)));
+
+    // ChannelData with 5 bytes of data is padded to 12 bytes
+    assert_eq!(frame_size(&[0x40, 0x00, 0x00, 0x05]), Some((12, 9)));
+
+    // ChannelData with aligned data has no padding
+    assert_eq!(frame_size(&[0x7F, 0xFF, 0x00, 0x04]), Some((8, 8)));
+}
+
+#[tokio::test]
+async fn test_stream_conn_framing() -> Result<(), Error> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let server_addr = listener.local_addr()?;
+
+    let client = StreamConn::from_tcp(TcpStream::connect(server_addr).await?)?;
+    let (server, _) = listener.accept().await?;
+    let server = StreamConn::from_tcp(server)?;
+
+    assert_eq!(client.local_addr().await?, server.remote_addr);
+    assert_eq!(server.local_addr().await?, client.remote_addr);
+
+    let mut request = Message::new();
+    request.build(&[Box::new(BINDING_REQUEST), Box::new(TransactionId::new())])?;
+    let channel_data = [0x40, 0x00, 0x00, 0x03, 0xAA, 0xBB, 0xCC];
+
+    // both messages are written back to back and must come out one per read
+    client.send(&request.raw).await?;
+    client.send_to(&channel_data, server_addr).await?;
+
+    let mut buf = vec![0_u8; 1500];
+    let (n, addr) = server.recv_from(&mut buf).await?;
+    assert_eq!(&buf[..n], &request.raw[..]);
+    assert_eq!(addr, client.local_addr);
+
+    let n = server.recv(&mut buf).await?;
+    assert_eq!(&buf[..n], &channel_data[..], "padding must be stripped");
+
+    let mut short = [0_u8; 4];
+    server.send(&request.raw).await?;
+    let result = client.recv(&mut short).await;
+    assert!(result.is_err(), "should fail with a short buffer");
+
+    // the message stays buffered after a failed read
+    let n = client.recv(&mut buf).await?;
+    assert_eq!(&buf[..n], &request.raw[..]);
+
+    drop(server);
+    let result = client.recv(&mut buf).await;
+    assert!(result.is_err(), "should fail once the remote end is closed");
+
+    Ok(())
+}
+
+/// Answers one binding request on `conn` with the address of the client, `remote`.
+async fn answer_binding(conn: &StreamConn, remote: SocketAddr) -> Result<(), Error> {
+    let mut buf = vec![0_u8; 1500];
+    let n = conn.recv(&mut buf).await?;
+    let mut request = Message::new();
+    request.raw = buf[..n].to_vec();
+    request.decode()?;
+
+    let mut response = Message::new();
+    response.build(&[
+        Box::new(request),
+        Box::new(BINDING_SUCCESS),
+        Box::new(XorMappedAddress {
+            ip: remote.ip(),
+            port: remote.port(),
+        }),
+    ])?;
+    conn.send(&response.raw).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_stream_conn_binding_request() -> Result<(), Error> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let server_addr = listener.local_addr()?;
+
+    tokio::spawn(async move {
+        let (stream, remote) = listener.accept().await?;
+        let conn = StreamConn::from_tcp(stream)?;
+        answer_binding(&conn, remote).await
+    });
+
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::new(StreamConn::from_tcp(
+        TcpStream::connect(server_addr).await?,
+    )?);
+    let local_addr = conn.local_addr().await?;
+
+    let addr =
+        crate::util::get_xormapped_addr(&conn, server_addr, std::time::Duration::from_secs(5))
+            .await?;
+    assert_eq!(addr.ip, local_addr.ip());
+    assert_eq!(addr.port, local_addr.port());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_stream_conn_tls_binding_request() -> Result<(), Error> {
+    // a stuns server with a self-signed certificate
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+    let server_config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![rustls::Certificate(cert.serialize_der().unwrap())],
+            rustls::PrivateKey(cert.serialize_private_key_der()),
+        )
+        .unwrap();
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let server_addr = listener.local_addr()?;
+
+    tokio::spawn(async move {
+        // the first client doesn't trust the certificate and aborts the handshake
+        let (stream, _) = listener.accept().await?;
+        assert!(acceptor.accept(stream).await.is_err());
+
+        let (stream, remote) = listener.accept().await?;
+        let local_addr = stream.local_addr()?;
+        let conn = StreamConn::new(acceptor.accept(stream).await?, local_addr, remote);
+        answer_binding(&conn, remote).await
+    });
+
+    let result =
+        StreamConn::from_tcp_with_tls(TcpStream::connect(server_addr).await?, "localhost", false)
+            .await;
+    assert!(
+        result.is_err(),
+        "the certificate is not signed by a trusted root"
+    );
+
+    let conn: Arc<dyn Conn + Send + Sync> = Arc::new(
+        StreamConn::from_tcp_with_tls(TcpStream::connect(server_addr).await?, "localhost", true)
+            .await?,
+    );
+    let local_addr = conn.local_addr().await?;
+
+    let addr =
+        crate::util::get_xormapped_addr(&conn, server_addr, std::time::Duration::from_secs(5))
+            .await?;
+    assert_eq!(addr.ip, local_addr.ip());
+    assert_eq!(addr.port, local_addr.port());
+
+    Ok(())
+}
@@ -5,12 +5,19 @@ use crate::errors::*;
 
 use util::Error;
 
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
 use std::convert::From;
 use std::fmt;
 
 /// The type of server used in the ice.URL structure.
 #[derive(PartialEq, Debug, Copy, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "lowercase")
+)]
 pub enum SchemeType {
     /// The URL represents a STUN server.
     Stun,
@@ -63,6 +70,11 @@ impl fmt::Display for SchemeType {
 
 /// The transport protocol type that is used in the `ice::url::Url` structure.
 #[derive(PartialEq, Debug, Copy, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "lowercase")
+)]
 pub enum ProtoType {
     /// The URL uses a UDP transport.
     Udp,
@@ -106,6 +118,7 @@ impl fmt::Display for ProtoType {
 
 /// Represents a STUN (rfc7064) or TURN (rfc7065) URL.
 #[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(default))]
 pub struct Url {
     pub scheme: SchemeType,
     pub host: String,
@@ -113,6 +126,9 @@ pub struct Url {
     pub username: String,
     pub password: String,
     pub proto: ProtoType,
+    /// Whether the URL gave the port. The DNS SRV records of URLs without one are looked up for
+    /// the servers to use, and `port` is the default of the scheme until then.
+    pub explicit_port: bool,
 }
 
 impl fmt::Display for Url {
@@ -235,6 +251,7 @@ impl Url {
             username: "".to_owned(),
             password: "".to_owned(),
             proto,
+            explicit_port: raw_parts.port().is_some(),
         })
     }
 
@@ -265,4 +282,13 @@ impl Url {
     pub fn is_secure(&self) -> bool {
         self.scheme == SchemeType::Stuns || self.scheme == SchemeType::Turns
     }
+
+    /// Returns the URL without the username and password, for keeping it around.
+    pub(crate) fn without_credentials(&self) -> Self {
+        Self {
+            username: String::new(),
+            password: String::new(),
+            ..self.clone()
+        }
+    }
 }
@@ -1,10 +1,13 @@
 #[cfg(test)]
 mod util_test;
 
-use crate::agent::agent_config::InterfaceFilterFn;
+use crate::agent::agent_config::{InterfaceFilterFn, IpFilterFn};
 use crate::errors::*;
+use crate::network_policy::*;
 use crate::network_type::*;
+use crate::socket_factory::*;
 
+use std::convert::TryFrom;
 use std::net::{IpAddr, SocketAddr};
 use stun::{agent::*, attributes::*, integrity::*, message::*, textattrs::*, xoraddr::*};
 
@@ -21,6 +24,12 @@ pub fn create_addr(_network: NetworkType, ip: IpAddr, port: u16) -> SocketAddr {
     SocketAddr::new(ip, port)
 }
 
+/// Whether `c` is an ice-char (RFC 8839 section 5.1), the characters of foundations, username
+/// fragments and passwords.
+pub const fn is_ice_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '+' || c == '/'
+}
+
 pub fn assert_inbound_username(m: &Message, expected_username: &str) -> Result<(), Error> {
     let mut username = Username::new(ATTR_USERNAME, String::new());
     username.get_from(m)?;
@@ -87,12 +96,83 @@ pub async fn stun_request(
     Ok(res)
 }
 
+/// Decodes the STUN message in `buf`, or returns `None` if it doesn't hold one.
+pub fn decode_stun_message(buf: &[u8]) -> Option<Message> {
+    if !is_message(buf) {
+        return None;
+    }
+    let mut m = Message::new();
+    m.unmarshal_binary(buf).ok()?;
+    Some(m)
+}
+
+/// Returns the number of the ERROR-CODE of `m`, which the stun crate keeps private.
+pub fn stun_error_code(m: &Message) -> Option<u16> {
+    let value = m.get(ATTR_ERROR_CODE).ok()?;
+    if value.len() < 4 {
+        return None;
+    }
+    Some(u16::from(value[2] & 0x07) * 100 + u16::from(value[3]))
+}
+
+/// Interface name prefixes of common VPN and tunnel drivers.
+const VPN_INTERFACE_PREFIXES: [&str; 8] = [
+    "tun",
+    "tap",
+    "utun",
+    "ppp",
+    "wg",
+    "ipsec",
+    "vpn",
+    "tailscale",
+];
+
+/// Guesses from its name whether an interface belongs to a VPN.
+pub fn is_vpn_interface(name: &str) -> bool {
+    let name = name.to_lowercase();
+    VPN_INTERFACE_PREFIXES
+        .iter()
+        .any(|prefix| name.starts_with(prefix))
+}
+
+/// A local address to gather host candidates from, along with what the `NetworkPolicy` thinks
+/// of its interface.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalInterfaceAddr {
+    pub ip: IpAddr,
+    /// Empty when the interface isn't known.
+    pub interface_name: String,
+    /// Identifies the interface, starting from 1.
+    pub network_id: u16,
+    pub interface_type: InterfaceType,
+    pub network_cost: u16,
+}
+
+impl LocalInterfaceAddr {
+    /// An address nothing is known about the interface of.
+    pub(crate) const fn unknown(ip: IpAddr) -> Self {
+        Self {
+            ip,
+            interface_name: String::new(),
+            network_id: 0,
+            interface_type: InterfaceType::Unknown,
+            network_cost: NETWORK_COST_UNKNOWN,
+        }
+    }
+}
+
+/// Returns the addresses to gather host candidates from, most preferred first: addresses of
+/// cheaper interfaces before the ones of more expensive interfaces, IPv6 before IPv4 (RFC 8421)
+/// and loopback addresses last. Otherwise the order of the interfaces is kept.
 pub async fn local_interfaces(
     vnet: &Arc<Net>,
     interface_filter: &Option<InterfaceFilterFn>,
+    ip_filter: &Option<IpFilterFn>,
+    include_loopback: bool,
+    network_policy: &(dyn NetworkPolicy + Send + Sync),
     network_types: &[NetworkType],
-) -> Vec<IpAddr> {
-    let mut ips = vec![];
+) -> Vec<LocalInterfaceAddr> {
+    let mut addrs = vec![];
     let interfaces = vnet.get_interfaces().await;
 
     let (mut ipv4requested, mut ipv6requested) = (false, false);
@@ -105,34 +185,109 @@ pub async fn local_interfaces(
         }
     }
 
-    for iface in interfaces {
+    for (index, iface) in interfaces.iter().enumerate() {
         if let Some(filter) = interface_filter {
             if !filter(iface.name()) {
                 continue;
             }
         }
 
+        let interface_type = network_policy.classify(iface.name());
+        let network_cost = network_policy
+            .cost(iface.name(), interface_type)
+            .min(NETWORK_COST_MAX);
         for ipnet in iface.addrs() {
             let ipaddr = ipnet.addr();
-            if !ipaddr.is_loopback()
+            if (include_loopback || !ipaddr.is_loopback())
                 && ((ipv4requested && ipaddr.is_ipv4()) || (ipv6requested && ipaddr.is_ipv6()))
+                && ip_filter.as_ref().map_or(true, |filter| filter(ipaddr))
             {
-                ips.push(ipaddr);
+                addrs.push(LocalInterfaceAddr {
+                    ip: ipaddr,
+                    interface_name: iface.name().to_owned(),
+                    network_id: u16::try_from(index + 1).unwrap_or(u16::MAX),
+                    interface_type,
+                    network_cost,
+                });
             }
         }
     }
 
-    ips
+    // stable, so that the interface order is kept within each group
+    addrs.sort_by_key(|addr| (addr.ip.is_loopback(), addr.network_cost, addr.ip.is_ipv4()));
+    addrs
+}
+
+/// Returns the configured bind addresses matching the requested network types, in the given
+/// order. Nothing is known about their interfaces.
+pub fn bind_interface_addrs(
+    bind_addresses: &[IpAddr],
+    network_types: &[NetworkType],
+) -> Vec<LocalInterfaceAddr> {
+    bind_addresses
+        .iter()
+        .filter(|ip| {
+            network_types
+                .iter()
+                .any(|typ| (typ.is_ipv4() && ip.is_ipv4()) || (typ.is_ipv6() && ip.is_ipv6()))
+        })
+        .map(|ip| LocalInterfaceAddr::unknown(*ip))
+        .collect()
+}
+
+/// Returns the local IP the host sends from to reach `remote_addr`.
+pub async fn local_ip_towards(net: &Arc<Net>, remote_addr: SocketAddr) -> Result<IpAddr, Error> {
+    let probe = net
+        .dail(remote_addr.is_ipv4(), &remote_addr.to_string())
+        .await?;
+    Ok(probe.local_addr().await?.ip())
+}
+
+/// Returns the interface `local_addr` is on, as `local_interfaces` describes it. The address a
+/// socket bound to the unspecified address sends from to `remote_addr` is looked up first.
+///
+/// Server reflexive and relay candidates take the network id, type and cost of their base from
+/// it, they send over the same link.
+pub async fn base_interface_addr(
+    net: &Arc<Net>,
+    network_policy: &(dyn NetworkPolicy + Send + Sync),
+    local_addr: SocketAddr,
+    remote_addr: SocketAddr,
+) -> LocalInterfaceAddr {
+    let ip = if local_addr.ip().is_unspecified() {
+        match local_ip_towards(net, remote_addr).await {
+            Ok(ip) => ip,
+            Err(_) => return LocalInterfaceAddr::unknown(local_addr.ip()),
+        }
+    } else {
+        local_addr.ip()
+    };
+
+    for (index, iface) in net.get_interfaces().await.iter().enumerate() {
+        if iface.addrs().iter().any(|ipnet| ipnet.addr() == ip) {
+            let interface_type = network_policy.classify(iface.name());
+            return LocalInterfaceAddr {
+                ip,
+                interface_name: iface.name().to_owned(),
+                network_id: u16::try_from(index + 1).unwrap_or(u16::MAX),
+                interface_type,
+                network_cost: network_policy
+                    .cost(iface.name(), interface_type)
+                    .min(NETWORK_COST_MAX),
+            };
+        }
+    }
+    LocalInterfaceAddr::unknown(ip)
 }
 
 pub async fn listen_udp_in_port_range(
-    vnet: &Arc<Net>,
+    socket_factory: &(dyn SocketFactory + Send + Sync),
     port_max: u16,
     port_min: u16,
     laddr: SocketAddr,
 ) -> Result<Arc<dyn Conn + Send + Sync>, Error> {
     if laddr.port() != 0 || (port_min == 0 && port_max == 0) {
-        return vnet.bind(laddr).await;
+        return socket_factory.bind_udp(laddr).await;
     }
     let i = if port_min == 0 { 1 } else { port_min };
     let j = if port_max == 0 { 0xFFFF } else { port_max };
@@ -144,7 +299,7 @@ pub async fn listen_udp_in_port_range(
     let mut port_current = port_start;
     loop {
         let laddr = SocketAddr::new(laddr.ip(), port_current);
-        match vnet.bind(laddr).await {
+        match socket_factory.bind_udp(laddr).await {
             Ok(c) => return Ok(c),
             Err(err) => log::debug!("failed to listen {}: {}", laddr, err),
         };
@@ -4,7 +4,29 @@ use super::*;
 async fn test_local_interfaces() -> Result<(), Error> {
     let vnet = Arc::new(Net::new(None));
     let interfaces = vnet.get_interfaces().await;
-    let ips = local_interfaces(&vnet, &None, &[NetworkType::Udp4, NetworkType::Udp6]).await;
+    let ips = local_interfaces(
+        &vnet,
+        &None,
+        &None,
+        false,
+        &DefaultNetworkPolicy,
+        &[NetworkType::Udp4, NetworkType::Udp6],
+    )
+    .await;
     log::info!("interfaces: {:?}, ips: {:?}", interfaces, ips);
     Ok(())
 }
+
+#[test]
+fn test_is_vpn_interface() {
+    for name in ["tun0", "utun3", "wg0", "ppp0", "tailscale0", "TAP-Windows"] {
+        assert!(is_vpn_interface(name), "{} should be a VPN interface", name);
+    }
+    for name in ["eth0", "en0", "wlan0", "lo"] {
+        assert!(
+            !is_vpn_interface(name),
+            "{} should not be a VPN interface",
+            name
+        );
+    }
+}